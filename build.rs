@@ -0,0 +1,5 @@
+fn main() {
+    // Only builds with the gRPC service need protoc
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/sabi.proto").expect("failed to compile proto/sabi.proto");
+}
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE songs ADD COLUMN duration FLOAT;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE songs
+  DROP COLUMN artist,
+  DROP COLUMN album,
+  DROP COLUMN track_number,
+  DROP COLUMN year;
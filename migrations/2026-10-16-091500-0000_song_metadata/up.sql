@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE songs
+  ADD COLUMN artist VARCHAR(255),
+  ADD COLUMN album VARCHAR(255),
+  ADD COLUMN track_number INT,
+  ADD COLUMN year INT;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_songs_checksum;
+ALTER TABLE songs DROP COLUMN checksum;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE songs ADD COLUMN checksum VARCHAR(64);
+
+CREATE UNIQUE INDEX idx_songs_checksum ON songs(checksum);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+CREATE TABLE matches (
+  id SERIAL PRIMARY KEY,
+  song_id INT NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+  score INT NOT NULL,
+  confidence REAL NOT NULL,
+  source VARCHAR(16) NOT NULL,
+  input VARCHAR(1024),
+  matched_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_matches_matched_at ON matches(matched_at);
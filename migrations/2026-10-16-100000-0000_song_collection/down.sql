@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_songs_collection_checksum;
+CREATE UNIQUE INDEX idx_songs_checksum ON songs(checksum);
+ALTER TABLE songs DROP COLUMN collection;
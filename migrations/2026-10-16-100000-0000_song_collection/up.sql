@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE songs ADD COLUMN collection VARCHAR(64) NOT NULL DEFAULT 'default';
+
+-- The same file may be ingested once per collection
+DROP INDEX idx_songs_checksum;
+CREATE UNIQUE INDEX idx_songs_collection_checksum ON songs(collection, checksum);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP VIEW all_fingerprints;
+DROP TABLE fingerprint_compact;
+DROP TABLE collections;
@@ -0,0 +1,27 @@
+-- Your SQL goes here
+
+-- Hash layout used by each collection: 64 (BIGINT) or 32 (compact INT)
+CREATE TABLE collections (
+  name VARCHAR(64) PRIMARY KEY,
+  hash_bits SMALLINT NOT NULL DEFAULT 64,
+  created_at TIMESTAMP DEFAULT NOW()
+);
+
+INSERT INTO collections (name) VALUES ('default');
+INSERT INTO collections (name) SELECT DISTINCT collection FROM songs ON CONFLICT DO NOTHING;
+
+-- Fingerprints of 32-bit collections, at roughly half the row size
+CREATE TABLE fingerprint_compact (
+  hash INT NOT NULL,
+  absolute_time_offset REAL NOT NULL,
+  song_id INT NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+  PRIMARY KEY (song_id, absolute_time_offset, hash)
+);
+
+CREATE INDEX idx_fingerprint_compact_hash ON fingerprint_compact(hash);
+
+-- Both fingerprint tables, for statistics and exports
+CREATE VIEW all_fingerprints AS
+  SELECT hash, absolute_time_offset, song_id FROM fingerprint
+  UNION ALL
+  SELECT hash, absolute_time_offset, song_id FROM fingerprint_compact;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Parameter set the fingerprints of each song were generated with
+ALTER TABLE songs ADD COLUMN fingerprint_version INT NOT NULL DEFAULT 1;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+-- Degenerate hashes found in too many songs, skipped by every lookup.
+-- `hash` is the value the matcher queries, per hash layout.
+CREATE TABLE denied_hashes (
+  hash BIGINT NOT NULL,
+  hash_bits SMALLINT NOT NULL,
+  song_count INT NOT NULL,
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (hash_bits, hash)
+);
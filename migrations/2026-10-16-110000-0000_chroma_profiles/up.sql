@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+-- Beat-synchronous chroma of each song, for cover matching. `profile` holds
+-- `beats` records of 13 little-endian REALs: the beat's start in seconds,
+-- then its 12 pitch classes.
+CREATE TABLE chroma_profiles (
+  song_id INT PRIMARY KEY REFERENCES songs(id) ON DELETE CASCADE,
+  tempo REAL NOT NULL,
+  beats INT NOT NULL,
+  profile BYTEA NOT NULL
+);
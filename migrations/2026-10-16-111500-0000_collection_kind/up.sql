@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- 'songs', or 'short_reference' for jingles and adverts fingerprinted densely
+ALTER TABLE collections
+  ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'songs';
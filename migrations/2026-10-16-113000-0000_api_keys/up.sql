@@ -0,0 +1,12 @@
+-- Your SQL goes here
+
+-- Keys clients of `serve` authenticate with. Only the SHA-256 of a key is
+-- stored; `scopes` lists what it may do, comma-separated (`recognise`,
+-- `ingest`).
+CREATE TABLE api_keys (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(64) NOT NULL UNIQUE,
+  key_hash CHAR(64) NOT NULL UNIQUE,
+  scopes VARCHAR(32) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
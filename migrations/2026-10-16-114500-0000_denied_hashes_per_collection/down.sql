@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE denied_hashes_global (
+  hash BIGINT NOT NULL,
+  hash_bits SMALLINT NOT NULL,
+  song_count INT NOT NULL,
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (hash_bits, hash)
+);
+
+INSERT INTO denied_hashes_global (hash, hash_bits, song_count, created_at)
+SELECT hash, hash_bits, MAX(song_count), MIN(created_at)
+FROM denied_hashes
+GROUP BY hash_bits, hash;
+
+DROP TABLE denied_hashes;
+ALTER TABLE denied_hashes_global RENAME TO denied_hashes;
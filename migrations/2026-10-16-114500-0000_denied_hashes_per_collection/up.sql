@@ -0,0 +1,20 @@
+-- Your SQL goes here
+
+-- Deny-lists are kept per collection, as a hash common in one catalog can be
+-- rare in another. Existing entries apply to every collection of their layout.
+CREATE TABLE denied_hashes_per_collection (
+  collection VARCHAR(64) NOT NULL,
+  hash BIGINT NOT NULL,
+  hash_bits SMALLINT NOT NULL,
+  song_count INT NOT NULL,
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (collection, hash)
+);
+
+INSERT INTO denied_hashes_per_collection (collection, hash, hash_bits, song_count, created_at)
+SELECT collections.name, denied_hashes.hash, denied_hashes.hash_bits, denied_hashes.song_count, denied_hashes.created_at
+FROM denied_hashes
+JOIN collections ON collections.hash_bits = denied_hashes.hash_bits;
+
+DROP TABLE denied_hashes;
+ALTER TABLE denied_hashes_per_collection RENAME TO denied_hashes;
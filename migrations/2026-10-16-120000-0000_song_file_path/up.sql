@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+-- Where the song was ingested from, for --play-from-match; NULL for uploads
+-- and imported archives
+ALTER TABLE songs
+  ADD COLUMN file_path TEXT;
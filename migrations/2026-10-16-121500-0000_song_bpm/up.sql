@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+-- Estimated tempo in beats per minute; NULL for songs ingested before it
+-- was estimated and for audio without a steady beat
+ALTER TABLE songs
+  ADD COLUMN bpm REAL;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+-- Estimated key, e.g. 'F# minor'; NULL for songs ingested before it was
+-- estimated
+ALTER TABLE songs
+  ADD COLUMN musical_key VARCHAR(16);
@@ -0,0 +1,103 @@
+#ifndef SABI_H
+#define SABI_H
+
+/* Generated by cbindgen from sabi-ffi/src/lib.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// An open index file and the settings queries are fingerprinted with
+typedef struct SabiIndex SabiIndex;
+
+// Hash of an anchor and target peak pair, and the anchor time in seconds
+typedef struct SabiFingerprint {
+  uint64_t hash;
+  float anchor_time;
+} SabiFingerprint;
+
+// A song the query matched. `title` and `artist` belong to the index and
+// stay valid until it is closed; `artist` is NULL when unknown.
+typedef struct SabiMatch {
+  uint32_t song_id;
+  // Hash hits in the winning offset bin
+  uint32_t score;
+  // Normalized 0..1 confidence
+  float confidence;
+  // Position in the song where the query starts, in seconds
+  float time_offset;
+  // `time_offset` refined to a fraction of a frame, for syncing playback
+  float refined_offset;
+  // Playback speed of the query relative to the song
+  float speed;
+  const char *title;
+  const char *artist;
+} SabiMatch;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Open the index file at `path`. Queries use the settings of the config file
+// at `config_path`, or of `sabi.toml` in the working directory if it exists
+// when NULL. Returns NULL on failure; close the index with
+// [`sabi_close_index`].
+//
+// # Safety
+// `path` and `config_path` must be NULL or NUL-terminated strings.
+struct SabiIndex *sabi_open_index(const char *path, const char *config_path);
+
+// Close an index from [`sabi_open_index`]. NULL is ignored.
+//
+// # Safety
+// `index` must be NULL or an index not closed before.
+void sabi_close_index(struct SabiIndex *index);
+
+// Fingerprint `frames` frames of interleaved `channels`-channel samples in
+// -1..1 at `sample_rate`, hashed like the songs of `collection` in `index`
+// (NULL for `default`). These are the fingerprints [`sabi_match_buffer`]
+// looks up. Writes at most `capacity` of them to `out` and returns how many
+// there are in all, so a short buffer can be grown and the call repeated.
+//
+// # Safety
+// `index` must be open, `collection` NULL or a NUL-terminated string,
+// `samples` valid for `frames * channels` reads and `out` for `capacity`
+// writes.
+int64_t sabi_fingerprint_buffer(const struct SabiIndex *index,
+                                const char *collection,
+                                const float *samples,
+                                size_t frames,
+                                uint32_t sample_rate,
+                                uint16_t channels,
+                                struct SabiFingerprint *out,
+                                size_t capacity);
+
+// Match `frames` frames of interleaved `channels`-channel samples in -1..1
+// at `sample_rate` against the songs of `collection` in `index` (NULL for
+// `default`). Writes the best matches, at most `capacity` and best first,
+// to `out` and returns how many were written.
+//
+// # Safety
+// `index` must be open, `collection` NULL or a NUL-terminated string,
+// `samples` valid for `frames * channels` reads and `out` for `capacity`
+// writes.
+int64_t sabi_match_buffer(const struct SabiIndex *index,
+                          const char *collection,
+                          const float *samples,
+                          size_t frames,
+                          uint32_t sample_rate,
+                          uint16_t channels,
+                          struct SabiMatch *out,
+                          size_t capacity);
+
+// Why the last failing call on this thread failed, NULL if none did. Valid
+// until the next failing call on this thread.
+const char *sabi_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SABI_H */
@@ -0,0 +1,284 @@
+//! C API of Sabi, for embedding the matcher in media players and mobile apps
+//! that ship a prebuilt index (`sabi export --format index`) instead of a
+//! database. `include/sabi.h` is generated from this file by `build.rs`.
+//!
+//! Failing calls return NULL or -1, and [`sabi_last_error`] tells why.
+
+use sabi::audio_processor::AudioProcessor;
+use sabi::config::Config;
+use sabi::db::{
+    mmap_index::MmapIndex,
+    storage::{FingerprintStore, retain_songs},
+};
+use sabi::dsp::Pipeline;
+use sabi::fingerprint::{FingerprintInfo, HashScheme, expand_query, vote_best_matches};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An open index file and the settings queries are fingerprinted with
+pub struct SabiIndex {
+    index: MmapIndex,
+    config: Config,
+    /// Title and artist of each song, owning the strings of [`SabiMatch`]
+    names: HashMap<i32, (CString, Option<CString>)>,
+}
+
+/// Hash of an anchor and target peak pair, and the anchor time in seconds
+#[repr(C)]
+pub struct SabiFingerprint {
+    pub hash: u64,
+    pub anchor_time: f32,
+}
+
+/// A song the query matched. `title` and `artist` belong to the index and
+/// stay valid until it is closed; `artist` is NULL when unknown.
+#[repr(C)]
+pub struct SabiMatch {
+    pub song_id: u32,
+    /// Hash hits in the winning offset bin
+    pub score: u32,
+    /// Normalized 0..1 confidence
+    pub confidence: f32,
+    /// Position in the song where the query starts, in seconds
+    pub time_offset: f32,
+    /// `time_offset` refined to a fraction of a frame, for syncing playback
+    pub refined_offset: f32,
+    /// Playback speed of the query relative to the song
+    pub speed: f32,
+    pub title: *const c_char,
+    pub artist: *const c_char,
+}
+
+/// Open the index file at `path`. Queries use the settings of the config file
+/// at `config_path`, or of `sabi.toml` in the working directory if it exists
+/// when NULL. Returns NULL on failure; close the index with
+/// [`sabi_close_index`].
+///
+/// # Safety
+/// `path` and `config_path` must be NULL or NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sabi_open_index(
+    path: *const c_char,
+    config_path: *const c_char,
+) -> *mut SabiIndex {
+    guard(ptr::null_mut(), || {
+        let path = unsafe { optional_str(path)? }.ok_or("path is NULL")?;
+        let config = Config::load(unsafe { optional_str(config_path)? }, &[])?;
+        let index = MmapIndex::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+        let names = index
+            .songs()
+            .iter()
+            .map(|(&id, song)| {
+                let artist = song.artist.as_deref().map(c_string);
+                (id, (c_string(&song.title), artist))
+            })
+            .collect();
+        Ok(Box::into_raw(Box::new(SabiIndex {
+            index,
+            config,
+            names,
+        })))
+    })
+}
+
+/// Close an index from [`sabi_open_index`]. NULL is ignored.
+///
+/// # Safety
+/// `index` must be NULL or an index not closed before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sabi_close_index(index: *mut SabiIndex) {
+    if !index.is_null() {
+        drop(unsafe { Box::from_raw(index) });
+    }
+}
+
+/// Fingerprint `frames` frames of interleaved `channels`-channel samples in
+/// -1..1 at `sample_rate`, hashed like the songs of `collection` in `index`
+/// (NULL for `default`). These are the fingerprints [`sabi_match_buffer`]
+/// looks up. Writes at most `capacity` of them to `out` and returns how many
+/// there are in all, so a short buffer can be grown and the call repeated.
+///
+/// # Safety
+/// `index` must be open, `collection` NULL or a NUL-terminated string,
+/// `samples` valid for `frames * channels` reads and `out` for `capacity`
+/// writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sabi_fingerprint_buffer(
+    index: *const SabiIndex,
+    collection: *const c_char,
+    samples: *const f32,
+    frames: usize,
+    sample_rate: u32,
+    channels: u16,
+    out: *mut SabiFingerprint,
+    capacity: usize,
+) -> i64 {
+    guard(-1, || {
+        let index = unsafe { index.as_ref() }.ok_or("index is NULL")?;
+        let collection = unsafe { optional_str(collection)? }.unwrap_or("default");
+        let samples = unsafe { interleaved(samples, frames, channels)? };
+        let (fingerprints, _) =
+            query_fingerprints(index, collection, samples, sample_rate, channels)?;
+
+        for (i, fingerprint) in fingerprints.iter().take(capacity).enumerate() {
+            let fingerprint = SabiFingerprint {
+                hash: fingerprint.hash,
+                anchor_time: fingerprint.abs_anchor_tm_offset,
+            };
+            unsafe { out.add(i).write(fingerprint) };
+        }
+        Ok(fingerprints.len() as i64)
+    })
+}
+
+/// Match `frames` frames of interleaved `channels`-channel samples in -1..1
+/// at `sample_rate` against the songs of `collection` in `index` (NULL for
+/// `default`). Writes the best matches, at most `capacity` and best first,
+/// to `out` and returns how many were written.
+///
+/// # Safety
+/// `index` must be open, `collection` NULL or a NUL-terminated string,
+/// `samples` valid for `frames * channels` reads and `out` for `capacity`
+/// writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sabi_match_buffer(
+    index: *const SabiIndex,
+    collection: *const c_char,
+    samples: *const f32,
+    frames: usize,
+    sample_rate: u32,
+    channels: u16,
+    out: *mut SabiMatch,
+    capacity: usize,
+) -> i64 {
+    guard(-1, || {
+        let index = unsafe { index.as_ref() }.ok_or("index is NULL")?;
+        let collection = unsafe { optional_str(collection)? }.unwrap_or("default");
+        let samples = unsafe { interleaved(samples, frames, channels)? };
+        let (fingerprints, scheme) =
+            query_fingerprints(index, collection, samples, sample_rate, channels)?;
+        let fingerprints = expand_query(fingerprints, scheme, &index.config.matching);
+
+        let hashes: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+        let mut matches = index
+            .index
+            .fetch_matches_grouped_by_hash(&hashes)
+            .map_err(|e| e.to_string())?;
+        // Songs of another fingerprint version cannot match
+        let version = index.config.fingerprint_version();
+        let song_ids = index.index.song_ids_at_version(collection, version);
+        retain_songs(&mut matches, &song_ids);
+        let results = vote_best_matches(
+            &fingerprints,
+            &matches,
+            Some(song_ids.len()),
+            capacity,
+            &index.config.matching,
+        );
+
+        for (i, result) in results.iter().take(capacity).enumerate() {
+            let (title, artist) = match index.names.get(&(result.song_id as i32)) {
+                Some((title, artist)) => (
+                    title.as_ptr(),
+                    artist.as_ref().map_or(ptr::null(), |a| a.as_ptr()),
+                ),
+                None => (ptr::null(), ptr::null()),
+            };
+            let matched = SabiMatch {
+                song_id: result.song_id,
+                score: result.score as u32,
+                confidence: result.confidence,
+                time_offset: result.time_offset,
+                refined_offset: result.refined_offset,
+                speed: result.speed,
+                title,
+                artist,
+            };
+            unsafe { out.add(i).write(matched) };
+        }
+        Ok(results.len().min(capacity) as i64)
+    })
+}
+
+/// Why the last failing call on this thread failed, NULL if none did. Valid
+/// until the next failing call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn sabi_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|error| error.as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Fingerprints of a query in the hash scheme of `collection`, through the
+/// same pipeline as `sabi --match`
+fn query_fingerprints(
+    index: &SabiIndex,
+    collection: &str,
+    samples: &[f32],
+    sample_rate: u32,
+    channels: u16,
+) -> Result<(Vec<FingerprintInfo>, HashScheme), String> {
+    if sample_rate == 0 {
+        return Err("sample_rate must be positive".to_string());
+    }
+    let scheme = index.index.collection_hash_scheme(collection);
+    let mono =
+        AudioProcessor::from_config(&index.config.decode).to_mono(samples, channels as usize);
+    Ok((
+        Pipeline::for_query(&index.config, scheme).fingerprints(mono, sample_rate),
+        scheme,
+    ))
+}
+
+/// Run `f`, turning its error or panic into `fallback` and the message of
+/// [`sabi_last_error`], as neither may cross into C
+fn guard<T>(fallback: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    let error = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(error)) => error,
+        Err(_) => "internal error".to_string(),
+    };
+    LAST_ERROR.set(Some(c_string(&error)));
+    fallback
+}
+
+/// # Safety
+/// `s` must be NULL or a NUL-terminated string.
+unsafe fn optional_str<'a>(s: *const c_char) -> Result<Option<&'a str>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map(Some)
+        .map_err(|_| "strings must be UTF-8".to_string())
+}
+
+/// # Safety
+/// `samples` must be valid for `frames * channels` reads.
+unsafe fn interleaved<'a>(
+    samples: *const f32,
+    frames: usize,
+    channels: u16,
+) -> Result<&'a [f32], String> {
+    if channels == 0 {
+        return Err("channels must be positive".to_string());
+    }
+    if frames == 0 {
+        return Ok(&[]);
+    }
+    if samples.is_null() {
+        return Err("samples is NULL".to_string());
+    }
+    Ok(unsafe { std::slice::from_raw_parts(samples, frames * channels as usize) })
+}
+
+/// `s` as a C string, dropping any NUL bytes
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
@@ -0,0 +1,95 @@
+//! WebAssembly bindings of the DSP pipeline, for browser clients that
+//! fingerprint audio themselves and send only the fingerprints to
+//! `sabi serve`. Build with `wasm-pack build sabi-wasm --target web`.
+
+use sabi::audio_processor::AudioProcessor;
+use sabi::config::Config;
+use sabi::fingerprint::HashScheme;
+use sabi::fpfile::FingerprintFile;
+use wasm_bindgen::prelude::*;
+
+/// Fingerprints audio with the settings of a server
+#[wasm_bindgen]
+pub struct Fingerprinter {
+    config: Config,
+    scheme: HashScheme,
+}
+
+#[wasm_bindgen]
+impl Fingerprinter {
+    /// `config` is the text of the server's `sabi.toml`, the built-in
+    /// defaults when undefined. `hashBits` is that of the collection matched
+    /// against: 64 (the default) or 32.
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: Option<String>, hash_bits: Option<i16>) -> Result<Fingerprinter, JsError> {
+        let config = match config {
+            Some(text) => Config::from_toml(&text).map_err(|e| JsError::new(&e))?,
+            None => Config::default(),
+        };
+        let scheme = match hash_bits {
+            Some(bits) => HashScheme::from_bits(bits)
+                .ok_or_else(|| JsError::new(&format!("unsupported hash width {}", bits)))?,
+            None => HashScheme::default(),
+        };
+        Ok(Fingerprinter { config, scheme })
+    }
+
+    /// Fingerprint `samples`, a Float32Array of interleaved `channels`-channel
+    /// audio in -1..1 at `sampleRate`, e.g. from an `AudioBuffer`
+    pub fn fingerprint(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        channels: usize,
+    ) -> Result<Fingerprints, JsError> {
+        if sample_rate == 0 || channels == 0 {
+            return Err(JsError::new("sampleRate and channels must be positive"));
+        }
+        let mono = AudioProcessor::new().to_mono(samples, channels);
+        Ok(Fingerprints {
+            file: FingerprintFile::from_samples(&mono, sample_rate, self.scheme, &self.config),
+        })
+    }
+}
+
+/// The fingerprints of one recording
+#[wasm_bindgen]
+pub struct Fingerprints {
+    file: FingerprintFile,
+}
+
+#[wasm_bindgen]
+impl Fingerprints {
+    /// Hashes, as a BigUint64Array
+    #[wasm_bindgen(getter)]
+    pub fn hashes(&self) -> Vec<u64> {
+        self.file.fingerprints.iter().map(|fp| fp.hash).collect()
+    }
+
+    /// Anchor time of each hash in seconds, as a Float32Array
+    #[wasm_bindgen(getter, js_name = anchorTimes)]
+    pub fn anchor_times(&self) -> Vec<f32> {
+        self.file
+            .fingerprints
+            .iter()
+            .map(|fp| fp.abs_anchor_tm_offset)
+            .collect()
+    }
+
+    /// Version of the fingerprinting settings, which the server must share
+    #[wasm_bindgen(getter, js_name = fingerprintVersion)]
+    pub fn fingerprint_version(&self) -> i32 {
+        self.file.fingerprint_version
+    }
+
+    /// The fingerprints as a `.fp` file, which `POST /recognise` takes in
+    /// place of audio
+    #[wasm_bindgen(js_name = toFile)]
+    pub fn to_file(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.file
+            .write_to(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+}
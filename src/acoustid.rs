@@ -0,0 +1,213 @@
+use crate::audio_processor::AudioProcessor;
+use crate::chromaprint;
+use crate::config::Config;
+use crate::http::{curl_available, post_form};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+const LOOKUP_URL: &str = "https://api.acoustid.org/v2/lookup";
+/// Metadata requested along with each match
+const LOOKUP_META: &str = "recordings releasegroups";
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    error: Option<LookupError>,
+}
+
+#[derive(Deserialize)]
+struct LookupError {
+    message: String,
+}
+
+/// An AcoustID track whose fingerprint resembles the query
+#[derive(Deserialize)]
+pub struct LookupResult {
+    pub id: String,
+    pub score: f32,
+    #[serde(default)]
+    pub recordings: Vec<Recording>,
+}
+
+/// A MusicBrainz recording linked to an AcoustID track
+#[derive(Deserialize)]
+pub struct Recording {
+    pub id: String,
+    title: Option<String>,
+    duration: Option<f64>,
+    #[serde(default)]
+    artists: Vec<Artist>,
+    #[serde(default)]
+    releasegroups: Vec<ReleaseGroup>,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    name: String,
+    joinphrase: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseGroup {
+    title: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// `sabi identify`: fingerprint `file_name` with Chromaprint, look it up in
+/// the AcoustID web service and print the MusicBrainz recordings it matches,
+/// for songs that are not in the local library
+pub fn run_identify(file_name: &str, api_key: &str, config: &Config) {
+    if !curl_available() {
+        eprintln!("❌ identify needs curl on the PATH to reach AcoustID");
+        return;
+    }
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let (samples, sample_rate) = match audio_processor.decode_file(file_name) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("❌ Cannot decode {}: {}", file_name, e);
+            return;
+        }
+    };
+    let results = match lookup(&samples, sample_rate, api_key) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return;
+        }
+    };
+    if results.is_empty() {
+        println!("No match in AcoustID");
+        return;
+    }
+
+    for result in results {
+        if result.recordings.is_empty() {
+            println!(
+                "🔎 AcoustID track {} (score={:.2}) has no MusicBrainz metadata",
+                result.id, result.score
+            );
+            continue;
+        }
+        for recording in result.recordings {
+            println!(
+                "🎵 {} - {} (score={:.2})",
+                artist_credit(&recording.artists),
+                recording.title.as_deref().unwrap_or("<untitled>"),
+                result.score
+            );
+            if let Some(duration) = recording.duration {
+                println!("   Duration: {:.0}s", duration);
+            }
+            let releases: Vec<String> = recording
+                .releasegroups
+                .iter()
+                .map(|group| match &group.kind {
+                    Some(kind) => format!("{} ({})", group.title, kind),
+                    None => group.title.clone(),
+                })
+                .collect();
+            if !releases.is_empty() {
+                println!("   Releases: {}", releases.join(", "));
+            }
+            println!("   https://musicbrainz.org/recording/{}", recording.id);
+        }
+    }
+}
+
+/// Look mono `samples` up in AcoustID by the Chromaprint fingerprint of
+/// their first [`chromaprint::MAX_DURATION_SECS`], best match first. Needs
+/// `curl`, see [`crate::http`].
+pub fn lookup(
+    samples: &[f32],
+    sample_rate: u32,
+    api_key: &str,
+) -> Result<Vec<LookupResult>, String> {
+    let duration = samples.len() as f32 / sample_rate as f32;
+    let fingerprint = chromaprint_samples(samples, sample_rate)?;
+    let encoded = chromaprint::encode(&fingerprint);
+    info!(
+        "Looking up a Chromaprint fingerprint of {} frames",
+        fingerprint.len()
+    );
+    debug!("Fingerprint: {}", encoded);
+
+    let duration_secs = (duration.round() as u32).to_string();
+    let response: LookupResponse = post_form(
+        LOOKUP_URL,
+        &[
+            ("client", api_key),
+            ("meta", LOOKUP_META),
+            ("duration", &duration_secs),
+            ("fingerprint", &encoded),
+        ],
+    )
+    .map_err(|e| format!("AcoustID lookup failed: {}", e))?;
+    if response.status != "ok" {
+        let message = response.error.map_or(response.status, |e| e.message);
+        return Err(format!("AcoustID lookup failed: {}", message));
+    }
+
+    let mut results = response.results;
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(results)
+}
+
+/// `sabi fingerprint --chromaprint`: print the Chromaprint fingerprint of
+/// `file_name` in the format of `fpcalc`
+pub fn print_chromaprint(file_name: &str, config: &Config) {
+    match chromaprint_file(file_name, config) {
+        Ok((fingerprint, duration)) => {
+            println!("DURATION={:.0}", duration);
+            println!("FINGERPRINT={}", chromaprint::encode(&fingerprint));
+        }
+        Err(e) => eprintln!("❌ {}", e),
+    }
+}
+
+/// Decode `file_name` and fingerprint it with Chromaprint. Also returns
+/// the duration of the whole file, in seconds.
+fn chromaprint_file(file_name: &str, config: &Config) -> Result<(Vec<u32>, f32), String> {
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let (samples, sample_rate) = audio_processor
+        .decode_file(file_name)
+        .map_err(|e| format!("Cannot decode {}: {}", file_name, e))?;
+    let duration = samples.len() as f32 / sample_rate as f32;
+    let fingerprint =
+        chromaprint_samples(&samples, sample_rate).map_err(|e| format!("{}: {}", file_name, e))?;
+    Ok((fingerprint, duration))
+}
+
+/// Chromaprint fingerprint of the first [`chromaprint::MAX_DURATION_SECS`]
+/// of `samples`
+fn chromaprint_samples(samples: &[f32], sample_rate: u32) -> Result<Vec<u32>, String> {
+    let analyzed = samples
+        .len()
+        .min((chromaprint::MAX_DURATION_SECS * sample_rate as f32) as usize);
+    let fingerprint = chromaprint::fingerprint(&samples[..analyzed], sample_rate);
+    if fingerprint.is_empty() {
+        return Err("audio is too short to fingerprint".to_string());
+    }
+    Ok(fingerprint)
+}
+
+/// Artist names joined as MusicBrainz credits them, e.g. "A feat. B"
+fn artist_credit(artists: &[Artist]) -> String {
+    if artists.is_empty() {
+        return "<unknown artist>".to_string();
+    }
+    let mut credit = String::new();
+    for (i, artist) in artists.iter().enumerate() {
+        credit.push_str(&artist.name);
+        match &artist.joinphrase {
+            Some(join) => credit.push_str(join),
+            None if i + 1 < artists.len() => credit.push_str(", "),
+            None => {}
+        }
+    }
+    credit
+}
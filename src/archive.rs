@@ -0,0 +1,270 @@
+use crate::db::{
+    bindings::NewSong,
+    connector::{CollectionKind, DB},
+    lmdb_store::LmdbStore,
+    mmap_index::MmapIndex,
+    storage::{StoreError, store_song},
+};
+use crate::encoding::{
+    read_array, read_f64, read_i32, read_i64, read_opt, read_str, read_u32, write_f64, write_i32,
+    write_i64, write_opt, write_str, write_u32,
+};
+use crate::fingerprint::{FingerprintInfo, HashScheme};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File signature of a library archive
+const MAGIC: &[u8; 4] = b"SABI";
+/// Bumped whenever the record layout changes
+const FORMAT_VERSION: u16 = 4;
+/// Songs in version 1 archives predate collections
+const DEFAULT_COLLECTION: &str = "default";
+/// Songs in archives before version 4 predate fingerprint versioning
+const LEGACY_FINGERPRINT_VERSION: i32 = 1;
+const ZSTD_LEVEL: i32 = 9;
+
+// Archive layout: MAGIC, FORMAT_VERSION (u16), then a zstd stream holding
+//   u32 song count, and per song:
+//     title, created_at (µs since epoch), duration, artist, album,
+//     track_number, year, checksum, collection (since version 2),
+//     u32 hash bits of the collection (since version 3),
+//     i32 fingerprint version (since version 4),
+//     u32 fingerprint count, then (u64 hash, f32 anchor time) pairs
+// Integers and floats are little-endian, strings carry a u32 byte length and
+// optional fields a 0/1 presence byte.
+
+/// What `export` writes
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    /// Compressed archive that `import` loads into another library
+    Archive,
+    /// Read-only index file for `--match --index`, no database needed
+    Index,
+}
+
+/// Write every song (or only those in `collection`) and its fingerprints to
+/// `out_path` in the given format
+pub fn export_library(out_path: &str, format: ExportFormat, collection: Option<&str>) {
+    if LmdbStore::configured() {
+        eprintln!("❌ export reads the Postgres fingerprint table, unset FINGERPRINT_LMDB_PATH");
+        return;
+    }
+
+    let db = DB::new();
+    let written = match format {
+        ExportFormat::Archive => write_archive(&db, out_path, collection),
+        ExportFormat::Index => write_index(&db, out_path, collection),
+    };
+    match written {
+        Ok((songs, fingerprints)) => println!(
+            "✅ Exported {} song(s) and {} fingerprint(s) to {}",
+            songs, fingerprints, out_path
+        ),
+        Err(e) => eprintln!("❌ Export failed: {}", e),
+    }
+}
+
+/// Load the songs of an archive written by [`export_library`], into their
+/// original collections or all into `collection`. Songs whose checksum is
+/// already in the target collection are skipped; ids are reassigned.
+pub fn import_library(in_path: &str, collection: Option<&str>) {
+    let db = DB::new();
+    match read_archive(&db, in_path, collection) {
+        Ok((imported, skipped)) => println!(
+            "✅ Imported {} song(s), skipped {} already in the library",
+            imported, skipped
+        ),
+        Err(e) => eprintln!("❌ Import failed: {}", e),
+    }
+}
+
+fn write_archive(db: &DB, out_path: &str, collection: Option<&str>) -> io::Result<(usize, usize)> {
+    let songs = db.fetch_all_songs(collection);
+    let schemes = db.collection_hash_schemes();
+
+    let mut file = BufWriter::new(File::create(out_path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    let mut w = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+    write_u32(&mut w, songs.len() as u32)?;
+
+    let mut total_fingerprints = 0;
+    for song in &songs {
+        write_str(&mut w, &song.title)?;
+        write_opt(&mut w, song.created_at.map(to_unix_micros), write_i64)?;
+        write_opt(&mut w, song.duration, write_f64)?;
+        write_opt(&mut w, song.artist.as_deref(), write_str)?;
+        write_opt(&mut w, song.album.as_deref(), write_str)?;
+        write_opt(&mut w, song.track_number, write_i32)?;
+        write_opt(&mut w, song.year, write_i32)?;
+        write_opt(&mut w, song.checksum.as_deref(), write_str)?;
+        write_str(&mut w, &song.collection)?;
+        let scheme = schemes.get(&song.collection).copied().unwrap_or_default();
+        write_u32(&mut w, scheme.bits() as u32)?;
+        write_i32(&mut w, song.fingerprint_version)?;
+
+        let fingerprints = db.fetch_fingerprints(song.id);
+        write_u32(&mut w, fingerprints.len() as u32)?;
+        for (hash, anchor_time) in &fingerprints {
+            w.write_all(&(*hash as u64).to_le_bytes())?;
+            w.write_all(&(*anchor_time as f32).to_le_bytes())?;
+        }
+        total_fingerprints += fingerprints.len();
+    }
+
+    w.finish()?.flush()?;
+    Ok((songs.len(), total_fingerprints))
+}
+
+fn write_index(db: &DB, out_path: &str, collection: Option<&str>) -> io::Result<(usize, usize)> {
+    let songs = db.fetch_all_songs(collection);
+    let schemes = db.collection_hash_schemes();
+
+    // Hashes deny-listed for a song's collection are left out, as lookups
+    // would skip them anyway
+    let mut denied = HashMap::new();
+    let mut entries = Vec::new();
+    for song in &songs {
+        let scheme = schemes.get(&song.collection).copied().unwrap_or_default();
+        let denied = denied
+            .entry(song.collection.as_str())
+            .or_insert_with(|| db.denied_hashes(&song.collection));
+        // Compact hashes are denied by the bit pattern of their low 32 bits
+        let is_denied = |hash: i64| match scheme {
+            HashScheme::Wide64 => denied.contains(&(hash as u64)),
+            HashScheme::Compact32 => denied.contains(&(hash as u32 as u64)),
+        };
+        entries.extend(
+            db.fetch_fingerprints(song.id)
+                .into_iter()
+                .filter(|&(hash, _)| !is_denied(hash))
+                .map(|(hash, anchor_time)| (hash as u64, song.id as u32, anchor_time as f32)),
+        );
+    }
+    let total_fingerprints = entries.len();
+
+    MmapIndex::write(out_path, &songs, &schemes, entries)?;
+    Ok((songs.len(), total_fingerprints))
+}
+
+fn read_archive(
+    db: &DB,
+    in_path: &str,
+    collection: Option<&str>,
+) -> Result<(usize, usize), StoreError> {
+    let mut file = BufReader::new(File::open(in_path)?);
+
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format!("{} is not a Sabi library archive", in_path).into());
+    }
+    let version = u16::from_le_bytes(read_array(&mut file)?);
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(format!(
+            "unsupported archive version {} (expected {})",
+            version, FORMAT_VERSION
+        )
+        .into());
+    }
+
+    let mut r = zstd::Decoder::with_buffer(file)?;
+    let song_count = read_u32(&mut r)?;
+
+    let (mut imported, mut skipped) = (0, 0);
+    for _ in 0..song_count {
+        let song = NewSong {
+            title: read_str(&mut r)?,
+            created_at: read_opt(&mut r, read_i64)?.map(from_unix_micros),
+            duration: read_opt(&mut r, read_f64)?,
+            artist: read_opt(&mut r, read_str)?,
+            album: read_opt(&mut r, read_str)?,
+            track_number: read_opt(&mut r, read_i32)?,
+            year: read_opt(&mut r, read_i32)?,
+            checksum: read_opt(&mut r, read_str)?,
+            collection: if version >= 2 {
+                read_str(&mut r)?
+            } else {
+                DEFAULT_COLLECTION.to_string()
+            },
+            fingerprint_version: LEGACY_FINGERPRINT_VERSION,
+            // Paths are of the exporting machine
+            file_path: None,
+            bpm: None,
+            musical_key: None,
+        };
+        let scheme = if version >= 3 {
+            let bits = read_u32(&mut r)?;
+            HashScheme::from_bits(bits as i16)
+                .ok_or_else(|| format!("unknown hash scheme of {} bits", bits))?
+        } else {
+            HashScheme::Wide64
+        };
+        let fingerprint_version = if version >= 4 {
+            read_i32(&mut r)?
+        } else {
+            LEGACY_FINGERPRINT_VERSION
+        };
+        let song = NewSong {
+            collection: collection.map_or(song.collection, str::to_string),
+            fingerprint_version,
+            ..song
+        };
+
+        let fingerprint_count = read_u32(&mut r)? as usize;
+        let mut fingerprints = Vec::with_capacity(fingerprint_count);
+        for _ in 0..fingerprint_count {
+            fingerprints.push(FingerprintInfo {
+                hash: u64::from_le_bytes(read_array(&mut r)?),
+                abs_anchor_tm_offset: f32::from_le_bytes(read_array(&mut r)?),
+                strength: 1.0,
+                neighbor: false,
+            });
+        }
+
+        if let Some(existing) = song
+            .checksum
+            .as_deref()
+            .and_then(|checksum| db.find_song_by_checksum(checksum, &song.collection))
+        {
+            println!(
+                "⏭️ '{}' already ingested as id={}, skipping",
+                song.title, existing.id
+            );
+            skipped += 1;
+            continue;
+        }
+
+        // Hashes of different schemes never match, so a collection only takes
+        // songs fingerprinted with its own
+        let target_scheme = db.ensure_collection(&song.collection, scheme, CollectionKind::Songs);
+        if target_scheme != scheme {
+            return Err(format!(
+                "'{}' has {:?} hashes but collection '{}' stores {:?}",
+                song.title, scheme, song.collection, target_scheme
+            )
+            .into());
+        }
+
+        let (song_id, count) = store_song(db, &song, fingerprints, scheme)?;
+        println!(
+            "Imported '{}' as id={} ({} fingerprints)",
+            song.title, song_id, count
+        );
+        imported += 1;
+    }
+
+    Ok((imported, skipped))
+}
+
+fn to_unix_micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as i64)
+}
+
+fn from_unix_micros(micros: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(micros.max(0) as u64)
+}
@@ -1,43 +1,262 @@
+use std::borrow::Cow;
 use std::f32::consts::PI;
+#[cfg(feature = "decode")]
 use std::fs::File;
+#[cfg(feature = "decode")]
+use std::io::{Cursor, Read};
+#[cfg(feature = "decode")]
+use std::process::{Command, Stdio};
+#[cfg(feature = "capture")]
 use std::sync::{Arc, Mutex, mpsc};
+#[cfg(feature = "capture")]
 use std::time::{Duration, Instant};
+#[cfg(feature = "capture")]
 use std::{env, thread};
 
+#[cfg(feature = "capture")]
 use cpal::{Devices, SampleRate, StreamConfig, SupportedStreamConfig};
+#[cfg(feature = "decode")]
 use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::{CodecRegistry, DecoderOptions};
+#[cfg(feature = "decode")]
+use symphonia::core::codecs::{CODEC_TYPE_NULL, CodecRegistry, DecoderOptions};
+#[cfg(feature = "decode")]
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::{MediaSource, MediaSourceStream};
-use symphonia::core::meta::MetadataOptions;
+#[cfg(feature = "decode")]
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+#[cfg(feature = "decode")]
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadOnlySource};
+#[cfg(feature = "decode")]
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+#[cfg(feature = "decode")]
 use symphonia::core::probe::{Hint, Probe};
+#[cfg(feature = "decode")]
+use symphonia::core::units::{Time, TimeBase};
+#[cfg(feature = "decode")]
 use symphonia::default;
 
+#[cfg(feature = "capture")]
+use crate::cancel::{CancelToken, POLL_INTERVAL};
+use crate::config::{AudioConfig, DecodeConfig, RecordingConfig};
+#[cfg(feature = "capture")]
+use crate::meter::{SPECTRUM_LEN, TerminalMeter};
+#[cfg(feature = "capture")]
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+#[cfg(feature = "decode")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "capture")]
+use tracing::level_filters::LevelFilter;
+#[cfg(feature = "decode")]
+use tracing::trace;
+use tracing::{debug, instrument, warn};
+#[cfg(feature = "capture")]
+use tracing::{error, info};
+
+/// Audio kept from before voice activity starts, so its onset is not cut off
+#[cfg(feature = "capture")]
+const VAD_PRE_ROLL_SECS: f32 = 0.25;
+/// Rate the ffmpeg fallback decodes to; preprocessing resamples from there
+#[cfg(feature = "ffmpeg-fallback")]
+const FFMPEG_SAMPLE_RATE: u32 = 44_100;
+/// Loudness is measured over blocks of this length...
+const LOUDNESS_BLOCK_SECS: f32 = 0.4;
+/// ...ignoring blocks quieter than this, in dBFS, so silence does not count
+const LOUDNESS_GATE_DB: f32 = -70.0;
+/// Normalization never amplifies more than this, in dB, so near-silent
+/// input is not blown up into noise
+const MAX_NORMALIZE_GAIN_DB: f32 = 40.0;
+/// Time the limiter takes to release by 1/e, in seconds
+const LIMITER_RELEASE_SECS: f32 = 0.05;
+
+/// Tags read from the container while probing a file
+#[derive(Debug, Default, Clone)]
+pub struct SongMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<i32>,
+    pub year: Option<i32>,
+    /// MusicBrainz recording MBID, as written by MusicBrainz Picard
+    pub musicbrainz_recording_id: Option<String>,
+}
+
+#[cfg(feature = "decode")]
+impl SongMetadata {
+    fn apply_tags(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let value = tag.value.to_string().trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                // "3/12" -> 3
+                Some(StandardTagKey::TrackNumber) => {
+                    self.track_number = value.split('/').next().and_then(|n| n.trim().parse().ok())
+                }
+                // "2019-03-01" or "2019" -> 2019
+                Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate)
+                    if self.year.is_none() =>
+                {
+                    self.year = value.get(..4).and_then(|y| y.parse().ok());
+                }
+                Some(StandardTagKey::MusicBrainzRecordingId) => {
+                    self.musicbrainz_recording_id = Some(value)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Energy and zero-crossing voice-activity detector. A frame is active when
+/// its RMS level is above a threshold and it crosses zero rarely enough to be
+/// tonal; hiss and other broadband noise cross zero on most samples.
+pub struct VoiceActivityDetector {
+    threshold_db: f32,
+    max_zero_crossing_rate: f32,
+}
+
+impl VoiceActivityDetector {
+    /// Length of the frames [`Self::is_active`] is meant for, in seconds
+    pub const FRAME_SECS: f32 = 0.02;
+
+    pub fn new(config: &RecordingConfig) -> Self {
+        Self {
+            threshold_db: config.vad_threshold_db,
+            max_zero_crossing_rate: config.vad_max_zero_crossing_rate,
+        }
+    }
+
+    pub fn is_active(&self, frame: &[f32]) -> bool {
+        if frame.len() < 2 {
+            return false;
+        }
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        let level_db = 20.0 * rms.max(1e-10).log10();
+        let crossings = frame
+            .windows(2)
+            .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+            .count();
+        let zero_crossing_rate = crossings as f32 / (frame.len() - 1) as f32;
+
+        level_db >= self.threshold_db && zero_crossing_rate <= self.max_zero_crossing_rate
+    }
+}
+
+/// Format of an audio file, see [`AudioProcessor::probe_info`]
+#[derive(Debug, Clone)]
+pub struct AudioInfo {
+    /// Seconds
+    pub duration: f64,
+    /// Hz
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Symphonia's short codec name, e.g. `mp3` or `pcm_s16le`
+    pub codec: String,
+    /// Index of the decoded track in the container
+    pub track: usize,
+    /// Number of tracks in the container, audio or not
+    pub track_count: usize,
+}
 
 pub struct AudioProcessor {
+    #[cfg(feature = "decode")]
     codec_registry: &'static CodecRegistry,
+    #[cfg(feature = "decode")]
     format_options: FormatOptions,
+    #[cfg(feature = "decode")]
     metadata_options: MetadataOptions,
+    #[cfg(feature = "decode")]
     probe: &'static Probe,
+    /// Index of the track to decode; see [`Self::with_track`]
+    #[cfg_attr(not(feature = "decode"), allow(dead_code))]
+    track: Option<usize>,
+    /// Decode files symphonia fails on with ffmpeg, see [`DecodeConfig`]
+    #[cfg_attr(not(feature = "ffmpeg-fallback"), allow(dead_code))]
+    ffmpeg_fallback: bool,
 }
 
 impl AudioProcessor {
     pub const TARGET_SAMPLE_RATE: u32 = 11025;
+    /// File name that stands for standard input, e.g. `ffmpeg ... -f wav - | sabi --match -f -`
+    pub const STDIN: &'static str = "-";
 
     pub fn new() -> Self {
         Self {
+            #[cfg(feature = "decode")]
             codec_registry: default::get_codecs(),
+            #[cfg(feature = "decode")]
             format_options: FormatOptions::default(),
+            #[cfg(feature = "decode")]
             metadata_options: MetadataOptions::default(),
+            #[cfg(feature = "decode")]
             probe: symphonia::default::get_probe(),
+            track: None,
+            ffmpeg_fallback: false,
         }
     }
 
+    /// A processor set up by the `[decode]` config section
+    pub fn from_config(config: &DecodeConfig) -> Self {
+        Self {
+            ffmpeg_fallback: config.ffmpeg_fallback,
+            ..Self::new()
+        }
+    }
+
+    /// Decode the track at `track` (0-based, counting every track of the
+    /// container) instead of picking one. Without it the container's default
+    /// track is decoded if it is audio, else its first audio track, so cover
+    /// art and video tracks are passed over.
+    pub fn with_track(mut self, track: Option<usize>) -> Self {
+        self.track = track;
+        self
+    }
+}
+
+/// Decoding files and streams, and writing WAV files
+#[cfg(feature = "decode")]
+impl AudioProcessor {
+    /// Index of the track to decode, see [`Self::with_track`]
+    fn select_track(&self, format: &dyn FormatReader) -> Result<usize, String> {
+        let tracks = format.tracks();
+        let decodable = |track: &Track| {
+            track.codec_params.codec != CODEC_TYPE_NULL
+                && self
+                    .codec_registry
+                    .get_codec(track.codec_params.codec)
+                    .is_some()
+        };
+        if let Some(index) = self.track {
+            return match tracks.get(index) {
+                Some(track) if decodable(track) => Ok(index),
+                Some(_) => Err(format!(
+                    "track {} is not an audio track that can be decoded",
+                    index
+                )),
+                None => Err(format!(
+                    "there is no track {}, the file has {} (counting from 0)",
+                    index,
+                    tracks.len()
+                )),
+            };
+        }
+        let default = format
+            .default_track()
+            .filter(|track| decodable(track))
+            .and_then(|default| tracks.iter().position(|t| t.id == default.id));
+        default
+            .or_else(|| tracks.iter().position(decodable))
+            .ok_or_else(|| "no audio track that can be decoded".to_string())
+    }
+
+    /// Decode a whole file to mono samples; [`Self::STDIN`] reads standard input
+    #[instrument(skip(self))]
     pub fn get_decoded_audio(&self, file_name: String) -> (Vec<f32>, u32) {
-        let file = self.read_return_file(file_name);
-        let (decoded_audio_samples, sample_rate) = match self.generate_audio_samples(file) {
+        let (decoded_audio_samples, sample_rate) = match self.decode_file(&file_name) {
             Ok(k) => k,
             Err(e) => {
                 panic!("Generating audio samples failed \n {}", e);
@@ -47,35 +266,297 @@ impl AudioProcessor {
         (decoded_audio_samples, sample_rate)
     }
 
-    fn generate_audio_samples(
+    /// Like [`Self::get_decoded_audio`], but returns an error for a missing
+    /// or undecodable file instead of panicking
+    pub fn decode_file(
         &self,
-        file: File,
+        file_name: &str,
     ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
-        let source: Box<dyn MediaSource> = Box::new(file);
+        if file_name == Self::STDIN {
+            self.get_decoded_audio_from_reader(std::io::stdin())
+        } else {
+            let decoded = self.generate_audio_samples(Box::new(File::open(file_name)?));
+            self.or_ffmpeg(decoded, file_name, 0.0, None)
+        }
+    }
 
-        let track = MediaSourceStream::new(source, Default::default());
+    /// Decode `duration` seconds (or the rest) of a file starting at `start`
+    /// seconds, seeking to the start instead of decoding the whole file.
+    /// [`Self::STDIN`] cannot seek, so the audio before `start` is decoded
+    /// and dropped.
+    pub fn get_decoded_audio_range(
+        &self,
+        file_name: String,
+        start: f64,
+        duration: Option<f64>,
+    ) -> (Vec<f32>, u32) {
+        let decoded = if file_name == Self::STDIN {
+            let stdin = Box::new(ReadOnlySource::new(std::io::stdin()));
+            self.generate_audio_samples_range(stdin, start, duration)
+        } else {
+            let file = Box::new(self.read_return_file(file_name.clone()));
+            let decoded = self.generate_audio_samples_range(file, start, duration);
+            self.or_ffmpeg(decoded, &file_name, start, duration)
+        };
+        match decoded {
+            Ok(k) => k,
+            Err(e) => {
+                panic!("Generating audio samples failed \n {}", e);
+            }
+        }
+    }
 
-        let prober = self
+    /// `decoded`, or with the ffmpeg fallback enabled, what ffmpeg makes of
+    /// the file when symphonia failed on it
+    fn or_ffmpeg(
+        &self,
+        decoded: Result<(Vec<f32>, u32), Box<dyn std::error::Error>>,
+        file_name: &str,
+        start: f64,
+        duration: Option<f64>,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        #[cfg(feature = "ffmpeg-fallback")]
+        if self.ffmpeg_fallback
+            && let Err(e) = &decoded
+        {
+            warn!("Cannot decode {} ({}), trying ffmpeg", file_name, e);
+            return self
+                .decode_with_ffmpeg(file_name, start, duration)
+                .map_err(|ffmpeg_error| format!("{}; ffmpeg: {}", e, ffmpeg_error).into());
+        }
+        #[cfg(not(feature = "ffmpeg-fallback"))]
+        let _ = (file_name, start, duration);
+        decoded
+    }
+
+    /// Decode `duration` seconds (or the rest) of a file from `start` on to
+    /// mono at [`FFMPEG_SAMPLE_RATE`] with an `ffmpeg` child process
+    #[cfg(feature = "ffmpeg-fallback")]
+    fn decode_with_ffmpeg(
+        &self,
+        file_name: &str,
+        start: f64,
+        duration: Option<f64>,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-hide_banner", "-loglevel", "error", "-nostdin"]);
+        if start > 0.0 {
+            command.args(["-ss", &start.to_string()]);
+        }
+        if let Some(duration) = duration {
+            command.args(["-t", &duration.to_string()]);
+        }
+        command.args(["-i", file_name]);
+        if let Some(track) = self.track {
+            command.args(["-map", &format!("0:{}", track)]);
+        }
+        let output = command
+            .args(["-vn", "-f", "f32le", "-ac", "1"])
+            .args(["-ar", &FFMPEG_SAMPLE_RATE.to_string(), "pipe:1"])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("cannot run ffmpeg: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+        }
+
+        let samples: Vec<f32> = output
+            .stdout
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        debug!("ffmpeg decoded {} mono samples", samples.len());
+        Ok((samples, FFMPEG_SAMPLE_RATE))
+    }
+
+    /// Duration and format of the decoded track of a file, as declared by its
+    /// container. What the container leaves out (often the length of
+    /// streamed MP3 and ADTS files) is found by decoding the track.
+    pub fn probe_info(&self, file_name: String) -> Result<AudioInfo, Box<dyn std::error::Error>> {
+        let file = File::open(&file_name)?;
+        let track = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut format = self
             .probe
             .format(
                 &Hint::new(),
                 track,
                 &self.format_options,
                 &self.metadata_options,
-            )
-            .expect("an error has occurred while probing");
+            )?
+            .format;
+
+        let track_index = self.select_track(format.as_ref())?;
+        let track_count = format.tracks().len();
+        let track = &format.tracks()[track_index];
+        let track_id = track.id;
+        let codec_params = track.codec_params.clone();
+        let codec = self
+            .codec_registry
+            .get_codec(codec_params.codec)
+            .map_or_else(|| "unknown".to_string(), |c| c.short_name.to_string());
+
+        let mut sample_rate = codec_params.sample_rate;
+        let mut channels = codec_params.channels.map(|c| c.count());
+        let mut frames = codec_params.n_frames;
+        if frames.is_none() || sample_rate.is_none() || channels.is_none() {
+            debug!(
+                "{} does not declare its length or format, decoding it",
+                file_name
+            );
+            let mut decoder = self
+                .codec_registry
+                .make(&codec_params, &DecoderOptions::default())?;
+            let mut decoded_frames = 0;
+            loop {
+                let packet = match format.next_packet() {
+                    Ok(packet) => packet,
+                    // EOF
+                    Err(Error::IoError(_)) => break,
+                    Err(e) => return Err(Box::new(e)),
+                };
+                if packet.track_id() != track_id {
+                    continue;
+                }
+                let decoded = match decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    Err(Error::DecodeError(_)) => continue,
+                    Err(e) => return Err(Box::new(e)),
+                };
+                sample_rate.get_or_insert(decoded.spec().rate);
+                channels.get_or_insert(decoded.spec().channels.count());
+                decoded_frames += decoded.frames() as u64;
+            }
+            frames = frames.or(Some(decoded_frames));
+        }
+
+        let sample_rate = sample_rate.ok_or("no sample rate")?;
+        Ok(AudioInfo {
+            duration: frames.unwrap_or(0) as f64 / sample_rate as f64,
+            sample_rate,
+            channels: channels.unwrap_or(0),
+            codec,
+            track: track_index,
+            track_count,
+        })
+    }
+
+    /// Probe a file for artist/album/track/year tags without decoding it.
+    /// Tags found outside the container (e.g. ID3v2) are read first, then the
+    /// container's own metadata, which takes precedence.
+    pub fn get_metadata(&self, file_name: String) -> SongMetadata {
+        let file = self.read_return_file(file_name);
+        let source: Box<dyn MediaSource> = Box::new(file);
+        let track = MediaSourceStream::new(source, Default::default());
+
+        let mut metadata = SongMetadata::default();
+        let Ok(mut prober) = self.probe.format(
+            &Hint::new(),
+            track,
+            &self.format_options,
+            &self.metadata_options,
+        ) else {
+            return metadata;
+        };
+
+        if let Some(revision) = prober.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.apply_tags(revision.tags());
+        }
+        if let Some(revision) = prober.format.metadata().current() {
+            metadata.apply_tags(revision.tags());
+        }
+
+        metadata
+    }
+
+    /// Decode an in-memory audio file (e.g. an HTTP upload) to mono samples
+    pub fn get_decoded_audio_from_bytes(
+        &self,
+        bytes: Vec<u8>,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        self.generate_audio_samples(Box::new(Cursor::new(bytes)))
+    }
+
+    /// Decode audio from any reader, such as a pipe. The reader is consumed
+    /// front to back once and never seeked, so the container must be
+    /// streamable (WAV, MP3, FLAC, Ogg, ADTS; not MP4 with a trailing index).
+    pub fn get_decoded_audio_from_reader(
+        &self,
+        reader: impl Read + Send + Sync + 'static,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        self.generate_audio_samples(Box::new(ReadOnlySource::new(reader)))
+    }
+
+    /// Decode audio from a seekable media source (a file, an in-memory
+    /// buffer, or any `Read + Seek` type implementing [`MediaSource`])
+    pub fn generate_audio_samples(
+        &self,
+        source: Box<dyn MediaSource>,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        self.generate_audio_samples_range(source, 0.0, None)
+    }
+
+    /// Decode `duration` seconds (or everything) from `start` seconds on to
+    /// mono. A non-zero `start` is reached by seeking, not by decoding the
+    /// audio before it.
+    fn generate_audio_samples_range(
+        &self,
+        source: Box<dyn MediaSource>,
+        start: f64,
+        duration: Option<f64>,
+    ) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+        let track = MediaSourceStream::new(source, Default::default());
+
+        let prober = self.probe.format(
+            &Hint::new(),
+            track,
+            &self.format_options,
+            &self.metadata_options,
+        )?;
         let mut format = prober.format;
 
-        let codec_params = &format.tracks().get(0).unwrap().codec_params;
-        let sample_rate = codec_params.sample_rate.unwrap();
+        let track = &format.tracks()[self.select_track(format.as_ref())?];
+        let track_id = track.id;
+        let codec_params = track.codec_params.clone();
+        // Some containers only reveal the rate once a packet is decoded
+        let mut sample_rate = codec_params.sample_rate;
+        let time_base = codec_params
+            .time_base
+            .or(sample_rate.map(|rate| TimeBase::new(1, rate)));
         let decoder_options = DecoderOptions::default();
+        debug!(
+            codec = ?codec_params.codec,
+            sample_rate,
+            channels = codec_params.channels.map(|c| c.count()),
+            "Decoding track {}",
+            track_id
+        );
 
-        let mut decoder = self
-            .codec_registry
-            .make(codec_params, &decoder_options)
-            .unwrap();
+        let start_ts = match time_base {
+            Some(time_base) => time_base.calc_timestamp(Time::from(start)),
+            None if start > 0.0 => {
+                return Err("the track has no timing information to start at an offset".into());
+            }
+            None => 0,
+        };
+        if start > 0.0
+            && let Err(e) = format.seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(start),
+                    track_id: Some(track_id),
+                },
+            )
+        {
+            // Unseekable sources (pipes, files without a seek index) still
+            // get there: packets before `start` are dropped below
+            debug!("Seeking failed, decoding up to {}s instead: {}", start, e);
+        }
+
+        let mut decoder = self.codec_registry.make(&codec_params, &decoder_options)?;
 
         let mut decoded_audio_samples = Vec::new();
+        // Packets skipped as corrupt, and their length in `time_base` units
+        let (mut dropped_packets, mut dropped_ts) = (0u64, 0u64);
         loop {
             let packet = match format.next_packet() {
                 Ok(packet) => packet,
@@ -83,33 +564,249 @@ impl AudioProcessor {
                 Err(Error::IoError(_)) => {
                     break;
                 }
+                // The stream changed parameters (e.g. a chained Ogg stream)
+                Err(Error::ResetRequired) => {
+                    debug!("Stream parameters changed, recreating the decoder");
+                    decoder = self.codec_registry.make(&codec_params, &decoder_options)?;
+                    continue;
+                }
                 Err(e) => return Err(Box::new(e)),
             };
+            if packet.track_id() != track_id {
+                continue;
+            }
 
-            let decoded_packet = decoder.decode(&packet).unwrap();
+            let decoded_packet = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt frame, the next one decodes fine
+                Err(Error::DecodeError(e)) => {
+                    debug!("Skipping undecodable packet at ts {}: {}", packet.ts(), e);
+                    dropped_packets += 1;
+                    dropped_ts += packet.dur();
+                    continue;
+                }
+                Err(Error::ResetRequired) => {
+                    debug!("Decoder reset required, recreating it");
+                    decoder = self.codec_registry.make(&codec_params, &decoder_options)?;
+                    continue;
+                }
+                Err(e) => return Err(Box::new(e)),
+            };
             let num_channels = decoded_packet.spec().channels.count();
+            let rate = *sample_rate.get_or_insert(decoded_packet.spec().rate);
+
+            // A seek lands on the packet containing `start` (a failed one on
+            // the first packet), drop the frames before it
+            let skip_frames = match time_base {
+                Some(time_base) if packet.ts() < start_ts => {
+                    let skipped = time_base.calc_time(start_ts - packet.ts());
+                    ((skipped.seconds as f64 + skipped.frac) * rate as f64).round() as usize
+                }
+                _ => 0,
+            };
 
             let mut sample_buf =
                 SampleBuffer::<f32>::new(decoded_packet.capacity() as u64, *decoded_packet.spec());
             sample_buf.copy_interleaved_ref(decoded_packet);
 
-            for i in (0..sample_buf.len()).step_by(num_channels) {
+            for i in (skip_frames * num_channels..sample_buf.len()).step_by(num_channels) {
                 let frame = &sample_buf.samples()[i..i + num_channels];
                 let mono_sample = frame.iter().sum::<f32>() / num_channels as f32;
                 decoded_audio_samples.push(mono_sample);
             }
+
+            if let Some(duration) = duration {
+                let max_samples = (duration * rate as f64).round() as usize;
+                if decoded_audio_samples.len() >= max_samples {
+                    decoded_audio_samples.truncate(max_samples);
+                    break;
+                }
+            }
         }
 
+        debug!("Decoded {} mono samples", decoded_audio_samples.len());
+        if dropped_packets > 0 {
+            let dropped = time_base.map(|time_base| time_base.calc_time(dropped_ts));
+            warn!(
+                "Skipped {} undecodable packets ({:.2}s of audio)",
+                dropped_packets,
+                dropped.map_or(0.0, |t| t.seconds as f64 + t.frac)
+            );
+        }
+        let sample_rate = sample_rate.ok_or("the audio track holds no audio")?;
         Ok((decoded_audio_samples, sample_rate))
     }
 
+    /// Write mono samples to a 16-bit PCM WAV file, clipping to [-1, 1]
+    pub fn write_wav(
+        &self,
+        file_name: &str,
+        samples: &[f32],
+        sample_rate: u32,
+    ) -> Result<(), hound::Error> {
+        let mut writer = hound::WavWriter::create(file_name, mono_wav_spec(sample_rate, 16))?;
+        for &sample in samples {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()
+    }
+
+    /// Write mono samples to a 32-bit float WAV file, which decodes back to
+    /// exactly the same samples
+    pub fn write_wav_float(
+        &self,
+        file_name: &str,
+        samples: &[f32],
+        sample_rate: u32,
+    ) -> Result<(), hound::Error> {
+        let mut writer = hound::WavWriter::create(file_name, mono_wav_spec(sample_rate, 32))?;
+        for &sample in samples {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()
+    }
+
+    /// Hex-encoded SHA-256 of the file's bytes, used to detect re-ingests
+    pub fn file_checksum(&self, file_name: String) -> String {
+        let mut file = self.read_return_file(file_name);
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher).expect("failed to read file for checksum");
+        format!("{:x}", hasher.finalize())
+    }
+
     fn read_return_file(&self, file_path: String) -> File {
-        let file = File::open(file_path).unwrap();
-        println!("read the file");
+        let file = File::open(&file_path).unwrap();
+        trace!("Opened {}", file_path);
         file
     }
+}
+
+/// Microphone input and playback
+#[cfg(feature = "capture")]
+impl AudioProcessor {
+    /// Record `config.duration` seconds from the default input device, or
+    /// less if `cancel` fires first, showing a [`TerminalMeter`] meanwhile
+    pub fn record_audio(
+        &self,
+        config: &RecordingConfig,
+        cancel: &CancelToken,
+    ) -> (Vec<f32>, SupportedStreamConfig) {
+        let (stream, recorded_samples, config_cpal) = self.start_input_stream();
+        let channels = config_cpal.channels() as usize;
+        let rate = config_cpal.sample_rate().0;
+        let mut meter = self.terminal_meter(config);
+        let started = Instant::now();
+        let end = started + Duration::from_secs_f32(config.duration);
+        while Instant::now() < end && !cancel.is_cancelled() {
+            thread::sleep(POLL_INTERVAL.min(end.saturating_duration_since(Instant::now())));
+            let tail = Self::tail(&recorded_samples.lock().unwrap(), channels);
+            meter.update(
+                &self.to_mono(&tail, channels),
+                rate,
+                &format!(
+                    "{:.1}s / {:.1}s",
+                    started.elapsed().as_secs_f32().min(config.duration),
+                    config.duration
+                ),
+            );
+        }
+        meter.clear();
+        drop(stream);
+
+        (recorded_samples.lock().unwrap().clone(), config_cpal)
+    }
+
+    /// Record from the default input device once [`VoiceActivityDetector`]
+    /// hears sound, until `config.duration` seconds of active audio were
+    /// captured or `config.max_duration` seconds passed in total. Returns no
+    /// samples if nothing was heard.
+    pub fn record_until_active(
+        &self,
+        config: &RecordingConfig,
+        cancel: &CancelToken,
+    ) -> (Vec<f32>, SupportedStreamConfig) {
+        let vad = VoiceActivityDetector::new(config);
+        let (stream, incoming, config_cpal) = self.start_input_stream();
+        let channels = config_cpal.channels() as usize;
+        let rate = config_cpal.sample_rate().0 as f32;
+        // Interleaved lengths, in whole sample frames
+        let frame_len = ((VoiceActivityDetector::FRAME_SECS * rate) as usize).max(1) * channels;
+        let pre_roll_len = (VAD_PRE_ROLL_SECS * rate) as usize * channels;
+        let active_frames_needed =
+            (config.duration / VoiceActivityDetector::FRAME_SECS).ceil() as usize;
+        let mut meter = self.terminal_meter(config);
 
-    pub fn record_audio(&self, duration_secs: u64) -> (Vec<f32>, SupportedStreamConfig) {
+        let mut pending: Vec<f32> = Vec::new();
+        let mut recorded: Vec<f32> = Vec::new();
+        let (mut started, mut active_frames, mut frames) = (false, 0, 0);
+        let deadline = Instant::now() + Duration::from_secs_f32(config.max_duration);
+        while active_frames < active_frames_needed
+            && Instant::now() < deadline
+            && !cancel.is_cancelled()
+        {
+            thread::sleep(POLL_INTERVAL);
+            pending.extend(incoming.lock().unwrap().drain(..));
+
+            let whole = pending.len() / frame_len * frame_len;
+            for frame in pending[..whole].chunks_exact(frame_len) {
+                let active = vad.is_active(&self.to_mono(frame, channels));
+                recorded.extend_from_slice(frame);
+                if !started && active {
+                    meter.clear();
+                    info!("Sound detected, capturing");
+                    started = true;
+                }
+                if started {
+                    frames += 1;
+                    active_frames += active as usize;
+                } else if recorded.len() > pre_roll_len {
+                    recorded.drain(..recorded.len() - pre_roll_len);
+                }
+            }
+            pending.drain(..whole);
+
+            let status = if started {
+                format!(
+                    "{:.1}s / {:.1}s of sound",
+                    active_frames as f32 * VoiceActivityDetector::FRAME_SECS,
+                    config.duration
+                )
+            } else {
+                "waiting for sound".to_string()
+            };
+            let tail = Self::tail(&recorded, channels);
+            meter.update(&self.to_mono(&tail, channels), rate as u32, &status);
+        }
+        meter.clear();
+        drop(stream);
+
+        if !started {
+            recorded.clear();
+        }
+        debug!(
+            "Captured {} frames, {} of them active",
+            frames, active_frames
+        );
+        (recorded, config_cpal)
+    }
+
+    /// The recording meter, unless `config.meter` is off or `--quiet` hides
+    /// progress output
+    fn terminal_meter(&self, config: &RecordingConfig) -> TerminalMeter {
+        TerminalMeter::new(config.meter && LevelFilter::current() >= LevelFilter::INFO)
+    }
+
+    /// The newest interleaved samples the meter needs, in whole frames
+    fn tail(interleaved: &[f32], channels: usize) -> Vec<f32> {
+        let len = interleaved.len() / channels * channels;
+        interleaved[len.saturating_sub(SPECTRUM_LEN * channels)..len].to_vec()
+    }
+
+    /// Start recording from the default input device into the returned
+    /// buffer, until the stream is dropped
+    pub fn start_input_stream(
+        &self,
+    ) -> (cpal::Stream, Arc<Mutex<Vec<f32>>>, SupportedStreamConfig) {
         let host = cpal::default_host();
         let device = host.default_input_device().expect("No input device found");
         let config_cpal = device.default_input_config().unwrap();
@@ -117,7 +814,7 @@ impl AudioProcessor {
         let recorded_samples = Arc::new(Mutex::new(Vec::new()));
         let samples_clone = recorded_samples.clone();
 
-        let err_fn = |err| eprintln!("Stream error: {}", err);
+        let err_fn = |err| error!("Stream error: {}", err);
 
         let stream = match config_cpal.sample_format() {
             cpal::SampleFormat::F32 => device
@@ -147,10 +844,7 @@ impl AudioProcessor {
         };
 
         stream.play().unwrap();
-        thread::sleep(Duration::from_secs(duration_secs));
-        drop(stream);
-
-        (recorded_samples.lock().unwrap().clone(), config_cpal)
+        (stream, recorded_samples, config_cpal)
     }
     pub fn play_recording(&self, recorded_samples: Vec<f32>, config: &StreamConfig) {
         let host = cpal::default_host();
@@ -158,7 +852,7 @@ impl AudioProcessor {
             .default_output_device()
             .expect("No output device available.");
 
-        println!("Output config: {:?}", config);
+        debug!("Output config: {:?}", config);
 
         let duration_secs =
             recorded_samples.len() as f32 / (config.sample_rate.0 as f32 * config.channels as f32);
@@ -174,7 +868,7 @@ impl AudioProcessor {
                         *sample = samples_iter.next().unwrap_or(0.0);
                     }
                 },
-                |err| eprintln!("An error occurred on the output stream: {}", err),
+                |err| error!("An error occurred on the output stream: {}", err),
                 None,
             )
             .expect("Failed to build output stream.");
@@ -186,6 +880,20 @@ impl AudioProcessor {
         thread::sleep(Duration::from_secs_f32(duration_secs + 1.0));
         println!("Playback finished.");
     }
+}
+
+/// The DSP stages between decoding and the STFT, free of I/O so they also
+/// build for WebAssembly
+impl AudioProcessor {
+    /// Average interleaved samples of `channels` channels to mono, as
+    /// decoding does
+    pub fn to_mono(&self, interleaved: &[f32], channels: usize) -> Vec<f32> {
+        interleaved
+            .chunks_exact(channels)
+            .map(|f| f.iter().sum::<f32>() / channels as f32)
+            .collect()
+    }
+
     pub fn resample_linear(&self, samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
         if from_rate == to_rate {
             return samples.to_vec();
@@ -213,6 +921,112 @@ impl AudioProcessor {
         resampled
     }
 
+    /// Low-pass filter decoded audio and resample it to the configured target
+    /// rate, ready for the STFT
+    #[instrument(skip_all, fields(samples = samples.len(), sample_rate))]
+    pub fn preprocess(&self, samples: &[f32], sample_rate: u32, config: &AudioConfig) -> Vec<f32> {
+        let conditioned = self.condition(samples, sample_rate, config);
+        let filtered =
+            self.apply_low_pass_filter(&conditioned, sample_rate, config.low_pass_cutoff);
+        self.resample_linear(&filtered, sample_rate, config.target_sample_rate)
+    }
+
+    /// The stages before the low-pass filter that `config` enables: DC and
+    /// rumble removal, then loudness normalization. Borrows `samples` when
+    /// none is.
+    pub fn condition<'a>(
+        &self,
+        samples: &'a [f32],
+        sample_rate: u32,
+        config: &AudioConfig,
+    ) -> Cow<'a, [f32]> {
+        let mut samples = Cow::Borrowed(samples);
+        if config.high_pass {
+            samples = Cow::Owned(self.apply_high_pass_filter(
+                &samples,
+                sample_rate,
+                config.high_pass_cutoff,
+            ));
+        }
+        if config.normalize {
+            samples = Cow::Owned(self.normalize_loudness(&samples, sample_rate, config));
+        }
+        samples
+    }
+
+    /// First-order IIR high-pass (a DC blocker at low cutoffs):
+    /// `y[n] = a * (y[n-1] + x[n] - x[n-1])`
+    pub fn apply_high_pass_filter(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        cutoff_freq: f32,
+    ) -> Vec<f32> {
+        let rc = 1.0 / (2.0 * PI * cutoff_freq);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = rc / (rc + dt);
+
+        let (mut previous_in, mut previous_out) = (samples.first().copied().unwrap_or(0.0), 0.0);
+        samples
+            .iter()
+            .map(|&x| {
+                previous_out = alpha * (previous_out + x - previous_in);
+                previous_in = x;
+                previous_out
+            })
+            .collect()
+    }
+
+    /// Scale `samples` so their gated RMS level (over 400 ms blocks, leaving
+    /// out silent ones, as EBU R128 does) is `config.target_loudness_db`, then
+    /// limit peaks to `config.limiter_ceiling_db` so quiet recordings are not
+    /// clipped on the way up
+    pub fn normalize_loudness(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        config: &AudioConfig,
+    ) -> Vec<f32> {
+        let db_to_gain = |db: f32| 10f32.powf(db / 20.0);
+        let block_len = ((LOUDNESS_BLOCK_SECS * sample_rate as f32) as usize).max(1);
+        let gate = db_to_gain(LOUDNESS_GATE_DB).powi(2);
+        let (energy, count) = samples
+            .chunks(block_len)
+            .map(|block| block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32)
+            .filter(|&mean_square| mean_square >= gate)
+            .fold((0.0, 0), |(sum, n), mean_square| (sum + mean_square, n + 1));
+        if count == 0 {
+            debug!("No audio above the loudness gate, not normalizing");
+            return samples.to_vec();
+        }
+
+        let loudness_db = 10.0 * (energy / count as f32).log10();
+        let gain_db = (config.target_loudness_db - loudness_db).min(MAX_NORMALIZE_GAIN_DB);
+        debug!(
+            "Loudness {:.1} dBFS, applying {:+.1} dB",
+            loudness_db, gain_db
+        );
+        let gain = db_to_gain(gain_db);
+
+        // Instant attack, exponential release: the envelope jumps to a peak
+        // and decays afterwards, and the gain keeps it under the ceiling
+        let ceiling = db_to_gain(config.limiter_ceiling_db);
+        let release = (-1.0 / (LIMITER_RELEASE_SECS * sample_rate as f32)).exp();
+        let mut envelope = 0.0f32;
+        samples
+            .iter()
+            .map(|&s| {
+                let amplified = s * gain;
+                envelope = amplified.abs().max(envelope * release);
+                if envelope > ceiling {
+                    amplified * ceiling / envelope
+                } else {
+                    amplified
+                }
+            })
+            .collect()
+    }
+
     /// This is useful for reducing high-frequency noise, like microphone hiss.
     pub fn apply_low_pass_filter(
         &self,
@@ -242,3 +1056,30 @@ impl AudioProcessor {
         filtered_samples
     }
 }
+
+/// Whether `ffmpeg` can be run
+#[cfg(feature = "decode")]
+pub fn ffmpeg_available() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Layout of the WAV files written by [`AudioProcessor::write_wav`] and
+/// [`AudioProcessor::write_wav_float`]
+#[cfg(feature = "decode")]
+fn mono_wav_spec(sample_rate: u32, bits_per_sample: u16) -> hound::WavSpec {
+    hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample,
+        sample_format: if bits_per_sample == 32 {
+            hound::SampleFormat::Float
+        } else {
+            hound::SampleFormat::Int
+        },
+    }
+}
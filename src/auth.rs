@@ -0,0 +1,170 @@
+use crate::db::{bindings::NewApiKey, connector::DB};
+use chrono::{DateTime, Local};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Prefix of generated keys, so leaked ones are easy to grep for
+const KEY_PREFIX: &str = "sabi_";
+/// Rate limiter buckets kept before full ones are forgotten
+const MAX_BUCKETS: usize = 10_000;
+
+/// What an API key may do on the server
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// `POST /recognise`
+    Recognise,
+    /// `POST /ingest`
+    Ingest,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Recognise => "recognise",
+            Scope::Ingest => "ingest",
+        }
+    }
+}
+
+/// Whether the comma-separated `scopes` of a key include `scope`
+pub fn has_scope(scopes: &str, scope: Scope) -> bool {
+    scopes.split(',').any(|s| s.trim() == scope.as_str())
+}
+
+/// What is stored of a key: its SHA-256, hex-encoded
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Create a key called `name` with `scopes` and print it. Only its hash is
+/// stored, so this is the one time it can be seen.
+pub fn run_create_key(db: &DB, name: &str, scopes: &[Scope]) {
+    let bytes: [u8; 24] = rand::rng().random();
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let key = format!("{}{}", KEY_PREFIX, hex);
+    let mut scope_names: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+    scope_names.sort_unstable();
+    scope_names.dedup();
+    let scope_list = scope_names.join(",");
+
+    let key_hash = hash_key(&key);
+    if let Err(e) = db.create_api_key(&NewApiKey {
+        name,
+        key_hash: &key_hash,
+        scopes: &scope_list,
+    }) {
+        eprintln!("❌ Cannot create key '{}': {}", name, e);
+        return;
+    }
+    println!("🔑 Created key '{}' ({})", name, scope_list);
+    println!("   {}", key);
+    println!("   Store it now, it cannot be shown again");
+}
+
+pub fn run_list_keys(db: &DB) {
+    let keys = db.list_api_keys();
+    if keys.is_empty() {
+        println!("No API keys");
+        return;
+    }
+    println!("{:<32}  {:<20}  {:<16}", "name", "scopes", "created");
+    for key in keys {
+        println!(
+            "{:<32}  {:<20}  {:<16}",
+            key.name,
+            key.scopes,
+            DateTime::<Local>::from(key.created_at).format("%Y-%m-%d %H:%M")
+        );
+    }
+}
+
+pub fn run_revoke_key(db: &DB, name: &str) {
+    if db.revoke_api_key(name) {
+        println!("🗑️ Revoked key '{}'", name);
+    } else {
+        eprintln!("❌ No key called '{}'", name);
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client (API key or address): `burst` tokens, refilled
+/// at `per_minute`, one taken per request
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// `None` when `per_minute` is 0, i.e. unlimited
+    pub fn new(per_minute: f64, burst: u32) -> Option<Self> {
+        (per_minute > 0.0).then(|| Self {
+            per_second: per_minute / 60.0,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take a token of `client`, or say how long until one is available
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // A full bucket is the same as no bucket
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_match_whole_names() {
+        assert!(has_scope("ingest,recognise", Scope::Recognise));
+        assert!(has_scope("recognise, ingest", Scope::Ingest));
+        assert!(!has_scope("recognise", Scope::Ingest));
+        assert!(!has_scope("ingestion", Scope::Ingest));
+        assert!(!has_scope("", Scope::Recognise));
+    }
+
+    #[test]
+    fn rate_limiter_allows_a_burst_per_client() {
+        assert!(RateLimiter::new(0.0, 5).is_none());
+
+        let limiter = RateLimiter::new(60.0, 2).unwrap();
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let wait = limiter.check("a").unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(limiter.check("b").is_ok());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::db::{connector::DB, storage::fingerprint_store};
+use crate::dsp::{Pipeline, Signal};
+use crate::fingerprint::{expand_query, vote_best_matches};
+use std::time::{Duration, Instant};
+
+/// Pipeline stages timed by `bench`: decoding, the stages of
+/// [`crate::dsp::STAGES`], then the lookup
+const STAGES: [&str; 9] = [
+    "decode", "source", "filter", "resample", "stft", "peaks", "hash", "db query", "voting",
+];
+
+/// Index of the stage named `name` in [`STAGES`]
+fn slot(name: &str) -> usize {
+    STAGES
+        .iter()
+        .position(|stage| *stage == name)
+        .unwrap_or_else(|| panic!("bench does not time a '{}' stage", name))
+}
+
+/// Time `f` and add the elapsed time to `slot`
+fn timed<T>(slot: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *slot += start.elapsed();
+    result
+}
+
+/// Run `file` through the recognition pipeline `runs` times and print the
+/// time spent in each stage, with throughput relative to the audio length.
+///
+/// Runs after the first are served from the hash lookup cache unless it is
+/// disabled with `HASH_CACHE_SIZE=0`, so the best run shows cached lookups.
+pub fn run_bench(file: &str, collection: &str, runs: usize, config: &Config) {
+    let runs = runs.max(1);
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let db = DB::new();
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+    let pipeline = Pipeline::from_config(config, scheme);
+
+    // timings[run][stage]
+    let mut timings = vec![[Duration::ZERO; STAGES.len()]; runs];
+    let mut audio_secs = 0.0;
+    let mut fingerprint_count = 0;
+
+    println!("⏱️ Benchmarking '{}' over {} run(s)", file, runs);
+    for stage_times in timings.iter_mut() {
+        let (samples, sample_rate) = timed(&mut stage_times[slot("decode")], || {
+            audio_processor.get_decoded_audio(file.to_string())
+        });
+        audio_secs = samples.len() as f64 / sample_rate as f64;
+
+        // High-pass and loudness normalization, when enabled, count as filtering
+        let signal = pipeline
+            .run_timed(
+                Signal::Audio {
+                    samples,
+                    sample_rate,
+                },
+                |name, elapsed| stage_times[slot(name)] += elapsed,
+            )
+            .expect("pipeline failed");
+        let Signal::Fingerprints(fingerprints) = signal else {
+            panic!("pipeline does not end in fingerprints");
+        };
+        let fingerprints = timed(&mut stage_times[slot("hash")], || {
+            store.without_denied(expand_query(fingerprints, scheme, &config.matching))
+        });
+        fingerprint_count = fingerprints.len();
+
+        let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+        let db_matches_by_hash = timed(&mut stage_times[slot("db query")], || {
+            store
+                .fetch_matches_grouped_by_hash(&hash_vec)
+                .expect("Fingerprint lookup failed")
+        });
+        timed(&mut stage_times[slot("voting")], || {
+            vote_best_matches(
+                &fingerprints,
+                &db_matches_by_hash,
+                store.song_count(),
+                5,
+                &config.matching,
+            )
+        });
+    }
+
+    println!(
+        "   {:.1}s of audio, {} fingerprints\n",
+        audio_secs, fingerprint_count
+    );
+    println!(
+        "   {:<14} {:>10} {:>10} {:>12}",
+        "stage", "mean ms", "best ms", "x-realtime"
+    );
+    let print_row = |name: &str, times: &[Duration]| {
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+        let best = times.iter().min().copied().unwrap_or_default();
+        println!(
+            "   {:<14} {:>10.2} {:>10.2} {:>11.1}x",
+            name,
+            mean.as_secs_f64() * 1000.0,
+            best.as_secs_f64() * 1000.0,
+            audio_secs / mean.as_secs_f64().max(f64::EPSILON)
+        );
+    };
+    for (stage, name) in STAGES.iter().enumerate() {
+        let times: Vec<Duration> = timings.iter().map(|run| run[stage]).collect();
+        print_row(name, &times);
+    }
+    let totals: Vec<Duration> = timings.iter().map(|run| run.iter().sum()).collect();
+    print_row("total", &totals);
+}
@@ -0,0 +1,47 @@
+#[cfg(feature = "native")]
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Exit code of a second Ctrl-C, as a shell reports a SIGINT death
+#[cfg(feature = "native")]
+const FORCED_EXIT_CODE: i32 = 130;
+/// How often waits that have no unit of work to finish check for cancellation
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Cooperative cancellation for long-running loops. Cloning shares the flag;
+/// loops poll [`Self::is_cancelled`] between units of work (a file, a
+/// snippet, a stream window), finish the one in progress and stop.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// A token that is never cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token cancelled by Ctrl-C or SIGTERM. A second signal exits at once,
+    /// e.g. when the unit of work in progress hangs; database writes are
+    /// single transactions, so that rolls back rather than leaving partial data.
+    #[cfg(feature = "native")]
+    pub fn on_signals() -> Self {
+        let token = Self::new();
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only fires once the flag is already set
+            signal_hook::flag::register_conditional_shutdown(
+                signal,
+                FORCED_EXIT_CODE,
+                Arc::clone(&token.0),
+            )
+            .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&token.0)))
+            .expect("failed to install the signal handler");
+        }
+        token
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,161 @@
+//! Harmonic pitch class profiles (HPCP) of magnitude spectra, and the
+//! musical key they point to. Cover matching compares songs by the HPCP of
+//! each beat; ingest sums it over a whole song to estimate its key.
+
+use std::f32::consts::PI;
+use std::fmt;
+
+pub const CHROMA_BINS: usize = 12;
+/// Spectral peaks outside this range, in Hz, do not count towards the HPCP
+const MIN_FREQ: f32 = 100.0;
+pub const MAX_FREQ: f32 = 5000.0;
+/// Pitch class 0 is A
+const REFERENCE_FREQ: f32 = 440.0;
+/// Peaks weaker than this fraction of the frame's strongest are ignored
+const PEAK_THRESHOLD: f32 = 0.01;
+/// A peak also counts towards the pitch of its first harmonics, with a
+/// weight decaying by this factor per harmonic
+const HARMONICS: usize = 4;
+const HARMONIC_DECAY: f32 = 0.6;
+/// Width of the cos² window a peak's weight is spread over, in semitones
+const SPREAD_SEMITONES: f32 = 4.0 / 3.0;
+/// Names of the pitch classes, from A
+const PITCH_NAMES: [&str; CHROMA_BINS] = [
+    "A", "Bb", "B", "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab",
+];
+/// Krumhansl-Kessler key profiles: how well each pitch class, in semitones
+/// above the tonic, fits a major and a minor key
+const MAJOR_PROFILE: [f32; CHROMA_BINS] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; CHROMA_BINS] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Harmonic pitch class profile of one magnitude spectrum whose bins are
+/// `bin_hz` apart: every spectral peak adds its energy to the pitch classes
+/// of the fundamentals it may be a harmonic of
+pub fn frame_hpcp(magnitudes: &[f32], bin_hz: f32) -> [f32; CHROMA_BINS] {
+    let mut hpcp = [0.0f32; CHROMA_BINS];
+    let min_bin = ((MIN_FREQ / bin_hz).ceil() as usize).max(1);
+    let max_bin = ((MAX_FREQ / bin_hz) as usize).min(magnitudes.len().saturating_sub(2));
+    if min_bin > max_bin {
+        return hpcp;
+    }
+
+    let strongest = magnitudes[min_bin..=max_bin]
+        .iter()
+        .fold(0.0f32, |max, &m| max.max(m));
+    if strongest <= 0.0 {
+        return hpcp;
+    }
+
+    for bin in min_bin..=max_bin {
+        let (before, peak, after) = (magnitudes[bin - 1], magnitudes[bin], magnitudes[bin + 1]);
+        if peak < strongest * PEAK_THRESHOLD || peak <= before || peak < after {
+            continue;
+        }
+        // Parabolic interpolation of the peak's frequency between bins
+        let curvature = before - 2.0 * peak + after;
+        let shift = if curvature != 0.0 {
+            0.5 * (before - after) / curvature
+        } else {
+            0.0
+        };
+        let freq = (bin as f32 + shift) * bin_hz;
+
+        for harmonic in 1..=HARMONICS {
+            let fundamental = freq / harmonic as f32;
+            if fundamental < MIN_FREQ {
+                break;
+            }
+            let weight = peak * peak * HARMONIC_DECAY.powi(harmonic as i32 - 1);
+            let pitch = CHROMA_BINS as f32 * (fundamental / REFERENCE_FREQ).log2();
+            for (class, value) in hpcp.iter_mut().enumerate() {
+                let distance = (pitch - class as f32).rem_euclid(CHROMA_BINS as f32);
+                let distance = distance.min(CHROMA_BINS as f32 - distance);
+                if distance <= SPREAD_SEMITONES / 2.0 {
+                    *value += weight * (PI * distance / SPREAD_SEMITONES).cos().powi(2);
+                }
+            }
+        }
+    }
+    scale_to_max(hpcp)
+}
+
+pub fn scale_to_max(chroma: [f32; CHROMA_BINS]) -> [f32; CHROMA_BINS] {
+    let max = chroma.iter().fold(0.0f32, |max, &v| max.max(v));
+    if max > 0.0 {
+        chroma.map(|v| v / max)
+    } else {
+        chroma
+    }
+}
+
+/// A musical key: its tonic as a pitch class counted from A, and its mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    pub tonic: usize,
+    pub minor: bool,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = if self.minor { "minor" } else { "major" };
+        write!(f, "{} {}", PITCH_NAMES[self.tonic % CHROMA_BINS], mode)
+    }
+}
+
+/// Key of a chroma summed over a whole song, by the Krumhansl-Schmuckler
+/// algorithm: the major or minor key profile, rotated to each of the 12
+/// tonics, that correlates best with it. `None` for a chroma without any
+/// energy or that fits no key.
+pub fn estimate_key(chroma: &[f32; CHROMA_BINS]) -> Option<Key> {
+    let candidates = (0..CHROMA_BINS).flat_map(|tonic| {
+        [(tonic, false), (tonic, true)].map(|(tonic, minor)| {
+            let profile = if minor {
+                &MINOR_PROFILE
+            } else {
+                &MAJOR_PROFILE
+            };
+            let rotated: Vec<f32> = (0..CHROMA_BINS)
+                .map(|semitones| chroma[(tonic + semitones) % CHROMA_BINS])
+                .collect();
+            (Key { tonic, minor }, correlation(&rotated, profile))
+        })
+    });
+    candidates
+        .filter(|(_, r)| *r > 0.0)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(key, _)| key)
+}
+
+/// Pearson correlation of `a` and `b`, 0 when either is flat
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean = |v: &[f32]| v.iter().sum::<f32>() / v.len() as f32;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a <= f32::EPSILON || var_b <= f32::EPSILON {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+/// Key of magnitude spectra at `sample_rate`, as the `stft` stage of
+/// [`crate::dsp`] yields them, from the sum of their frames' HPCP
+pub fn spectra_key(frames: &[(f32, Vec<f32>)], sample_rate: u32) -> Option<Key> {
+    let mut total = [0.0f32; CHROMA_BINS];
+    for (_, magnitudes) in frames {
+        // Magnitudes cover the bins up to Nyquist, half the frame
+        let bin_hz = sample_rate as f32 / (2 * magnitudes.len().max(1)) as f32;
+        for (sum, value) in total.iter_mut().zip(frame_hpcp(magnitudes, bin_hz)) {
+            *sum += value;
+        }
+    }
+    estimate_key(&total)
+}
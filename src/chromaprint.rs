@@ -0,0 +1,308 @@
+use crate::audio_processor::AudioProcessor;
+use crate::fft::fft::CooleyTukeyFFT;
+use std::f32::consts::PI;
+
+// A port of Chromaprint's default algorithm (`CHROMAPRINT_ALGORITHM_TEST2`),
+// the fingerprint AcoustID indexes. Unlike Sabi's peak-pair hashes it
+// describes the whole track as one sequence of 32-bit sub-fingerprints, one
+// per STFT frame, each encoding how the 12 pitch classes (chroma) change
+// over the next 16 frames.
+
+/// Rate Chromaprint analyzes audio at
+pub const SAMPLE_RATE: u32 = 11_025;
+/// Audio fingerprinted by default, from the start of the file, as `fpcalc`
+pub const MAX_DURATION_SECS: f32 = 120.0;
+/// Algorithm id written into compressed fingerprints
+const ALGORITHM_TEST2: u8 = 1;
+
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+const NUM_BANDS: usize = 12;
+/// Smoothing applied to consecutive chroma vectors
+const FILTER_COEFFICIENTS: [f32; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+/// Chroma vectors quieter than this are zeroed instead of normalized
+const NORMALIZE_THRESHOLD: f32 = 0.01;
+/// Frames covered by the widest classifier filter
+const MAX_FILTER_WIDTH: usize = 16;
+const GRAY_CODE: [u32; 4] = [0, 1, 3, 2];
+
+/// Region of the chroma image a classifier compares, and how
+struct Filter {
+    /// Shape of the comparison, 0 to 5
+    kind: u8,
+    /// First chroma band
+    y: usize,
+    /// Chroma bands covered
+    height: usize,
+    /// Frames covered
+    width: usize,
+}
+
+/// A filter and the thresholds quantizing its response into 2 bits
+struct Classifier {
+    filter: Filter,
+    thresholds: [f32; 3],
+}
+
+const fn classifier(kind: u8, y: usize, height: usize, width: usize, t: [f32; 3]) -> Classifier {
+    Classifier {
+        filter: Filter {
+            kind,
+            y,
+            height,
+            width,
+        },
+        thresholds: t,
+    }
+}
+
+/// The 16 classifiers of the default algorithm, trained by Chromaprint
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(0, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(4, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+    classifier(1, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(3, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(3, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(4, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+    classifier(1, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(5, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+    classifier(3, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(2, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(3, 5, 6, 4, [-0.0799915, -0.00729616, 0.063262]),
+    classifier(1, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(3, 4, 2, 14, [-0.164292, -0.0321188, 0.0846339]),
+];
+
+/// Chromaprint sub-fingerprints of mono `samples`, which are resampled to
+/// [`SAMPLE_RATE`] first. Empty for audio shorter than about two seconds.
+pub fn fingerprint(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+    let audio_processor = AudioProcessor::new();
+    let filtered = audio_processor.apply_low_pass_filter(samples, sample_rate, 5000.0);
+    let resampled = audio_processor.resample_linear(&filtered, sample_rate, SAMPLE_RATE);
+
+    let image = chroma_image(&resampled);
+    if image.len() < MAX_FILTER_WIDTH {
+        return Vec::new();
+    }
+    let integral = IntegralImage::new(&image);
+
+    (0..=image.len() - MAX_FILTER_WIDTH)
+        .map(|offset| {
+            CLASSIFIERS.iter().fold(0u32, |bits, classifier| {
+                let value = classifier.filter.apply(&integral, offset);
+                (bits << 2) | GRAY_CODE[quantize(value, &classifier.thresholds)]
+            })
+        })
+        .collect()
+}
+
+/// Filtered and normalized chroma vectors, one per frame
+fn chroma_image(samples: &[f32]) -> Vec<[f32; NUM_BANDS]> {
+    let fft = CooleyTukeyFFT::new(FRAME_SIZE, FRAME_SIZE - HOP_SIZE);
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f32 / (FRAME_SIZE - 1) as f32).cos())
+        .collect();
+
+    // Pitch class of each FFT bin in the analyzed range
+    let freq_to_index =
+        |freq: f64| (FRAME_SIZE as f64 * freq / SAMPLE_RATE as f64).round() as usize;
+    let min_index = freq_to_index(MIN_FREQ).max(1);
+    let max_index = freq_to_index(MAX_FREQ).min(FRAME_SIZE / 2);
+    let notes: Vec<usize> = (min_index..max_index)
+        .map(|i| {
+            let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (freq / (440.0 / 16.0)).log2();
+            (NUM_BANDS as f64 * (octave - octave.floor())) as usize
+        })
+        .collect();
+
+    let chroma: Vec<[f32; NUM_BANDS]> = (0..)
+        .map(|frame| frame * HOP_SIZE)
+        .take_while(|&start| start + FRAME_SIZE <= samples.len())
+        .map(|start| {
+            let windowed = samples[start..start + FRAME_SIZE]
+                .iter()
+                .zip(&window)
+                .map(|(sample, w)| sample * w)
+                .collect();
+            let spectrum = fft.perform_fft(windowed);
+
+            let mut features = [0.0f32; NUM_BANDS];
+            for (bin, &note) in spectrum[min_index..max_index].iter().zip(&notes) {
+                features[note] += bin.norm_sqr();
+            }
+            features
+        })
+        .collect();
+
+    chroma
+        .windows(FILTER_COEFFICIENTS.len())
+        .map(|frames| {
+            let mut smoothed = [0.0f32; NUM_BANDS];
+            for (features, coefficient) in frames.iter().zip(FILTER_COEFFICIENTS) {
+                for (s, f) in smoothed.iter_mut().zip(features) {
+                    *s += f * coefficient;
+                }
+            }
+
+            let norm = smoothed.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm < NORMALIZE_THRESHOLD {
+                [0.0; NUM_BANDS]
+            } else {
+                smoothed.map(|v| v / norm)
+            }
+        })
+        .collect()
+}
+
+/// Sums over rectangles of the chroma image in constant time
+struct IntegralImage {
+    /// `sums[r][c]` is the sum of the first `r` frames and `c` bands
+    sums: Vec<[f32; NUM_BANDS + 1]>,
+}
+
+impl IntegralImage {
+    fn new(image: &[[f32; NUM_BANDS]]) -> Self {
+        let mut sums = vec![[0.0; NUM_BANDS + 1]; image.len() + 1];
+        for (r, row) in image.iter().enumerate() {
+            for c in 0..NUM_BANDS {
+                sums[r + 1][c + 1] = row[c] + sums[r][c + 1] + sums[r + 1][c] - sums[r][c];
+            }
+        }
+        Self { sums }
+    }
+
+    /// Sum of frames `x1..x2` and bands `y1..y2`
+    fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f32 {
+        self.sums[x2][y2] - self.sums[x1][y2] - self.sums[x2][y1] + self.sums[x1][y1]
+    }
+}
+
+impl Filter {
+    /// Response of the filter placed at frame `x`
+    fn apply(&self, image: &IntegralImage, x: usize) -> f32 {
+        let (y, w, h) = (self.y, self.width, self.height);
+        let area = |x1, y1, x2, y2| image.area(x1, y1, x2, y2);
+        let (a, b) = match self.kind {
+            0 => (area(x, y, x + w, y + h), 0.0),
+            // Lower half of the bands against the upper half
+            1 => (
+                area(x, y + h / 2, x + w, y + h),
+                area(x, y, x + w, y + h / 2),
+            ),
+            // Later half of the frames against the earlier half
+            2 => (
+                area(x + w / 2, y, x + w, y + h),
+                area(x, y, x + w / 2, y + h),
+            ),
+            // Checkerboard of four quadrants
+            3 => (
+                area(x, y + h / 2, x + w / 2, y + h) + area(x + w / 2, y, x + w, y + h / 2),
+                area(x, y, x + w / 2, y + h / 2) + area(x + w / 2, y + h / 2, x + w, y + h),
+            ),
+            // Middle third of the bands against the outer thirds
+            4 => (
+                area(x, y + h / 3, x + w, y + 2 * (h / 3)),
+                area(x, y, x + w, y + h / 3) + area(x, y + 2 * (h / 3), x + w, y + h),
+            ),
+            // Middle third of the frames against the outer thirds
+            _ => (
+                area(x + w / 3, y, x + 2 * (w / 3), y + h),
+                area(x, y, x + w / 3, y + h) + area(x + 2 * (w / 3), y, x + w, y + h),
+            ),
+        };
+        ((1.0 + a) / (1.0 + b)).ln()
+    }
+}
+
+fn quantize(value: f32, thresholds: &[f32; 3]) -> usize {
+    if value < thresholds[1] {
+        if value < thresholds[0] { 0 } else { 1 }
+    } else if value < thresholds[2] {
+        2
+    } else {
+        3
+    }
+}
+
+/// Compress `fingerprint` the way `chromaprint_encode_fingerprint` does and
+/// encode it as URL-safe base64, as AcoustID and `fpcalc` expect it
+pub fn encode(fingerprint: &[u32]) -> String {
+    // Each sub-fingerprint is XORed with the previous one, and the positions
+    // of its set bits are stored as deltas: 3-bit values, with deltas of 7 and
+    // more continued in a separate stream of 5-bit values
+    let mut normal_bits = Vec::new();
+    let mut exceptional_bits = Vec::new();
+    let mut previous = 0u32;
+    for &sub_fingerprint in fingerprint {
+        let mut x = sub_fingerprint ^ previous;
+        previous = sub_fingerprint;
+
+        let (mut bit, mut last_bit) = (1u32, 0u32);
+        while x != 0 {
+            if x & 1 != 0 {
+                let delta = bit - last_bit;
+                if delta >= 7 {
+                    normal_bits.push(7);
+                    exceptional_bits.push(delta - 7);
+                } else {
+                    normal_bits.push(delta);
+                }
+                last_bit = bit;
+            }
+            x >>= 1;
+            bit += 1;
+        }
+        normal_bits.push(0);
+    }
+
+    let count = fingerprint.len() as u32;
+    let mut bytes = vec![
+        ALGORITHM_TEST2,
+        (count >> 16) as u8,
+        (count >> 8) as u8,
+        count as u8,
+    ];
+    pack_bits(&normal_bits, 3, &mut bytes);
+    pack_bits(&exceptional_bits, 5, &mut bytes);
+    base64_url(&bytes)
+}
+
+/// Append `values`, `width` bits each, least significant bit first
+fn pack_bits(values: &[u32], width: u32, out: &mut Vec<u8>) {
+    let (mut buffer, mut buffered) = (0u32, 0u32);
+    for &value in values {
+        buffer |= (value & ((1 << width) - 1)) << buffered;
+        buffered += width;
+        while buffered >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            buffered -= 8;
+        }
+    }
+    if buffered > 0 {
+        out.push(buffer as u8);
+    }
+}
+
+/// Base64 with the URL-safe alphabet and without padding
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    encoded
+}
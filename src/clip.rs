@@ -0,0 +1,55 @@
+use crate::audio_processor::AudioProcessor;
+
+/// Cut `duration` seconds from `start` out of `file` and write them to `out`
+/// as a mono 16-bit WAV, resampled to `sample_rate` if one is given. Handy
+/// for building test sets and reproducing a failing match without ffmpeg.
+pub fn run_clip(
+    file: &str,
+    start: f64,
+    duration: f64,
+    out: &str,
+    sample_rate: Option<u32>,
+    track: Option<usize>,
+) {
+    if start < 0.0 || duration <= 0.0 {
+        eprintln!("Error: --start must not be negative and --duration must be positive");
+        return;
+    }
+    if sample_rate == Some(0) {
+        eprintln!("Error: --sample-rate must be positive");
+        return;
+    }
+
+    let audio_processor = AudioProcessor::new().with_track(track);
+    if file != AudioProcessor::STDIN {
+        match audio_processor.probe_info(file.to_string()) {
+            Ok(info) if start >= info.duration => {
+                eprintln!(
+                    "❌ {} is only {:.2}s long, cannot start a clip at {}s",
+                    file, info.duration, start
+                );
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("❌ Cannot read {}: {}", file, e);
+                return;
+            }
+        }
+    }
+
+    let (samples, source_rate) =
+        audio_processor.get_decoded_audio_range(file.to_string(), start, Some(duration));
+    let rate = sample_rate.unwrap_or(source_rate);
+    let samples = audio_processor.resample_linear(&samples, source_rate, rate);
+    if let Err(e) = audio_processor.write_wav(out, &samples, rate) {
+        eprintln!("❌ Failed to write {}: {}", out, e);
+        return;
+    }
+
+    let written = samples.len() as f64 / rate as f64;
+    if written + 0.01 < duration {
+        println!("⚠️ The file ends {:.2}s into the clip", written);
+    }
+    println!("✅ Wrote {:.2}s at {} Hz to {}", written, rate, out);
+}
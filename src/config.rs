@@ -0,0 +1,806 @@
+use crate::audio_processor::AudioProcessor;
+#[cfg(feature = "db-postgres")]
+use crate::db::connector::CollectionKind;
+use crate::dsp;
+use crate::fingerprint::FINGERPRINT_VERSION;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "db-postgres")]
+use std::borrow::Cow;
+use std::{fs, path::Path};
+use toml::{Table, Value};
+
+/// Read from the working directory when no `--config` is given
+const DEFAULT_CONFIG_PATH: &str = "sabi.toml";
+
+/// Every tunable of the recognition pipeline.
+///
+/// Loaded from `sabi.toml`, where any missing key keeps its built-in default,
+/// then adjusted by `--set section.key=value` on the command line. Changing
+/// `[audio]`, `[fft]`, `[fingerprint]` or `[pipeline]` changes the
+/// fingerprints themselves, so a library ingested with other settings has to
+/// be rebuilt with `reingest`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub audio: AudioConfig,
+    pub fft: FftConfig,
+    pub fingerprint: FingerprintConfig,
+    pub pipeline: PipelineConfig,
+    pub matching: MatchingConfig,
+    pub recording: RecordingConfig,
+    pub decode: DecodeConfig,
+    pub enrich: EnrichConfig,
+    pub cover: CoverConfig,
+    pub short_reference: ShortReferenceConfig,
+    pub notify: NotifyConfig,
+    pub playback: PlaybackConfig,
+    pub server: ServerConfig,
+}
+
+/// Preprocessing of decoded audio
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Rate everything is resampled to before the STFT, in Hz
+    pub target_sample_rate: u32,
+    /// Cutoff of the low-pass filter applied before resampling, in Hz
+    pub low_pass_cutoff: f32,
+    /// Remove DC offset and rumble below `high_pass_cutoff` first
+    pub high_pass: bool,
+    /// Cutoff of that first-order high-pass filter, in Hz
+    pub high_pass_cutoff: f32,
+    /// Bring the audio to `target_loudness_db` before filtering
+    pub normalize: bool,
+    /// Gated RMS level normalization aims for, in dBFS
+    pub target_loudness_db: f32,
+    /// Peak level the limiter after normalization holds samples below, in dBFS
+    pub limiter_ceiling_db: f32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            target_sample_rate: AudioProcessor::TARGET_SAMPLE_RATE,
+            low_pass_cutoff: 5000.0,
+            high_pass: false,
+            high_pass_cutoff: 20.0,
+            normalize: false,
+            target_loudness_db: -20.0,
+            limiter_ceiling_db: -1.0,
+        }
+    }
+}
+
+/// STFT framing and peak picking
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FftConfig {
+    /// Samples per frame, a power of two
+    pub chunk_size: usize,
+    /// Samples shared by consecutive frames
+    pub overlap_size: usize,
+    /// Transform each frame zero-padded to this many times `chunk_size`, a
+    /// power of two up to 8, for finer frequency bins at the same frame
+    /// length
+    pub zero_padding: usize,
+    /// A peak must be this many times stronger than its band's average
+    pub threshold_multiplier: f32,
+    /// Strongest peaks kept per band and frame
+    pub max_peaks_per_band: usize,
+    /// Divide each bin's magnitude by its running average over time before
+    /// picking peaks
+    pub whitening: bool,
+    /// Per-frame decay of that running average; closer to 1 averages longer
+    pub whitening_decay: f32,
+    /// How peaks are selected from the spectrogram
+    pub peak_picking: PeakPicking,
+    /// Height of the `local_max` neighborhood in frequency bins of the
+    /// unpadded frame
+    pub neighborhood_bins: usize,
+    /// Width of the `local_max` neighborhood in frames
+    pub neighborhood_frames: usize,
+    /// Lowest frequency peaks are picked at, in Hz
+    pub min_freq: f32,
+    /// Highest frequency peaks are picked at, in Hz; at most half of
+    /// `audio.target_sample_rate`
+    pub max_freq: f32,
+    /// Pick no peaks in frames of silence, such as quiet intros and outros,
+    /// whose peaks are only the noise floor
+    pub skip_silence: bool,
+    /// dB below the recording's loud level under which a frame is silence
+    pub silence_threshold_db: f32,
+}
+
+/// Peak selection strategy of `[fft]`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeakPicking {
+    /// Local maxima along frequency, thresholded against their band per frame
+    #[default]
+    Bands,
+    /// Points that are the strongest of their time × frequency neighborhood
+    LocalMax,
+}
+
+impl Default for FftConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 2048,
+            overlap_size: 1024,
+            zero_padding: 1,
+            threshold_multiplier: 1.75,
+            max_peaks_per_band: 5,
+            whitening: false,
+            whitening_decay: 0.95,
+            peak_picking: PeakPicking::Bands,
+            neighborhood_bins: 9,
+            neighborhood_frames: 9,
+            min_freq: 20.0,
+            max_freq: 5000.0,
+            skip_silence: true,
+            silence_threshold_db: 50.0,
+        }
+    }
+}
+
+/// Peak pairing and hash quantization
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FingerprintConfig {
+    /// Frames after an anchor whose peaks are paired with it
+    pub max_target_zone: usize,
+    /// First frame after an anchor that is paired with it
+    pub min_target_zone_dist: usize,
+    /// Width of the frequency bins in a hash, in Hz
+    pub freq_step: f32,
+    /// Width of the time-delta bins in a hash, in seconds
+    pub delta_step: f32,
+    /// Strongest peaks per frame used as anchors, 0 for all
+    pub max_anchors_per_frame: usize,
+    /// Strongest target peaks paired with each anchor, 0 for all in the zone
+    pub max_pairs_per_anchor: usize,
+    /// Fingerprints a library song keeps per second of audio, strongest
+    /// first, 0 for all. Queries are never pruned.
+    pub max_fingerprints_per_second: usize,
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        Self {
+            max_target_zone: 60,
+            min_target_zone_dist: 1,
+            freq_step: 50.0,
+            delta_step: 0.1,
+            max_anchors_per_frame: 0,
+            max_pairs_per_anchor: 0,
+            max_fingerprints_per_second: 0,
+        }
+    }
+}
+
+/// Order of the fingerprinting stages (see [`crate::dsp`])
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Stages run in this order. `source`, `filter` and `resample` may be
+    /// reordered or left out; `stft`, `peaks` and `hash` come last.
+    pub stages: Vec<String>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            stages: dsp::STAGES.iter().map(|stage| stage.to_string()).collect(),
+        }
+    }
+}
+
+/// Offset voting and confidence scoring
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchingConfig {
+    /// Width of the offset histogram bins votes are counted in, in seconds
+    pub vote_bin_width: f32,
+    /// Results below this confidence are discarded as "no match"
+    pub min_confidence: f32,
+    /// Results with fewer hash hits in their winning offset bin are
+    /// discarded too
+    pub min_score: usize,
+    /// Fraction of query fingerprints landing in the winning offset bin that
+    /// is treated as a fully dense match
+    pub full_match_density: f32,
+    /// Weight each hash hit by the rarity of its hash across the library
+    pub idf_weighting: bool,
+    /// Weight each hash hit by the strength of the query's peak pair
+    pub magnitude_weighting: bool,
+    /// Also look up the hashes one quantization bin off each query hash
+    pub neighbor_expansion: bool,
+    /// Vote weight of a hit found through a neighboring hash
+    pub neighbor_weight: f32,
+    /// Also fit alignments for queries played slightly fast or slow
+    pub speed_search: bool,
+    /// Largest speed difference `speed_search` covers, as a fraction
+    pub max_speed_deviation: f32,
+    /// Speeds tried across that range
+    pub speed_steps: usize,
+    /// Rate every song on its own instead of against the best other one, and
+    /// keep all above `min_confidence`, for queries holding several songs at
+    /// once such as mashups and transitions
+    pub multi_match: bool,
+}
+
+impl Default for MatchingConfig {
+    fn default() -> Self {
+        Self {
+            vote_bin_width: 0.03,
+            min_confidence: crate::fingerprint::MIN_CONFIDENCE,
+            min_score: 0,
+            full_match_density: 0.02,
+            idf_weighting: false,
+            magnitude_weighting: false,
+            neighbor_expansion: false,
+            neighbor_weight: 0.5,
+            speed_search: false,
+            max_speed_deviation: 0.03,
+            speed_steps: 13,
+            multi_match: false,
+        }
+    }
+}
+
+/// Microphone capture for `--recognise`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Seconds recorded; with `vad`, seconds of active audio captured
+    pub duration: f32,
+    /// Apply the `[audio]` high-pass to microphone input even when
+    /// `audio.high_pass` is off; cheap microphones add DC bias and rumble
+    pub high_pass: bool,
+    /// Wait for sound before recording and stop once `duration` seconds of
+    /// it were heard, instead of recording a fixed `duration`
+    pub vad: bool,
+    /// `vad` gives up after this many seconds in total
+    pub max_duration: f32,
+    /// A frame is active when louder than this, in dBFS
+    pub vad_threshold_db: f32,
+    /// ...and crossing zero on at most this fraction of samples, which
+    /// rejects hiss and other broadband noise
+    pub vad_max_zero_crossing_rate: f32,
+    /// Subtract the estimated noise spectrum from recorded queries before
+    /// peak picking; songs being ingested are never denoised
+    pub denoise: bool,
+    /// Seconds at the start of a query the noise spectrum is first estimated from
+    pub denoise_secs: f32,
+    /// Multiple of the noise estimate subtracted from each bin
+    pub denoise_over_subtraction: f32,
+    /// Fraction of a bin's magnitude always kept, so subtraction cannot empty it
+    pub denoise_floor: f32,
+    /// Show the input level and a coarse spectrum on stderr while recording,
+    /// when it is a terminal
+    pub meter: bool,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            duration: 5.0,
+            high_pass: true,
+            vad: false,
+            max_duration: 20.0,
+            vad_threshold_db: -40.0,
+            vad_max_zero_crossing_rate: 0.3,
+            denoise: false,
+            denoise_secs: 0.3,
+            denoise_over_subtraction: 1.5,
+            denoise_floor: 0.2,
+            meter: true,
+        }
+    }
+}
+
+/// Opening and decoding audio files
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodeConfig {
+    /// Decode files symphonia cannot open (WMA, RealAudio, ALAC in unusual
+    /// containers) by piping them through `ffmpeg`; needs the
+    /// `ffmpeg-fallback` cargo feature and ffmpeg on the PATH
+    pub ffmpeg_fallback: bool,
+}
+
+/// MusicBrainz lookups of `--ingest --enrich`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichConfig {
+    /// AcoustID API key, to identify songs by their audio; without one only
+    /// songs tagged with a MusicBrainz id, or a title and artist, are found
+    pub acoustid_key: Option<String>,
+    /// JSON file caching lookups between runs
+    pub cache_file: String,
+    /// Lowest AcoustID score a match is accepted with, in [0, 1]
+    pub min_acoustid_score: f32,
+    /// Lowest MusicBrainz search score a tag match is accepted with, up to 100
+    pub min_search_score: u32,
+}
+
+impl Default for EnrichConfig {
+    fn default() -> Self {
+        Self {
+            acoustid_key: None,
+            cache_file: ".sabi-musicbrainz.json".to_string(),
+            min_acoustid_score: 0.8,
+            min_search_score: 90,
+        }
+    }
+}
+
+/// Chroma profiles and `--match --mode cover`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoverConfig {
+    /// Store the beat chroma of every ingested or reingested song, which
+    /// cover matching compares queries with
+    pub ingest_profiles: bool,
+    /// Songs below this chroma similarity are not reported, up to 1
+    pub min_similarity: f32,
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        Self {
+            ingest_profiles: true,
+            min_similarity: 0.5,
+        }
+    }
+}
+
+/// Fingerprinting of `short-reference` collections and `detect`. Jingles and
+/// adverts last a few seconds, so they need more fingerprints per second than
+/// songs, and pairs close enough in time to fit inside the clip.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShortReferenceConfig {
+    /// Replaces `fft.threshold_multiplier`
+    pub threshold_multiplier: f32,
+    /// Replaces `fft.max_peaks_per_band`
+    pub max_peaks_per_band: usize,
+    /// Replaces `fingerprint.max_target_zone`
+    pub max_target_zone: usize,
+    /// Aligned hash hits an occurrence needs at least
+    pub min_hits: usize,
+    /// Fraction of a clip's fingerprints an occurrence has to hit
+    pub min_coverage: f32,
+}
+
+impl Default for ShortReferenceConfig {
+    fn default() -> Self {
+        Self {
+            threshold_multiplier: 1.3,
+            max_peaks_per_band: 8,
+            max_target_zone: 20,
+            min_hits: 12,
+            min_coverage: 0.1,
+        }
+    }
+}
+
+/// Where `--recognise`, `monitor` and `serve` report confident matches. No
+/// sink is set up by default.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Matches below this confidence are not reported, in [0, 1]
+    pub min_confidence: f32,
+    /// URL every match is POSTed to as JSON
+    pub webhook_url: Option<String>,
+    /// File a line is appended to per match, like MPD's log
+    pub mpd_log: Option<String>,
+    /// Last.fm API account and the user session scrobbles are sent with;
+    /// all three or none
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub lastfm_session_key: Option<String>,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            min_confidence: 0.5,
+            webhook_url: None,
+            mpd_log: None,
+            lastfm_api_key: None,
+            lastfm_api_secret: None,
+            lastfm_session_key: None,
+        }
+    }
+}
+
+/// How `--play-from-match` hands the matched song over
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// Player to run instead of playing through the default output device,
+    /// split into arguments at whitespace. `{path}` is replaced by the song's
+    /// file and `{start}` by the position to start at, in seconds.
+    pub command: Option<String>,
+}
+
+/// Access control of `serve`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Refuse recognition requests without an API key; `/ingest` always
+    /// needs one
+    pub require_auth: bool,
+    /// Requests each API key, or each address without a key, may make per
+    /// minute on average; 0 for no limit
+    pub rate_limit_per_minute: f64,
+    /// Requests allowed in a burst on top of the average
+    pub rate_limit_burst: u32,
+    /// Recognitions in progress at once, over HTTP and gRPC; more are
+    /// refused until one finishes
+    pub max_concurrent_recognitions: usize,
+    /// Jobs each stage of the recognition pipeline runs at once, 0 for one
+    /// per CPU
+    pub pipeline_workers: usize,
+    /// Jobs waiting between two stages before the earlier one waits too
+    pub pipeline_queue: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            require_auth: false,
+            rate_limit_per_minute: 60.0,
+            rate_limit_burst: 10,
+            max_concurrent_recognitions: 32,
+            pipeline_workers: 0,
+            pipeline_queue: 4,
+        }
+    }
+}
+
+impl Config {
+    /// Read `path`, or `sabi.toml` if it exists, and apply `overrides` of the
+    /// form `section.key=value` on top
+    pub fn load(path: Option<&str>, overrides: &[String]) -> Result<Self, String> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_table(DEFAULT_CONFIG_PATH)?,
+            None => Table::new(),
+        };
+        for assignment in overrides {
+            apply_override(&mut table, assignment)?;
+        }
+        Self::from_table(table)
+    }
+
+    /// Parse the text of a config file, e.g. one fetched by a browser client
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let table = text
+            .parse::<Table>()
+            .map_err(|e| format!("config is not valid TOML: {}", e))?;
+        Self::from_table(table)
+    }
+
+    fn from_table(table: Table) -> Result<Self, String> {
+        let config: Config = Value::Table(table)
+            .try_into()
+            .map_err(|e| format!("invalid configuration: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject settings the pipeline cannot run with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.decode.ffmpeg_fallback && !cfg!(feature = "ffmpeg-fallback") {
+            return Err(
+                "decode.ffmpeg_fallback needs sabi built with `--features ffmpeg-fallback`"
+                    .to_string(),
+            );
+        }
+        let nyquist = self.audio.target_sample_rate as f32 / 2.0;
+        if self.fft.min_freq < 0.0
+            || self.fft.min_freq >= self.fft.max_freq
+            || self.fft.max_freq > nyquist
+        {
+            return Err(format!(
+                "fft.min_freq must be below fft.max_freq, which must be at most half of audio.target_sample_rate ({} Hz)",
+                nyquist
+            ));
+        }
+        if self.audio.low_pass_cutoff > nyquist {
+            return Err(format!(
+                "audio.low_pass_cutoff must be at most half of audio.target_sample_rate ({} Hz)",
+                nyquist
+            ));
+        }
+        dsp::validate_stages(&self.pipeline.stages)?;
+        if !self.fft.chunk_size.is_power_of_two() {
+            return Err("fft.chunk_size must be a power of two".to_string());
+        }
+        if self.fft.overlap_size >= self.fft.chunk_size {
+            return Err("fft.overlap_size must be smaller than fft.chunk_size".to_string());
+        }
+        if !self.fft.zero_padding.is_power_of_two() || self.fft.zero_padding > 8 {
+            return Err("fft.zero_padding must be 1, 2, 4 or 8".to_string());
+        }
+        if self.audio.high_pass_cutoff <= 0.0
+            || self.audio.high_pass_cutoff >= self.audio.low_pass_cutoff
+        {
+            return Err(
+                "audio.high_pass_cutoff must be positive and below audio.low_pass_cutoff"
+                    .to_string(),
+            );
+        }
+        if self.audio.limiter_ceiling_db > 0.0
+            || self.audio.target_loudness_db >= self.audio.limiter_ceiling_db
+        {
+            return Err(
+                "audio.target_loudness_db must be below audio.limiter_ceiling_db, which must be at most 0"
+                    .to_string(),
+            );
+        }
+        if !(0.0..1.0).contains(&self.fft.whitening_decay) {
+            return Err("fft.whitening_decay must be in [0, 1)".to_string());
+        }
+        if self.fft.silence_threshold_db <= 0.0 {
+            return Err("fft.silence_threshold_db must be positive".to_string());
+        }
+        if self.recording.duration <= 0.0 || self.recording.max_duration < self.recording.duration {
+            return Err(
+                "recording.duration must be positive and at most recording.max_duration"
+                    .to_string(),
+            );
+        }
+        if self.recording.vad_max_zero_crossing_rate <= 0.0
+            || self.recording.vad_max_zero_crossing_rate > 1.0
+        {
+            return Err("recording.vad_max_zero_crossing_rate must be in (0, 1]".to_string());
+        }
+        if self.recording.denoise_secs <= 0.0
+            || self.recording.denoise_over_subtraction < 0.0
+            || !(0.0..=1.0).contains(&self.recording.denoise_floor)
+        {
+            return Err(
+                "recording.denoise_secs must be positive, denoise_over_subtraction at least 0 and denoise_floor in [0, 1]"
+                    .to_string(),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.enrich.min_acoustid_score)
+            || self.enrich.min_search_score > 100
+        {
+            return Err(
+                "enrich.min_acoustid_score must be in [0, 1] and enrich.min_search_score at most 100"
+                    .to_string(),
+            );
+        }
+        if self.cover.min_similarity > 1.0 {
+            return Err("cover.min_similarity must be at most 1".to_string());
+        }
+        if self.short_reference.max_target_zone <= self.fingerprint.min_target_zone_dist
+            || !(0.0..=1.0).contains(&self.short_reference.min_coverage)
+        {
+            return Err(
+                "short_reference.max_target_zone must be above fingerprint.min_target_zone_dist and min_coverage in [0, 1]"
+                    .to_string(),
+            );
+        }
+        if self.server.rate_limit_per_minute < 0.0 || self.server.rate_limit_burst == 0 {
+            return Err(
+                "server.rate_limit_per_minute must not be negative and rate_limit_burst at least 1"
+                    .to_string(),
+            );
+        }
+        if self.server.max_concurrent_recognitions == 0 || self.server.pipeline_queue == 0 {
+            return Err(
+                "server.max_concurrent_recognitions and pipeline_queue must be at least 1"
+                    .to_string(),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.notify.min_confidence) {
+            return Err("notify.min_confidence must be in [0, 1]".to_string());
+        }
+        let lastfm = [
+            &self.notify.lastfm_api_key,
+            &self.notify.lastfm_api_secret,
+            &self.notify.lastfm_session_key,
+        ];
+        if lastfm.iter().any(|value| value.is_some()) && lastfm.iter().any(|value| value.is_none())
+        {
+            return Err(
+                "notify.lastfm_api_key, lastfm_api_secret and lastfm_session_key go together"
+                    .to_string(),
+            );
+        }
+        if self.audio.target_sample_rate == 0
+            || self.fingerprint.freq_step <= 0.0
+            || self.fingerprint.delta_step <= 0.0
+            || self.matching.vote_bin_width <= 0.0
+        {
+            return Err("sample rate, bin widths and steps must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// The settings songs of a `kind` collection are fingerprinted with:
+    /// these, or for short references with `[short_reference]` applied and
+    /// no per-second fingerprint budget
+    #[cfg(feature = "db-postgres")]
+    pub fn for_collection(&self, kind: CollectionKind) -> Cow<'_, Config> {
+        match kind {
+            CollectionKind::Songs => Cow::Borrowed(self),
+            CollectionKind::ShortReference => {
+                let mut config = self.clone();
+                config.fft.threshold_multiplier = self.short_reference.threshold_multiplier;
+                config.fft.max_peaks_per_band = self.short_reference.max_peaks_per_band;
+                config.fingerprint.max_target_zone = self.short_reference.max_target_zone;
+                config.fingerprint.max_fingerprints_per_second = 0;
+                Cow::Owned(config)
+            }
+        }
+    }
+
+    /// Version stored with the songs fingerprinted under this config:
+    /// [`FINGERPRINT_VERSION`] when every setting of
+    /// [`Self::fingerprint_parameters`] is at its default, otherwise one
+    /// derived from them. A song ingested under other settings then counts as
+    /// outdated, like one from an older release, instead of silently never
+    /// matching. Short reference collections pick their peaks differently, so
+    /// their version is that of [`Self::for_collection`].
+    pub fn fingerprint_version(&self) -> i32 {
+        let parameters = self.fingerprint_parameters();
+        if parameters == Config::default().fingerprint_parameters() {
+            return FINGERPRINT_VERSION;
+        }
+        let digest = Sha256::digest(format!("{}:{}", FINGERPRINT_VERSION, parameters.join(",")));
+        // Far above any released version, and positive
+        let derived = u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        (derived >> 2 | 1 << 30) as i32
+    }
+
+    /// Every `[audio]`, `[fft]`, `[fingerprint]` and `[pipeline]` setting the
+    /// hashes of a song depend on, as `key=value`. Settings of a step that is
+    /// turned off are left out, and so is the library-only
+    /// `max_fingerprints_per_second`, which drops fingerprints but leaves the
+    /// rest unchanged.
+    fn fingerprint_parameters(&self) -> Vec<String> {
+        let (audio, fft, fingerprint) = (&self.audio, &self.fft, &self.fingerprint);
+        let mut parameters = vec![
+            format!("audio.target_sample_rate={}", audio.target_sample_rate),
+            format!("audio.low_pass_cutoff={}", audio.low_pass_cutoff),
+            format!("audio.high_pass={}", audio.high_pass),
+            format!("audio.normalize={}", audio.normalize),
+            format!("fft.chunk_size={}", fft.chunk_size),
+            format!("fft.overlap_size={}", fft.overlap_size),
+            format!("fft.zero_padding={}", fft.zero_padding),
+            format!("fft.threshold_multiplier={}", fft.threshold_multiplier),
+            format!("fft.max_peaks_per_band={}", fft.max_peaks_per_band),
+            format!("fft.whitening={}", fft.whitening),
+            format!("fft.peak_picking={:?}", fft.peak_picking),
+            format!("fft.min_freq={}", fft.min_freq),
+            format!("fft.max_freq={}", fft.max_freq),
+            format!("fft.skip_silence={}", fft.skip_silence),
+            format!(
+                "fingerprint.max_target_zone={}",
+                fingerprint.max_target_zone
+            ),
+            format!(
+                "fingerprint.min_target_zone_dist={}",
+                fingerprint.min_target_zone_dist
+            ),
+            format!("fingerprint.freq_step={}", fingerprint.freq_step),
+            format!("fingerprint.delta_step={}", fingerprint.delta_step),
+            format!(
+                "fingerprint.max_anchors_per_frame={}",
+                fingerprint.max_anchors_per_frame
+            ),
+            format!(
+                "fingerprint.max_pairs_per_anchor={}",
+                fingerprint.max_pairs_per_anchor
+            ),
+            format!("pipeline.stages={}", self.pipeline.stages.join(">")),
+        ];
+        if audio.high_pass {
+            parameters.push(format!("audio.high_pass_cutoff={}", audio.high_pass_cutoff));
+        }
+        if audio.normalize {
+            parameters.push(format!(
+                "audio.target_loudness_db={}",
+                audio.target_loudness_db
+            ));
+            parameters.push(format!(
+                "audio.limiter_ceiling_db={}",
+                audio.limiter_ceiling_db
+            ));
+        }
+        if fft.whitening {
+            parameters.push(format!("fft.whitening_decay={}", fft.whitening_decay));
+        }
+        if fft.peak_picking == PeakPicking::LocalMax {
+            parameters.push(format!("fft.neighborhood_bins={}", fft.neighborhood_bins));
+            parameters.push(format!(
+                "fft.neighborhood_frames={}",
+                fft.neighborhood_frames
+            ));
+        }
+        if fft.skip_silence {
+            parameters.push(format!(
+                "fft.silence_threshold_db={}",
+                fft.silence_threshold_db
+            ));
+        }
+        parameters
+    }
+}
+
+fn read_table(path: &str) -> Result<Table, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    text.parse::<Table>()
+        .map_err(|e| format!("{} is not valid TOML: {}", path, e))
+}
+
+/// Set the dotted `key=value` in `table`. The value is parsed as TOML and
+/// taken as a plain string if that fails.
+fn apply_override(table: &mut Table, assignment: &str) -> Result<(), String> {
+    let (key, raw) = assignment
+        .split_once('=')
+        .ok_or_else(|| format!("override '{}' is not of the form key=value", assignment))?;
+    let raw = raw.trim();
+    let value = format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()));
+
+    let mut sections: Vec<&str> = key.trim().split('.').collect();
+    let field = sections.pop().unwrap_or_default();
+    let mut current = table;
+    for section in sections {
+        current = current
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("'{}' in '{}' is not a section", section, key))?;
+    }
+    current.insert(field.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_settings_keep_the_released_version() {
+        assert_eq!(Config::default().fingerprint_version(), FINGERPRINT_VERSION);
+    }
+
+    #[test]
+    fn changed_hash_settings_derive_their_own_version() {
+        let mut config = Config::default();
+        config.fft.chunk_size = 4096;
+        let version = config.fingerprint_version();
+        assert!(version > FINGERPRINT_VERSION);
+        assert_eq!(version, config.clone().fingerprint_version());
+
+        config.fft.overlap_size += 1;
+        assert_ne!(config.fingerprint_version(), version);
+    }
+
+    #[test]
+    fn settings_leaving_hashes_alone_keep_the_version() {
+        let mut config = Config::default();
+        config.fingerprint.max_fingerprints_per_second = 30;
+        config.fft.whitening_decay = 0.5;
+        assert_eq!(config.fingerprint_version(), FINGERPRINT_VERSION);
+
+        config.fft.whitening = true;
+        let whitened = config.fingerprint_version();
+        assert_ne!(whitened, FINGERPRINT_VERSION);
+        config.fft.whitening_decay = 0.9;
+        assert_ne!(config.fingerprint_version(), whitened);
+    }
+}
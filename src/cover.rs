@@ -0,0 +1,402 @@
+use crate::audio_processor::AudioProcessor;
+use crate::chroma::{self, CHROMA_BINS, MAX_FREQ, scale_to_max};
+use crate::config::Config;
+use crate::db::{bindings::ChromaProfileRow, connector::DB};
+use crate::fft::fft::CooleyTukeyFFT;
+use crate::scanner::format_timestamp;
+use crate::tempo::{self, OnsetDetector};
+use tracing::{info, warn};
+
+// Cover and live-version matching. Exact hashes only survive a replay of the
+// same recording; a cover changes the timbre, tempo and often the key. What
+// it keeps is the harmony, so songs are also described by the harmonic pitch
+// class profile (HPCP) of each beat. A query is compared with every song by
+// sliding its beat chroma over the song's, after rotating it into the song's
+// key, which makes the comparison independent of tempo and transposition.
+
+/// Rate audio is analyzed at
+const SAMPLE_RATE: u32 = 11_025;
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = 512;
+/// How strongly the beat tracker keeps beats one period apart
+const TIGHTNESS: f32 = 100.0;
+/// Length of the segments used instead of beats when no beat is found
+const FALLBACK_SEGMENT_SECS: f32 = 0.5;
+/// Beats a query and a song have to overlap by to be compared
+pub const MIN_BEATS: usize = 8;
+/// Cover candidates printed
+const TOP_CANDIDATES: usize = 5;
+
+/// How `--match` compares a snippet with the library
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Fingerprint hashes: finds the same recording, even in noise
+    #[default]
+    Exact,
+    /// Beat-synchronous chroma: finds covers, live versions and remixes
+    Cover,
+}
+
+/// Beat-synchronous chroma of a recording
+pub struct ChromaProfile {
+    /// Estimated tempo, in BPM
+    pub tempo: f32,
+    /// Start of each beat, in seconds
+    pub beat_times: Vec<f32>,
+    /// Mean HPCP over each beat, scaled to a maximum of 1
+    pub beats: Vec<[f32; CHROMA_BINS]>,
+}
+
+/// Bytes per beat in a stored profile: its start time and its chroma, as
+/// little-endian `f32`s
+const BEAT_BYTES: usize = 4 * (1 + CHROMA_BINS);
+
+impl ChromaProfile {
+    pub fn to_row(&self, song_id: i32) -> ChromaProfileRow {
+        let mut profile = Vec::with_capacity(self.beats.len() * BEAT_BYTES);
+        for (time, chroma) in self.beat_times.iter().zip(&self.beats) {
+            profile.extend_from_slice(&time.to_le_bytes());
+            for value in chroma {
+                profile.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        ChromaProfileRow {
+            song_id,
+            tempo: self.tempo,
+            beats: self.beats.len() as i32,
+            profile,
+        }
+    }
+
+    pub fn from_row(row: &ChromaProfileRow) -> Result<Self, String> {
+        if row.profile.len() != row.beats as usize * BEAT_BYTES {
+            return Err(format!(
+                "chroma profile of song {} holds {} bytes, expected {} beats",
+                row.song_id,
+                row.profile.len(),
+                row.beats
+            ));
+        }
+        let floats: Vec<f32> = row
+            .profile
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let (mut beat_times, mut beats) = (Vec::new(), Vec::new());
+        for beat in floats.chunks_exact(1 + CHROMA_BINS) {
+            beat_times.push(beat[0]);
+            let mut chroma = [0.0; CHROMA_BINS];
+            chroma.copy_from_slice(&beat[1..]);
+            beats.push(chroma);
+        }
+        Ok(Self {
+            tempo: row.tempo,
+            beat_times,
+            beats,
+        })
+    }
+}
+
+/// Chroma profile of mono `samples`
+pub fn chroma_profile(samples: &[f32], sample_rate: u32) -> ChromaProfile {
+    let audio_processor = AudioProcessor::new();
+    let filtered = audio_processor.apply_low_pass_filter(samples, sample_rate, MAX_FREQ);
+    let resampled = audio_processor.resample_linear(&filtered, sample_rate, SAMPLE_RATE);
+    let fft = CooleyTukeyFFT::new(FRAME_SIZE, FRAME_SIZE - HOP_SIZE);
+    let bin_hz = SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+
+    let (mut hpcp, mut onsets) = (Vec::new(), Vec::new());
+    let mut onset_detector = OnsetDetector::new();
+    for (_, spectrum) in fft.spectra(&resampled, SAMPLE_RATE) {
+        let magnitudes: Vec<f32> = spectrum[..FRAME_SIZE / 2]
+            .iter()
+            .map(|bin| bin.norm_sqr().sqrt())
+            .collect();
+        hpcp.push(chroma::frame_hpcp(&magnitudes, bin_hz));
+        onsets.push(onset_detector.next(&magnitudes));
+    }
+
+    let frame_secs = HOP_SIZE as f32 / SAMPLE_RATE as f32;
+    let (tempo, boundaries) = track_beats(&onsets, frame_secs).unwrap_or_else(|| {
+        let segment = (FALLBACK_SEGMENT_SECS / frame_secs).round() as usize;
+        (
+            60.0 / FALLBACK_SEGMENT_SECS,
+            (0..hpcp.len()).step_by(segment).collect(),
+        )
+    });
+
+    let mut profile = ChromaProfile {
+        tempo,
+        beat_times: Vec::new(),
+        beats: Vec::new(),
+    };
+    for pair in boundaries.windows(2) {
+        let mut chroma = [0.0f32; CHROMA_BINS];
+        for frame in &hpcp[pair[0]..pair[1]] {
+            for (sum, value) in chroma.iter_mut().zip(frame) {
+                *sum += value;
+            }
+        }
+        profile.beat_times.push(pair[0] as f32 * frame_secs);
+        profile.beats.push(scale_to_max(chroma));
+    }
+    profile
+}
+
+/// Tempo in BPM and beat positions, as frame indexes, of an onset strength
+/// envelope, by dynamic programming (Ellis, 2007): the beat period is that of
+/// [`tempo::beat_period`], then beats are placed on strong onsets while
+/// keeping about one period apart. `None` for audio too short or without
+/// onsets.
+fn track_beats(onsets: &[f32], frame_secs: f32) -> Option<(f32, Vec<usize>)> {
+    let n = onsets.len();
+    let period = tempo::beat_period(onsets, frame_secs)?;
+    let (_, std) = tempo::mean_and_std(onsets);
+
+    let envelope: Vec<f32> = onsets.iter().map(|o| o / std).collect();
+    let mut score = vec![0.0f32; n];
+    let mut backlink: Vec<Option<usize>> = vec![None; n];
+    for (t, onset) in envelope.iter().enumerate() {
+        let (lo, hi) = (t.saturating_sub(2 * period), t.saturating_sub(period / 2));
+        let mut best: Option<(usize, f32)> = None;
+        if t >= period / 2 {
+            for (previous, prior) in (lo..=hi).zip(&score[lo..=hi]) {
+                let ratio = (t - previous) as f32 / period as f32;
+                let candidate = prior - TIGHTNESS * ratio.ln().powi(2);
+                if best.is_none_or(|(_, current)| candidate > current) {
+                    best = Some((previous, candidate));
+                }
+            }
+        }
+        score[t] = onset + best.map_or(0.0, |(_, s)| s);
+        backlink[t] = best.map(|(previous, _)| previous);
+    }
+
+    let last = (n - period..n).max_by(|&a, &b| score[a].total_cmp(&score[b]))?;
+    let mut beats = vec![last];
+    while let Some(previous) = backlink[*beats.last()?] {
+        beats.push(previous);
+    }
+    beats.reverse();
+    Some((60.0 / (period as f32 * frame_secs), beats))
+}
+
+/// Best alignment of a query with a song, see [`compare`]
+pub struct CoverMatch {
+    /// Mean correlation of the aligned beats' chroma, up to 1
+    pub similarity: f32,
+    /// Semitones the query is transposed by against the song
+    pub transposition: i32,
+    /// Tempo of the query relative to the song
+    pub tempo_ratio: f32,
+    /// Where in the song the query starts, in seconds
+    pub song_time: f32,
+}
+
+/// Compare the beat chroma of `query` with `song`. The query is rotated into
+/// the song's key by the optimal transposition index, then slid over it beat
+/// by beat at the song's tempo, half and twice of it, in case the beat
+/// tracker locked onto a different metrical level. `None` when they do not
+/// overlap by [`MIN_BEATS`].
+pub fn compare(query: &ChromaProfile, song: &ChromaProfile) -> Option<CoverMatch> {
+    if query.beats.len() < MIN_BEATS || song.beats.len() < MIN_BEATS {
+        return None;
+    }
+
+    // Rotation of the song's pitch classes that best matches the query's
+    let key_profile = |profile: &ChromaProfile| {
+        let mut sum = [0.0f32; CHROMA_BINS];
+        for beat in &profile.beats {
+            for (s, v) in sum.iter_mut().zip(beat) {
+                *s += v;
+            }
+        }
+        sum
+    };
+    let (query_key, song_key) = (key_profile(query), key_profile(song));
+    let rotation = (0..CHROMA_BINS).max_by(|&a, &b| {
+        let fit = |k: usize| {
+            (0..CHROMA_BINS)
+                .map(|i| query_key[i] * song_key[(i + k) % CHROMA_BINS])
+                .sum::<f32>()
+        };
+        fit(a).total_cmp(&fit(b))
+    })?;
+
+    let query_beats: Vec<[f32; CHROMA_BINS]> = query.beats.iter().map(centered_unit).collect();
+
+    let mut best: Option<CoverMatch> = None;
+    for (beats_per_query_beat, song_beats) in song_tempo_variants(&song.beats) {
+        let song_beats: Vec<(usize, [f32; CHROMA_BINS])> = song_beats
+            .into_iter()
+            .map(|(start, chroma)| {
+                let rotated: [f32; CHROMA_BINS] =
+                    std::array::from_fn(|i| chroma[(i + rotation) % CHROMA_BINS]);
+                (start, centered_unit(&rotated))
+            })
+            .collect();
+
+        let (q, s) = (query_beats.len() as isize, song_beats.len() as isize);
+        for lag in (MIN_BEATS as isize - q)..=(s - MIN_BEATS as isize) {
+            let first = lag.max(0);
+            let last = (lag + q).min(s);
+            if last - first < MIN_BEATS as isize {
+                continue;
+            }
+            let similarity = (first..last)
+                .map(|j| dot(&query_beats[(j - lag) as usize], &song_beats[j as usize].1))
+                .sum::<f32>()
+                / (last - first) as f32;
+            if best.as_ref().is_none_or(|b| similarity > b.similarity) {
+                let start = song_beats[first as usize].0;
+                best = Some(CoverMatch {
+                    similarity,
+                    transposition: semitones(rotation),
+                    tempo_ratio: beats_per_query_beat * query.tempo / song.tempo,
+                    song_time: song.beat_times[start],
+                });
+            }
+        }
+    }
+    best
+}
+
+/// Beat chroma, each with the index of the song beat it starts at
+type IndexedBeats = Vec<(usize, [f32; CHROMA_BINS])>;
+
+/// The song's beats as they are, merged in pairs (the query's beat tracker
+/// found half the song's tempo) and each split in two (twice the tempo),
+/// with the number of song beats per query beat
+fn song_tempo_variants(beats: &[[f32; CHROMA_BINS]]) -> Vec<(f32, IndexedBeats)> {
+    let same = beats.iter().copied().enumerate().collect();
+    let halved = beats
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let merged: [f32; CHROMA_BINS] =
+                std::array::from_fn(|bin| pair.iter().map(|beat| beat[bin]).sum());
+            (2 * i, merged)
+        })
+        .collect();
+    let doubled = beats
+        .iter()
+        .enumerate()
+        .flat_map(|(i, beat)| [(i, *beat), (i, *beat)])
+        .collect();
+    vec![(1.0, same), (2.0, halved), (0.5, doubled)]
+}
+
+/// `chroma` minus its mean, scaled to unit length, so the dot product of two
+/// is their correlation
+fn centered_unit(chroma: &[f32; CHROMA_BINS]) -> [f32; CHROMA_BINS] {
+    let mean = chroma.iter().sum::<f32>() / CHROMA_BINS as f32;
+    let centered = chroma.map(|v| v - mean);
+    let norm = dot(&centered, &centered).sqrt();
+    if norm > f32::EPSILON {
+        centered.map(|v| v / norm)
+    } else {
+        [0.0; CHROMA_BINS]
+    }
+}
+
+fn dot(a: &[f32; CHROMA_BINS], b: &[f32; CHROMA_BINS]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// A song pitch class rotation as the query's transposition, -5 to +6
+fn semitones(rotation: usize) -> i32 {
+    let shift = ((CHROMA_BINS - rotation) % CHROMA_BINS) as i32;
+    if shift > 6 { shift - 12 } else { shift }
+}
+
+/// Compute and store the chroma profile of an ingested song. A failure is
+/// only reported, the song stays matchable by its fingerprints.
+pub fn store_profile(db: &DB, song_id: i32, samples: &[f32], sample_rate: u32) {
+    let profile = chroma_profile(samples, sample_rate);
+    info!(
+        "Chroma profile: {} beats at {:.0} BPM",
+        profile.beats.len(),
+        profile.tempo
+    );
+    if let Err(e) = db.write_chroma_profile(&profile.to_row(song_id)) {
+        eprintln!(
+            "⚠️ Failed to store the chroma profile of song {}, reingest to retry: {}",
+            song_id, e
+        );
+    }
+}
+
+/// `--match --mode cover`: find songs in `collection` that `file_name` may be
+/// a cover, live version or remix of, by chroma similarity
+pub fn run_cover_match(file_name: &str, track: Option<usize>, collection: &str, config: &Config) {
+    let audio_processor = AudioProcessor::from_config(&config.decode).with_track(track);
+    let (samples, sample_rate) = match audio_processor.decode_file(file_name) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("❌ Cannot decode {}: {}", file_name, e);
+            return;
+        }
+    };
+    let query = chroma_profile(&samples, sample_rate);
+    if query.beats.len() < MIN_BEATS {
+        eprintln!(
+            "❌ {} is too short for cover matching, it needs at least {} beats",
+            file_name, MIN_BEATS
+        );
+        return;
+    }
+    info!(
+        "Query chroma: {} beats at {:.0} BPM",
+        query.beats.len(),
+        query.tempo
+    );
+
+    let db = DB::new();
+    let rows = db.fetch_chroma_profiles(collection);
+    if rows.is_empty() {
+        println!(
+            "❌ No chroma profiles in collection '{}'; ingest with cover.ingest_profiles on, or run reingest",
+            collection
+        );
+        return;
+    }
+
+    let mut candidates: Vec<(i32, CoverMatch)> = rows
+        .iter()
+        .filter_map(|row| match ChromaProfile::from_row(row) {
+            Ok(profile) => compare(&query, &profile).map(|found| (row.song_id, found)),
+            Err(e) => {
+                warn!("Skipping corrupt chroma profile: {}", e);
+                None
+            }
+        })
+        .filter(|(_, found)| found.similarity >= config.cover.min_similarity)
+        .collect();
+    candidates.sort_by(|a, b| b.1.similarity.total_cmp(&a.1.similarity));
+    candidates.truncate(TOP_CANDIDATES);
+    info!("Compared with {} chroma profiles", rows.len());
+
+    if candidates.is_empty() {
+        println!("❌ No covers found");
+        return;
+    }
+    let song_ids: Vec<i32> = candidates.iter().map(|(id, _)| *id).collect();
+    let songs = db.fetch_songs(&song_ids);
+
+    println!("🎸 Possible covers and versions:");
+    for (song_id, found) in candidates {
+        let title = songs
+            .get(&song_id)
+            .map_or("<unknown>", |s| s.title.as_str());
+        println!(
+            "  id={} title=\"{}\" similarity={:.2}",
+            song_id, title, found.similarity
+        );
+        println!(
+            "    from {} of the song, {:+} semitones, {:.0}% of its tempo",
+            format_timestamp(found.song_time),
+            found.transposition,
+            found.tempo_ratio * 100.0
+        );
+    }
+}
@@ -1,2 +1,9 @@
+#[cfg(feature = "db-postgres")]
+pub mod async_connector;
 pub mod bindings;
+#[cfg(feature = "db-postgres")]
 pub mod connector;
+#[cfg(feature = "db-postgres")]
+pub mod lmdb_store;
+pub mod mmap_index;
+pub mod storage;
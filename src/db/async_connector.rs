@@ -0,0 +1,205 @@
+use crate::{
+    db::{
+        bindings::Songs,
+        storage::{AsyncFingerprintStore, PoolStatus, StoreError},
+    },
+    fingerprint::HashScheme,
+};
+use deadpool_postgres::{
+    Manager, Pool,
+    tokio_postgres::{Config, NoTls},
+};
+use dotenvy::dotenv;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    str::FromStr,
+};
+
+/// Default number of pooled connections, overridable via `DATABASE_POOL_SIZE`
+const DEFAULT_POOL_SIZE: usize = 16;
+
+/// Non-blocking handle to the fingerprint database for server mode.
+///
+/// Backed by tokio-postgres with a deadpool connection pool; cheap to clone.
+#[derive(Clone)]
+pub struct AsyncDB {
+    pool: Pool,
+}
+
+impl AsyncDB {
+    pub fn new() -> Self {
+        dotenv().ok();
+
+        let db_url = env::var("DATABASE_URL").expect("Db url must be set");
+        let pool_size = env::var("DATABASE_POOL_SIZE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_POOL_SIZE);
+
+        let pg_config = Config::from_str(&db_url)
+            .unwrap_or_else(|e| panic!("Invalid DATABASE_URL {}: {}", db_url, e));
+        let pool = Pool::builder(Manager::new(pg_config, NoTls))
+            .max_size(pool_size.max(1))
+            .build()
+            .expect("Error building database pool");
+
+        Self { pool }
+    }
+}
+
+impl AsyncFingerprintStore for AsyncDB {
+    async fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+        scheme: HashScheme,
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let mut map: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        if hashes.is_empty() {
+            return Ok(map);
+        }
+
+        let client = self.pool.get().await?;
+        let rows = match scheme {
+            HashScheme::Wide64 => {
+                let statement = client
+                    .prepare_cached(
+                        "SELECT hash , song_id , absolute_time_offset FROM fingerprint
+                         WHERE hash = ANY($1)",
+                    )
+                    .await?;
+                client.query(&statement, &[&hashes]).await?
+            }
+            HashScheme::Compact32 => {
+                // Compact hashes are stored as the bit pattern of their low 32 bits
+                let compact: Vec<i32> = hashes.iter().map(|&h| h as u32 as i32).collect();
+                let statement = client
+                    .prepare_cached(
+                        "SELECT hash::BIGINT & 4294967295 , song_id , absolute_time_offset::FLOAT8
+                         FROM fingerprint_compact WHERE hash = ANY($1)",
+                    )
+                    .await?;
+                client.query(&statement, &[&compact]).await?
+            }
+        };
+
+        for row in rows {
+            let hash: i64 = row.get(0);
+            let song_id: i32 = row.get(1);
+            let db_time: f64 = row.get(2);
+            map.entry(hash as u64)
+                .or_default()
+                .push((song_id as u32, db_time as f32));
+        }
+        Ok(map)
+    }
+
+    async fn fetch_songs(&self, song_ids: &[i32]) -> Result<HashMap<i32, Songs>, StoreError> {
+        if song_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached(
+                "SELECT id , title , created_at , duration , artist , album , track_number , year , checksum , collection , fingerprint_version , file_path , bpm , musical_key
+                 FROM songs WHERE id = ANY($1)",
+            )
+            .await?;
+
+        let rows = client.query(&statement, &[&song_ids]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let song = Songs {
+                    id: row.get(0),
+                    title: row.get(1),
+                    created_at: row.get(2),
+                    duration: row.get(3),
+                    artist: row.get(4),
+                    album: row.get(5),
+                    track_number: row.get(6),
+                    year: row.get(7),
+                    checksum: row.get(8),
+                    collection: row.get(9),
+                    fingerprint_version: row.get(10),
+                    file_path: row.get(11),
+                    bpm: row.get(12),
+                    musical_key: row.get(13),
+                };
+                (song.id, song)
+            })
+            .collect())
+    }
+
+    async fn song_versions_in_collection(
+        &self,
+        collection: &str,
+    ) -> Result<HashMap<u32, i32>, StoreError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached("SELECT id, fingerprint_version FROM songs WHERE collection = $1")
+            .await?;
+
+        let rows = client.query(&statement, &[&collection]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get::<_, i32>(0) as u32, row.get(1)))
+            .collect())
+    }
+
+    async fn denied_hashes(&self, collection: &str) -> Result<HashSet<u64>, StoreError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached("SELECT hash FROM denied_hashes WHERE collection = $1")
+            .await?;
+
+        let rows = client.query(&statement, &[&collection]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .collect())
+    }
+
+    async fn collection_hash_scheme(
+        &self,
+        collection: &str,
+    ) -> Result<Option<HashScheme>, StoreError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached("SELECT hash_bits FROM collections WHERE name = $1")
+            .await?;
+
+        match client.query_opt(&statement, &[&collection]).await? {
+            Some(row) => {
+                let bits: i16 = row.get(0);
+                HashScheme::from_bits(bits)
+                    .map(Some)
+                    .ok_or_else(|| format!("unknown hash scheme of {} bits", bits).into())
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn api_key_scopes(&self, key_hash: &str) -> Result<Option<String>, StoreError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare_cached("SELECT scopes FROM api_keys WHERE key_hash = $1")
+            .await?;
+
+        Ok(client
+            .query_opt(&statement, &[&key_hash])
+            .await?
+            .map(|row| row.get(0)))
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        let status = self.pool.status();
+        Some(PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+        })
+    }
+}
@@ -1,10 +1,14 @@
 use std::time::SystemTime;
 
+#[cfg(feature = "db-postgres")]
 use diesel::prelude::*;
 
+#[cfg(feature = "db-postgres")]
 #[derive(Queryable, Selectable, Insertable, Debug)]
 #[diesel(table_name = crate::schema::fingerprint)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
+// Required for binary COPY, which has no DEFAULT placeholder
+#[diesel(treat_none_as_default_value = false)]
 pub struct Fingerprint {
     pub hash: i64,
     pub absolute_time_offset: f64,
@@ -12,23 +16,137 @@ pub struct Fingerprint {
     pub created_at: Option<SystemTime>,
 }
 
-#[derive(Queryable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::songs)]
+#[cfg(feature = "db-postgres")]
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::fingerprint_compact)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(treat_none_as_default_value = false)]
+pub struct CompactFingerprint {
+    pub hash: i32,
+    pub absolute_time_offset: f32,
+    pub song_id: i32,
+}
+
+#[derive(Debug)]
+#[cfg_attr(
+    feature = "db-postgres",
+    derive(Queryable, Selectable),
+    diesel(table_name = crate::schema::songs),
+    diesel(check_for_backend(diesel::pg::Pg))
+)]
 pub struct Songs {
     pub id: i32,
     pub title: String,
     pub created_at: Option<SystemTime>,
+    pub duration: Option<f64>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<i32>,
+    pub year: Option<i32>,
+    pub checksum: Option<String>,
+    pub collection: String,
+    pub fingerprint_version: i32,
+    /// Where the song was ingested from, if from a file on disk
+    pub file_path: Option<String>,
+    /// Estimated tempo in beats per minute
+    pub bpm: Option<f32>,
+    /// Estimated key, e.g. "F# minor"
+    pub musical_key: Option<String>,
 }
 
+#[cfg(feature = "db-postgres")]
 #[derive(Insertable)]
 #[diesel(table_name = crate::schema::songs)]
 pub struct NewSong {
     pub title: String,
     pub created_at: Option<SystemTime>,
+    pub duration: Option<f64>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<i32>,
+    pub year: Option<i32>,
+    pub checksum: Option<String>,
+    pub collection: String,
+    pub fingerprint_version: i32,
+    /// Where the song was ingested from, if from a file on disk
+    pub file_path: Option<String>,
+    /// Estimated tempo in beats per minute
+    pub bpm: Option<f32>,
+    /// Estimated key, e.g. "F# minor"
+    pub musical_key: Option<String>,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::matches)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MatchRecord {
+    pub song_id: i32,
+    pub score: i32,
+    pub confidence: f32,
+    pub source: String,
+    pub input: Option<String>,
+    pub matched_at: SystemTime,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::matches)]
+pub struct NewMatch {
+    pub song_id: i32,
+    pub score: i32,
+    pub confidence: f32,
+    pub source: String,
+    pub input: Option<String>,
+    pub matched_at: SystemTime,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::denied_hashes)]
+pub struct NewDeniedHash {
+    pub collection: String,
+    pub hash: i64,
+    pub hash_bits: i16,
+    pub song_count: i32,
 }
 
-use diesel::sql_types::{BigInt, Double, Integer};
+/// Stored chroma profile of a song, see [`crate::cover::ChromaProfile`]
+#[cfg(feature = "db-postgres")]
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = crate::schema::chroma_profiles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ChromaProfileRow {
+    pub song_id: i32,
+    pub tempo: f32,
+    pub beats: i32,
+    pub profile: Vec<u8>,
+}
+
+/// A key `serve` accepts, as listed, see [`crate::auth`]. Its hash is only
+/// ever compared in the database.
+#[cfg(feature = "db-postgres")]
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::api_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub name: String,
+    pub scopes: String,
+    pub created_at: SystemTime,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::api_keys)]
+pub struct NewApiKey<'a> {
+    pub name: &'a str,
+    pub key_hash: &'a str,
+    pub scopes: &'a str,
+}
+
+#[cfg(feature = "db-postgres")]
+use diesel::sql_types::{BigInt, Double, Float, Integer, Nullable, Text, Timestamp};
+#[cfg(feature = "db-postgres")]
 #[derive(QueryableByName, Debug)]
 pub struct FingerprintMatch {
     #[diesel(sql_type = BigInt)]
@@ -40,3 +158,65 @@ pub struct FingerprintMatch {
     #[diesel(sql_type = Double)]
     pub absolute_time_offset: f64,
 }
+
+#[cfg(feature = "db-postgres")]
+#[derive(QueryableByName, Debug)]
+pub struct SongSummary {
+    #[diesel(sql_type = Integer)]
+    pub id: i32,
+
+    #[diesel(sql_type = Text)]
+    pub title: String,
+
+    #[diesel(sql_type = Nullable<Double>)]
+    pub duration: Option<f64>,
+
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    pub created_at: Option<SystemTime>,
+
+    #[diesel(sql_type = Nullable<Float>)]
+    pub bpm: Option<f32>,
+
+    #[diesel(sql_type = Nullable<Text>)]
+    pub musical_key: Option<String>,
+
+    #[diesel(sql_type = BigInt)]
+    pub fingerprint_count: i64,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(QueryableByName, Debug)]
+pub struct HashFrequency {
+    #[diesel(sql_type = BigInt)]
+    pub hash: i64,
+
+    #[diesel(sql_type = BigInt)]
+    pub occurrences: i64,
+
+    #[diesel(sql_type = BigInt)]
+    pub song_count: i64,
+}
+
+/// Hashes shared by exactly `song_count` songs, and their fingerprints
+#[cfg(feature = "db-postgres")]
+#[derive(QueryableByName, Debug)]
+pub struct HashSpread {
+    #[diesel(sql_type = BigInt)]
+    pub song_count: i64,
+
+    #[diesel(sql_type = BigInt)]
+    pub hashes: i64,
+
+    #[diesel(sql_type = BigInt)]
+    pub occurrences: i64,
+}
+
+#[cfg(feature = "db-postgres")]
+#[derive(QueryableByName, Debug)]
+pub struct RelationSize {
+    #[diesel(sql_type = BigInt)]
+    pub table_bytes: i64,
+
+    #[diesel(sql_type = BigInt)]
+    pub index_bytes: i64,
+}
@@ -1,53 +1,389 @@
 use crate::{
-    db::bindings::{Fingerprint, FingerprintMatch, NewSong, Songs},
-    fingerprint::FingerprintInfo,
+    db::bindings::{
+        ApiKey, ChromaProfileRow, CompactFingerprint, Fingerprint, FingerprintMatch, HashFrequency,
+        HashSpread, MatchRecord, NewApiKey, NewDeniedHash, NewMatch, NewSong, RelationSize,
+        SongSummary, Songs,
+    },
+    fingerprint::{FingerprintInfo, HashScheme, VoteResult},
 };
-use diesel::{RunQueryDsl, dsl::insert_into, prelude::*, upsert::on_constraint};
+use diesel::{
+    RunQueryDsl,
+    dsl::insert_into,
+    prelude::*,
+    r2d2::{ConnectionManager, Pool, PooledConnection},
+    upsert::{excluded, on_constraint},
+};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use dotenvy::dotenv;
-use std::{collections::HashMap, env, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    time::SystemTime,
+};
+use tracing::{debug, info, trace, warn};
+
+/// Hash lookups up to this size use `= ANY($1)`, larger ones a temp table
+const TEMP_TABLE_THRESHOLD: usize = 50_000;
+/// Hashes bound per `= ANY($1)` statement
+const ANY_CHUNK_SIZE: usize = 10_000;
+
+/// The SQL migrations under `migrations/`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Column to order song listings by
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum SongSort {
+    Id,
+    Title,
+    Duration,
+    Fingerprints,
+    Date,
+    Bpm,
+    Key,
+}
+
+impl SongSort {
+    fn column(&self) -> &'static str {
+        match self {
+            SongSort::Id => "s.id",
+            SongSort::Title => "s.title",
+            SongSort::Duration => "s.duration",
+            SongSort::Fingerprints => "fingerprint_count",
+            SongSort::Date => "s.created_at",
+            SongSort::Bpm => "s.bpm",
+            SongSort::Key => "s.musical_key",
+        }
+    }
+}
+
+/// Where a recognised snippet came from, as stored in `matches.source`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum MatchSource {
+    Mic,
+    File,
+    /// Internet radio followed by `monitor`
+    Stream,
+}
+
+impl MatchSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchSource::Mic => "mic",
+            MatchSource::File => "file",
+            MatchSource::Stream => "stream",
+        }
+    }
+}
+
+/// What a collection holds, as stored in `collections.kind`. Fixed when the
+/// collection is created, like its hash scheme.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CollectionKind {
+    /// Full songs, fingerprinted with the configured settings
+    #[default]
+    Songs,
+    /// Jingles, adverts and idents of a few seconds, fingerprinted more
+    /// densely as set in `[short_reference]`, for `detect`
+    ShortReference,
+}
+
+impl CollectionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CollectionKind::Songs => "songs",
+            CollectionKind::ShortReference => "short_reference",
+        }
+    }
+
+    fn from_name(kind: &str) -> Option<Self> {
+        match kind {
+            "songs" => Some(CollectionKind::Songs),
+            "short_reference" => Some(CollectionKind::ShortReference),
+            _ => None,
+        }
+    }
+}
+
+/// Default number of pooled connections, overridable via `DATABASE_POOL_SIZE`
+const DEFAULT_POOL_SIZE: u32 = 4;
 
+/// Handle to the fingerprint database.
+///
+/// Wraps an r2d2 connection pool, so it is cheap to clone and can be shared
+/// across threads; each method checks out a connection for its own duration.
+#[derive(Clone)]
 pub struct DB {
-    pub connector: PgConnection,
+    pool: Pool<ConnectionManager<PgConnection>>,
 }
 
 impl DB {
     pub fn new() -> Self {
         dotenv().ok();
 
+        let pool_size = env::var("DATABASE_POOL_SIZE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_POOL_SIZE);
+
+        Self::with_pool_size(pool_size)
+    }
+
+    /// Connect with at most `max_size` pooled connections. Connections are
+    /// opened lazily, on first use.
+    pub fn with_pool_size(max_size: u32) -> Self {
+        dotenv().ok();
+
         let db_url = env::var("DATABASE_URL").expect("Db url must be set");
 
-        let conn = PgConnection::establish(&db_url)
+        let manager = ConnectionManager::<PgConnection>::new(&db_url);
+        let pool = Pool::builder()
+            .max_size(max_size.max(1))
+            .min_idle(Some(0))
+            .build(manager)
             .unwrap_or_else(|_| panic!("Error connecting to {} ", db_url));
 
-        Self { connector: conn }
+        let db = Self { pool };
+        db.run_migrations();
+        db
+    }
+
+    fn conn(&self) -> PooledConnection<ConnectionManager<PgConnection>> {
+        self.pool
+            .get()
+            .expect("Error checking out a database connection")
+    }
+
+    /// Apply any embedded migrations the database hasn't seen yet, so a
+    /// fresh database is usable without running diesel-cli by hand.
+    pub fn run_migrations(&self) {
+        let mut conn = self.conn();
+        let applied = conn
+            .run_pending_migrations(MIGRATIONS)
+            .unwrap_or_else(|e| panic!("Error running migrations: {}", e));
+
+        for version in applied {
+            info!("Applied migration {}", version);
+        }
     }
 
-    pub fn write_song(&mut self, song_name: &String) -> i32 {
+    /// Look up a song previously ingested into `in_collection` by the
+    /// SHA-256 of its source file
+    pub fn find_song_by_checksum(&self, file_checksum: &str, in_collection: &str) -> Option<Songs> {
         use crate::schema::songs::dsl::*;
 
-        let song = NewSong {
-            title: song_name.clone(),
-            created_at: Some(SystemTime::now()),
-        };
+        songs
+            .select(Songs::as_select())
+            .filter(checksum.eq(file_checksum))
+            .filter(collection.eq(in_collection))
+            .first(&mut self.conn())
+            .optional()
+            .unwrap()
+    }
+
+    /// The hash scheme of `name`, if the collection exists
+    pub fn collection_hash_scheme(&self, name_in: &str) -> Option<HashScheme> {
+        use crate::schema::collections::dsl::*;
+
+        collections
+            .select(hash_bits)
+            .filter(name.eq(name_in))
+            .first::<i16>(&mut self.conn())
+            .optional()
+            .unwrap()
+            .map(|bits| HashScheme::from_bits(bits).expect("Unknown hash scheme in collections"))
+    }
+
+    /// Hash scheme of every collection, keyed by name
+    pub fn collection_hash_schemes(&self) -> HashMap<String, HashScheme> {
+        use crate::schema::collections::dsl::*;
 
-        let inserted_record = insert_into(songs)
-            .values(&song)
-            .get_result::<Songs>(&mut self.connector)
+        collections
+            .select((name, hash_bits))
+            .load::<(String, i16)>(&mut self.conn())
+            .unwrap()
+            .into_iter()
+            .filter_map(|(n, bits)| Some((n, HashScheme::from_bits(bits)?)))
+            .collect()
+    }
+
+    /// What `name_in` holds; a collection that does not exist yet holds songs
+    pub fn collection_kind(&self, name_in: &str) -> CollectionKind {
+        use crate::schema::collections::dsl::*;
+
+        collections
+            .select(kind)
+            .filter(name.eq(name_in))
+            .first::<String>(&mut self.conn())
+            .optional()
+            .unwrap()
+            .map_or(CollectionKind::Songs, |k| {
+                CollectionKind::from_name(&k).expect("Unknown collection kind in collections")
+            })
+    }
+
+    /// Create collection `name_in` of `collection_kind` with `scheme` unless
+    /// it already exists. Returns the scheme the collection actually uses.
+    pub fn ensure_collection(
+        &self,
+        name_in: &str,
+        scheme: HashScheme,
+        collection_kind: CollectionKind,
+    ) -> HashScheme {
+        use crate::schema::collections::dsl::*;
+
+        insert_into(collections)
+            .values((
+                name.eq(name_in),
+                hash_bits.eq(scheme.bits()),
+                kind.eq(collection_kind.as_str()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&mut self.conn())
             .unwrap();
 
-        println!("inserted record {:?} ", inserted_record);
-        inserted_record.id
+        self.collection_hash_scheme(name_in)
+            .expect("Collection vanished after insert")
     }
 
-    pub fn write_fingerprints(&mut self, _song_id: i32, fingerprint_info: Vec<FingerprintInfo>) {
-        use crate::schema::fingerprint::dsl::*;
-        use std::collections::HashSet;
+    /// Remove the `collections` row of `name_in` and its deny-list. Its
+    /// songs have to be deleted first.
+    pub fn delete_collection(&self, name_in: &str) -> Result<usize, diesel::result::Error> {
+        use crate::schema::{collections::dsl::*, denied_hashes};
 
-        const BATCH_SIZE: usize = 15_000;
+        self.conn().transaction(|conn| {
+            diesel::delete(denied_hashes::table.filter(denied_hashes::collection.eq(name_in)))
+                .execute(conn)?;
+            diesel::delete(collections.filter(name.eq(name_in))).execute(conn)
+        })
+    }
+
+    /// Insert a song row and all of its fingerprints in one transaction, so a
+    /// failure part-way leaves no orphaned song behind. `scheme` picks the
+    /// fingerprint table. Returns the new song id and the number of
+    /// fingerprints written.
+    pub fn write_song_with_fingerprints(
+        &self,
+        song: &NewSong,
+        fingerprint_info: Vec<FingerprintInfo>,
+        scheme: HashScheme,
+    ) -> Result<(i32, usize), diesel::result::Error> {
+        self.write_song_with(song, |conn, song_id| {
+            Self::write_fingerprints(conn, song_id, fingerprint_info, scheme)
+        })
+    }
+
+    /// Deduplicate `fingerprint_info` and write it to the fingerprint table
+    /// of `scheme` on `conn`
+    fn write_fingerprints(
+        conn: &mut PgConnection,
+        song_id: i32,
+        fingerprint_info: Vec<FingerprintInfo>,
+        scheme: HashScheme,
+    ) -> Result<usize, diesel::result::Error> {
+        let fingerprint_info = Self::dedupe_fingerprints(fingerprint_info);
+
+        // --- Binary COPY, falling back to batched INSERTs ---
+        // COPY has no ON CONFLICT and aborts on any duplicate row; running
+        // it in a nested transaction (savepoint) lets the INSERT path retry.
+        match scheme {
+            HashScheme::Wide64 => {
+                let fingerprints: Vec<Fingerprint> = fingerprint_info
+                    .iter()
+                    .map(|f| Fingerprint {
+                        hash: f.hash as i64,
+                        absolute_time_offset: f.abs_anchor_tm_offset as f64,
+                        song_id,
+                        created_at: Some(SystemTime::now()),
+                    })
+                    .collect();
+
+                conn.transaction(|conn| Self::copy_fingerprints(conn, &fingerprints))
+                    .or_else(|e| {
+                        warn!("COPY failed ({}), falling back to batched INSERTs", e);
+                        Self::insert_fingerprints(conn, &fingerprints)
+                    })
+            }
+            HashScheme::Compact32 => {
+                let fingerprints: Vec<CompactFingerprint> = fingerprint_info
+                    .iter()
+                    .map(|f| CompactFingerprint {
+                        hash: f.hash as u32 as i32,
+                        absolute_time_offset: f.abs_anchor_tm_offset,
+                        song_id,
+                    })
+                    .collect();
+
+                conn.transaction(|conn| Self::copy_compact_fingerprints(conn, &fingerprints))
+                    .or_else(|e| {
+                        warn!("COPY failed ({}), falling back to batched INSERTs", e);
+                        Self::insert_compact_fingerprints(conn, &fingerprints)
+                    })
+            }
+        }
+    }
+
+    /// Replace the fingerprints of `for_song_id` with `fingerprint_info` and
+    /// mark the song as fingerprinted at `version` (see
+    /// [`Config::fingerprint_version`]), in one transaction. Returns the
+    /// number of fingerprints written.
+    ///
+    /// [`Config::fingerprint_version`]: crate::config::Config::fingerprint_version
+    pub fn replace_fingerprints(
+        &self,
+        for_song_id: i32,
+        fingerprint_info: Vec<FingerprintInfo>,
+        scheme: HashScheme,
+        version: i32,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::{fingerprint, fingerprint_compact, songs};
+
+        self.conn().transaction(|conn| {
+            diesel::delete(fingerprint::table.filter(fingerprint::song_id.eq(for_song_id)))
+                .execute(conn)?;
+            diesel::delete(
+                fingerprint_compact::table.filter(fingerprint_compact::song_id.eq(for_song_id)),
+            )
+            .execute(conn)?;
+
+            let count = Self::write_fingerprints(conn, for_song_id, fingerprint_info, scheme)?;
+            diesel::update(songs::table.find(for_song_id))
+                .set(songs::fingerprint_version.eq(version))
+                .execute(conn)?;
+            Ok(count)
+        })
+    }
+
+    /// Insert a song row and run `write_fingerprints` with its new id inside
+    /// the same transaction; an error from it rolls the song row back.
+    /// Returns the new song id and whatever count `write_fingerprints` reports.
+    pub fn write_song_with<E>(
+        &self,
+        song: &NewSong,
+        write_fingerprints: impl FnOnce(&mut PgConnection, i32) -> Result<usize, E>,
+    ) -> Result<(i32, usize), E>
+    where
+        E: From<diesel::result::Error>,
+    {
+        use crate::schema::songs::dsl::*;
+
+        let mut pooled = self.conn();
+        let conn: &mut PgConnection = &mut pooled;
+
+        conn.transaction(|conn| {
+            let inserted_record = insert_into(songs).values(song).get_result::<Songs>(conn)?;
+            debug!("Inserted song {:?}", inserted_record);
+
+            let count = write_fingerprints(conn, inserted_record.id)?;
+            Ok((inserted_record.id, count))
+        })
+    }
+
+    /// Deduplicate (hash, time) per song
+    fn dedupe_fingerprints(fingerprint_info: Vec<FingerprintInfo>) -> Vec<FingerprintInfo> {
+        use std::collections::HashSet;
 
-        // --- Deduplicate (hash, time) per song ---
         let mut seen = HashSet::new();
-        let mut fingerprints: Vec<Fingerprint> = Vec::new();
+        let mut fingerprints: Vec<FingerprintInfo> = Vec::new();
 
         for f_info in fingerprint_info {
             let key = (
@@ -55,53 +391,160 @@ impl DB {
                 (f_info.abs_anchor_tm_offset * 100.0).round() as i64,
             ); // bucket time
             if seen.insert(key) {
-                fingerprints.push(Fingerprint {
-                    hash: f_info.hash as i64,
-                    absolute_time_offset: f_info.abs_anchor_tm_offset as f64,
-                    song_id: _song_id,
-                    created_at: Some(SystemTime::now()),
-                });
+                fingerprints.push(f_info);
             }
         }
+        fingerprints
+    }
+
+    /// Stream all fingerprints in a single binary `COPY ... FROM STDIN`
+    fn copy_fingerprints(
+        conn: &mut PgConnection,
+        fingerprints: &[Fingerprint],
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::fingerprint::dsl::*;
+
+        diesel::copy_from(fingerprint)
+            .from_insertable(fingerprints)
+            .execute(conn)
+    }
+
+    /// Insert fingerprints in batches, skipping rows that already exist
+    fn insert_fingerprints(
+        conn: &mut PgConnection,
+        fingerprints: &[Fingerprint],
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::fingerprint::dsl::*;
+
+        const BATCH_SIZE: usize = 15_000;
+
+        let mut total_inserted = 0;
+        for batch in fingerprints.chunks(BATCH_SIZE) {
+            let inserted_count = insert_into(fingerprint)
+                .values(batch)
+                .on_conflict(on_constraint("fingerprint_pkey"))
+                .do_nothing()
+                .execute(conn)?;
 
-        if fingerprints.is_empty() {
-            println!("No new fingerprints to write for song_id: {}", _song_id);
-            return;
+            total_inserted += inserted_count;
+            trace!("Batch executed. Affected rows: {}", inserted_count);
         }
+        Ok(total_inserted)
+    }
 
-        // --- Transaction to insert fingerprints in batches ---
-        let result: Result<usize, diesel::result::Error> = self.connector.transaction(|conn| {
-            let mut total_inserted = 0;
-            for batch in fingerprints.chunks(BATCH_SIZE) {
-                let inserted_count = insert_into(fingerprint)
-                    .values(batch)
-                    .on_conflict(on_constraint("fingerprint_pkey"))
-                    .do_nothing()
-                    .execute(conn)?;
+    fn copy_compact_fingerprints(
+        conn: &mut PgConnection,
+        fingerprints: &[CompactFingerprint],
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::fingerprint_compact::dsl::*;
 
-                total_inserted += inserted_count;
-                println!("Batch executed. Affected rows: {}", inserted_count);
-            }
-            Ok(total_inserted)
-        });
+        diesel::copy_from(fingerprint_compact)
+            .from_insertable(fingerprints)
+            .execute(conn)
+    }
+
+    fn insert_compact_fingerprints(
+        conn: &mut PgConnection,
+        fingerprints: &[CompactFingerprint],
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::fingerprint_compact::dsl::*;
 
-        match result {
-            Ok(count) => println!(
-                "✅ Successfully committed {} new fingerprints to the database.",
-                count
-            ),
-            Err(e) => eprintln!("❌ Transaction failed: {:?}", e),
+        // Three binds per row, so larger batches than the wide table
+        const BATCH_SIZE: usize = 20_000;
+
+        let mut total_inserted = 0;
+        for batch in fingerprints.chunks(BATCH_SIZE) {
+            total_inserted += insert_into(fingerprint_compact)
+                .values(batch)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
         }
+        Ok(total_inserted)
     }
+
+    /// Look up `hashes_in` in the fingerprint table of `scheme`
     pub fn fetch_matches_grouped_by_hash(
-        &mut self,
-        hashes_in: &Vec<i64>,
+        &self,
+        hashes_in: &[i64],
+        scheme: HashScheme,
     ) -> std::collections::HashMap<u64, Vec<(u32, f32)>> {
         if hashes_in.is_empty() {
             return std::collections::HashMap::new();
         }
 
-        let records: Vec<FingerprintMatch> = self.connector.transaction(|conn| {
+        // Typical snippet queries go through a cached `= ANY($1)` statement;
+        // only very large ones pay for creating and filling a temp table
+        let records = match scheme {
+            HashScheme::Compact32 => self.fetch_compact_matches_any(hashes_in),
+            HashScheme::Wide64 if hashes_in.len() <= TEMP_TABLE_THRESHOLD => {
+                self.fetch_matches_any(hashes_in)
+            }
+            HashScheme::Wide64 => self.fetch_matches_temp_table(hashes_in),
+        };
+
+        let mut map: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+
+        for rec in records {
+            let h = rec.hash as u64;
+            let sid = rec.song_id as u32;
+            let db_time = rec.absolute_time_offset as f32;
+            map.entry(h).or_default().push((sid, db_time));
+        }
+        map
+    }
+
+    /// Look hashes up with `WHERE hash = ANY($1)`, one array bind per chunk
+    fn fetch_matches_any(&self, hashes_in: &[i64]) -> Vec<FingerprintMatch> {
+        use crate::schema::fingerprint::dsl::*;
+
+        let mut conn = self.conn();
+        let mut records = Vec::new();
+
+        for chunk in hashes_in.chunks(ANY_CHUNK_SIZE) {
+            let rows: Vec<(i64, i32, f64)> = fingerprint
+                .select((hash, song_id, absolute_time_offset))
+                .filter(hash.eq_any(chunk))
+                .load(&mut conn)
+                .expect("Hash lookup failed");
+
+            records.extend(rows.into_iter().map(|(h, sid, t)| FingerprintMatch {
+                hash: h,
+                song_id: sid,
+                absolute_time_offset: t,
+            }));
+        }
+        records
+    }
+
+    /// [`Self::fetch_matches_any`] against `fingerprint_compact`. Hashes are
+    /// stored as the bit pattern of their low 32 bits.
+    fn fetch_compact_matches_any(&self, hashes_in: &[i64]) -> Vec<FingerprintMatch> {
+        use crate::schema::fingerprint_compact::dsl::*;
+
+        let compact: Vec<i32> = hashes_in.iter().map(|&h| h as u32 as i32).collect();
+
+        let mut conn = self.conn();
+        let mut records = Vec::new();
+
+        for chunk in compact.chunks(ANY_CHUNK_SIZE) {
+            let rows: Vec<(i32, i32, f32)> = fingerprint_compact
+                .select((hash, song_id, absolute_time_offset))
+                .filter(hash.eq_any(chunk))
+                .load(&mut conn)
+                .expect("Hash lookup failed");
+
+            records.extend(rows.into_iter().map(|(h, sid, t)| FingerprintMatch {
+                hash: h as u32 as i64,
+                song_id: sid,
+                absolute_time_offset: t as f64,
+            }));
+        }
+        records
+    }
+
+    /// Look hashes up by joining against a temporary table of the query hashes
+    fn fetch_matches_temp_table(&self, hashes_in: &[i64]) -> Vec<FingerprintMatch> {
+        self.conn().transaction(|conn| {
             diesel::sql_query(
                 "CREATE TEMPORARY TABLE Temp_hashes (hash BIGINT NOT NULl PRIMARY KEY) ON COMMIT DROP;"
             ).execute(conn).unwrap();
@@ -137,23 +580,10 @@ impl DB {
 
 
 
-        }).expect("Transaction failed");
-
-        let mut map: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
-
-        for rec in records {
-            let h = rec.hash as u64;
-            let sid = rec.song_id as u32;
-            let db_time = rec.absolute_time_offset as f32;
-            map.entry(h).or_default().push((sid, db_time));
-        }
-        map
+        }).expect("Transaction failed")
     }
 
-    pub fn fetch_song_titles(
-        &mut self,
-        song_ids: &[i32],
-    ) -> std::collections::HashMap<i32, String> {
+    pub fn fetch_song_titles(&self, song_ids: &[i32]) -> std::collections::HashMap<i32, String> {
         use crate::schema::songs::dsl::*;
 
         if song_ids.is_empty() {
@@ -163,7 +593,7 @@ impl DB {
         let rows: Vec<Songs> = songs
             .select(songs::all_columns())
             .filter(id.eq_any(song_ids))
-            .get_results(&mut self.connector)
+            .get_results(&mut self.conn())
             .unwrap_or_default();
 
         let mut map = std::collections::HashMap::new();
@@ -172,4 +602,595 @@ impl DB {
         }
         map
     }
+
+    pub fn fetch_songs(&self, song_ids: &[i32]) -> HashMap<i32, Songs> {
+        use crate::schema::songs::dsl::*;
+
+        if song_ids.is_empty() {
+            return HashMap::new();
+        }
+
+        let rows: Vec<Songs> = songs
+            .select(Songs::as_select())
+            .filter(id.eq_any(song_ids))
+            .get_results(&mut self.conn())
+            .unwrap_or_default();
+
+        rows.into_iter().map(|row| (row.id, row)).collect()
+    }
+
+    /// Songs matching an exact id or a case-insensitive `LIKE` title pattern
+    pub fn find_songs(&self, song_id: Option<i32>, title_pattern: Option<&str>) -> Vec<Songs> {
+        use crate::schema::songs::dsl::*;
+
+        let mut query = songs.select(Songs::as_select()).into_boxed();
+        if let Some(sid) = song_id {
+            query = query.filter(id.eq(sid));
+        }
+        if let Some(pattern) = title_pattern {
+            query = query.filter(title.ilike(pattern));
+        }
+
+        query.order(id.asc()).load(&mut self.conn()).unwrap()
+    }
+
+    pub fn count_fingerprints(&self, song_ids: &[i32]) -> i64 {
+        use crate::schema::all_fingerprints::dsl::*;
+
+        all_fingerprints
+            .filter(song_id.eq_any(song_ids))
+            .count()
+            .get_result(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Delete songs and all of their fingerprints in one transaction.
+    /// Returns `(songs_deleted, fingerprints_deleted)`.
+    pub fn delete_songs(&self, song_ids: &[i32]) -> Result<(usize, usize), diesel::result::Error> {
+        use crate::schema::{fingerprint, fingerprint_compact, songs};
+
+        self.conn().transaction(|conn| {
+            let fingerprints_deleted =
+                diesel::delete(fingerprint::table.filter(fingerprint::song_id.eq_any(song_ids)))
+                    .execute(conn)?
+                    + diesel::delete(
+                        fingerprint_compact::table
+                            .filter(fingerprint_compact::song_id.eq_any(song_ids)),
+                    )
+                    .execute(conn)?;
+            let songs_deleted =
+                diesel::delete(songs::table.filter(songs::id.eq_any(song_ids))).execute(conn)?;
+            Ok((songs_deleted, fingerprints_deleted))
+        })
+    }
+
+    /// Fold `duplicate_ids` into `keep_id` in one transaction: tags missing on
+    /// the kept song are taken from the first duplicate that has them, match
+    /// history is moved over, then the duplicates and their fingerprints are
+    /// deleted. Returns `(songs_deleted, fingerprints_deleted)`.
+    pub fn merge_songs(
+        &self,
+        keep_id: i32,
+        duplicate_ids: &[i32],
+    ) -> Result<(usize, usize), diesel::result::Error> {
+        use crate::schema::{fingerprint, fingerprint_compact, matches, songs};
+
+        self.conn().transaction(|conn| {
+            let keep: Songs = songs::table
+                .find(keep_id)
+                .select(Songs::as_select())
+                .first(conn)?;
+            let duplicates: Vec<Songs> = songs::table
+                .filter(songs::id.eq_any(duplicate_ids))
+                .select(Songs::as_select())
+                .order(songs::id.asc())
+                .load(conn)?;
+
+            diesel::update(songs::table.find(keep_id))
+                .set((
+                    songs::artist.eq(keep
+                        .artist
+                        .or_else(|| duplicates.iter().find_map(|s| s.artist.clone()))),
+                    songs::album.eq(keep
+                        .album
+                        .or_else(|| duplicates.iter().find_map(|s| s.album.clone()))),
+                    songs::track_number.eq(keep
+                        .track_number
+                        .or_else(|| duplicates.iter().find_map(|s| s.track_number))),
+                    songs::year.eq(keep.year.or_else(|| duplicates.iter().find_map(|s| s.year))),
+                ))
+                .execute(conn)?;
+
+            diesel::update(matches::table.filter(matches::song_id.eq_any(duplicate_ids)))
+                .set(matches::song_id.eq(keep_id))
+                .execute(conn)?;
+
+            let fingerprints_deleted = diesel::delete(
+                fingerprint::table.filter(fingerprint::song_id.eq_any(duplicate_ids)),
+            )
+            .execute(conn)?
+                + diesel::delete(
+                    fingerprint_compact::table
+                        .filter(fingerprint_compact::song_id.eq_any(duplicate_ids)),
+                )
+                .execute(conn)?;
+            let songs_deleted =
+                diesel::delete(songs::table.filter(songs::id.eq_any(duplicate_ids)))
+                    .execute(conn)?;
+            Ok((songs_deleted, fingerprints_deleted))
+        })
+    }
+
+    /// One page of songs with their fingerprint counts. `pattern` is matched
+    /// case-insensitively against title, artist and album.
+    pub fn list_songs(
+        &self,
+        pattern: &str,
+        sort: SongSort,
+        descending: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<SongSummary>, diesel::result::Error> {
+        use diesel::sql_types::{BigInt, Text};
+
+        let query = format!(
+            "
+            SELECT
+                s.id , s.title , s.duration , s.created_at , s.bpm , s.musical_key , COUNT(f.hash) AS fingerprint_count
+            FROM
+                songs AS s
+            LEFT JOIN
+                all_fingerprints AS f ON f.song_id = s.id
+            WHERE
+                s.title ILIKE $1 OR s.artist ILIKE $1 OR s.album ILIKE $1
+            GROUP BY
+                s.id
+            ORDER BY
+                {} {} NULLS LAST , s.id
+            LIMIT $2 OFFSET $3;
+            ",
+            sort.column(),
+            if descending { "DESC" } else { "ASC" }
+        );
+
+        diesel::sql_query(query)
+            .bind::<Text, _>(pattern)
+            .bind::<BigInt, _>(limit)
+            .bind::<BigInt, _>(offset)
+            .load::<SongSummary>(&mut self.conn())
+    }
+
+    pub fn count_songs(&self) -> i64 {
+        use crate::schema::songs::dsl::*;
+
+        songs.count().get_result(&mut self.conn()).unwrap()
+    }
+
+    pub fn count_all_fingerprints(&self) -> i64 {
+        use crate::schema::all_fingerprints::dsl::*;
+
+        all_fingerprints
+            .count()
+            .get_result(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Number of fingerprints stored for each song that has any
+    pub fn fingerprint_counts_per_song(&self) -> Vec<(i32, i64)> {
+        use crate::schema::all_fingerprints::dsl::*;
+        use diesel::dsl::count_star;
+
+        all_fingerprints
+            .group_by(song_id)
+            .select((song_id, count_star()))
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// The hashes stored most often, i.e. collision hotspots
+    pub fn most_common_hashes(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<HashFrequency>, diesel::result::Error> {
+        use diesel::sql_types::BigInt;
+
+        let query = "
+            SELECT
+                hash , COUNT(*) AS occurrences , COUNT(DISTINCT song_id) AS song_count
+            FROM
+                all_fingerprints
+            GROUP BY
+                hash
+            ORDER BY
+                occurrences DESC
+            LIMIT $1;
+            ";
+
+        diesel::sql_query(query)
+            .bind::<BigInt, _>(limit)
+            .load::<HashFrequency>(&mut self.conn())
+    }
+
+    /// How many hashes of the songs of `collection`, stored in the table of
+    /// `scheme`, are shared by how many of those songs, ordered by song count
+    pub fn hash_spread(&self, collection: &str, scheme: HashScheme) -> Vec<HashSpread> {
+        use diesel::sql_types::Text;
+
+        let (table, _) = fingerprint_table(scheme);
+        let query = format!(
+            "
+            SELECT
+                song_count , COUNT(*) AS hashes , SUM(occurrences)::BIGINT AS occurrences
+            FROM (
+                SELECT
+                    COUNT(*) AS occurrences , COUNT(DISTINCT song_id) AS song_count
+                FROM
+                    {table}
+                JOIN
+                    songs ON songs.id = {table}.song_id
+                WHERE
+                    songs.collection = $1
+                GROUP BY
+                    hash
+            ) AS per_hash
+            GROUP BY
+                song_count
+            ORDER BY
+                song_count;
+            "
+        );
+
+        diesel::sql_query(query)
+            .bind::<Text, _>(collection)
+            .load::<HashSpread>(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Up to `limit` hashes stored for more than `min_songs` songs of
+    /// `collection`, whose hashes are of `scheme`, most widespread first.
+    /// Hashes are the values the matcher queries, as
+    /// [`Self::fetch_matches_grouped_by_hash`] takes them.
+    pub fn widespread_hashes(
+        &self,
+        collection: &str,
+        scheme: HashScheme,
+        min_songs: i64,
+        limit: i64,
+    ) -> Vec<HashFrequency> {
+        use diesel::sql_types::{BigInt, Text};
+
+        let (table, hash_column) = fingerprint_table(scheme);
+        let query = format!(
+            "
+            SELECT
+                {hash_column} AS hash , COUNT(*) AS occurrences , COUNT(DISTINCT song_id) AS song_count
+            FROM
+                {table}
+            JOIN
+                songs ON songs.id = {table}.song_id
+            WHERE
+                songs.collection = $1
+            GROUP BY
+                hash
+            HAVING
+                COUNT(DISTINCT song_id) > $2
+            ORDER BY
+                song_count DESC , occurrences DESC
+            LIMIT $3;
+            "
+        );
+
+        diesel::sql_query(query)
+            .bind::<Text, _>(collection)
+            .bind::<BigInt, _>(min_songs)
+            .bind::<BigInt, _>(limit)
+            .load::<HashFrequency>(&mut self.conn())
+            .unwrap()
+    }
+
+    /// The deny-listed hashes of `collection`, which its lookups skip
+    pub fn denied_hashes(&self, in_collection: &str) -> HashSet<u64> {
+        use crate::schema::denied_hashes::dsl::*;
+
+        denied_hashes
+            .select(hash)
+            .filter(collection.eq(in_collection))
+            .load::<i64>(&mut self.conn())
+            .unwrap()
+            .into_iter()
+            .map(|h| h as u64)
+            .collect()
+    }
+
+    /// Replace the deny-list of `in_collection`, whose hashes are of
+    /// `scheme`, with `hashes` in one transaction. Returns the number of
+    /// hashes now denied.
+    pub fn replace_denied_hashes(
+        &self,
+        in_collection: &str,
+        scheme: HashScheme,
+        hashes: &[HashFrequency],
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::denied_hashes::dsl::*;
+
+        let rows: Vec<NewDeniedHash> = hashes
+            .iter()
+            .map(|h| NewDeniedHash {
+                collection: in_collection.to_string(),
+                hash: h.hash,
+                hash_bits: scheme.bits(),
+                song_count: h.song_count as i32,
+            })
+            .collect();
+
+        self.conn().transaction(|conn| {
+            diesel::delete(denied_hashes.filter(collection.eq(in_collection))).execute(conn)?;
+            let mut inserted = 0;
+            for batch in rows.chunks(5000) {
+                inserted += insert_into(denied_hashes).values(batch).execute(conn)?;
+            }
+            Ok(inserted)
+        })
+    }
+
+    /// Approximate on-disk size of both fingerprint tables and their indexes
+    pub fn fingerprint_relation_size(&self) -> RelationSize {
+        diesel::sql_query(
+            "
+            SELECT
+                pg_relation_size('fingerprint') + pg_relation_size('fingerprint_compact') AS table_bytes ,
+                pg_indexes_size('fingerprint') + pg_indexes_size('fingerprint_compact') AS index_bytes;
+            ",
+        )
+        .get_result::<RelationSize>(&mut self.conn())
+        .unwrap()
+    }
+
+    /// Songs without any fingerprints, e.g. left behind by an interrupted
+    /// ingest from before ingests were transactional
+    pub fn find_orphan_songs(&self) -> Vec<Songs> {
+        use crate::schema::{all_fingerprints, songs};
+        use diesel::dsl::{exists, not};
+
+        songs::table
+            .select(Songs::as_select())
+            .filter(not(exists(
+                all_fingerprints::table.filter(all_fingerprints::song_id.eq(songs::id)),
+            )))
+            .order(songs::id.asc())
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Songs in any collection ingested from a file with this SHA-256
+    pub fn find_songs_by_checksum(&self, file_checksum: &str) -> Vec<Songs> {
+        use crate::schema::songs::dsl::*;
+
+        songs
+            .select(Songs::as_select())
+            .filter(checksum.eq(file_checksum))
+            .order(id.asc())
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Record that the songs with this SHA-256 can be played from `path`
+    pub fn set_file_path(
+        &self,
+        file_checksum: &str,
+        path: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::songs::dsl::*;
+
+        diesel::update(songs.filter(checksum.eq(file_checksum)))
+            .set(file_path.eq(path))
+            .execute(&mut self.conn())
+    }
+
+    /// Record the estimated tempo and key of song `song_id`
+    pub fn set_analysis(
+        &self,
+        song_id: i32,
+        tempo: Option<f32>,
+        key: Option<&str>,
+    ) -> Result<usize, diesel::result::Error> {
+        use crate::schema::songs::dsl::*;
+
+        diesel::update(songs.find(song_id))
+            .set((bpm.eq(tempo), musical_key.eq(key)))
+            .execute(&mut self.conn())
+    }
+
+    /// Number of songs, in `in_collection` or anywhere, fingerprinted at
+    /// another version than `current`
+    pub fn count_outdated_songs(&self, in_collection: Option<&str>, current: i32) -> i64 {
+        use crate::schema::songs::dsl::*;
+
+        let mut query = songs.filter(fingerprint_version.ne(current)).into_boxed();
+        if let Some(in_collection) = in_collection {
+            query = query.filter(collection.eq(in_collection));
+        }
+
+        query.count().get_result(&mut self.conn()).unwrap()
+    }
+
+    /// Number of songs per collection and fingerprint version
+    pub fn song_counts_by_version(&self) -> Vec<(String, i32, i64)> {
+        use crate::schema::songs::dsl::*;
+        use diesel::dsl::count_star;
+
+        songs
+            .group_by((collection, fingerprint_version))
+            .select((collection, fingerprint_version, count_star()))
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Every song in the library, or only those in `in_collection`, ordered by id
+    pub fn fetch_all_songs(&self, in_collection: Option<&str>) -> Vec<Songs> {
+        use crate::schema::songs::dsl::*;
+
+        let mut query = songs
+            .select(Songs::as_select())
+            .order(id.asc())
+            .into_boxed();
+        if let Some(in_collection) = in_collection {
+            query = query.filter(collection.eq(in_collection));
+        }
+
+        query.load(&mut self.conn()).unwrap()
+    }
+
+    /// Ids of the songs in `in_collection`
+    pub fn song_ids_in_collection(&self, in_collection: &str) -> Vec<i32> {
+        use crate::schema::songs::dsl::*;
+
+        songs
+            .select(id)
+            .filter(collection.eq(in_collection))
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Ids of the songs in `in_collection` fingerprinted at `version`
+    pub fn song_ids_at_version(&self, in_collection: &str, version: i32) -> Vec<i32> {
+        use crate::schema::songs::dsl::*;
+
+        songs
+            .select(id)
+            .filter(collection.eq(in_collection))
+            .filter(fingerprint_version.eq(version))
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// All `(hash, anchor_time)` pairs of one song, in time order
+    pub fn fetch_fingerprints(&self, for_song_id: i32) -> Vec<(i64, f64)> {
+        use crate::schema::all_fingerprints::dsl::*;
+
+        all_fingerprints
+            .filter(song_id.eq(for_song_id))
+            .select((hash, absolute_time_offset))
+            .order(absolute_time_offset.asc())
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Store a song's chroma profile, replacing the one it had
+    pub fn write_chroma_profile(
+        &self,
+        row: &ChromaProfileRow,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::chroma_profiles::dsl::*;
+
+        insert_into(chroma_profiles)
+            .values(row)
+            .on_conflict(song_id)
+            .do_update()
+            .set((
+                tempo.eq(excluded(tempo)),
+                beats.eq(excluded(beats)),
+                profile.eq(excluded(profile)),
+            ))
+            .execute(&mut self.conn())
+            .map(|_| ())
+    }
+
+    /// Chroma profiles of the songs in `in_collection`
+    pub fn fetch_chroma_profiles(&self, in_collection: &str) -> Vec<ChromaProfileRow> {
+        use crate::schema::{chroma_profiles, songs};
+
+        chroma_profiles::table
+            .inner_join(songs::table)
+            .filter(songs::collection.eq(in_collection))
+            .select(ChromaProfileRow::as_select())
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Log a recognition event for `result` in the `matches` history
+    pub fn record_match(
+        &self,
+        result: &VoteResult,
+        from_source: MatchSource,
+        input_name: Option<&str>,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::matches::dsl::*;
+
+        let new_match = NewMatch {
+            song_id: result.song_id as i32,
+            score: result.score as i32,
+            confidence: result.confidence,
+            source: from_source.as_str().to_string(),
+            input: input_name.map(str::to_string),
+            matched_at: SystemTime::now(),
+        };
+
+        insert_into(matches)
+            .values(&new_match)
+            .execute(&mut self.conn())
+            .map(|_| ())
+    }
+
+    /// The most recent recognition events with their song titles, newest
+    /// first, optionally only those from `from_source`
+    pub fn fetch_match_history(
+        &self,
+        from_source: Option<MatchSource>,
+        limit: i64,
+    ) -> Result<Vec<(MatchRecord, String)>, diesel::result::Error> {
+        use crate::schema::{matches, songs};
+
+        let mut query = matches::table
+            .inner_join(songs::table)
+            .select((MatchRecord::as_select(), songs::title))
+            .order(matches::matched_at.desc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(from_source) = from_source {
+            query = query.filter(matches::source.eq(from_source.as_str()));
+        }
+
+        query.load(&mut self.conn())
+    }
+
+    /// Store a new API key; fails if the name is taken
+    pub fn create_api_key(&self, key: &NewApiKey) -> Result<(), diesel::result::Error> {
+        use crate::schema::api_keys::dsl::*;
+
+        insert_into(api_keys)
+            .values(key)
+            .execute(&mut self.conn())
+            .map(|_| ())
+    }
+
+    pub fn list_api_keys(&self) -> Vec<ApiKey> {
+        use crate::schema::api_keys::dsl::*;
+
+        api_keys
+            .select(ApiKey::as_select())
+            .order(name)
+            .load(&mut self.conn())
+            .unwrap()
+    }
+
+    /// Delete the key called `name_in`, returning whether it existed
+    pub fn revoke_api_key(&self, name_in: &str) -> bool {
+        use crate::schema::api_keys::dsl::*;
+
+        diesel::delete(api_keys.filter(name.eq(name_in)))
+            .execute(&mut self.conn())
+            .unwrap()
+            > 0
+    }
+}
+
+/// Fingerprint table of `scheme` and the SQL expression turning its `hash`
+/// column into the value the matcher queries
+fn fingerprint_table(scheme: HashScheme) -> (&'static str, &'static str) {
+    match scheme {
+        HashScheme::Wide64 => ("fingerprint", "hash"),
+        // Compact hashes are stored as the bit pattern of their low 32 bits
+        HashScheme::Compact32 => ("fingerprint_compact", "hash::BIGINT & 4294967295"),
+    }
 }
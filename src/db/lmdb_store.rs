@@ -0,0 +1,177 @@
+use crate::{
+    db::storage::{FingerprintStore, StoreError},
+    fingerprint::FingerprintInfo,
+};
+use dotenvy::dotenv;
+use heed::{Database, DatabaseFlags, Env, EnvOpenOptions, MdbError, PutFlags, types::Bytes};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::Path,
+    sync::OnceLock,
+};
+
+/// Upper bound of the memory map; only pages actually written use disk
+const MAP_SIZE: usize = 64 * 1024 * 1024 * 1024;
+
+/// The index of `FINGERPRINT_LMDB_PATH`, opened on first use. heed refuses
+/// to open an environment twice while it is alive, so every caller shares
+/// this one.
+static STORE: OnceLock<Option<LmdbStore>> = OnceLock::new();
+
+/// Fingerprint index stored in an embedded LMDB environment.
+///
+/// Every hash is a single big-endian 8-byte key holding a sorted set of
+/// fixed-size 8-byte values, `song_id (u32 BE) ‖ anchor_time (f32 bits BE)`,
+/// so a lookup is one B-tree descent followed by a contiguous page read.
+#[derive(Clone)]
+pub struct LmdbStore {
+    env: Env,
+    index: Database<Bytes, Bytes>,
+}
+
+impl LmdbStore {
+    /// Open (or create) the index at `path`. Only [`Self::from_env`] calls
+    /// this, once per process.
+    fn open(path: &Path) -> Result<Self, StoreError> {
+        fs::create_dir_all(path)?;
+
+        // SAFETY: `STORE` opens the environment once per process and its
+        // files are only ever modified through LMDB
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(1)
+                .open(path)?
+        };
+
+        let mut wtxn = env.write_txn()?;
+        let index = env
+            .database_options()
+            .types::<Bytes, Bytes>()
+            .name("fingerprints")
+            .flags(DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED)
+            .create(&mut wtxn)?;
+        wtxn.commit()?;
+
+        Ok(Self { env, index })
+    }
+
+    /// Whether `FINGERPRINT_LMDB_PATH` is set, i.e. fingerprints live in
+    /// LMDB rather than Postgres
+    pub fn configured() -> bool {
+        dotenv().ok();
+
+        env::var_os("FINGERPRINT_LMDB_PATH").is_some()
+    }
+
+    /// The index configured by `FINGERPRINT_LMDB_PATH`, if any, opened on the
+    /// first call and shared by every later one. When it is unset,
+    /// fingerprints live in Postgres.
+    pub fn from_env() -> Option<Self> {
+        STORE
+            .get_or_init(|| {
+                dotenv().ok();
+
+                let path = env::var("FINGERPRINT_LMDB_PATH").ok()?;
+                Some(Self::open(Path::new(&path)).expect("Failed to open LMDB fingerprint index"))
+            })
+            .clone()
+    }
+
+    /// Store the fingerprints of `song_id` in a single write transaction.
+    /// Returns the number of new entries.
+    pub fn write_fingerprints(
+        &self,
+        song_id: i32,
+        fingerprints: &[FingerprintInfo],
+    ) -> Result<usize, StoreError> {
+        // Sorted inserts keep LMDB appending to the same pages
+        let mut entries: Vec<([u8; 8], [u8; 8])> = fingerprints
+            .iter()
+            .map(|f| {
+                (
+                    f.hash.to_be_bytes(),
+                    encode_entry(song_id as u32, f.abs_anchor_tm_offset),
+                )
+            })
+            .collect();
+        entries.sort_unstable();
+        entries.dedup();
+
+        let mut wtxn = self.env.write_txn()?;
+        let mut inserted = 0;
+        for (key, value) in &entries {
+            match self
+                .index
+                .put_with_flags(&mut wtxn, PutFlags::NO_DUP_DATA, key, value)
+            {
+                Ok(()) => inserted += 1,
+                Err(heed::Error::Mdb(MdbError::KeyExist)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        wtxn.commit()?;
+
+        Ok(inserted)
+    }
+
+    /// Remove every fingerprint belonging to `song_ids`. There is no reverse
+    /// index, so this walks the whole database.
+    pub fn delete_songs(&self, song_ids: &[i32]) -> Result<usize, StoreError> {
+        let song_ids: HashSet<u32> = song_ids.iter().map(|&id| id as u32).collect();
+
+        let mut wtxn = self.env.write_txn()?;
+        let mut deleted = 0;
+        let mut iter = self.index.iter_mut(&mut wtxn)?;
+        while let Some(item) = iter.next() {
+            let (_, value) = item?;
+            let (song_id, _) = decode_entry(value);
+            if song_ids.contains(&song_id) {
+                // SAFETY: nothing borrowed from the iterator outlives this call
+                unsafe { iter.del_current()? };
+                deleted += 1;
+            }
+        }
+        drop(iter);
+        wtxn.commit()?;
+
+        Ok(deleted)
+    }
+}
+
+impl FingerprintStore for LmdbStore {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let unique: HashSet<u64> = hashes.iter().map(|&h| h as u64).collect();
+
+        let rtxn = self.env.read_txn()?;
+        let mut grouped: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        for hash in unique {
+            if let Some(duplicates) = self.index.get_duplicates(&rtxn, &hash.to_be_bytes())? {
+                let entries = grouped.entry(hash).or_default();
+                for item in duplicates {
+                    let (_, value) = item?;
+                    entries.push(decode_entry(value));
+                }
+            }
+        }
+
+        Ok(grouped)
+    }
+}
+
+fn encode_entry(song_id: u32, anchor_time: f32) -> [u8; 8] {
+    let mut value = [0u8; 8];
+    value[..4].copy_from_slice(&song_id.to_be_bytes());
+    value[4..].copy_from_slice(&anchor_time.to_bits().to_be_bytes());
+    value
+}
+
+fn decode_entry(value: &[u8]) -> (u32, f32) {
+    let song_id = u32::from_be_bytes(value[..4].try_into().unwrap());
+    let anchor_time = f32::from_bits(u32::from_be_bytes(value[4..8].try_into().unwrap()));
+    (song_id, anchor_time)
+}
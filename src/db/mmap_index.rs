@@ -0,0 +1,233 @@
+use crate::{
+    db::{
+        bindings::Songs,
+        storage::{FingerprintStore, StoreError},
+    },
+    encoding::{
+        read_f64, read_i32, read_opt, read_str, read_u32, write_f64, write_i32, write_opt,
+        write_str, write_u32,
+    },
+    fingerprint::HashScheme,
+};
+use memmap2::Mmap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+/// File signature of an index file
+const INDEX_MAGIC: &[u8; 4] = b"SBIX";
+/// Bumped whenever the layout changes
+const INDEX_VERSION: u16 = 4;
+const HEADER_LEN: usize = 32;
+/// Bytes per fingerprint entry
+pub const ENTRY_LEN: usize = 16;
+
+/// Read-only fingerprint index in a single memory-mapped file, for matching
+/// on machines without a database.
+///
+/// Layout, little-endian:
+/// - 32-byte header: `SBIX`, u16 version, 2 padding bytes, u64 entry count,
+///   u32 song count, 4 padding bytes, u64 offset of the song table
+/// - `(u64 hash, u32 song_id, f32 anchor_time)` entries sorted by hash, so a
+///   lookup is a binary search over the mapping
+/// - song table: id, title, duration, artist, album, track_number, year,
+///   collection, u32 hash bits of the collection, fingerprint version,
+///   encoded like the fields of a library archive
+pub struct MmapIndex {
+    mmap: Mmap,
+    entry_count: usize,
+    songs: HashMap<i32, Songs>,
+    schemes: HashMap<String, HashScheme>,
+}
+
+impl MmapIndex {
+    /// Write an index of `songs`, the hash schemes of their collections and
+    /// their `(hash, song_id, anchor_time)` entries to `out_path`
+    pub fn write(
+        out_path: &str,
+        songs: &[Songs],
+        schemes: &HashMap<String, HashScheme>,
+        mut entries: Vec<(u64, u32, f32)>,
+    ) -> io::Result<()> {
+        entries.sort_unstable_by_key(|&(hash, song_id, _)| (hash, song_id));
+
+        let songs_offset = HEADER_LEN + entries.len() * ENTRY_LEN;
+
+        let mut w = BufWriter::new(File::create(out_path)?);
+        w.write_all(INDEX_MAGIC)?;
+        w.write_all(&INDEX_VERSION.to_le_bytes())?;
+        w.write_all(&[0; 2])?;
+        w.write_all(&(entries.len() as u64).to_le_bytes())?;
+        w.write_all(&(songs.len() as u32).to_le_bytes())?;
+        w.write_all(&[0; 4])?;
+        w.write_all(&(songs_offset as u64).to_le_bytes())?;
+
+        for (hash, song_id, anchor_time) in &entries {
+            w.write_all(&hash.to_le_bytes())?;
+            w.write_all(&song_id.to_le_bytes())?;
+            w.write_all(&anchor_time.to_le_bytes())?;
+        }
+
+        for song in songs {
+            write_i32(&mut w, song.id)?;
+            write_str(&mut w, &song.title)?;
+            write_opt(&mut w, song.duration, write_f64)?;
+            write_opt(&mut w, song.artist.as_deref(), write_str)?;
+            write_opt(&mut w, song.album.as_deref(), write_str)?;
+            write_opt(&mut w, song.track_number, write_i32)?;
+            write_opt(&mut w, song.year, write_i32)?;
+            write_str(&mut w, &song.collection)?;
+            let scheme = schemes.get(&song.collection).copied().unwrap_or_default();
+            write_u32(&mut w, scheme.bits() as u32)?;
+            write_i32(&mut w, song.fingerprint_version)?;
+        }
+
+        w.flush()
+    }
+
+    /// Map the index at `path` and load its song table
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let file = File::open(path)?;
+        // SAFETY: index files are written once and never modified in place
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < HEADER_LEN || &mmap[..4] != INDEX_MAGIC {
+            return Err(format!("{} is not a Sabi index file", path).into());
+        }
+        let version = u16::from_le_bytes(mmap[4..6].try_into().unwrap());
+        if version != INDEX_VERSION {
+            return Err(format!(
+                "unsupported index version {} (expected {})",
+                version, INDEX_VERSION
+            )
+            .into());
+        }
+
+        let entry_count = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let song_count = u32::from_le_bytes(mmap[16..20].try_into().unwrap());
+        let songs_offset = u64::from_le_bytes(mmap[24..32].try_into().unwrap()) as usize;
+        if songs_offset != HEADER_LEN + entry_count * ENTRY_LEN || songs_offset > mmap.len() {
+            return Err(format!("{} is truncated or corrupt", path).into());
+        }
+
+        let mut table = &mmap[songs_offset..];
+        let mut songs = HashMap::with_capacity(song_count as usize);
+        let mut schemes = HashMap::new();
+        for _ in 0..song_count {
+            let mut song = Songs {
+                id: read_i32(&mut table)?,
+                title: read_str(&mut table)?,
+                created_at: None,
+                duration: read_opt(&mut table, read_f64)?,
+                artist: read_opt(&mut table, read_str)?,
+                album: read_opt(&mut table, read_str)?,
+                track_number: read_opt(&mut table, read_i32)?,
+                year: read_opt(&mut table, read_i32)?,
+                checksum: None,
+                collection: read_str(&mut table)?,
+                fingerprint_version: 0,
+                file_path: None,
+                bpm: None,
+                musical_key: None,
+            };
+            let bits = read_u32(&mut table)?;
+            let scheme = HashScheme::from_bits(bits as i16)
+                .ok_or_else(|| format!("unknown hash scheme of {} bits", bits))?;
+            song.fingerprint_version = read_i32(&mut table)?;
+            schemes.insert(song.collection.clone(), scheme);
+            songs.insert(song.id, song);
+        }
+
+        Ok(Self {
+            mmap,
+            entry_count,
+            songs,
+            schemes,
+        })
+    }
+
+    /// Hash scheme the songs of `collection` were fingerprinted with
+    pub fn collection_hash_scheme(&self, collection: &str) -> HashScheme {
+        self.schemes.get(collection).copied().unwrap_or_default()
+    }
+
+    /// Ids of the songs in `collection` fingerprinted at `version`
+    pub fn song_ids_at_version(&self, collection: &str, version: i32) -> HashSet<u32> {
+        self.songs
+            .values()
+            .filter(|song| song.collection == collection && song.fingerprint_version == version)
+            .map(|song| song.id as u32)
+            .collect()
+    }
+
+    /// Number of songs in `collection` fingerprinted at another version than
+    /// `current`
+    pub fn outdated_song_count(&self, collection: &str, current: i32) -> usize {
+        self.songs
+            .values()
+            .filter(|song| song.collection == collection && song.fingerprint_version != current)
+            .count()
+    }
+
+    /// The song table, keyed by id
+    pub fn songs(&self) -> &HashMap<i32, Songs> {
+        &self.songs
+    }
+
+    /// The song table, keyed by id
+    pub fn into_songs(self) -> HashMap<i32, Songs> {
+        self.songs
+    }
+
+    fn entry(&self, i: usize) -> (u64, u32, f32) {
+        let bytes = &self.mmap[HEADER_LEN + i * ENTRY_LEN..HEADER_LEN + (i + 1) * ENTRY_LEN];
+        (
+            u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            f32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        )
+    }
+
+    /// Index of the first entry whose hash is `>= hash`
+    fn lower_bound(&self, hash: u64) -> usize {
+        let (mut lo, mut hi) = (0, self.entry_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).0 < hash {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+impl FingerprintStore for MmapIndex {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let unique: HashSet<u64> = hashes.iter().map(|&h| h as u64).collect();
+
+        let mut grouped: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        for hash in unique {
+            let mut i = self.lower_bound(hash);
+            while i < self.entry_count {
+                let (entry_hash, song_id, anchor_time) = self.entry(i);
+                if entry_hash != hash {
+                    break;
+                }
+                grouped
+                    .entry(hash)
+                    .or_default()
+                    .push((song_id, anchor_time));
+                i += 1;
+            }
+        }
+
+        Ok(grouped)
+    }
+}
@@ -0,0 +1,354 @@
+#[cfg(feature = "db-postgres")]
+use crate::db::{bindings::NewSong, connector::DB, lmdb_store::LmdbStore};
+use crate::{
+    db::bindings::Songs,
+    fingerprint::{FingerprintInfo, HashScheme},
+};
+#[cfg(feature = "db-postgres")]
+use lru::LruCache;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "db-postgres")]
+use std::{
+    env,
+    num::NonZeroUsize,
+    sync::{Mutex, OnceLock},
+};
+use tracing::warn;
+
+/// Boxed error returned by fallible storage backends
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default number of hashes kept by the lookup cache, overridable via
+/// `HASH_CACHE_SIZE` (`0` disables the cache)
+#[cfg(feature = "db-postgres")]
+const DEFAULT_HASH_CACHE_SIZE: usize = 100_000;
+
+#[cfg(feature = "db-postgres")]
+type HashCache = Mutex<LruCache<(HashScheme, u64), Vec<(u32, f32)>>>;
+
+/// Process-wide cache of Postgres hash lookups, shared by every store handed
+/// out by [`fingerprint_store`]
+#[cfg(feature = "db-postgres")]
+static HASH_CACHE: OnceLock<Option<HashCache>> = OnceLock::new();
+
+#[cfg(feature = "db-postgres")]
+fn hash_cache() -> Option<&'static HashCache> {
+    HASH_CACHE
+        .get_or_init(|| {
+            let size = env::var("HASH_CACHE_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_HASH_CACHE_SIZE);
+            NonZeroUsize::new(size).map(|capacity| Mutex::new(LruCache::new(capacity)))
+        })
+        .as_ref()
+}
+
+/// Blocking read access to a fingerprint index, shareable across threads
+pub trait FingerprintStore: Send + Sync {
+    /// All stored `(song_id, anchor_time)` pairs for each of `hashes`
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError>;
+
+    /// Songs the lookups can return, for inverse document frequency
+    /// weighting; `None` when the index does not know
+    fn song_count(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether lookups skip `hash` as deny-listed
+    fn is_denied(&self, _hash: u64) -> bool {
+        false
+    }
+
+    /// `fingerprints` without the deny-listed ones, so they are not counted
+    /// in the query length voting divides by
+    fn without_denied(&self, mut fingerprints: Vec<FingerprintInfo>) -> Vec<FingerprintInfo> {
+        fingerprints.retain(|fp| !self.is_denied(fp.hash));
+        fingerprints
+    }
+}
+
+/// The Postgres fingerprint table holding hashes of one scheme
+#[cfg(feature = "db-postgres")]
+struct PgStore {
+    db: DB,
+    scheme: HashScheme,
+}
+
+#[cfg(feature = "db-postgres")]
+impl FingerprintStore for PgStore {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        Ok(self.db.fetch_matches_grouped_by_hash(hashes, self.scheme))
+    }
+}
+
+/// Serves repeated lookups (overlapping windows in scan and listen modes)
+/// from [`HASH_CACHE`] and only queries `inner` for the misses. Hashes with
+/// no matches are cached too.
+#[cfg(feature = "db-postgres")]
+struct CachedStore {
+    inner: Box<dyn FingerprintStore>,
+    cache: &'static HashCache,
+    scheme: HashScheme,
+}
+
+#[cfg(feature = "db-postgres")]
+impl FingerprintStore for CachedStore {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let mut grouped: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        let mut misses: HashSet<i64> = HashSet::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for &hash in hashes {
+                match cache.get(&(self.scheme, hash as u64)) {
+                    Some(entries) if !entries.is_empty() => {
+                        grouped.insert(hash as u64, entries.clone());
+                    }
+                    Some(_) => {}
+                    None => {
+                        misses.insert(hash);
+                    }
+                }
+            }
+        }
+
+        if misses.is_empty() {
+            return Ok(grouped);
+        }
+
+        let misses: Vec<i64> = misses.into_iter().collect();
+        let fetched = self.inner.fetch_matches_grouped_by_hash(&misses)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        for &hash in &misses {
+            let entries = fetched.get(&(hash as u64)).cloned().unwrap_or_default();
+            cache.put((self.scheme, hash as u64), entries);
+        }
+        grouped.extend(fetched);
+
+        Ok(grouped)
+    }
+}
+
+/// Drop `hashes` from the lookup cache so later lookups see newly written
+/// fingerprints
+#[cfg(feature = "db-postgres")]
+fn invalidate_cached_hashes(scheme: HashScheme, hashes: impl IntoIterator<Item = u64>) {
+    // Only a cache that was already created can hold stale entries
+    if let Some(Some(cache)) = HASH_CACHE.get() {
+        let mut cache = cache.lock().unwrap();
+        for hash in hashes {
+            cache.pop(&(scheme, hash));
+        }
+    }
+}
+
+/// Drop every match that does not belong to one of `song_ids`
+pub fn retain_songs(grouped: &mut HashMap<u64, Vec<(u32, f32)>>, song_ids: &HashSet<u32>) {
+    grouped.retain(|_, entries| {
+        entries.retain(|(song_id, _)| song_ids.contains(song_id));
+        !entries.is_empty()
+    });
+}
+
+/// Restricts the lookups of `inner` to the songs of one collection, so
+/// separate catalogs never cross-match
+#[cfg(feature = "db-postgres")]
+struct CollectionFilter {
+    inner: Box<dyn FingerprintStore>,
+    song_ids: HashSet<u32>,
+}
+
+#[cfg(feature = "db-postgres")]
+impl FingerprintStore for CollectionFilter {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let mut grouped = self.inner.fetch_matches_grouped_by_hash(hashes)?;
+        retain_songs(&mut grouped, &self.song_ids);
+        Ok(grouped)
+    }
+
+    fn song_count(&self) -> Option<usize> {
+        Some(self.song_ids.len())
+    }
+
+    fn is_denied(&self, hash: u64) -> bool {
+        self.inner.is_denied(hash)
+    }
+}
+
+/// Skips the hashes `analyze-index` deny-listed for the collection before
+/// asking `inner`, so degenerate hashes neither cost a lookup nor cast votes
+#[cfg(feature = "db-postgres")]
+struct DeniedHashFilter {
+    inner: Box<dyn FingerprintStore>,
+    denied: HashSet<u64>,
+}
+
+#[cfg(feature = "db-postgres")]
+impl FingerprintStore for DeniedHashFilter {
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+    ) -> Result<HashMap<u64, Vec<(u32, f32)>>, StoreError> {
+        let allowed: Vec<i64> = hashes
+            .iter()
+            .copied()
+            .filter(|&hash| !self.is_denied(hash as u64))
+            .collect();
+        self.inner.fetch_matches_grouped_by_hash(&allowed)
+    }
+
+    fn is_denied(&self, hash: u64) -> bool {
+        self.denied.contains(&hash)
+    }
+}
+
+/// Warn that `outdated` songs were fingerprinted with other parameters than
+/// the current ones, so their hashes cannot match and they were left out
+pub fn warn_outdated(outdated: usize) {
+    if outdated > 0 {
+        warn!(
+            "Skipped {} song(s) fingerprinted with another parameter set, rebuild them with `reingest --outdated`",
+            outdated
+        );
+    }
+}
+
+/// The configured fingerprint index, restricted to `collection`: LMDB when
+/// `FINGERPRINT_LMDB_PATH` is set, otherwise the Postgres fingerprint table
+/// of the collection's hash scheme, fronted by the in-process lookup cache.
+/// Hashes deny-listed for the collection are never looked up. Songs
+/// fingerprinted at another version than `version` are left out, with a
+/// warning.
+#[cfg(feature = "db-postgres")]
+pub fn fingerprint_store(db: &DB, collection: &str, version: i32) -> Box<dyn FingerprintStore> {
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+    let pg = PgStore {
+        db: db.clone(),
+        scheme,
+    };
+
+    let inner: Box<dyn FingerprintStore> = match (LmdbStore::from_env(), hash_cache()) {
+        (Some(store), _) => Box::new(store),
+        (None, Some(cache)) => Box::new(CachedStore {
+            inner: Box::new(pg),
+            cache,
+            scheme,
+        }),
+        (None, None) => Box::new(pg),
+    };
+
+    let denied = db.denied_hashes(collection);
+    let inner: Box<dyn FingerprintStore> = if denied.is_empty() {
+        inner
+    } else {
+        Box::new(DeniedHashFilter { inner, denied })
+    };
+
+    warn_outdated(db.count_outdated_songs(Some(collection), version) as usize);
+
+    let song_ids = db
+        .song_ids_at_version(collection, version)
+        .into_iter()
+        .map(|id| id as u32)
+        .collect();
+    Box::new(CollectionFilter { inner, song_ids })
+}
+
+/// Insert `song` and its fingerprints, hashed with `scheme`, into the
+/// configured index as one unit. With LMDB the fingerprints are written
+/// inside the song row's transaction, so a failed write still rolls the song
+/// back and leaves no orphan.
+#[cfg(feature = "db-postgres")]
+pub fn store_song(
+    db: &DB,
+    song: &NewSong,
+    fingerprints: Vec<FingerprintInfo>,
+    scheme: HashScheme,
+) -> Result<(i32, usize), StoreError> {
+    let hashes: Vec<u64> = fingerprints.iter().map(|f| f.hash).collect();
+
+    let written = match LmdbStore::from_env() {
+        Some(store) => db.write_song_with(song, |_, song_id| {
+            store.write_fingerprints(song_id, &fingerprints)
+        }),
+        None => db
+            .write_song_with_fingerprints(song, fingerprints, scheme)
+            .map_err(Into::into),
+    }?;
+
+    invalidate_cached_hashes(scheme, hashes);
+    Ok(written)
+}
+
+/// Async read access to a fingerprint index, for serving lookups from a
+/// tokio runtime without blocking its worker threads
+pub trait AsyncFingerprintStore {
+    /// All stored `(song_id, anchor_time)` pairs for each of `hashes`, looked
+    /// up among fingerprints of `scheme`
+    fn fetch_matches_grouped_by_hash(
+        &self,
+        hashes: &[i64],
+        scheme: HashScheme,
+    ) -> impl Future<Output = Result<HashMap<u64, Vec<(u32, f32)>>, StoreError>> + Send;
+
+    fn fetch_songs(
+        &self,
+        song_ids: &[i32],
+    ) -> impl Future<Output = Result<HashMap<i32, Songs>, StoreError>> + Send;
+
+    /// Fingerprint version of each song in `collection`, by id
+    fn song_versions_in_collection(
+        &self,
+        collection: &str,
+    ) -> impl Future<Output = Result<HashMap<u32, i32>, StoreError>> + Send;
+
+    /// The hashes deny-listed for `collection`, which its lookups skip
+    fn denied_hashes(
+        &self,
+        collection: &str,
+    ) -> impl Future<Output = Result<HashSet<u64>, StoreError>> + Send;
+
+    /// Hash scheme of `collection`, if it exists
+    fn collection_hash_scheme(
+        &self,
+        collection: &str,
+    ) -> impl Future<Output = Result<Option<HashScheme>, StoreError>> + Send;
+
+    /// Comma-separated scopes of the API key whose SHA-256 is `key_hash`, if
+    /// there is one
+    fn api_key_scopes(
+        &self,
+        key_hash: &str,
+    ) -> impl Future<Output = Result<Option<String>, StoreError>> + Send;
+
+    /// Connections of the pool behind the store, for `/metrics`; `None`
+    /// without a pool
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+}
+
+/// Snapshot of a database connection pool
+pub struct PoolStatus {
+    /// Connections the pool may open
+    pub max_size: usize,
+    /// Connections open, idle or in use
+    pub size: usize,
+    /// Idle connections
+    pub available: usize,
+    /// Requests waiting for a connection
+    pub waiting: usize,
+}
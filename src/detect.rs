@@ -0,0 +1,168 @@
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::db::connector::{CollectionKind, DB};
+use crate::db::storage::fingerprint_store;
+use crate::dsp::Pipeline;
+use crate::fft::fft::CooleyTukeyFFT;
+use crate::fingerprint::{FingerprintStream, expand_query};
+use crate::scanner::format_timestamp;
+use std::collections::{HashMap, HashSet};
+
+/// One airing of a reference clip in the recording
+struct Occurrence {
+    song_id: i32,
+    /// Where the clip starts in the recording, in seconds
+    start: f32,
+    hits: usize,
+}
+
+/// Finds every airing of the short reference clips (jingles, ads, station
+/// idents) of a `short_reference` collection in a long recording.
+///
+/// The whole recording is fingerprinted once and each hit votes for the
+/// offset at which its clip would start. Offsets of one clip with enough
+/// votes are occurrences; unlike `scan`, a clip aired twice in a minute is
+/// reported twice.
+pub fn run_detect(file_name: &str, collection: &str, config: &Config) {
+    let db = DB::new();
+    let kind = db.collection_kind(collection);
+    if kind != CollectionKind::ShortReference {
+        eprintln!(
+            "❌ Collection '{}' holds {}; ingest clips with --collection-kind short-reference",
+            collection,
+            kind.as_str()
+        );
+        return;
+    }
+    let config = config.for_collection(kind);
+    let settings = &config.short_reference;
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let fft = CooleyTukeyFFT::from_config(&config.fft);
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name.to_string());
+    let (resampled, target_sr) =
+        Pipeline::from_config(&config, scheme).audio(audio_samples, sample_rate);
+    println!(
+        "📻 Detecting '{}' clips in {:.1}s of audio",
+        collection,
+        resampled.len() as f32 / target_sr as f32
+    );
+
+    let mut stream = FingerprintStream::new(scheme, config.fingerprint.clone());
+    let mut fingerprints = Vec::new();
+    for frame in fft.frames(&resampled, target_sr) {
+        fingerprints.extend(stream.push(frame));
+    }
+    fingerprints.extend(stream.finish());
+    let fingerprints = store.without_denied(expand_query(fingerprints, scheme, &config.matching));
+    if fingerprints.is_empty() {
+        println!("No fingerprints generated, nothing to detect");
+        return;
+    }
+
+    let hashes: Vec<i64> = fingerprints
+        .iter()
+        .map(|f| f.hash as i64)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let matches = store
+        .fetch_matches_grouped_by_hash(&hashes)
+        .expect("Fingerprint lookup failed");
+
+    // (clip, start offset bin) -> votes
+    let bin_width = config.matching.vote_bin_width;
+    let mut votes: HashMap<(i32, i32), usize> = HashMap::new();
+    for fp in &fingerprints {
+        for &(song_id, ref_time) in matches.get(&fp.hash).into_iter().flatten() {
+            let bin = ((fp.abs_anchor_tm_offset - ref_time) / bin_width).round() as i32;
+            *votes.entry((song_id as i32, bin)).or_default() += 1;
+        }
+    }
+
+    let clip_ids: Vec<i32> = votes
+        .keys()
+        .map(|&(song_id, _)| song_id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let clips = db.fetch_songs(&clip_ids);
+    let fingerprint_counts: HashMap<i32, i64> = db
+        .fingerprint_counts_per_song()
+        .into_iter()
+        .filter(|(song_id, _)| clips.contains_key(song_id))
+        .collect();
+
+    // Votes of neighbouring bins are pooled so jitter of one bin does not
+    // split an airing
+    let pooled = |song_id: i32, bin: i32| -> usize {
+        (bin - 1..=bin + 1)
+            .map(|b| votes.get(&(song_id, b)).copied().unwrap_or(0))
+            .sum()
+    };
+    let mut candidates: Vec<Occurrence> = votes
+        .keys()
+        .filter_map(|&(song_id, bin)| {
+            let hits = pooled(song_id, bin);
+            let required = settings.min_hits.max(
+                (settings.min_coverage
+                    * fingerprint_counts.get(&song_id).copied().unwrap_or(0) as f32)
+                    .ceil() as usize,
+            );
+            (hits >= required).then_some(Occurrence {
+                song_id,
+                // A clip cut off at the start of the recording begins before it
+                start: (bin as f32 * bin_width).max(0.0),
+                hits,
+            })
+        })
+        .collect();
+
+    // Strongest first, dropping weaker offsets of the same clip that overlap
+    // an airing already kept
+    candidates.sort_by(|a, b| {
+        b.hits
+            .cmp(&a.hits)
+            .then(a.start.total_cmp(&b.start))
+            .then(a.song_id.cmp(&b.song_id))
+    });
+    let mut occurrences: Vec<Occurrence> = Vec::new();
+    for candidate in candidates {
+        let length = clips
+            .get(&candidate.song_id)
+            .and_then(|clip| clip.duration)
+            .unwrap_or(0.0) as f32;
+        let overlaps = occurrences.iter().any(|kept| {
+            kept.song_id == candidate.song_id
+                && (kept.start - candidate.start).abs() < length.max(bin_width * 2.0)
+        });
+        if !overlaps {
+            occurrences.push(candidate);
+        }
+    }
+    occurrences.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+    if occurrences.is_empty() {
+        println!("No clips detected");
+        return;
+    }
+    println!("📜 {} occurrences:", occurrences.len());
+    for occurrence in &occurrences {
+        let clip = clips.get(&occurrence.song_id);
+        let coverage = fingerprint_counts
+            .get(&occurrence.song_id)
+            .filter(|&&count| count > 0)
+            .map_or(0.0, |&count| occurrence.hits as f32 / count as f32);
+        println!(
+            "  {} ({:.2}s) {} (hits={}, coverage={:.0}%)",
+            format_timestamp(occurrence.start),
+            occurrence.start,
+            clip.map_or("<unknown>", |clip| clip.title.as_str()),
+            occurrence.hits,
+            (coverage * 100.0).min(100.0)
+        );
+    }
+}
@@ -0,0 +1,581 @@
+//! The fingerprinting pipeline every mode shares: source → filter → resample
+//! → STFT → peaks → hash. Each step is a [`Stage`], and a [`PipelineBuilder`]
+//! puts them together in the order of `[pipeline] stages`, so an experiment
+//! such as resampling before filtering is a config change, and a mode that
+//! needs a stage done differently (denoised queries, a stronger high-pass
+//! for microphones) replaces just that stage.
+
+use crate::audio_processor::AudioProcessor;
+use crate::chroma::Key;
+use crate::config::{AudioConfig, Config, FftConfig, FingerprintConfig, RecordingConfig};
+use crate::fft::fft::{CooleyTukeyFFT, FFTDistribution};
+use crate::fingerprint::{FingerprintInfo, HashScheme, generate_audio_fingerprint};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Stage names of `[pipeline] stages`, in their default order
+pub const STAGES: [&str; 6] = ["source", "filter", "resample", "stft", "peaks", "hash"];
+
+/// What flows from one stage to the next
+pub enum Signal {
+    /// Samples at `sample_rate`, interleaved until the source stage mixes
+    /// them down to mono
+    Audio {
+        samples: Vec<f32>,
+        sample_rate: u32,
+    },
+    /// Magnitude spectrum of each frame, with the frame start in seconds
+    Spectra {
+        frames: Vec<(f32, Vec<f32>)>,
+        sample_rate: u32,
+    },
+    /// Peaks of each frame
+    Peaks(Vec<FFTDistribution>),
+    Fingerprints(Vec<FingerprintInfo>),
+}
+
+impl Signal {
+    fn kind(&self) -> &'static str {
+        match self {
+            Signal::Audio { .. } => "audio",
+            Signal::Spectra { .. } => "spectra",
+            Signal::Peaks(_) => "peaks",
+            Signal::Fingerprints(_) => "fingerprints",
+        }
+    }
+}
+
+/// One step of a [`Pipeline`]
+pub trait Stage: Send + Sync {
+    /// What the stage is timed, replaced and removed by, one of [`STAGES`]
+    /// for the built-in ones
+    fn name(&self) -> &str;
+
+    /// Turn the previous stage's output into this one's. Fails when handed a
+    /// kind of signal the stage does not take.
+    fn process(&self, signal: Signal) -> Result<Signal, String>;
+}
+
+/// Error of a stage handed a signal it does not take
+fn unexpected(stage: &str, wanted: &str, signal: &Signal) -> String {
+    format!(
+        "the {} stage takes {}, not {}",
+        stage,
+        wanted,
+        signal.kind()
+    )
+}
+
+/// Mixes interleaved input of `channels` channels down to mono
+pub struct SourceStage {
+    channels: usize,
+}
+
+impl SourceStage {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels: channels.max(1),
+        }
+    }
+}
+
+impl Stage for SourceStage {
+    fn name(&self) -> &str {
+        "source"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        match signal {
+            Signal::Audio {
+                samples,
+                sample_rate,
+            } if self.channels > 1 => Ok(Signal::Audio {
+                samples: AudioProcessor::new().to_mono(&samples, self.channels),
+                sample_rate,
+            }),
+            Signal::Audio { .. } => Ok(signal),
+            other => Err(unexpected(self.name(), "audio", &other)),
+        }
+    }
+}
+
+/// The high-pass and loudness normalization `[audio]` enables, then the
+/// anti-aliasing low-pass filter
+pub struct FilterStage {
+    config: AudioConfig,
+}
+
+impl FilterStage {
+    pub fn new(config: &AudioConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl Stage for FilterStage {
+    fn name(&self) -> &str {
+        "filter"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Audio {
+            samples,
+            sample_rate,
+        } = signal
+        else {
+            return Err(unexpected(self.name(), "audio", &signal));
+        };
+        let audio_processor = AudioProcessor::new();
+        let conditioned = audio_processor.condition(&samples, sample_rate, &self.config);
+        Ok(Signal::Audio {
+            samples: audio_processor.apply_low_pass_filter(
+                &conditioned,
+                sample_rate,
+                self.config.low_pass_cutoff,
+            ),
+            sample_rate,
+        })
+    }
+}
+
+/// Linear resampling to `audio.target_sample_rate`
+pub struct ResampleStage {
+    target_sample_rate: u32,
+}
+
+impl ResampleStage {
+    pub fn new(target_sample_rate: u32) -> Self {
+        Self { target_sample_rate }
+    }
+}
+
+impl Stage for ResampleStage {
+    fn name(&self) -> &str {
+        "resample"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Audio {
+            samples,
+            sample_rate,
+        } = signal
+        else {
+            return Err(unexpected(self.name(), "audio", &signal));
+        };
+        Ok(Signal::Audio {
+            samples: AudioProcessor::new().resample_linear(
+                &samples,
+                sample_rate,
+                self.target_sample_rate,
+            ),
+            sample_rate: self.target_sample_rate,
+        })
+    }
+}
+
+/// Windowed STFT, with the spectra denoised and whitened if enabled, and
+/// frames of silence muted if skipped
+pub struct StftStage {
+    fft: CooleyTukeyFFT,
+    /// Where the seconds of silence muted are reported, if anywhere
+    silent_secs: Option<Arc<Mutex<f32>>>,
+}
+
+impl StftStage {
+    pub fn new(config: &FftConfig) -> Self {
+        Self {
+            fft: CooleyTukeyFFT::from_config(config),
+            silent_secs: None,
+        }
+    }
+
+    /// Also denoise as set in `[recording]`; for queries only
+    pub fn with_query_denoise(self, config: &RecordingConfig) -> Self {
+        Self {
+            fft: self.fft.with_query_denoise(config),
+            ..self
+        }
+    }
+
+    /// Also store the seconds of silence muted in `silent_secs`, to be read
+    /// once the pipeline has run
+    pub fn reporting_silence(self, silent_secs: Arc<Mutex<f32>>) -> Self {
+        Self {
+            silent_secs: Some(silent_secs),
+            ..self
+        }
+    }
+}
+
+impl Stage for StftStage {
+    fn name(&self) -> &str {
+        "stft"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Audio {
+            samples,
+            sample_rate,
+        } = signal
+        else {
+            return Err(unexpected(self.name(), "audio", &signal));
+        };
+        let silent = self.fft.silent_frames(&samples);
+        if let Some(silent_secs) = &self.silent_secs {
+            *silent_secs.lock().map_err(|e| e.to_string())? =
+                self.fft.silent_secs(&silent, sample_rate);
+        }
+        let spectra = self.fft.spectra(&samples, sample_rate);
+        let magnitudes = self.fft.magnitude_spectra(spectra, sample_rate);
+        Ok(Signal::Spectra {
+            frames: self.fft.mute_silent(magnitudes, silent).collect(),
+            sample_rate,
+        })
+    }
+}
+
+/// Peak picking as set in `[fft]`
+pub struct PeakStage {
+    fft: CooleyTukeyFFT,
+}
+
+impl PeakStage {
+    pub fn new(config: &FftConfig) -> Self {
+        Self {
+            fft: CooleyTukeyFFT::from_config(config),
+        }
+    }
+}
+
+impl Stage for PeakStage {
+    fn name(&self) -> &str {
+        "peaks"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Spectra {
+            frames,
+            sample_rate,
+        } = signal
+        else {
+            return Err(unexpected(self.name(), "spectra", &signal));
+        };
+        Ok(Signal::Peaks(
+            self.fft.peaks(frames.into_iter(), sample_rate).collect(),
+        ))
+    }
+}
+
+/// Tempo and key of a song, estimated from the spectra it is fingerprinted
+/// from
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpectraAnalysis {
+    pub bpm: Option<f32>,
+    pub key: Option<Key>,
+}
+
+/// Passes spectra on unchanged while estimating their tempo and key, see
+/// [`crate::tempo`] and [`crate::chroma`]. Not one of [`STAGES`]; put it
+/// after `stft` with [`PipelineBuilder::insert_after`] and read the result
+/// from the handle it was made with once the pipeline has run.
+pub struct AnalysisStage {
+    analysis: Arc<Mutex<SpectraAnalysis>>,
+}
+
+impl AnalysisStage {
+    pub fn new(analysis: Arc<Mutex<SpectraAnalysis>>) -> Self {
+        Self { analysis }
+    }
+}
+
+impl Stage for AnalysisStage {
+    fn name(&self) -> &str {
+        "analysis"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Spectra {
+            frames,
+            sample_rate,
+        } = &signal
+        else {
+            return Err(unexpected(self.name(), "spectra", &signal));
+        };
+        *self.analysis.lock().map_err(|e| e.to_string())? = SpectraAnalysis {
+            bpm: crate::tempo::spectra_bpm(frames),
+            key: crate::chroma::spectra_key(frames, *sample_rate),
+        };
+        Ok(signal)
+    }
+}
+
+/// Pairing peaks into hashes of one scheme
+pub struct HashStage {
+    scheme: HashScheme,
+    config: FingerprintConfig,
+}
+
+impl HashStage {
+    pub fn new(scheme: HashScheme, config: &FingerprintConfig) -> Self {
+        Self {
+            scheme,
+            config: config.clone(),
+        }
+    }
+}
+
+impl Stage for HashStage {
+    fn name(&self) -> &str {
+        "hash"
+    }
+
+    fn process(&self, signal: Signal) -> Result<Signal, String> {
+        let Signal::Peaks(frames) = signal else {
+            return Err(unexpected(self.name(), "peaks", &signal));
+        };
+        Ok(Signal::Fingerprints(generate_audio_fingerprint(
+            &frames,
+            self.scheme,
+            &self.config,
+        )))
+    }
+}
+
+/// Check `[pipeline] stages`: known names, each at most once, with `stft`,
+/// `peaks` and `hash` following the audio stages in that order
+pub fn validate_stages(stages: &[String]) -> Result<(), String> {
+    let position = |name: &str| stages.iter().position(|stage| stage == name);
+    for (i, stage) in stages.iter().enumerate() {
+        if !STAGES.contains(&stage.as_str()) {
+            return Err(format!(
+                "pipeline.stages has unknown stage '{}', expected some of {:?}",
+                stage, STAGES
+            ));
+        }
+        if position(stage) != Some(i) {
+            return Err(format!("pipeline.stages lists '{}' twice", stage));
+        }
+    }
+    let tail: Vec<&str> = stages[stages.len().saturating_sub(3)..]
+        .iter()
+        .map(String::as_str)
+        .collect();
+    if tail != ["stft", "peaks", "hash"] {
+        return Err("pipeline.stages must end with \"stft\", \"peaks\", \"hash\"".to_string());
+    }
+    Ok(())
+}
+
+/// Stages run one after another on a whole signal
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// The built-in stages in the order of `config.pipeline.stages`, set up
+    /// from `config` and hashing with `scheme`
+    pub fn builder(config: &Config, scheme: HashScheme) -> PipelineBuilder {
+        let mut builder = PipelineBuilder::new();
+        for name in &config.pipeline.stages {
+            let stage: Box<dyn Stage> = match name.as_str() {
+                "source" => Box::new(SourceStage::new(1)),
+                "filter" => Box::new(FilterStage::new(&config.audio)),
+                "resample" => Box::new(ResampleStage::new(config.audio.target_sample_rate)),
+                "stft" => Box::new(StftStage::new(&config.fft)),
+                "peaks" => Box::new(PeakStage::new(&config.fft)),
+                "hash" => Box::new(HashStage::new(scheme, &config.fingerprint)),
+                // Rejected when the config was loaded
+                _ => continue,
+            };
+            builder.stages.push(stage);
+        }
+        builder
+    }
+
+    /// The pipeline of [`Self::builder`] as it is
+    pub fn from_config(config: &Config, scheme: HashScheme) -> Self {
+        Self::builder(config, scheme).build()
+    }
+
+    /// The built-in pipeline for queries, whose spectra are denoised as set
+    /// in `[recording]`
+    pub fn for_query(config: &Config, scheme: HashScheme) -> Self {
+        Self::builder(config, scheme)
+            .replace(StftStage::new(&config.fft).with_query_denoise(&config.recording))
+            .build()
+    }
+
+    /// The query pipeline for microphone input, which is high-passed unless
+    /// `recording.high_pass` is off
+    pub fn for_recording(config: &Config, scheme: HashScheme) -> Self {
+        let audio = AudioConfig {
+            high_pass: config.audio.high_pass || config.recording.high_pass,
+            ..config.audio.clone()
+        };
+        Self::builder(config, scheme)
+            .replace(FilterStage::new(&audio))
+            .replace(StftStage::new(&config.fft).with_query_denoise(&config.recording))
+            .build()
+    }
+
+    /// Run every stage on `signal`
+    pub fn run(&self, signal: Signal) -> Result<Signal, String> {
+        self.run_timed(signal, |_, _| {})
+    }
+
+    /// Like [`Self::run`], reporting the name and duration of each stage to
+    /// `observe`
+    pub fn run_timed(
+        &self,
+        mut signal: Signal,
+        mut observe: impl FnMut(&str, Duration),
+    ) -> Result<Signal, String> {
+        for stage in &self.stages {
+            let started = Instant::now();
+            signal = stage.process(signal)?;
+            observe(stage.name(), started.elapsed());
+        }
+        Ok(signal)
+    }
+
+    /// `samples` at `sample_rate` through the stages before `stft`, and the
+    /// rate they come out at
+    pub fn audio(&self, samples: Vec<f32>, sample_rate: u32) -> (Vec<f32>, u32) {
+        match self.run_until(samples, sample_rate, "stft", |_, _| {}) {
+            Signal::Audio {
+                samples,
+                sample_rate,
+            } => (samples, sample_rate),
+            other => panic!("pipeline yields {} before the STFT", other.kind()),
+        }
+    }
+
+    /// Peak frames of `samples` at `sample_rate`, through the stages before
+    /// `hash`
+    pub fn peak_frames(&self, samples: Vec<f32>, sample_rate: u32) -> Vec<FFTDistribution> {
+        self.peak_frames_timed(samples, sample_rate, |_, _| {})
+    }
+
+    /// Like [`Self::peak_frames`], reporting each stage to `observe` as
+    /// [`Self::run_timed`] does
+    pub fn peak_frames_timed(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        observe: impl FnMut(&str, Duration),
+    ) -> Vec<FFTDistribution> {
+        match self.run_until(samples, sample_rate, "hash", observe) {
+            Signal::Peaks(frames) => frames,
+            other => panic!("pipeline yields {} before hashing, not peaks", other.kind()),
+        }
+    }
+
+    /// Fingerprints of `samples` at `sample_rate`, through every stage
+    pub fn fingerprints(&self, samples: Vec<f32>, sample_rate: u32) -> Vec<FingerprintInfo> {
+        self.fingerprints_timed(samples, sample_rate, |_, _| {})
+    }
+
+    /// Like [`Self::fingerprints`], reporting each stage to `observe` as
+    /// [`Self::run_timed`] does
+    pub fn fingerprints_timed(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        observe: impl FnMut(&str, Duration),
+    ) -> Vec<FingerprintInfo> {
+        match self.run_until(samples, sample_rate, "", observe) {
+            Signal::Fingerprints(fingerprints) => fingerprints,
+            other => panic!("pipeline ends in {}, not fingerprints", other.kind()),
+        }
+    }
+
+    /// Run the stages before the one named `name` on audio. The built-in
+    /// stages only fail in an order the config would have rejected, so a
+    /// failure is a bug.
+    fn run_until(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        name: &str,
+        mut observe: impl FnMut(&str, Duration),
+    ) -> Signal {
+        let mut signal = Signal::Audio {
+            samples,
+            sample_rate,
+        };
+        for stage in self.stages.iter().take_while(|stage| stage.name() != name) {
+            let started = Instant::now();
+            signal = stage.process(signal).unwrap_or_else(|e| panic!("{}", e));
+            observe(stage.name(), started.elapsed());
+        }
+        signal
+    }
+}
+
+/// Assembles a [`Pipeline`] stage by stage
+pub struct PipelineBuilder {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl PipelineBuilder {
+    /// A pipeline without stages
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Append `stage`
+    pub fn stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Put `stage` in place of the stage of the same name, or append it if
+    /// there is none
+    pub fn replace(mut self, stage: impl Stage + 'static) -> Self {
+        match self.position(stage.name()) {
+            Some(i) => self.stages[i] = Box::new(stage),
+            None => self.stages.push(Box::new(stage)),
+        }
+        self
+    }
+
+    /// Insert `stage` right after the stage named `after`, or append it if
+    /// there is none
+    pub fn insert_after(mut self, after: &str, stage: impl Stage + 'static) -> Self {
+        let at = self.position(after).map_or(self.stages.len(), |i| i + 1);
+        self.stages.insert(at, Box::new(stage));
+        self
+    }
+
+    /// Keep only the stages before the one named `name`
+    pub fn until(mut self, name: &str) -> Self {
+        if let Some(i) = self.position(name) {
+            self.stages.truncate(i);
+        }
+        self
+    }
+
+    /// Keep only the stage named `name` and the ones after it
+    pub fn starting_at(mut self, name: &str) -> Self {
+        let i = self.position(name).unwrap_or(self.stages.len());
+        self.stages.drain(..i);
+        self
+    }
+
+    /// Drop the stage named `name`, if there is one
+    pub fn without(mut self, name: &str) -> Self {
+        self.stages.retain(|stage| stage.name() != name);
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            stages: self.stages,
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.name() == name)
+    }
+}
@@ -0,0 +1,82 @@
+//! Little-endian encoding of the fields of library archives, index files and
+//! fingerprint files
+
+use std::io::{self, Read, Write};
+
+pub fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_i32(w: &mut impl Write, value: i32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_i64(w: &mut impl Write, value: i64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f64(w: &mut impl Write, value: f64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_str(w: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())
+}
+
+pub fn write_opt<W: Write, T>(
+    w: &mut W,
+    value: Option<T>,
+    write: impl Fn(&mut W, T) -> io::Result<()>,
+) -> io::Result<()> {
+    match value {
+        Some(v) => {
+            w.write_all(&[1])?;
+            write(w, v)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+pub fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    read_array(r).map(u32::from_le_bytes)
+}
+
+pub fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    read_array(r).map(i32::from_le_bytes)
+}
+
+pub fn read_i64(r: &mut impl Read) -> io::Result<i64> {
+    read_array(r).map(i64::from_le_bytes)
+}
+
+pub fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    read_array(r).map(f64::from_le_bytes)
+}
+
+pub fn read_str(r: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn read_opt<R: Read, T>(
+    r: &mut R,
+    read: impl Fn(&mut R) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    match read_array::<1>(r)? {
+        [0] => Ok(None),
+        [1] => read(r).map(Some),
+        [flag] => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid presence flag {}", flag),
+        )),
+    }
+}
@@ -1,7 +1,16 @@
 use ordered_float::OrderedFloat;
 
+use crate::config::{FftConfig, PeakPicking, RecordingConfig};
 use crate::fft::complex::Complex;
+use std::collections::VecDeque;
 use std::f32::consts::PI;
+use tracing::{debug, debug_span};
+
+/// Edges splitting the analysis band into the bands peaks are capped in, in Hz
+const PEAK_BAND_EDGES: [f32; 2] = [300.0, 2000.0];
+/// Percentile of its frame levels a recording's loud level is taken at, see
+/// [`CooleyTukeyFFT::silent_frames`]
+const LOUD_PERCENTILE: f32 = 0.9;
 
 pub struct FFTDistribution {
     pub time: OrderedFloat<f32>,
@@ -14,10 +23,189 @@ pub struct PeakInfo {
     pub magnitude: OrderedFloat<f32>,
 }
 
+/// Magnitude spectrogram as a matrix: one row per frame, one column per
+/// frequency bin or band
+pub struct Spectrogram {
+    /// Start of each frame, in seconds
+    pub times: Vec<f32>,
+    /// Center frequency of each column, in Hz
+    pub freqs: Vec<f32>,
+    /// `times.len()` rows of `freqs.len()` magnitudes
+    pub magnitudes: Vec<Vec<f32>>,
+}
+
+impl Spectrogram {
+    /// The same frames in `bands` triangular mel bands spread evenly between
+    /// `min_freq` and `max_freq` on the mel scale, each band rising from its
+    /// lower neighbor's center to its own and falling to the next. A band's
+    /// magnitude is the mean of its bins under that weighting, so wide high
+    /// bands do not outweigh narrow low ones.
+    pub fn to_mel(&self, bands: usize, min_freq: f32, max_freq: f32) -> Spectrogram {
+        let mel = |freq: f32| 2595.0 * (1.0 + freq / 700.0).log10();
+        let hz = |mel: f32| 700.0 * (10f32.powf(mel / 2595.0) - 1.0);
+        let (low, high) = (mel(min_freq), mel(max_freq));
+        let edges: Vec<f32> = (0..bands + 2)
+            .map(|i| hz(low + (high - low) * i as f32 / (bands + 1) as f32))
+            .collect();
+
+        let weights: Vec<Vec<f32>> = edges
+            .windows(3)
+            .map(|band| {
+                let (left, center, right) = (band[0], band[1], band[2]);
+                let weights: Vec<f32> = self
+                    .freqs
+                    .iter()
+                    .map(|&freq| {
+                        if freq <= left || freq >= right {
+                            0.0
+                        } else if freq <= center {
+                            (freq - left) / (center - left)
+                        } else {
+                            (right - freq) / (right - center)
+                        }
+                    })
+                    .collect();
+                let total: f32 = weights.iter().sum();
+                if total > 0.0 {
+                    weights.iter().map(|w| w / total).collect()
+                } else {
+                    weights
+                }
+            })
+            .collect();
+        let magnitudes = self
+            .magnitudes
+            .iter()
+            .map(|frame| {
+                weights
+                    .iter()
+                    .map(|band| band.iter().zip(frame).map(|(w, m)| w * m).sum())
+                    .collect()
+            })
+            .collect();
+        Spectrogram {
+            times: self.times.clone(),
+            freqs: edges[1..=bands].to_vec(),
+            magnitudes,
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 pub struct CooleyTukeyFFT {
     CHUNK_SIZE: usize,
     OVERLAP_SIZE: usize,
+    /// Frames are zero-padded to this many times `CHUNK_SIZE` before the
+    /// transform
+    zero_padding: usize,
+    threshold_multiplier: f32,
+    max_peaks_per_band: usize,
+    /// Decay of the spectral whitening envelope, `None` when whitening is off
+    whitening_decay: Option<f32>,
+    /// Spectral subtraction of query noise, `None` when denoising is off
+    denoise: Option<DenoiseParams>,
+    peak_picking: PeakPicking,
+    /// Half-widths of the `LocalMax` neighborhood, in bins and frames
+    neighborhood_bins: usize,
+    neighborhood_frames: usize,
+    /// Band peaks are picked in, in Hz
+    min_freq: f32,
+    max_freq: f32,
+    /// Frames this many dB below the recording's loud level yield no peaks,
+    /// `None` when silence is not skipped
+    silence_threshold_db: Option<f32>,
+}
+
+/// Running per-bin magnitude envelope for spectral whitening. Each frame's
+/// magnitudes are divided by an exponential moving average of the same bin
+/// over the previous frames, so a sustained bass line stops dwarfing the
+/// mid and high bands and peaks are picked where the spectrum changes.
+pub struct SpectralWhitener {
+    decay: f32,
+    envelope: Vec<f32>,
+}
+
+impl SpectralWhitener {
+    /// Envelope values below this are treated as silence
+    const FLOOR: f32 = 1e-6;
+
+    pub fn new(decay: f32) -> Self {
+        Self {
+            decay,
+            envelope: Vec::new(),
+        }
+    }
+
+    /// Whiten one frame in place. The first frame only seeds the envelope.
+    fn apply(&mut self, magnitudes: &mut [f32]) {
+        if self.envelope.len() != magnitudes.len() {
+            self.envelope = magnitudes.to_vec();
+            return;
+        }
+        for (magnitude, envelope) in magnitudes.iter_mut().zip(&mut self.envelope) {
+            let raw = *magnitude;
+            *magnitude /= envelope.max(Self::FLOOR);
+            *envelope = self.decay * *envelope + (1.0 - self.decay) * raw;
+        }
+    }
+}
+
+/// Settings of [`SpectralSubtractor`], from the `[recording]` config section
+#[derive(Clone, Copy)]
+struct DenoiseParams {
+    profile_secs: f32,
+    over_subtraction: f32,
+    floor: f32,
+}
+
+/// Spectral subtraction. The noise spectrum is estimated as the mean
+/// magnitude of the first frames, then tracks each bin's minimum (a quiet or
+/// silent frame lowers it at once, it rises back slowly), and
+/// `over_subtraction` times the estimate is taken off every frame.
+struct SpectralSubtractor {
+    params: DenoiseParams,
+    /// Frames averaged into the initial estimate
+    profile_frames: usize,
+    frames_seen: usize,
+    noise: Vec<f32>,
+}
+
+impl SpectralSubtractor {
+    /// Per-frame growth allowed to the noise estimate after the profile
+    const RISE: f32 = 1.005;
+
+    fn new(params: DenoiseParams, profile_frames: usize) -> Self {
+        Self {
+            params,
+            profile_frames: profile_frames.max(1),
+            frames_seen: 0,
+            noise: Vec::new(),
+        }
+    }
+
+    /// Denoise one frame in place, updating the noise estimate with it first
+    fn apply(&mut self, magnitudes: &mut [f32]) {
+        if self.noise.len() != magnitudes.len() {
+            self.noise = vec![0.0; magnitudes.len()];
+            self.frames_seen = 0;
+        }
+        self.frames_seen += 1;
+        if self.frames_seen <= self.profile_frames {
+            let n = self.frames_seen as f32;
+            for (noise, &magnitude) in self.noise.iter_mut().zip(magnitudes.iter()) {
+                *noise += (magnitude - *noise) / n;
+            }
+        } else {
+            for (noise, &magnitude) in self.noise.iter_mut().zip(magnitudes.iter()) {
+                *noise = (*noise * Self::RISE).min(magnitude);
+            }
+        }
+
+        for (magnitude, &noise) in magnitudes.iter_mut().zip(&self.noise) {
+            *magnitude = (*magnitude - self.params.over_subtraction * noise)
+                .max(self.params.floor * *magnitude);
+        }
+    }
 }
 
 #[allow(dead_code, non_snake_case)]
@@ -27,12 +215,53 @@ impl CooleyTukeyFFT {
             panic!("Chunk Size must be power of two for this implementation to work")
         }
 
+        let defaults = FftConfig::default();
         Self {
             CHUNK_SIZE,
             OVERLAP_SIZE,
+            zero_padding: 1,
+            threshold_multiplier: defaults.threshold_multiplier,
+            max_peaks_per_band: defaults.max_peaks_per_band,
+            whitening_decay: None,
+            denoise: None,
+            peak_picking: defaults.peak_picking,
+            neighborhood_bins: defaults.neighborhood_bins / 2,
+            neighborhood_frames: defaults.neighborhood_frames / 2,
+            min_freq: defaults.min_freq,
+            max_freq: defaults.max_freq,
+            silence_threshold_db: None,
         }
     }
 
+    /// Framing and peak picking as set in the `[fft]` config section
+    pub fn from_config(config: &FftConfig) -> Self {
+        Self {
+            threshold_multiplier: config.threshold_multiplier,
+            max_peaks_per_band: config.max_peaks_per_band,
+            whitening_decay: config.whitening.then_some(config.whitening_decay),
+            peak_picking: config.peak_picking,
+            // Padding narrows the bins, so the neighborhood spans as many Hz
+            neighborhood_bins: config.neighborhood_bins / 2 * config.zero_padding,
+            neighborhood_frames: config.neighborhood_frames / 2,
+            min_freq: config.min_freq,
+            max_freq: config.max_freq,
+            silence_threshold_db: config.skip_silence.then_some(config.silence_threshold_db),
+            zero_padding: config.zero_padding,
+            ..Self::new(config.chunk_size, config.overlap_size)
+        }
+    }
+
+    /// Also denoise the spectra as set in `[recording]`, if enabled there.
+    /// For queries only: ingested songs must not be denoised.
+    pub fn with_query_denoise(mut self, config: &RecordingConfig) -> Self {
+        self.denoise = config.denoise.then_some(DenoiseParams {
+            profile_secs: config.denoise_secs,
+            over_subtraction: config.denoise_over_subtraction,
+            floor: config.denoise_floor,
+        });
+        self
+    }
+
     fn apply_hann_window(&self, chunk: &[f32]) -> Vec<f32> {
         let n = chunk.len();
         chunk
@@ -92,52 +321,204 @@ impl CooleyTukeyFFT {
         }
     }
 
-    fn perform_fft(&self, buff: Vec<f32>) -> Vec<Complex> {
+    /// Spectrum of one frame, which must be `CHUNK_SIZE` long and already
+    /// windowed, zero-padded to [`Self::fft_size`] first
+    pub fn perform_fft(&self, buff: Vec<f32>) -> Vec<Complex> {
         let mut complex_buff = self.convert_to_complex_buffer(buff);
+        complex_buff.resize(self.fft_size(), Complex::new(0.0, 0.0));
 
         self.cooley_tukey_fft(&mut complex_buff);
 
         complex_buff
     }
 
+    /// Length of the transform: `CHUNK_SIZE` times the zero padding, so bins
+    /// are `sample_rate / fft_size` Hz apart
+    pub fn fft_size(&self) -> usize {
+        self.CHUNK_SIZE * self.zero_padding
+    }
+
+    /// Windowed spectrum of every frame of `buffer`, with the frame start in
+    /// seconds. Lazy, so frames can be consumed one at a time.
+    pub fn spectra<'a>(
+        &'a self,
+        buffer: &'a [f32],
+        sample_rate: u32,
+    ) -> impl Iterator<Item = (f32, Vec<Complex>)> + 'a {
+        let hop = self.CHUNK_SIZE - self.OVERLAP_SIZE;
+        (0..)
+            .map(move |frame| frame * hop)
+            .take_while(move |&position| position + self.CHUNK_SIZE <= buffer.len())
+            .map(move |position| {
+                let chunk = &buffer[position..position + self.CHUNK_SIZE];
+                let windowed_chunk = self.apply_hann_window(chunk);
+                let time = position as f32 / sample_rate as f32;
+                (time, self.perform_fft(windowed_chunk))
+            })
+    }
+
     pub fn generate_freq_time_distribution(
         &self,
         buffer: Vec<f32>,
         sample_rate: u32,
     ) -> Vec<FFTDistribution> {
-        let buf_len = buffer.len();
-        let mut position = 0;
+        let _span = debug_span!("stft", samples = buffer.len(), sample_rate).entered();
+        let frames: Vec<FFTDistribution> = self.frames(&buffer, sample_rate).collect();
+        debug!("Computed {} peak frames", frames.len());
+        frames
+    }
 
-        let mut fingerprints = Vec::new();
-        println!("The buf len is {} ", buf_len);
+    /// Peak-picked frames of `buffer`, produced lazily one at a time (see
+    /// [`crate::fingerprint::FingerprintStream`])
+    pub fn frames<'a>(
+        &'a self,
+        buffer: &'a [f32],
+        sample_rate: u32,
+    ) -> impl Iterator<Item = FFTDistribution> + 'a {
+        let silent = self.silent_frames(buffer);
+        let magnitudes = self.magnitude_spectra(self.spectra(buffer, sample_rate), sample_rate);
+        self.peaks(self.mute_silent(magnitudes, silent), sample_rate)
+    }
 
-        while position + self.CHUNK_SIZE <= buf_len {
-            let chunk = &buffer[position..position + self.CHUNK_SIZE];
+    /// Magnitude spectrogram of `buffer`, denoised and whitened if enabled as
+    /// peak picking sees it, but with every bin rather than only the peaks
+    pub fn spectrogram(&self, buffer: &[f32], sample_rate: u32) -> Spectrogram {
+        let bin_hz = sample_rate as f32 / self.fft_size() as f32;
+        let (times, magnitudes) = self
+            .magnitude_spectra(self.spectra(buffer, sample_rate), sample_rate)
+            .unzip();
+        Spectrogram {
+            times,
+            freqs: (0..self.fft_size() / 2)
+                .map(|bin| bin as f32 * bin_hz)
+                .collect(),
+            magnitudes,
+        }
+    }
 
-            let windowed_chunk = self.apply_hann_window(chunk);
+    /// Which frames of `buffer`, framed as by [`Self::spectra`], are silence:
+    /// digital silence, or quieter than the recording's loud level (the
+    /// [`LOUD_PERCENTILE`] of its frame levels) by more than the silence
+    /// threshold. The threshold follows the recording, so the noise floor of
+    /// a quiet intro counts as silence however loud it is in absolute terms.
+    /// None are when silence is not skipped.
+    pub fn silent_frames(&self, buffer: &[f32]) -> Vec<bool> {
+        let hop = self.CHUNK_SIZE - self.OVERLAP_SIZE;
+        let frames = (0..)
+            .map(|frame| frame * hop)
+            .take_while(|&position| position + self.CHUNK_SIZE <= buffer.len());
+        let Some(threshold_db) = self.silence_threshold_db else {
+            return frames.map(|_| false).collect();
+        };
 
-            let fft_output = self.perform_fft(windowed_chunk);
+        let levels: Vec<f32> = frames
+            .map(|position| {
+                let chunk = &buffer[position..position + self.CHUNK_SIZE];
+                let mean_square = chunk.iter().map(|s| s * s).sum::<f32>() / chunk.len() as f32;
+                10.0 * mean_square.log10()
+            })
+            .collect();
+        let mut sorted = levels.clone();
+        sorted.sort_by(f32::total_cmp);
+        let loud = sorted
+            .get(((sorted.len().saturating_sub(1)) as f32 * LOUD_PERCENTILE).round() as usize)
+            .copied()
+            .unwrap_or(f32::NEG_INFINITY);
+        levels
+            .iter()
+            .map(|&level| level == f32::NEG_INFINITY || level < loud - threshold_db)
+            .collect()
+    }
 
-            let peaks = self.find_peaks(&fft_output, sample_rate);
+    /// Seconds at `sample_rate` in the frames [`Self::silent_frames`] marks
+    /// in `silent`
+    pub fn silent_secs(&self, silent: &[bool], sample_rate: u32) -> f32 {
+        let silent = silent.iter().filter(|&&s| s).count();
+        (silent * (self.CHUNK_SIZE - self.OVERLAP_SIZE)) as f32 / sample_rate as f32
+    }
 
-            let time = position as f32 / sample_rate as f32;
+    /// Zero the magnitudes of the frames `silent` marks, so no peaks are
+    /// picked in them while they keep their place in time
+    pub fn mute_silent<'a>(
+        &'a self,
+        magnitudes: impl Iterator<Item = (f32, Vec<f32>)> + 'a,
+        silent: Vec<bool>,
+    ) -> impl Iterator<Item = (f32, Vec<f32>)> + 'a {
+        magnitudes
+            .zip(silent.into_iter().chain(std::iter::repeat(false)))
+            .map(|((time, mut magnitudes), silent)| {
+                if silent {
+                    magnitudes.fill(0.0);
+                }
+                (time, magnitudes)
+            })
+    }
 
-            let fingerprint = FFTDistribution {
-                time: OrderedFloat(time),
-                peaks: peaks,
-            };
+    /// Pick the peaks of consecutive spectra from [`Self::spectra`], as set in
+    /// the `[fft]` config section. `LocalMax` looks ahead by the neighborhood
+    /// half-height, so its frames come out that many spectra late.
+    pub fn pick_peaks<'a>(
+        &'a self,
+        spectra: impl Iterator<Item = (f32, Vec<Complex>)> + 'a,
+        sample_rate: u32,
+    ) -> Box<dyn Iterator<Item = FFTDistribution> + 'a> {
+        self.peaks(self.magnitude_spectra(spectra, sample_rate), sample_rate)
+    }
 
-            fingerprints.push(fingerprint);
+    /// Magnitudes of consecutive spectra from [`Self::spectra`], denoised and
+    /// whitened if enabled
+    pub fn magnitude_spectra<'a>(
+        &'a self,
+        spectra: impl Iterator<Item = (f32, Vec<Complex>)> + 'a,
+        sample_rate: u32,
+    ) -> impl Iterator<Item = (f32, Vec<f32>)> + 'a {
+        let mut whitener = self.whitening_decay.map(SpectralWhitener::new);
+        let hop_secs = (self.CHUNK_SIZE - self.OVERLAP_SIZE) as f32 / sample_rate as f32;
+        let mut subtractor = self.denoise.map(|params| {
+            SpectralSubtractor::new(params, (params.profile_secs / hop_secs).ceil() as usize)
+        });
+        spectra.map(move |(time, spectrum)| {
+            (
+                time,
+                self.magnitudes(&spectrum, subtractor.as_mut(), whitener.as_mut()),
+            )
+        })
+    }
 
-            position += self.CHUNK_SIZE - self.OVERLAP_SIZE;
+    /// Peaks of consecutive magnitude spectra from
+    /// [`Self::magnitude_spectra`], picked as set in the `[fft]` config
+    /// section
+    pub fn peaks<'a>(
+        &'a self,
+        magnitudes: impl Iterator<Item = (f32, Vec<f32>)> + 'a,
+        sample_rate: u32,
+    ) -> Box<dyn Iterator<Item = FFTDistribution> + 'a> {
+        match self.peak_picking {
+            PeakPicking::Bands => {
+                Box::new(magnitudes.map(move |(time, magnitudes)| FFTDistribution {
+                    time: OrderedFloat(time),
+                    peaks: self.band_peaks(magnitudes, sample_rate),
+                }))
+            }
+            PeakPicking::LocalMax => Box::new(LocalMaxFrames {
+                fft: self,
+                magnitudes,
+                sample_rate,
+                window: VecDeque::new(),
+                center: 0,
+            }),
         }
-
-        fingerprints
     }
 
-    fn find_peaks(&self, complex_buffer: &[Complex], sample_rate: u32) -> Vec<PeakInfo> {
-        let n = complex_buffer.len();
-        let half_n = n / 2;
+    /// Magnitude spectrum (first half) of one frame, denoised and whitened if
+    /// enabled
+    fn magnitudes(
+        &self,
+        complex_buffer: &[Complex],
+        subtractor: Option<&mut SpectralSubtractor>,
+        whitener: Option<&mut SpectralWhitener>,
+    ) -> Vec<f32> {
+        let half_n = complex_buffer.len() / 2;
 
         // Compute magnitudes
         let mut magnitudes: Vec<f32> = complex_buffer[..half_n]
@@ -145,6 +526,21 @@ impl CooleyTukeyFFT {
             .map(|&c| c.norm_sqr().sqrt())
             .collect();
 
+        if let Some(subtractor) = subtractor {
+            subtractor.apply(&mut magnitudes);
+        }
+        if let Some(whitener) = whitener {
+            whitener.apply(&mut magnitudes);
+        }
+        magnitudes
+    }
+
+    /// Peaks of one frame's magnitude spectrum: local maxima along frequency
+    /// that stand out from their band's average
+    fn band_peaks(&self, mut magnitudes: Vec<f32>, sample_rate: u32) -> Vec<PeakInfo> {
+        let half_n = magnitudes.len();
+        let n = half_n * 2;
+
         // --- Normalize magnitudes per frame ---
         if let Some(&max_val) = magnitudes.iter().max_by(|a, b| a.partial_cmp(b).unwrap()) {
             if max_val > 0.0 {
@@ -161,10 +557,7 @@ impl CooleyTukeyFFT {
             if magnitudes[i - 1] < magnitudes[i] && magnitudes[i] > magnitudes[i + 1] {
                 let freq = i as f32 * (sample_rate as f32 / n as f32);
 
-                let lower_freq_limit = FreqRange::Low.get_freq();
-                let higher_freq_limit = FreqRange::High.get_freq();
-
-                if lower_freq_limit < freq && freq < higher_freq_limit {
+                if self.min_freq < freq && freq < self.max_freq {
                     raw_peaks.push(PeakInfo {
                         freq: OrderedFloat(freq),
                         magnitude: OrderedFloat(magnitudes[i]),
@@ -173,29 +566,8 @@ impl CooleyTukeyFFT {
             }
         }
 
-        // --- Band splitting ---
-        let low_band: Vec<PeakInfo> = raw_peaks
-            .iter()
-            .filter(|p| (20.0..300.0).contains(&p.freq.into_inner()))
-            .cloned()
-            .collect();
-
-        let mid_band: Vec<PeakInfo> = raw_peaks
-            .iter()
-            .filter(|p| (300.0..2000.0).contains(&p.freq.into_inner()))
-            .cloned()
-            .collect();
-
-        let high_band: Vec<PeakInfo> = raw_peaks
-            .iter()
-            .filter(|p| (2000.0..5000.0).contains(&p.freq.into_inner()))
-            .cloned()
-            .collect();
-
         // --- Dynamic Thresholding & Peak Selection ---
         let mut final_peaks = Vec::new();
-        const THRESHOLD_MULTIPLIER: f32 = 1.75; // Peak must be 1.75x stronger than the band's average.
-        const MAX_PEAKS_PER_BAND: usize = 5; // Safety cap to prevent too many fingerprints from one frame.
 
         let process_band = |band: Vec<PeakInfo>| -> Vec<PeakInfo> {
             if band.is_empty() {
@@ -206,7 +578,8 @@ impl CooleyTukeyFFT {
             let total_magnitude: f32 = band.iter().map(|p| p.magnitude.into_inner()).sum();
             let average_magnitude = total_magnitude / band.len() as f32;
 
-            let threshold = average_magnitude * THRESHOLD_MULTIPLIER;
+            // Peak must be this much stronger than the band's average
+            let threshold = average_magnitude * self.threshold_multiplier;
 
             // 1. Filter the peaks that are stronger than the threshold
             let mut strong_peaks: Vec<PeakInfo> = band
@@ -217,19 +590,99 @@ impl CooleyTukeyFFT {
             // 2. Sort the remaining strong peaks by magnitude
             strong_peaks.sort_by(|a, b| b.magnitude.partial_cmp(&a.magnitude).unwrap());
 
-            // 3. Apply the safety cap
-            strong_peaks.truncate(MAX_PEAKS_PER_BAND);
+            // 3. Apply the safety cap against too many fingerprints from one frame
+            strong_peaks.truncate(self.max_peaks_per_band);
 
             strong_peaks
         };
 
         // Process each band and collect the results
-        final_peaks.extend(process_band(low_band));
-        final_peaks.extend(process_band(mid_band));
-        final_peaks.extend(process_band(high_band));
+        for (low, high) in self.peak_bands() {
+            let band: Vec<PeakInfo> = raw_peaks
+                .iter()
+                .filter(|p| (low..high).contains(&p.freq.into_inner()))
+                .cloned()
+                .collect();
+            final_peaks.extend(process_band(band));
+        }
 
         final_peaks
     }
+
+    /// Peaks of `window[center]` that are the strongest point of their
+    /// time × frequency neighborhood within `window`, and stronger than the
+    /// frame's average by the threshold multiplier. The strongest
+    /// `max_peaks_per_band` are kept per band, with magnitudes relative to the
+    /// frame's loudest bin as in band picking.
+    fn local_max_peaks(
+        &self,
+        window: &VecDeque<(f32, Vec<f32>)>,
+        center: usize,
+        sample_rate: u32,
+    ) -> Vec<PeakInfo> {
+        let magnitudes = &window[center].1;
+        let half_n = magnitudes.len();
+        let n = half_n * 2;
+        let frame_max = magnitudes.iter().copied().fold(0.0, f32::max);
+        if frame_max <= 0.0 {
+            return Vec::new();
+        }
+        let average = magnitudes.iter().sum::<f32>() / half_n as f32;
+        let threshold = average * self.threshold_multiplier;
+
+        let mut candidates = Vec::new();
+        let inner_bins = magnitudes.iter().enumerate().take(half_n - 1).skip(1);
+        for (bin, &magnitude) in inner_bins {
+            let freq = bin as f32 * (sample_rate as f32 / n as f32);
+            if magnitude <= threshold || freq <= self.min_freq || freq >= self.max_freq {
+                continue;
+            }
+
+            // Equal neighbors earlier in time or lower in frequency win the tie
+            let first_bin = bin.saturating_sub(self.neighborhood_bins);
+            let last_bin = (bin + self.neighborhood_bins).min(half_n - 1);
+            let is_max = window.iter().enumerate().all(|(frame, (_, other))| {
+                (first_bin..=last_bin).all(|other_bin| {
+                    let value = other[other_bin];
+                    let earlier = (frame, other_bin) < (center, bin);
+                    value < magnitude || (value == magnitude && !earlier)
+                })
+            });
+            if is_max {
+                candidates.push(PeakInfo {
+                    freq: OrderedFloat(freq),
+                    magnitude: OrderedFloat(magnitude / frame_max),
+                });
+            }
+        }
+
+        let mut peaks = Vec::new();
+        for (low, high) in self.peak_bands() {
+            let mut band: Vec<PeakInfo> = candidates
+                .iter()
+                .filter(|p| (low..high).contains(&p.freq.into_inner()))
+                .cloned()
+                .collect();
+            band.sort_by_key(|p| std::cmp::Reverse(p.magnitude));
+            band.truncate(self.max_peaks_per_band);
+            peaks.extend(band);
+        }
+        peaks
+    }
+
+    /// Bands peaks are capped in: the analysis band, split at the
+    /// [`PEAK_BAND_EDGES`] that fall inside it
+    fn peak_bands(&self) -> Vec<(f32, f32)> {
+        let inner = PEAK_BAND_EDGES
+            .into_iter()
+            .filter(|&edge| self.min_freq < edge && edge < self.max_freq);
+        let edges: Vec<f32> = std::iter::once(self.min_freq)
+            .chain(inner)
+            .chain(std::iter::once(self.max_freq))
+            .collect();
+        edges.windows(2).map(|pair| (pair[0], pair[1])).collect()
+    }
+
     fn convert_to_complex_buffer(&self, buffer: Vec<f32>) -> Vec<Complex> {
         buffer
             .iter()
@@ -238,27 +691,50 @@ impl CooleyTukeyFFT {
     }
 }
 
-pub enum FreqRange {
-    Low,
-    High,
+/// `LocalMax` peak picking over a sliding window of magnitude spectra: the
+/// center frame is emitted once the frames after it are in
+struct LocalMaxFrames<'a, I> {
+    fft: &'a CooleyTukeyFFT,
+    magnitudes: I,
+    sample_rate: u32,
+    /// Frames within the neighborhood of the center frame, oldest first
+    window: VecDeque<(f32, Vec<f32>)>,
+    /// Index in `window` of the next frame to emit
+    center: usize,
 }
 
-impl FreqRange {
-    pub fn get_freq(&self) -> f32 {
-        match self {
-            FreqRange::Low => 20.0,
-            FreqRange::High => 5_000.0,
+impl<I: Iterator<Item = (f32, Vec<f32>)>> Iterator for LocalMaxFrames<'_, I> {
+    type Item = FFTDistribution;
+
+    fn next(&mut self) -> Option<FFTDistribution> {
+        let reach = self.fft.neighborhood_frames;
+        while self.window.len() <= self.center + reach {
+            match self.magnitudes.next() {
+                Some(frame) => self.window.push_back(frame),
+                None => break,
+            }
+        }
+        if self.center >= self.window.len() {
+            return None;
         }
+
+        let frame = FFTDistribution {
+            time: OrderedFloat(self.window[self.center].0),
+            peaks: self
+                .fft
+                .local_max_peaks(&self.window, self.center, self.sample_rate),
+        };
+        if self.center < reach {
+            self.center += 1;
+        } else {
+            self.window.pop_front();
+        }
+        Some(frame)
     }
 }
 
 impl Default for CooleyTukeyFFT {
     fn default() -> Self {
-        let chunk_size = 2048;
-        let overlap_size = chunk_size / 2;
-        Self {
-            CHUNK_SIZE: chunk_size,
-            OVERLAP_SIZE: overlap_size,
-        }
+        Self::from_config(&FftConfig::default())
     }
 }
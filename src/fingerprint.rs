@@ -1,129 +1,889 @@
-use crate::fft::fft::FFTDistribution;
+use crate::config::{FingerprintConfig, MatchingConfig};
+use crate::fft::fft::{FFTDistribution, PeakInfo};
 use ordered_float::OrderedFloat;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use tracing::{debug, instrument};
 
-/// Tunable parameters
-const MAX_TARGET_ZONE: usize = 60; // look ahead ~10s
-const MIN_TARGET_ZONE_DIST: usize = 1;
-const FREQ_STEP: f32 = 50.0; // coarser bins
-const DELTA_STEP: f32 = 0.1; // 100ms bins
+/// Identifies the default fingerprinting parameters (see [`crate::config`])
+/// and the hashing algorithm. Bump it whenever any of them changes:
+/// fingerprints of another version stop matching, and `reingest --outdated`
+/// rebuilds those songs.
+pub const FINGERPRINT_VERSION: i32 = 2;
+
+/// Results below this confidence are discarded as "no match".
+pub const MIN_CONFIDENCE: f32 = 0.15;
+
+/// Length of the slices of query time [`VoteResult::coverage`] counts, in
+/// seconds
+const COVERAGE_SLICE_SECS: f32 = 0.5;
+
+/// Lag step of the cross-correlation [`VoteResult::refined_offset`] is
+/// searched with, in seconds; the peak is then interpolated between steps
+const REFINE_STEP_SECS: f32 = 0.001;
+
+/// Shortest stretch of the query, in seconds, whose fingerprints a song's
+/// hit density is measured against under `multi_match`, so a few hits
+/// bunched together do not count as a dense match
+const MIN_CONCURRENT_SPAN_SECS: f32 = 2.0;
+
+/// Bit layout of fingerprint hashes. Fixed per collection, since hashes of
+/// different schemes never match.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashScheme {
+    /// `anchor band << 30 | target band << 14 | delta`, stored as BIGINT
+    #[default]
+    Wide64,
+    /// 9-bit anchor band, 9-bit target band, 14-bit delta, stored as INT
+    Compact32,
+}
+
+impl HashScheme {
+    /// Hash width, as stored in `collections.hash_bits`
+    pub fn bits(&self) -> i16 {
+        match self {
+            HashScheme::Wide64 => 64,
+            HashScheme::Compact32 => 32,
+        }
+    }
+
+    pub fn from_bits(bits: i16) -> Option<Self> {
+        match bits {
+            64 => Some(HashScheme::Wide64),
+            32 => Some(HashScheme::Compact32),
+            _ => None,
+        }
+    }
+
+    /// Largest anchor / target frequency bin a hash can hold
+    fn max_freq_bin(&self) -> u32 {
+        match self {
+            HashScheme::Wide64 => u32::MAX,
+            HashScheme::Compact32 => 511,
+        }
+    }
+
+    /// `(anchor band, target band, delta)` of a hash, the inverse of `pack`
+    fn unpack(&self, hash: u64) -> (u32, u32, u32) {
+        let delta_bin = (hash & 0x3fff) as u32;
+        match self {
+            HashScheme::Wide64 => (
+                (hash >> 30) as u32,
+                ((hash >> 14) & 0xffff) as u32,
+                delta_bin,
+            ),
+            HashScheme::Compact32 => (
+                ((hash >> 23) & 0x1ff) as u32,
+                ((hash >> 14) & 0x1ff) as u32,
+                delta_bin,
+            ),
+        }
+    }
+
+    /// The hashes one bin off `hash` in any of anchor band, target band and
+    /// delta, i.e. what a peak drifting into the adjacent bin produces
+    fn neighbors(&self, hash: u64) -> Vec<u64> {
+        let (anchor, target, delta) = self.unpack(hash);
+        let shift = |bin: u32, step: i32, max: u32| {
+            bin.checked_add_signed(step)
+                .filter(|&shifted| shifted <= max)
+        };
+
+        let mut neighbors = Vec::with_capacity(26);
+        for da in -1..=1 {
+            for dt in -1..=1 {
+                for dd in -1..=1 {
+                    if (da, dt, dd) == (0, 0, 0) {
+                        continue;
+                    }
+                    if let (Some(a), Some(t), Some(d)) = (
+                        shift(anchor, da, self.max_freq_bin()),
+                        shift(target, dt, self.max_freq_bin()),
+                        shift(delta, dd, 16383),
+                    ) {
+                        neighbors.push(self.pack(a, t, d));
+                    }
+                }
+            }
+        }
+        neighbors
+    }
+
+    fn pack(&self, anchor_freq_bin: u32, target_freq_bin: u32, delta_bin: u32) -> u64 {
+        match self {
+            HashScheme::Wide64 => {
+                (anchor_freq_bin as u64) << 30 | (target_freq_bin as u64) << 14 | (delta_bin as u64)
+            }
+            HashScheme::Compact32 => {
+                (anchor_freq_bin.min(511) as u64) << 23
+                    | (target_freq_bin.min(511) as u64) << 14
+                    | (delta_bin as u64)
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct FingerprintInfo {
     pub hash: u64,
     pub abs_anchor_tm_offset: f32,
+    /// Product of the anchor and target peak magnitudes (each normalized to
+    /// its frame's loudest bin), 1 where unknown
+    pub strength: f32,
+    /// Added by [`expand_query`] as a neighbor of a real fingerprint
+    pub neighbor: bool,
 }
 
 #[derive(Debug)]
 pub struct VoteResult {
     pub song_id: u32,
+    /// Hash hits in the winning offset bin
     pub score: usize,
+    /// The same hits weighted as set in [`MatchingConfig`], which results are
+    /// ranked by; equal to `score` without weighting
+    pub weight: f32,
     pub time_offset: f32,
+    /// `time_offset` refined below the vote bin width by cross-correlating
+    /// the query's aligned anchor times with the song's, for applications
+    /// syncing playback to the query
+    pub refined_offset: f32,
+    /// Normalized 0..1 confidence, see [`match_confidence`].
+    pub confidence: f32,
+    /// Playback speed of the query relative to the song, the slope of the
+    /// fitted alignment; 1 unless `speed_search` is on
+    pub speed: f32,
+    /// Position in the database song (seconds) where the aligned hits begin
+    pub match_start: f32,
+    /// Position in the database song (seconds) where the aligned hits end
+    pub match_end: f32,
+    /// Fraction of the query's duration with aligned hits, in half-second
+    /// slices. A snippet matched throughout scores near 1; one whose votes
+    /// come from a short repeated phrase stays low despite a high `score`.
+    pub coverage: f32,
+}
+
+/// Votes collected by one offset bin, plus the span of query anchors behind them
+struct OffsetBin {
+    votes: usize,
+    weight: f32,
+    first_query_time: f32,
+    last_query_time: f32,
+}
+
+impl Default for OffsetBin {
+    fn default() -> Self {
+        Self {
+            votes: 0,
+            weight: 0.0,
+            first_query_time: f32::INFINITY,
+            last_query_time: f32::NEG_INFINITY,
+        }
+    }
 }
 
 /// Quantize a frequency in Hz into coarse bins
-fn quantize_freq(freq: f32) -> u32 {
-    (freq / FREQ_STEP).round() as u32
+fn quantize_freq(freq: f32, freq_step: f32) -> u32 {
+    (freq / freq_step).round() as u32
 }
 
 /// Quantize a time delta into coarse bins
-fn quantize_time_delta(delta: f32) -> u32 {
-    ((delta / DELTA_STEP).round() as u32).min(16383)
+fn quantize_time_delta(delta: f32, delta_step: f32) -> u32 {
+    ((delta / delta_step).round() as u32).min(16383)
 }
 
 /// Generate fingerprints with quantization + fan-out
-pub fn generate_audio_fingerprint(fft_buffer: &Vec<FFTDistribution>) -> Vec<FingerprintInfo> {
+///
+/// Every anchor peak is paired with every peak in its target zone unless
+/// `config.max_anchors_per_frame` / `config.max_pairs_per_anchor` cap the
+/// fan-out, keeping the strongest anchors and pairs, to trade index size for
+/// recall on dense music.
+#[instrument(skip_all, fields(frames = fft_buffer.len(), ?scheme))]
+pub fn generate_audio_fingerprint(
+    fft_buffer: &[FFTDistribution],
+    scheme: HashScheme,
+    config: &FingerprintConfig,
+) -> Vec<FingerprintInfo> {
     let buf_len = fft_buffer.len();
     let mut fingerprints = Vec::new();
 
-    for (idx, fft_distribution) in fft_buffer.iter().enumerate() {
-        let time = fft_distribution.time.into_inner();
+    for idx in 0..buf_len {
+        let end_idx = (idx + config.max_target_zone).min(buf_len);
+        fingerprints.extend(anchor_frame_fingerprints(
+            &fft_buffer[idx..end_idx],
+            scheme,
+            config,
+        ));
+    }
+
+    debug!("Generated {} fingerprints", fingerprints.len());
+    fingerprints
+}
+
+/// Prune a library song to at most `config.max_fingerprints_per_second`
+/// fingerprints in every second of audio, keeping the strongest (by the
+/// product of both peak magnitudes). Budgeting each second rather than the
+/// whole song keeps quiet passages matchable next to loud ones.
+pub fn cap_song_fingerprints(
+    fingerprints: Vec<FingerprintInfo>,
+    config: &FingerprintConfig,
+) -> Vec<FingerprintInfo> {
+    let budget = config.max_fingerprints_per_second;
+    if budget == 0 {
+        return fingerprints;
+    }
+
+    let generated = fingerprints.len();
+    let mut by_second: BTreeMap<i64, Vec<FingerprintInfo>> = BTreeMap::new();
+    for fingerprint in fingerprints {
+        let second = fingerprint.abs_anchor_tm_offset.floor() as i64;
+        by_second.entry(second).or_default().push(fingerprint);
+    }
+
+    let mut kept = Vec::with_capacity(generated.min(by_second.len() * budget));
+    for mut second in by_second.into_values() {
+        if second.len() > budget {
+            second.sort_by(|a, b| b.strength.total_cmp(&a.strength));
+            second.truncate(budget);
+            second.sort_by(|a, b| a.abs_anchor_tm_offset.total_cmp(&b.abs_anchor_tm_offset));
+        }
+        kept.extend(second);
+    }
+
+    debug!("Kept {} of {} fingerprints", kept.len(), generated);
+    kept
+}
+
+/// Fingerprints anchored in `frames[0]`, paired with the peaks of the rest of
+/// `frames` from `config.min_target_zone_dist` on. `frames` is the anchor
+/// frame followed by its (possibly truncated) target zone.
+fn anchor_frame_fingerprints(
+    frames: &[FFTDistribution],
+    scheme: HashScheme,
+    config: &FingerprintConfig,
+) -> Vec<FingerprintInfo> {
+    let mut fingerprints = Vec::new();
+    let Some(fft_distribution) = frames.first() else {
+        return fingerprints;
+    };
+    // look ahead within target zone
+    if config.min_target_zone_dist >= frames.len() {
+        return fingerprints;
+    }
+    let time = fft_distribution.time.into_inner();
 
-        for anchor_peak in &fft_distribution.peaks {
-            let anchor_freq_bin = quantize_freq(anchor_peak.freq.into_inner());
+    // Strongest peaks of the frame first when capped
+    let mut anchors: Vec<&PeakInfo> = fft_distribution.peaks.iter().collect();
+    if config.max_anchors_per_frame > 0 && anchors.len() > config.max_anchors_per_frame {
+        anchors.sort_by_key(|peak| std::cmp::Reverse(peak.magnitude));
+        anchors.truncate(config.max_anchors_per_frame);
+    }
 
-            // look ahead within target zone
-            let start_idx = idx + MIN_TARGET_ZONE_DIST;
-            let end_idx = (idx + MAX_TARGET_ZONE).min(buf_len);
+    for anchor_peak in anchors {
+        let anchor_freq_bin = quantize_freq(anchor_peak.freq.into_inner(), config.freq_step);
 
-            if start_idx >= end_idx {
+        let mut pairs = Vec::new();
+        for slice in &frames[config.min_target_zone_dist..] {
+            let time_delta = slice.time.into_inner() - time;
+            if time_delta <= 0.0 {
                 continue;
             }
+            let delta_bin = quantize_time_delta(time_delta, config.delta_step);
 
-            for slice in &fft_buffer[start_idx..end_idx] {
-                let time_delta = slice.time.into_inner() - time;
-                if time_delta <= 0.0 {
-                    continue;
-                }
-                let delta_bin = quantize_time_delta(time_delta);
+            for target_peak in &slice.peaks {
+                let target_freq_bin =
+                    quantize_freq(target_peak.freq.into_inner(), config.freq_step);
+
+                let hash = scheme.pack(anchor_freq_bin, target_freq_bin, delta_bin);
+
+                let strength =
+                    anchor_peak.magnitude.into_inner() * target_peak.magnitude.into_inner();
+
+                pairs.push(FingerprintInfo {
+                    hash,
+                    abs_anchor_tm_offset: time,
+                    strength,
+                    neighbor: false,
+                });
+            }
+        }
+
+        // Keep the strongest pairs when capped
+        if config.max_pairs_per_anchor > 0 && pairs.len() > config.max_pairs_per_anchor {
+            pairs.sort_by(|a, b| b.strength.total_cmp(&a.strength));
+            pairs.truncate(config.max_pairs_per_anchor);
+        }
+        fingerprints.extend(pairs);
+    }
+
+    fingerprints
+}
+
+/// Incremental counterpart of [`generate_audio_fingerprint`] for live input:
+/// STFT frames are pushed one at a time, and the fingerprints of an anchor
+/// frame are emitted as soon as its target zone is complete. Only the last
+/// `max_target_zone` frames are kept.
+///
+/// Pushing every frame and then calling [`FingerprintStream::finish`] yields
+/// exactly the fingerprints of [`generate_audio_fingerprint`], in the same
+/// order.
+pub struct FingerprintStream {
+    scheme: HashScheme,
+    config: FingerprintConfig,
+    /// Oldest pending anchor frame first, followed by its target zone so far
+    frames: VecDeque<FFTDistribution>,
+}
+
+impl FingerprintStream {
+    pub fn new(scheme: HashScheme, config: FingerprintConfig) -> Self {
+        Self {
+            scheme,
+            frames: VecDeque::with_capacity(config.max_target_zone + 1),
+            config,
+        }
+    }
 
-                for target_peak in &slice.peaks {
-                    let target_freq_bin = quantize_freq(target_peak.freq.into_inner());
+    /// Add the next frame and return the fingerprints it completed
+    pub fn push(&mut self, frame: FFTDistribution) -> Vec<FingerprintInfo> {
+        self.frames.push_back(frame);
 
-                    // construct 64-bit hash
-                    let hash = (anchor_freq_bin as u64) << 30
-                        | (target_freq_bin as u64) << 14
-                        | (delta_bin as u64);
+        let mut fingerprints = Vec::new();
+        while !self.frames.is_empty() && self.frames.len() >= self.config.max_target_zone {
+            fingerprints.extend(self.emit_oldest());
+        }
+        fingerprints
+    }
+
+    /// End of input: flush the anchors whose target zone ran past the end
+    pub fn finish(mut self) -> Vec<FingerprintInfo> {
+        let mut fingerprints = Vec::new();
+        while !self.frames.is_empty() {
+            fingerprints.extend(self.emit_oldest());
+        }
+        fingerprints
+    }
 
-                    let strength =
-                        anchor_peak.magnitude.into_inner() * target_peak.magnitude.into_inner();
+    /// Fingerprints of the oldest frame against the frames after it, which
+    /// is then dropped
+    fn emit_oldest(&mut self) -> Vec<FingerprintInfo> {
+        let zone = self.config.max_target_zone.min(self.frames.len());
+        let frames = self.frames.make_contiguous();
+        let fingerprints = anchor_frame_fingerprints(&frames[..zone], self.scheme, &self.config);
+        self.frames.pop_front();
+        fingerprints
+    }
+}
 
-                    fingerprints.push(FingerprintInfo {
-                        hash,
-                        abs_anchor_tm_offset: time,
-                    });
+/// Prepare query fingerprints for lookup: with `config.neighbor_expansion`,
+/// every fingerprint is followed by its neighbors one quantization bin off in
+/// anchor band, target band and delta (see [`HashScheme::neighbors`]), so a
+/// peak that drifted into the adjacent bin still finds its hash. Their hits
+/// count `config.neighbor_weight` of a vote.
+pub fn expand_query(
+    fingerprints: Vec<FingerprintInfo>,
+    scheme: HashScheme,
+    config: &MatchingConfig,
+) -> Vec<FingerprintInfo> {
+    if !config.neighbor_expansion {
+        return fingerprints;
+    }
+
+    let mut expanded = Vec::with_capacity(fingerprints.len() * 27);
+    for fp in fingerprints {
+        expanded.push(fp);
+        expanded.extend(
+            scheme
+                .neighbors(fp.hash)
+                .into_iter()
+                .map(|hash| FingerprintInfo {
+                    hash,
+                    neighbor: true,
+                    ..fp
+                }),
+        );
+    }
+    expanded
+}
+
+/// Confidence of a song scoring `score` votes of total `weight` when the best
+/// other song's weight was `runner_up`, for a query of `query_len`
+/// fingerprints.
+///
+/// Combines the margin over the runner-up with the density of aligned hits, so
+/// a noise clip that scrapes together a handful of votes stays near zero.
+/// `full_match_density` is the hit density treated as a fully dense match.
+fn match_confidence(
+    score: usize,
+    weight: f32,
+    runner_up: f32,
+    query_len: usize,
+    full_match_density: f32,
+) -> f32 {
+    if score == 0 || query_len == 0 || runner_up >= weight {
+        return 0.0;
+    }
+
+    let margin = (weight - runner_up) / weight;
+    let density = (score as f32 / query_len as f32 / full_match_density).min(1.0);
+
+    margin * density
+}
+
+/// Every hash hit of `song_id` as (query anchor time, song anchor time), the
+/// raw material of the offset vote
+pub fn alignment_hits(
+    query_fingerprints: &[FingerprintInfo],
+    db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_id: u32,
+) -> Vec<(f32, f32)> {
+    let mut hits = Vec::new();
+    for fp in query_fingerprints {
+        if let Some(db_matches) = db_matches_by_hash.get(&fp.hash) {
+            for &(match_song_id, db_time) in db_matches {
+                if match_song_id == song_id {
+                    hits.push((fp.abs_anchor_tm_offset, db_time));
                 }
             }
         }
     }
+    hits
+}
 
-    fingerprints
+/// Votes per offset bin (`bin_width` seconds wide) of the given hits, as
+/// counted by [`vote_best_matches`]
+pub fn offset_histogram(hits: &[(f32, f32)], bin_width: f32) -> BTreeMap<i32, usize> {
+    let mut histogram = BTreeMap::new();
+    for &(query_time, db_time) in hits {
+        let offset_bin = ((db_time - query_time) / bin_width).round() as i32;
+        *histogram.entry(offset_bin).or_default() += 1;
+    }
+    histogram
+}
+
+/// Inverse document frequency of every hash in `db_matches_by_hash`,
+/// `ln(1 + N / df)` where `df` is the number of songs holding the hash and `N`
+/// is `song_count`, or the number of songs hit at all when unknown
+fn idf_weights(
+    db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_count: Option<usize>,
+) -> HashMap<u64, f32> {
+    let songs_per_hash: HashMap<u64, usize> = db_matches_by_hash
+        .iter()
+        .map(|(&hash, matches)| {
+            let songs: HashSet<u32> = matches.iter().map(|&(song_id, _)| song_id).collect();
+            (hash, songs.len())
+        })
+        .collect();
+    let song_count = song_count.unwrap_or_else(|| {
+        db_matches_by_hash
+            .values()
+            .flatten()
+            .map(|&(song_id, _)| song_id)
+            .collect::<HashSet<_>>()
+            .len()
+    });
+
+    songs_per_hash
+        .into_iter()
+        .map(|(hash, df)| (hash, (1.0 + song_count as f32 / df.max(1) as f32).ln()))
+        .collect()
+}
+
+/// Query playback speeds to fit alignments for: just 1, or with
+/// `config.speed_search` `speed_steps` evenly spaced speeds within
+/// `±max_speed_deviation`
+fn candidate_speeds(config: &MatchingConfig) -> Vec<f32> {
+    if !config.speed_search || config.speed_steps < 2 {
+        return vec![1.0];
+    }
+    let steps = config.speed_steps;
+    let deviation = config.max_speed_deviation;
+    (0..steps)
+        .map(|i| 1.0 - deviation + 2.0 * deviation * i as f32 / (steps - 1) as f32)
+        .collect()
 }
 
 /// Vote using histogram of offsets (robust Shazam-like approach)
+///
+/// Each hit of query time `q` on song time `d` votes for the alignment
+/// `d = speed * q + offset`. By default the speed is 1, so votes pile up in one
+/// offset bin. With `config.speed_search` the vote is a Hough transform over
+/// a range of speeds instead, so a query played slightly fast or slow (radio
+/// pitching, turntables) still gathers its hits along a sloped line.
+///
+/// Every hash hit is one vote. With `config.idf_weighting` a hit also counts
+/// for the rarity of its hash among the `song_count` songs of the index (see
+/// [`idf_weights`]), and with `config.magnitude_weighting` for the strength of
+/// the query peaks, so hashes shared by half the library stop dominating.
+///
+/// Results with fewer hits than `config.min_score` are dropped, and so is
+/// everything when the best one has a confidence below
+/// `config.min_confidence`, so an empty result means "no match". Below it the
+/// best `top_k` are kept, each with its margin over the next one as its
+/// confidence. With `config.multi_match` every result is held to both limits
+/// on its own and all that pass are kept.
+#[instrument(skip_all, fields(query = query_fingerprints.len(), hashes = db_matches_by_hash.len()))]
 pub fn vote_best_matches(
     query_fingerprints: &[FingerprintInfo],
     db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_count: Option<usize>,
     top_k: usize,
+    config: &MatchingConfig,
 ) -> Vec<VoteResult> {
-    if query_fingerprints.is_empty() {
+    // Neighbors added by `expand_query` are not part of the query itself
+    let query_len = query_fingerprints.iter().filter(|fp| !fp.neighbor).count();
+    if query_len == 0 {
         return Vec::new();
     }
 
-    // offset_histograms[song_id][offset_bin] = vote count
-    let mut offset_histograms: HashMap<u32, HashMap<i32, usize>> = HashMap::new();
+    let idf = config
+        .idf_weighting
+        .then(|| idf_weights(db_matches_by_hash, song_count));
+
+    let speeds = candidate_speeds(config);
+
+    // offset_histograms[song_id][(speed index, offset_bin)] = votes + query anchor span
+    let mut offset_histograms: HashMap<u32, HashMap<(usize, i32), OffsetBin>> = HashMap::new();
 
     for fp in query_fingerprints {
         if let Some(db_matches) = db_matches_by_hash.get(&fp.hash) {
+            let mut weight = idf.as_ref().map_or(1.0, |idf| idf[&fp.hash]);
+            if config.magnitude_weighting {
+                weight *= fp.strength;
+            }
+            if fp.neighbor {
+                weight *= config.neighbor_weight;
+            }
             for &(song_id, db_time) in db_matches {
-                let offset = db_time - fp.abs_anchor_tm_offset;
-                let offset_bin = (offset / 0.03).round() as i32; // 50 ms bins
+                let hist = offset_histograms.entry(song_id).or_default();
+                for (speed_idx, speed) in speeds.iter().enumerate() {
+                    let offset = db_time - speed * fp.abs_anchor_tm_offset;
+                    let offset_bin = (offset / config.vote_bin_width).round() as i32;
 
-                *offset_histograms
-                    .entry(song_id)
-                    .or_default()
-                    .entry(offset_bin)
-                    .or_default() += 1;
+                    let bin = hist.entry((speed_idx, offset_bin)).or_default();
+                    bin.votes += 1;
+                    bin.weight += weight;
+                    bin.first_query_time = bin.first_query_time.min(fp.abs_anchor_tm_offset);
+                    bin.last_query_time = bin.last_query_time.max(fp.abs_anchor_tm_offset);
+                }
             }
         }
     }
 
-    // For each song, take the offset bin with max votes
+    // For each song, take the offset bin with max votes, on ties the one
+    // closest to normal speed
+    let speed_distance = |speed_idx: usize| (speeds[speed_idx] - 1.0).abs();
     let mut results = Vec::new();
+    // Speed index and offset bin of each song's alignment
+    let mut alignments: HashMap<u32, (usize, i32)> = HashMap::new();
+    // Under `multi_match`: each song's strongest bin away from its alignment,
+    // and the query fingerprints around its aligned hits
+    let mut own_baselines: HashMap<u32, (f32, usize)> = HashMap::new();
     for (song_id, hist) in offset_histograms {
-        if let Some((&best_bin, &score)) = hist.iter().max_by_key(|&(_, &v)| v) {
+        if let Some((&(speed_idx, best_bin), bin)) = hist.iter().max_by(|(ka, a), (kb, b)| {
+            a.weight
+                .total_cmp(&b.weight)
+                .then(speed_distance(kb.0).total_cmp(&speed_distance(ka.0)))
+        }) {
+            let speed = speeds[speed_idx];
+            let time_offset = best_bin as f32 * config.vote_bin_width; // convert back to seconds
+            alignments.insert(song_id, (speed_idx, best_bin));
+            if config.multi_match {
+                let runner_up = hist
+                    .iter()
+                    .filter(|&(&(s, b), _)| s == speed_idx && (b - best_bin).abs() > 1)
+                    .map(|(_, other)| other.weight)
+                    .fold(0.0, f32::max);
+                let local_len = query_len_around(
+                    query_fingerprints,
+                    bin.first_query_time,
+                    bin.last_query_time,
+                );
+                own_baselines.insert(song_id, (runner_up, local_len));
+            }
             results.push(VoteResult {
                 song_id,
-                score,
-                time_offset: best_bin as f32 * 0.03, // convert back to seconds
+                score: bin.votes,
+                weight: bin.weight,
+                time_offset,
+                refined_offset: time_offset,
+                confidence: 0.0,
+                speed,
+                match_start: time_offset + speed * bin.first_query_time,
+                match_end: time_offset + speed * bin.last_query_time,
+                coverage: 0.0,
             });
         }
     }
 
-    results.sort_by(|a, b| b.score.cmp(&a.score));
-    if results.len() > top_k {
+    results.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+    debug!("{} candidate song(s)", results.len());
+
+    // Each song is compared against the next weaker candidate, the best one
+    // thus against the strongest *other* song. Under `multi_match` each is
+    // compared against its own votes at other offsets instead, so songs
+    // heard at once do not cancel each other out.
+    let weights: Vec<f32> = results.iter().map(|r| r.weight).collect();
+    for (rank, r) in results.iter_mut().enumerate() {
+        let (runner_up, query_len) = match own_baselines.get(&r.song_id) {
+            Some(&baseline) => baseline,
+            None => (weights.get(rank + 1).copied().unwrap_or(0.0), query_len),
+        };
+        r.confidence = match_confidence(
+            r.score,
+            r.weight,
+            runner_up,
+            query_len,
+            config.full_match_density,
+        );
+    }
+    let passes =
+        |r: &VoteResult| r.confidence >= config.min_confidence && r.score >= config.min_score;
+    if config.multi_match {
+        // Every song above the cutoff may be playing
+        results.retain(passes);
+    } else if results.first().is_some_and(passes) {
+        // The others are alternatives to the best match, ranked below it
+        // rather than rated on their own
+        results.retain(|r| r.score >= config.min_score);
         results.truncate(top_k);
+    } else {
+        results.clear();
+    }
+
+    for r in &mut results {
+        let (speed_idx, offset_bin) = alignments[&r.song_id];
+        r.coverage = query_coverage(
+            query_fingerprints,
+            db_matches_by_hash,
+            r.song_id,
+            (speeds[speed_idx], offset_bin),
+            config.vote_bin_width,
+        );
+        r.refined_offset = refine_offset(
+            query_fingerprints,
+            db_matches_by_hash,
+            r.song_id,
+            (r.speed, r.time_offset),
+            config.vote_bin_width,
+        );
     }
 
     results
 }
+
+/// The offset of `song_id` near `coarse_offset` at which its hits line up
+/// best, to a fraction of a frame.
+///
+/// Cross-correlates the anchor times of the query with those of the song,
+/// both smoothed by a Gaussian of half a vote bin, over lags within one vote
+/// bin of `coarse_offset`. The correlation at a lag is then the sum over hits
+/// of the kernel at their distance from it, and peaks between the frame
+/// quantized hit offsets in proportion to how many fall on either side. The
+/// best lag step is refined by fitting a parabola through it and its
+/// neighbors.
+fn refine_offset(
+    query_fingerprints: &[FingerprintInfo],
+    db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_id: u32,
+    (speed, coarse_offset): (f32, f32),
+    vote_bin_width: f32,
+) -> f32 {
+    let mut hit_offsets = Vec::new();
+    for fp in query_fingerprints {
+        let Some(db_matches) = db_matches_by_hash.get(&fp.hash) else {
+            continue;
+        };
+        for &(id, db_time) in db_matches {
+            let offset = db_time - speed * fp.abs_anchor_tm_offset;
+            if id == song_id && (offset - coarse_offset).abs() <= vote_bin_width {
+                hit_offsets.push(offset);
+            }
+        }
+    }
+    if hit_offsets.is_empty() {
+        return coarse_offset;
+    }
+
+    let sigma = vote_bin_width / 2.0;
+    let correlation = |lag: f32| -> f32 {
+        hit_offsets
+            .iter()
+            .map(|&offset| (-((offset - lag) / sigma).powi(2) / 2.0).exp())
+            .sum()
+    };
+    let steps = (vote_bin_width / REFINE_STEP_SECS).round() as i32;
+    let lag = |step: i32| coarse_offset + step as f32 * REFINE_STEP_SECS;
+    let best = (-steps..=steps)
+        .max_by(|&a, &b| correlation(lag(a)).total_cmp(&correlation(lag(b))))
+        .unwrap_or(0);
+
+    let (before, peak, after) = (
+        correlation(lag(best - 1)),
+        correlation(lag(best)),
+        correlation(lag(best + 1)),
+    );
+    let curvature = before - 2.0 * peak + after;
+    let shift = if curvature < 0.0 {
+        ((before - after) / (2.0 * curvature)).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    lag(best) + shift * REFINE_STEP_SECS
+}
+
+/// Whether `results` hold several songs heard at once, e.g. a mashup or a
+/// transition between tracks. Only reported under `multi_match`, as ranked
+/// results otherwise leave every song but the first in doubt.
+pub fn concurrent_matches(results: &[VoteResult], config: &MatchingConfig) -> bool {
+    config.multi_match && results.len() > 1
+}
+
+/// Query fingerprints, without neighbors, anchored between `first` and
+/// `last` seconds, widened to at least [`MIN_CONCURRENT_SPAN_SECS`]
+fn query_len_around(query_fingerprints: &[FingerprintInfo], first: f32, last: f32) -> usize {
+    let widen = ((MIN_CONCURRENT_SPAN_SECS - (last - first)) / 2.0).max(0.0);
+    let (first, last) = (first - widen, last + widen);
+    query_fingerprints
+        .iter()
+        .filter(|fp| !fp.neighbor && (first..=last).contains(&fp.abs_anchor_tm_offset))
+        .count()
+}
+
+/// Fraction of the [`COVERAGE_SLICE_SECS`] slices of the query's anchor time
+/// span holding a hit of `song_id` on the alignment `(speed, offset_bin)`
+fn query_coverage(
+    query_fingerprints: &[FingerprintInfo],
+    db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_id: u32,
+    (speed, offset_bin): (f32, i32),
+    vote_bin_width: f32,
+) -> f32 {
+    let (first, last) = query_fingerprints.iter().filter(|fp| !fp.neighbor).fold(
+        (f32::INFINITY, f32::NEG_INFINITY),
+        |(first, last), fp| {
+            (
+                first.min(fp.abs_anchor_tm_offset),
+                last.max(fp.abs_anchor_tm_offset),
+            )
+        },
+    );
+    if first > last {
+        return 0.0;
+    }
+    let slices = ((last - first) / COVERAGE_SLICE_SECS) as usize + 1;
+    let mut covered = vec![false; slices];
+    for fp in query_fingerprints {
+        let Some(db_matches) = db_matches_by_hash.get(&fp.hash) else {
+            continue;
+        };
+        let aligned = db_matches.iter().any(|&(id, db_time)| {
+            let offset = db_time - speed * fp.abs_anchor_tm_offset;
+            id == song_id && (offset / vote_bin_width).round() as i32 == offset_bin
+        });
+        if aligned {
+            let slice = ((fp.abs_anchor_tm_offset - first).max(0.0) / COVERAGE_SLICE_SECS) as usize;
+            covered[slice.min(slices - 1)] = true;
+        }
+    }
+    covered.iter().filter(|&&c| c).count() as f32 / slices as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query of `len` fingerprints 0.1 s apart
+    fn query(len: usize) -> Vec<FingerprintInfo> {
+        (0..len)
+            .map(|i| FingerprintInfo {
+                hash: i as u64,
+                abs_anchor_tm_offset: i as f32 * 0.1,
+                strength: 1.0,
+                neighbor: false,
+            })
+            .collect()
+    }
+
+    /// A library in which each `(song_id, hits)` holds the first `hits`
+    /// fingerprints of `query` 5 s in
+    fn library(query: &[FingerprintInfo], songs: &[(u32, usize)]) -> HashMap<u64, Vec<(u32, f32)>> {
+        let mut library: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        for &(song_id, hits) in songs {
+            for fp in &query[..hits] {
+                library
+                    .entry(fp.hash)
+                    .or_default()
+                    .push((song_id, 5.0 + fp.abs_anchor_tm_offset));
+            }
+        }
+        library
+    }
+
+    #[test]
+    fn top_k_ranks_weaker_songs_below_the_best() {
+        let query = query(100);
+        let library = library(&query, &[(1, 100), (2, 40), (3, 10)]);
+        let config = MatchingConfig::default();
+
+        let results = vote_best_matches(&query, &library, None, 5, &config);
+        let ranked: Vec<(u32, usize)> = results.iter().map(|r| (r.song_id, r.score)).collect();
+        assert_eq!(ranked, [(1, 100), (2, 40), (3, 10)]);
+        assert!((results[0].confidence - 0.6).abs() < 1e-6);
+        assert!((results[1].confidence - 0.75).abs() < 1e-6);
+
+        let results = vote_best_matches(&query, &library, None, 2, &config);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn top_k_is_empty_when_the_best_is_not_confident() {
+        let query = query(100);
+        let library = library(&query, &[(1, 50), (2, 50)]);
+        let results = vote_best_matches(&query, &library, None, 5, &MatchingConfig::default());
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn refined_offset_lies_between_the_hits_of_the_song() {
+        let query = query(10);
+        let mut library: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        for (i, fp) in query.iter().enumerate() {
+            let offset = if i % 2 == 0 { 5.01 } else { 5.02 };
+            library.entry(fp.hash).or_default().extend([
+                (1, offset + fp.abs_anchor_tm_offset),
+                (2, 5.0 + fp.abs_anchor_tm_offset),
+            ]);
+        }
+
+        let refined = refine_offset(&query, &library, 1, (1.0, 5.0), 0.03);
+        assert!((refined - 5.015).abs() < REFINE_STEP_SECS);
+        let refined = refine_offset(&query, &library, 3, (1.0, 5.0), 0.03);
+        assert_eq!(refined, 5.0);
+    }
+
+    #[test]
+    fn songs_heard_one_after_another_are_concurrent_under_multi_match() {
+        let query = query(100);
+        let mut library: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+        for fp in &query {
+            let song_id = if fp.hash < 50 { 1 } else { 2 };
+            library
+                .entry(fp.hash)
+                .or_default()
+                .push((song_id, 5.0 + fp.abs_anchor_tm_offset));
+        }
+
+        let mut config = MatchingConfig {
+            multi_match: true,
+            ..MatchingConfig::default()
+        };
+        let results = vote_best_matches(&query, &library, None, 5, &config);
+        let mut songs: Vec<u32> = results.iter().map(|r| r.song_id).collect();
+        songs.sort_unstable();
+        assert_eq!(songs, [1, 2]);
+        assert!(concurrent_matches(&results, &config));
+        assert!(!concurrent_matches(&results[..1], &config));
+
+        config.multi_match = false;
+        assert!(!concurrent_matches(&results, &config));
+    }
+}
@@ -0,0 +1,333 @@
+#[cfg(feature = "decode")]
+use crate::audio_processor::{AudioProcessor, SongMetadata};
+use crate::config::{
+    AudioConfig, Config, FftConfig, FingerprintConfig, MatchingConfig, PipelineConfig,
+};
+use crate::dsp::Pipeline;
+use crate::encoding::{
+    read_array, read_f64, read_i32, read_opt, read_str, read_u32, write_f64, write_i32, write_opt,
+    write_str, write_u32,
+};
+use crate::fingerprint::{FingerprintInfo, HashScheme, VoteResult, vote_best_matches};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+#[cfg(feature = "decode")]
+use std::path::Path;
+
+/// File signature of a fingerprint file
+pub const MAGIC: &[u8; 4] = b"SBFP";
+/// Bumped whenever the record layout changes
+const FORMAT_VERSION: u16 = 1;
+/// `compare` calls two files the same recording from this [`similarity`] on
+pub const SAME_RECORDING_SIMILARITY: f32 = 0.2;
+
+// Layout: MAGIC, FORMAT_VERSION (u16), then
+//   i32 fingerprint version, u32 hash bits,
+//   parameters (the [audio], [fft], [fingerprint] and [pipeline] config
+//   sections as TOML),
+//   source file name, duration, checksum, artist, album,
+//   u32 fingerprint count, then (u64 hash, f32 anchor time) pairs
+// with the same encoding as library archives (see `crate::archive`).
+
+/// Pipeline settings that determine the fingerprints of a file
+#[derive(Serialize)]
+struct Parameters<'a> {
+    audio: &'a AudioConfig,
+    fft: &'a FftConfig,
+    fingerprint: &'a FingerprintConfig,
+    pipeline: &'a PipelineConfig,
+}
+
+/// Fingerprints of one audio file, with what is needed to use them without
+/// the file or a database
+pub struct FingerprintFile {
+    pub fingerprint_version: i32,
+    pub scheme: HashScheme,
+    /// Parameters the fingerprints were generated with, as TOML
+    pub parameters: String,
+    /// File name of the source audio, `-` for standard input
+    pub source: String,
+    pub duration: f64,
+    /// SHA-256 of the source file, empty for standard input
+    pub checksum: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub fingerprints: Vec<FingerprintInfo>,
+}
+
+impl FingerprintFile {
+    /// Decode and fingerprint `file_name` with `config`
+    #[cfg(feature = "decode")]
+    pub fn generate(file_name: &str, scheme: HashScheme, config: &Config) -> Self {
+        let audio_processor = AudioProcessor::from_config(&config.decode);
+        let (samples, sample_rate) = audio_processor.get_decoded_audio(file_name.to_string());
+        // Standard input can only be read once
+        let from_stdin = file_name == AudioProcessor::STDIN;
+        let metadata = if from_stdin {
+            SongMetadata::default()
+        } else {
+            audio_processor.get_metadata(file_name.to_string())
+        };
+
+        Self {
+            source: Path::new(file_name)
+                .file_name()
+                .map_or_else(|| file_name.to_string(), |n| n.to_string_lossy().into()),
+            checksum: if from_stdin {
+                String::new()
+            } else {
+                audio_processor.file_checksum(file_name.to_string())
+            },
+            artist: metadata.artist,
+            album: metadata.album,
+            ..Self::from_samples(&samples, sample_rate, scheme, config)
+        }
+    }
+
+    /// Fingerprint decoded mono `samples` with `config`, leaving the source
+    /// file fields empty
+    pub fn from_samples(
+        samples: &[f32],
+        sample_rate: u32,
+        scheme: HashScheme,
+        config: &Config,
+    ) -> Self {
+        Self {
+            fingerprint_version: config.fingerprint_version(),
+            scheme,
+            parameters: parameters_toml(config),
+            source: String::new(),
+            duration: samples.len() as f64 / sample_rate as f64,
+            checksum: String::new(),
+            artist: None,
+            album: None,
+            fingerprints: Pipeline::from_config(config, scheme)
+                .fingerprints(samples.to_vec(), sample_rate),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    /// Serialize into any writer, e.g. the body of an HTTP upload
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+        write_i32(&mut w, self.fingerprint_version)?;
+        write_u32(&mut w, self.scheme.bits() as u32)?;
+        write_str(&mut w, &self.parameters)?;
+        write_str(&mut w, &self.source)?;
+        write_f64(&mut w, self.duration)?;
+        write_str(&mut w, &self.checksum)?;
+        write_opt(&mut w, self.artist.as_deref(), write_str)?;
+        write_opt(&mut w, self.album.as_deref(), write_str)?;
+
+        write_u32(&mut w, self.fingerprints.len() as u32)?;
+        for fp in &self.fingerprints {
+            w.write_all(&fp.hash.to_le_bytes())?;
+            w.write_all(&fp.abs_anchor_tm_offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Parse a fingerprint file from any reader, e.g. an HTTP upload
+    pub fn read_from(mut r: impl Read) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if &read_array::<4>(&mut r)? != MAGIC {
+            return Err(invalid("not a Sabi fingerprint file".to_string()));
+        }
+        let version = u16::from_le_bytes(read_array(&mut r)?);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "unsupported fingerprint file version {} (expected {})",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let fingerprint_version = read_i32(&mut r)?;
+        let bits = read_u32(&mut r)?;
+        let scheme = HashScheme::from_bits(bits as i16)
+            .ok_or_else(|| invalid(format!("unknown hash scheme of {} bits", bits)))?;
+        let parameters = read_str(&mut r)?;
+        let source = read_str(&mut r)?;
+        let duration = read_f64(&mut r)?;
+        let checksum = read_str(&mut r)?;
+        let artist = read_opt(&mut r, read_str)?;
+        let album = read_opt(&mut r, read_str)?;
+
+        let count = read_u32(&mut r)? as usize;
+        let mut fingerprints = Vec::with_capacity(count);
+        for _ in 0..count {
+            fingerprints.push(FingerprintInfo {
+                hash: u64::from_le_bytes(read_array(&mut r)?),
+                abs_anchor_tm_offset: f32::from_le_bytes(read_array(&mut r)?),
+                strength: 1.0,
+                neighbor: false,
+            });
+        }
+
+        Ok(Self {
+            fingerprint_version,
+            scheme,
+            parameters,
+            source,
+            duration,
+            checksum,
+            artist,
+            album,
+            fingerprints,
+        })
+    }
+}
+
+/// The fingerprint-relevant config sections as TOML, so files generated
+/// with different settings can be told apart
+fn parameters_toml(config: &Config) -> String {
+    toml::to_string(&Parameters {
+        audio: &config.audio,
+        fft: &config.fft,
+        fingerprint: &config.fingerprint,
+        pipeline: &config.pipeline,
+    })
+    .expect("config serializes to TOML")
+}
+
+/// `sabi fingerprint`: write the fingerprints of `file` to `out`
+/// [default: the file name with an `.fp` extension]
+#[cfg(feature = "decode")]
+pub fn run_fingerprint(file: &str, out: Option<&str>, scheme: HashScheme, config: &Config) {
+    if file == AudioProcessor::STDIN && out.is_none() {
+        eprintln!("❌ --out is required when reading audio from stdin");
+        return;
+    }
+    let out = out.map_or_else(
+        || {
+            Path::new(file)
+                .with_extension("fp")
+                .to_string_lossy()
+                .into_owned()
+        },
+        str::to_string,
+    );
+
+    let fp_file = FingerprintFile::generate(file, scheme, config);
+    match fp_file.save(&out) {
+        Ok(()) => println!(
+            "✅ Wrote {} fingerprints of {:.1}s of audio to {}",
+            fp_file.fingerprints.len(),
+            fp_file.duration,
+            out
+        ),
+        Err(e) => eprintln!("❌ Could not write {}: {}", out, e),
+    }
+}
+
+/// `sabi compare`: score how similar the audio behind two fingerprint files is
+pub fn run_compare(a_path: &str, b_path: &str, config: &Config) {
+    let (a, b) = match (FingerprintFile::load(a_path), FingerprintFile::load(b_path)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) => return eprintln!("❌ {}: {}", a_path, e),
+        (_, Err(e)) => return eprintln!("❌ {}: {}", b_path, e),
+    };
+
+    if a.scheme != b.scheme || a.fingerprint_version != b.fingerprint_version {
+        eprintln!(
+            "❌ Not comparable: {:?} hashes (version {}) vs {:?} hashes (version {})",
+            a.scheme, a.fingerprint_version, b.scheme, b.fingerprint_version
+        );
+        return;
+    }
+    if a.parameters != b.parameters {
+        println!("⚠️ The files were generated with different parameters, expect a low score");
+    }
+
+    for (path, file) in [(a_path, &a), (b_path, &b)] {
+        println!(
+            "{}: '{}', {:.1}s, {} fingerprints",
+            path,
+            file.source,
+            file.duration,
+            file.fingerprints.len()
+        );
+    }
+    if !a.checksum.is_empty() && a.checksum == b.checksum {
+        println!("🟰 Same source file (identical checksum)");
+    }
+
+    // Shared hashes regardless of timing
+    let a_hashes: HashSet<u64> = a.fingerprints.iter().map(|fp| fp.hash).collect();
+    let b_hashes: HashSet<u64> = b.fingerprints.iter().map(|fp| fp.hash).collect();
+    let shared = a_hashes.intersection(&b_hashes).count();
+    let union = a_hashes.union(&b_hashes).count().max(1);
+    println!(
+        "Shared hashes: {} ({:.1}% Jaccard)",
+        shared,
+        shared as f32 / union as f32 * 100.0
+    );
+
+    match similarity(&a.fingerprints, &b.fingerprints, &config.matching) {
+        Some((similarity, result)) => {
+            println!(
+                "Aligned hits: {} with B at A's time {:+.2}s, similarity {:.1}%",
+                result.score,
+                result.time_offset,
+                similarity * 100.0
+            );
+            if similarity >= SAME_RECORDING_SIMILARITY {
+                println!("✅ Likely the same recording");
+            } else {
+                println!("❌ Probably different recordings");
+            }
+        }
+        None => println!("❌ No aligned hits, different recordings"),
+    }
+}
+
+/// Time-aligned similarity of two fingerprint sets: the share of the
+/// shorter set's fingerprints that hit the other at the best offset, with
+/// that vote. `None` when no hash is shared.
+pub fn similarity(
+    a: &[FingerprintInfo],
+    b: &[FingerprintInfo],
+    config: &MatchingConfig,
+) -> Option<(f32, VoteResult)> {
+    // Vote A's fingerprints against B as a one-song library
+    let mut b_by_hash: HashMap<u64, Vec<(u32, f32)>> = HashMap::new();
+    for fp in b {
+        b_by_hash
+            .entry(fp.hash)
+            .or_default()
+            .push((0, fp.abs_anchor_tm_offset));
+    }
+    let result = vote_best_matches(a, &b_by_hash, None, 1, config)
+        .into_iter()
+        .next()?;
+
+    // Count each of A's fingerprints once, however many of B's it hits
+    let tolerance = config.vote_bin_width / 2.0;
+    let aligned = a
+        .iter()
+        .filter(|fp| {
+            b_by_hash.get(&fp.hash).is_some_and(|matches| {
+                matches.iter().any(|&(_, b_time)| {
+                    let offset = b_time - result.speed * fp.abs_anchor_tm_offset;
+                    (offset - result.time_offset).abs() <= tolerance
+                })
+            })
+        })
+        .count();
+
+    let shorter = a.len().min(b.len()).max(1);
+    Some(((aligned as f32 / shorter as f32).min(1.0), result))
+}
@@ -0,0 +1,125 @@
+//! Golden fingerprint regression tests.
+//!
+//! Every `tests/fixtures/*.wav` is decoded and run through the default
+//! [`Pipeline`] (filter → resample → STFT → peak picking → hashing), and the
+//! exact fingerprints are compared with the `.golden` file next to it. Any
+//! change to that output fails here. When a change is intended, bump
+//! [`FINGERPRINT_VERSION`] and rewrite the golden files with
+//! `SABI_BLESS=1 cargo test golden`.
+
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::dsp::Pipeline;
+use crate::fft::fft::CooleyTukeyFFT;
+use crate::fingerprint::{
+    FINGERPRINT_VERSION, FingerprintStream, HashScheme, generate_audio_fingerprint,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn fixture_wavs() -> Vec<PathBuf> {
+    let mut wavs: Vec<PathBuf> = fs::read_dir(fixtures_dir())
+        .expect("tests/fixtures is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+        .collect();
+    wavs.sort();
+    assert!(!wavs.is_empty(), "no .wav fixtures in tests/fixtures");
+    wavs
+}
+
+/// Fingerprints of `wav` as golden file text: a version header, then one
+/// `hash anchor_time` line per fingerprint. Times use the shortest exact
+/// `f32` representation, so the text round-trips bit for bit.
+fn serialize_fingerprints(wav: &Path) -> String {
+    let config = Config::default();
+    let (samples, sample_rate) =
+        AudioProcessor::new().get_decoded_audio(wav.to_string_lossy().into());
+    let fingerprints =
+        Pipeline::from_config(&config, HashScheme::default()).fingerprints(samples, sample_rate);
+
+    let mut text = format!("# fingerprint version {}\n", FINGERPRINT_VERSION);
+    for fp in fingerprints {
+        text.push_str(&format!("{:016x} {}\n", fp.hash, fp.abs_anchor_tm_offset));
+    }
+    text
+}
+
+#[test]
+fn golden_fingerprints() {
+    let bless = std::env::var_os("SABI_BLESS").is_some();
+    let mut mismatches = Vec::new();
+    for wav in &fixture_wavs() {
+        let actual = serialize_fingerprints(wav);
+        let golden_path = wav.with_extension("golden");
+        if bless {
+            fs::write(&golden_path, &actual).unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&golden_path)
+            .unwrap_or_else(|e| panic!("{}: {}", golden_path.display(), e));
+        if actual == expected {
+            continue;
+        }
+        let first_difference = actual
+            .lines()
+            .zip(expected.lines())
+            .position(|(a, e)| a != e)
+            .unwrap_or(actual.lines().count().min(expected.lines().count()));
+        mismatches.push(format!(
+            "{}: {} fingerprint lines, expected {}; first difference on line {}",
+            wav.display(),
+            actual.lines().count() - 1,
+            expected.lines().count() - 1,
+            first_difference + 1
+        ));
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "fingerprints changed:\n{}\nif intended, bump FINGERPRINT_VERSION and rerun with SABI_BLESS=1",
+        mismatches.join("\n")
+    );
+}
+
+/// Streaming generation must reproduce batch generation exactly, order included
+#[test]
+fn stream_matches_batch() {
+    let config = Config::default();
+    let audio_processor = AudioProcessor::new();
+    let fft = CooleyTukeyFFT::from_config(&config.fft);
+    let scheme = HashScheme::default();
+
+    for wav in fixture_wavs() {
+        let (samples, sample_rate) =
+            audio_processor.get_decoded_audio(wav.to_string_lossy().into());
+        let resampled = audio_processor.preprocess(&samples, sample_rate, &config.audio);
+        let rate = config.audio.target_sample_rate;
+
+        let batch = generate_audio_fingerprint(
+            &fft.generate_freq_time_distribution(resampled.clone(), rate),
+            scheme,
+            &config.fingerprint,
+        );
+
+        let mut stream = FingerprintStream::new(scheme, config.fingerprint.clone());
+        let mut streamed = Vec::new();
+        for frame in fft.frames(&resampled, rate) {
+            streamed.extend(stream.push(frame));
+        }
+        streamed.extend(stream.finish());
+
+        let key = |fp: &crate::fingerprint::FingerprintInfo| (fp.hash, fp.abs_anchor_tm_offset);
+        assert_eq!(
+            batch.iter().map(key).collect::<Vec<_>>(),
+            streamed.iter().map(key).collect::<Vec<_>>(),
+            "{}",
+            wav.display()
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use crate::audio_processor::AudioProcessor;
+use crate::auth::Scope;
+use crate::db::{bindings::Songs, storage::AsyncFingerprintStore};
+use crate::fingerprint::{VoteResult, concurrent_matches};
+use crate::ingest::{Upload, ingest_upload};
+use crate::pipeline::{MatchTarget, PipelineError, Recognition};
+use crate::server::{AppState, Denied, MAX_UPLOAD_BYTES, check_access, notify};
+use proto::sabi_server::{Sabi, SabiServer};
+use proto::{AudioChunk, Candidate, IngestChunk, IngestReply, RecogniseUpdate};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info};
+
+pub mod proto {
+    tonic::include_proto!("sabi.v1");
+}
+
+/// New audio between interim updates, in seconds
+const INTERIM_SECS: f32 = 2.0;
+/// Updates match at most this much of the latest audio, in seconds
+const MAX_WINDOW_SECS: f32 = 20.0;
+/// Candidates per update unless the client asks for another number
+const DEFAULT_TOP_K: usize = 5;
+
+/// The `Sabi` service of `proto/sabi.proto`, sharing its state, keys and
+/// rate limits with the HTTP API
+struct Service<S> {
+    state: AppState<S>,
+}
+
+/// Serve gRPC on `addr` until Ctrl-C
+pub(crate) async fn serve<S>(addr: SocketAddr, state: AppState<S>)
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    info!("📡 gRPC listening on {}", addr);
+    let service = SabiServer::new(Service { state }).max_decoding_message_size(MAX_UPLOAD_BYTES);
+    let served = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+    if let Err(e) = served {
+        error!("gRPC server failed: {}", e);
+    }
+}
+
+impl<S> Service<S>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    /// Check the `authorization: Bearer <key>` metadata of `request`. Not an
+    /// `async fn`, so the returned future does not hold the request, whose
+    /// body is not `Sync`.
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        scope: Scope,
+        required: bool,
+    ) -> impl Future<Output = Result<(), Status>> + Send + '_ {
+        let key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let client = request.remote_addr().map(|addr| addr.ip());
+
+        async move {
+            check_access(&self.state, key.as_deref(), client, scope, required)
+                .await
+                .map_err(|denied| match denied {
+                    Denied::MissingKey | Denied::UnknownKey => {
+                        Status::unauthenticated(denied.to_string())
+                    }
+                    Denied::MissingScope(_) => Status::permission_denied(denied.to_string()),
+                    Denied::RateLimited(_) => Status::resource_exhausted(denied.to_string()),
+                    Denied::Store(_) => Status::internal(denied.to_string()),
+                })
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S> Sabi for Service<S>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    type RecogniseStream = ReceiverStream<Result<RecogniseUpdate, Status>>;
+
+    async fn recognise(
+        &self,
+        request: Request<Streaming<AudioChunk>>,
+    ) -> Result<Response<Self::RecogniseStream>, Status> {
+        let required = self.state.config.server.require_auth;
+        self.authorize(&request, Scope::Recognise, required).await?;
+        // A stream keeps its place until it ends
+        let admitted = self.state.pipeline.admit().map_err(pipeline_status)?;
+
+        let mut chunks = request.into_inner();
+        let (updates, receiver) = mpsc::channel(4);
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            let _admitted = admitted;
+            let started = Instant::now();
+            let mut bytes = 0;
+            let outcome = recognise_stream(&state, &mut chunks, &updates, &mut bytes).await;
+            state.metrics.request(bytes);
+            state
+                .metrics
+                .finish(started.elapsed(), outcome.as_ref().ok().copied());
+            if let Err(status) = outcome {
+                let _ = updates.send(Err(status)).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn ingest(
+        &self,
+        request: Request<Streaming<IngestChunk>>,
+    ) -> Result<Response<IngestReply>, Status> {
+        self.authorize(&request, Scope::Ingest, true).await?;
+
+        let mut chunks = request.into_inner();
+        let mut metadata = None;
+        let mut data = Vec::new();
+        while let Some(chunk) = chunks.message().await? {
+            metadata = metadata.or(chunk.metadata);
+            data.extend_from_slice(&chunk.data);
+            if data.len() > MAX_UPLOAD_BYTES {
+                return Err(Status::resource_exhausted(format!(
+                    "files are limited to {} bytes",
+                    MAX_UPLOAD_BYTES
+                )));
+            }
+        }
+        let metadata = metadata
+            .ok_or_else(|| Status::invalid_argument("the first chunk needs the metadata"))?;
+        if metadata.title.is_empty() {
+            return Err(Status::invalid_argument("the song needs a title"));
+        }
+
+        let upload = Upload {
+            title: metadata.title,
+            artist: non_empty(metadata.artist),
+            album: non_empty(metadata.album),
+            collection: non_empty(metadata.collection).unwrap_or_else(|| "default".to_string()),
+        };
+        let config = Arc::clone(&self.state.config);
+        let started = Instant::now();
+        let (song_id, fingerprints) =
+            tokio::task::spawn_blocking(move || ingest_upload(data, &upload, &config))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(Status::invalid_argument)?;
+        if let Some(count) = fingerprints {
+            self.state.metrics.ingested(count, started.elapsed());
+        }
+
+        Ok(Response::new(IngestReply {
+            song_id,
+            fingerprints: fingerprints.unwrap_or(0) as u32,
+            created: fingerprints.is_some(),
+        }))
+    }
+}
+
+/// Match the audio of `chunks` as it arrives, sending updates, and return
+/// whether the final update found a match. `bytes` counts the PCM received.
+async fn recognise_stream<S>(
+    state: &AppState<S>,
+    chunks: &mut Streaming<AudioChunk>,
+    updates: &mpsc::Sender<Result<RecogniseUpdate, Status>>,
+    bytes: &mut usize,
+) -> Result<bool, Status>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let first = chunks
+        .message()
+        .await?
+        .ok_or_else(|| Status::invalid_argument("no audio was sent"))?;
+    let format = first
+        .format
+        .clone()
+        .ok_or_else(|| Status::invalid_argument("the first chunk needs the audio format"))?;
+    if format.sample_rate == 0 || format.channels == 0 {
+        return Err(Status::invalid_argument(
+            "sample_rate and channels must be positive",
+        ));
+    }
+    let collection = non_empty(format.collection).unwrap_or_else(|| "default".to_string());
+    let target = MatchTarget {
+        scheme: state
+            .store
+            .collection_hash_scheme(&collection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .unwrap_or_default(),
+        collection,
+        top_k: match format.top_k {
+            0 => DEFAULT_TOP_K,
+            top_k => top_k as usize,
+        },
+    };
+
+    let audio_processor = AudioProcessor::new();
+    let window_len = (MAX_WINDOW_SECS * format.sample_rate as f32) as usize;
+    let interim_len = (INTERIM_SECS * format.sample_rate as f32) as usize;
+    let (mut samples, mut received, mut since_update) = (Vec::new(), 0usize, 0usize);
+    let mut chunk = Some(first);
+    while let Some(AudioChunk { pcm, .. }) = chunk {
+        *bytes += pcm.len();
+        let interleaved: Vec<f32> = pcm
+            .chunks_exact(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) as f32 / 32768.0)
+            .collect();
+        let mono = audio_processor.to_mono(&interleaved, format.channels as usize);
+        received += mono.len();
+        since_update += mono.len();
+        samples.extend(mono);
+        if samples.len() > window_len {
+            samples.drain(..samples.len() - window_len);
+        }
+
+        if since_update >= interim_len {
+            since_update = 0;
+            let (results, songs) =
+                best_matches(state, &target, &samples, format.sample_rate).await?;
+            let update = RecogniseUpdate {
+                done: false,
+                audio_seconds: received as f32 / format.sample_rate as f32,
+                candidates: candidates(&results, &songs),
+                concurrent: concurrent_matches(&results, &state.config.matching),
+            };
+            if updates.send(Ok(update)).await.is_err() {
+                // The client hung up
+                return Ok(false);
+            }
+        }
+        chunk = chunks.message().await?;
+    }
+
+    let (results, songs) = best_matches(state, &target, &samples, format.sample_rate).await?;
+    if let Some(best) = results.first() {
+        notify(&state.notifier, best, songs.get(&(best.song_id as i32)));
+    }
+    info!(
+        seconds = received as f32 / format.sample_rate as f32,
+        top = results.first().map(|r| r.song_id),
+        "Recognised a gRPC stream with {} match(es)",
+        results.len()
+    );
+    let _ = updates
+        .send(Ok(RecogniseUpdate {
+            done: true,
+            audio_seconds: received as f32 / format.sample_rate as f32,
+            candidates: candidates(&results, &songs),
+            concurrent: concurrent_matches(&results, &state.config.matching),
+        }))
+        .await;
+    Ok(!results.is_empty())
+}
+
+/// The best matches of mono `samples` at `sample_rate` and their songs
+async fn best_matches<S>(
+    state: &AppState<S>,
+    target: &MatchTarget,
+    samples: &[f32],
+    sample_rate: u32,
+) -> Result<(Vec<VoteResult>, HashMap<i32, Songs>), Status>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let Recognition { results, .. } = state
+        .pipeline
+        .recognise_samples(samples.to_vec(), sample_rate, target.clone())
+        .await
+        .map_err(pipeline_status)?;
+    let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
+    let songs = state
+        .store
+        .fetch_songs(&song_ids)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+    Ok((results, songs))
+}
+
+fn pipeline_status(e: PipelineError) -> Status {
+    match e {
+        PipelineError::Busy => Status::unavailable(e.to_string()),
+        PipelineError::BadInput(_) => Status::invalid_argument(e.to_string()),
+        PipelineError::Internal(_) => Status::internal(e.to_string()),
+    }
+}
+
+fn candidates(results: &[VoteResult], songs: &HashMap<i32, Songs>) -> Vec<Candidate> {
+    results
+        .iter()
+        .map(|r| {
+            let song = songs.get(&(r.song_id as i32));
+            Candidate {
+                song_id: r.song_id,
+                title: song.map_or_else(|| "<unknown>".to_string(), |s| s.title.clone()),
+                artist: song.and_then(|s| s.artist.clone()).unwrap_or_default(),
+                score: r.score as u32,
+                confidence: r.confidence,
+                time_offset: r.time_offset,
+                coverage: r.coverage,
+                refined_offset: r.refined_offset,
+            }
+        })
+        .collect()
+}
+
+/// Proto3 strings are empty rather than absent
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::process::{Command, Stdio};
+
+/// Give up on a web service that has not answered after this many seconds
+const TIMEOUT_SECS: u32 = 30;
+/// MusicBrainz asks clients to identify themselves with a contact URL
+const USER_AGENT: &str = concat!(
+    "sabi/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/BeroBrine/Sabi )"
+);
+
+/// Whether `curl` can be run. Web services are reached through it, so
+/// Sabi needs no TLS stack of its own.
+pub fn curl_available() -> bool {
+    Command::new("curl")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// POST `form` URL-encoded to `url` and parse the JSON response body, also
+/// for error statuses, since web services explain errors in the body
+pub fn post_form<T: DeserializeOwned>(url: &str, form: &[(&str, &str)]) -> Result<T, String> {
+    request(url, form, false)
+}
+
+/// GET `url` with `query` URL-encoded onto it and parse the JSON response body
+pub fn get_json<T: DeserializeOwned>(url: &str, query: &[(&str, &str)]) -> Result<T, String> {
+    request(url, query, true)
+}
+
+/// POST `body` as JSON to `url`, e.g. a webhook. The response body is
+/// ignored; an error status is an error.
+pub fn post_json(url: &str, body: &impl Serialize) -> Result<(), String> {
+    let body = serde_json::to_string(body).map_err(|e| e.to_string())?;
+    let output = curl()
+        .arg("--fail")
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", &body])
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// `curl` with the options every request shares
+fn curl() -> Command {
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--location"])
+        .args(["--max-time", &TIMEOUT_SECS.to_string()])
+        .args(["--user-agent", USER_AGENT]);
+    command
+}
+
+fn request<T: DeserializeOwned>(
+    url: &str,
+    params: &[(&str, &str)],
+    get: bool,
+) -> Result<T, String> {
+    let mut command = curl();
+    if get {
+        command.arg("--get");
+    }
+    for (name, value) in params {
+        command
+            .arg("--data-urlencode")
+            .arg(format!("{}={}", name, value));
+    }
+
+    let output = command
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("unexpected response: {}", e))
+}
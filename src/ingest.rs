@@ -0,0 +1,612 @@
+use crate::audio_processor::AudioProcessor;
+use crate::cancel::CancelToken;
+use crate::config::Config;
+use crate::cover;
+use crate::db::{
+    bindings::NewSong,
+    connector::{CollectionKind, DB},
+    mmap_index,
+    storage::store_song,
+};
+use crate::dsp::{AnalysisStage, Pipeline, PipelineBuilder, SpectraAnalysis, StftStage};
+use crate::fingerprint::{FingerprintInfo, HashScheme, cap_song_fingerprints};
+use crate::library::format_bytes;
+use crate::musicbrainz::Enricher;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+/// Journal of a directory ingest, kept in the directory itself
+const JOURNAL_FILE: &str = ".sabi-ingest.json";
+/// Hashes listed by `--dry-run` as the most collided
+const TOP_COLLISIONS: usize = 10;
+/// Rough on-disk bytes per fingerprint row in Postgres, heap and both
+/// indexes with tuple overhead, by table (`fingerprint`, `fingerprint_compact`)
+const ROW_BYTES_WIDE: usize = 120;
+const ROW_BYTES_COMPACT: usize = 88;
+
+/// Progress of a directory ingest, rewritten after every file so an
+/// interrupted run can be continued with `--resume`
+#[derive(Serialize, Deserialize, Default)]
+struct IngestJournal {
+    collection: String,
+    /// Files ingested, or skipped as already in the collection
+    completed: Vec<String>,
+    /// Files that could not be ingested, with the reason
+    failed: BTreeMap<String, String>,
+    /// Files not attempted yet
+    pending: Vec<String>,
+}
+
+impl IngestJournal {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| format!("{} is corrupt: {}", path.display(), e))
+    }
+
+    /// Write via a temporary file, so a crash mid-write keeps the old journal
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// Collection songs are ingested into, and what to create it as if it does
+/// not exist yet. A requested scheme or kind must match an existing
+/// collection's.
+pub struct IngestTarget<'a> {
+    pub collection: &'a str,
+    pub hash_scheme: Option<HashScheme>,
+    pub kind: Option<CollectionKind>,
+}
+
+/// Ingest `path`: a single audio file, or every file in a directory. With
+/// `enrich`, missing tags are looked up in MusicBrainz.
+pub fn run_ingest(
+    db: &DB,
+    path: &str,
+    target: &IngestTarget,
+    resume: bool,
+    enrich: bool,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    let mut enricher = if enrich {
+        match Enricher::new(&config.enrich) {
+            Ok(enricher) => Some(enricher),
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    if Path::new(path).is_dir() {
+        ingest_directory(db, path, target, resume, enricher.as_mut(), config, cancel);
+    } else if let Err(e) = ingest_file(db, path.to_string(), target, enricher.as_mut(), config) {
+        eprintln!("❌ {}", e);
+    }
+}
+
+/// Ingest every file in `dir` in name order, journaling progress in the
+/// directory. With `resume`, files completed or failed by an earlier run are
+/// skipped. A file that fails (even by panicking in a decoder) is recorded and
+/// the run goes on; the journal is removed once every file went in.
+/// Cancelling stops after the file in progress, whose song is stored in one
+/// transaction, and keeps the journal for `--resume`.
+fn ingest_directory(
+    db: &DB,
+    dir: &str,
+    target: &IngestTarget,
+    resume: bool,
+    mut enricher: Option<&mut Enricher>,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    let journal_path = Path::new(dir).join(JOURNAL_FILE);
+    let collection = target.collection;
+
+    let files = match list_files(dir) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("❌ Cannot read directory '{}': {}", dir, e);
+            return;
+        }
+    };
+
+    let mut journal = if resume && journal_path.exists() {
+        let journal = match IngestJournal::load(&journal_path) {
+            Ok(journal) => journal,
+            Err(e) => {
+                eprintln!("❌ Cannot resume: {}", e);
+                return;
+            }
+        };
+        if journal.collection != collection {
+            eprintln!(
+                "❌ The interrupted ingest was into collection '{}', not '{}'",
+                journal.collection, collection
+            );
+            return;
+        }
+        println!(
+            "⏯️ Resuming: {} done, {} failed earlier",
+            journal.completed.len(),
+            journal.failed.len()
+        );
+        journal
+    } else {
+        if journal_path.exists() {
+            println!("⚠️ Starting over; pass --resume to continue the earlier, unfinished ingest");
+        }
+        IngestJournal {
+            collection: collection.to_string(),
+            ..Default::default()
+        }
+    };
+    // Files added to the directory since the interruption are picked up too
+    journal.pending = files
+        .into_iter()
+        .filter(|file| !journal.completed.contains(file) && !journal.failed.contains_key(file))
+        .collect();
+
+    let total = journal.pending.len();
+    let (mut ingested, mut failed) = (0, 0);
+    for n in 1..=total {
+        if cancel.is_cancelled() {
+            break;
+        }
+        if let Err(e) = journal.save(&journal_path) {
+            eprintln!("⚠️ Cannot write {}: {}", journal_path.display(), e);
+        }
+        let file = journal.pending[0].clone();
+        println!("[{}/{}] {}", n, total, file);
+
+        let outcome =
+            catch_panic(|| ingest_file(db, file.clone(), target, enricher.as_deref_mut(), config))
+                .flatten();
+
+        journal.pending.remove(0);
+        match outcome {
+            Ok(()) => {
+                journal.completed.push(file);
+                ingested += 1;
+            }
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                journal.failed.insert(file, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "Processed {} file(s) this run, {} failed; {} done, {} failed in total",
+        ingested + failed,
+        failed,
+        journal.completed.len(),
+        journal.failed.len()
+    );
+    if !journal.pending.is_empty() {
+        if let Err(e) = journal.save(&journal_path) {
+            eprintln!("⚠️ Cannot write {}: {}", journal_path.display(), e);
+        }
+        println!(
+            "⏹️ Interrupted with {} file(s) left, continue with --resume",
+            journal.pending.len()
+        );
+    } else if journal.failed.is_empty() {
+        let _ = fs::remove_file(&journal_path);
+    } else {
+        if let Err(e) = journal.save(&journal_path) {
+            eprintln!("⚠️ Cannot write {}: {}", journal_path.display(), e);
+        }
+        println!("Failures:");
+        for (file, reason) in &journal.failed {
+            println!("  {}: {}", file, reason);
+        }
+        println!(
+            "They are kept in {} and skipped by --resume; delete it to retry them",
+            journal_path.display()
+        );
+    }
+}
+
+/// Non-hidden files directly in `dir`, sorted by name
+fn list_files(dir: &str) -> std::io::Result<Vec<String>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    files.sort();
+    Ok(files
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Run `f`, turning a panic (e.g. in a decoder) into an error message
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "panicked".to_string())
+    })
+}
+
+/// `--ingest --dry-run`: run decode, STFT and fingerprinting on `path` (a
+/// file or directory) and print what ingesting it would add, without
+/// touching the database. Fingerprints use `hash_scheme`, or the default
+/// scheme, and the settings of a `kind` collection, since the collection's
+/// own are not looked up.
+pub fn run_dry_run(
+    path: &str,
+    hash_scheme: Option<HashScheme>,
+    kind: CollectionKind,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    let config = config.for_collection(kind);
+    let config = config.as_ref();
+    let files = if Path::new(path).is_dir() {
+        match list_files(path) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("❌ Cannot read directory '{}': {}", path, e);
+                return;
+            }
+        }
+    } else if path == AudioProcessor::STDIN {
+        eprintln!("❌ --ingest needs a file; stdin has no title, tags or checksum");
+        return;
+    } else {
+        vec![path.to_string()]
+    };
+    let scheme = hash_scheme.unwrap_or_default();
+    println!("🧪 Dry run with {:?} hashes, nothing is written", scheme);
+
+    // hash -> (occurrences, indexes of the files it occurs in)
+    let mut hashes: HashMap<u64, (usize, HashSet<usize>)> = HashMap::new();
+    let (mut fingerprints, mut seconds) = (0usize, 0f64);
+    let (mut fingerprinted, mut failed) = (0usize, 0usize);
+    for (i, file) in files.iter().enumerate() {
+        if cancel.is_cancelled() {
+            println!("⏹️ Interrupted, statistics cover the files so far");
+            break;
+        }
+        let (file_fingerprints, duration) =
+            match catch_panic(|| fingerprint_file(file, scheme, config)) {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("❌ {}: {}", file, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+        println!(
+            "   {}: {:.1}s, {} fingerprints, {:.1}/s",
+            file,
+            duration,
+            file_fingerprints.len(),
+            file_fingerprints.len() as f64 / duration.max(f64::EPSILON)
+        );
+        for fp in &file_fingerprints {
+            let entry = hashes.entry(fp.hash).or_default();
+            entry.0 += 1;
+            entry.1.insert(i);
+        }
+        fingerprints += file_fingerprints.len();
+        seconds += duration;
+        fingerprinted += 1;
+    }
+    if fingerprinted == 0 {
+        return;
+    }
+
+    println!("\n📊 Dry run statistics:");
+    println!(
+        "   Files: {} fingerprinted, {} failed",
+        fingerprinted, failed
+    );
+    println!("   Audio: {:.1}s", seconds);
+    println!(
+        "   Fingerprints: {} ({} distinct hashes)",
+        fingerprints,
+        hashes.len()
+    );
+    println!(
+        "   Density: {:.1} fingerprints/s",
+        fingerprints as f64 / seconds.max(f64::EPSILON)
+    );
+
+    let row_bytes = match scheme {
+        HashScheme::Wide64 => ROW_BYTES_WIDE,
+        HashScheme::Compact32 => ROW_BYTES_COMPACT,
+    };
+    println!(
+        "   Estimated growth: ~{} in Postgres, {} in an index file",
+        format_bytes((fingerprints * row_bytes) as i64),
+        format_bytes((fingerprints * mmap_index::ENTRY_LEN) as i64)
+    );
+
+    let mut collided: Vec<(u64, usize, usize)> = hashes
+        .into_iter()
+        .filter(|(_, (occurrences, _))| *occurrences > 1)
+        .map(|(hash, (occurrences, files))| (hash, occurrences, files.len()))
+        .collect();
+    collided.sort_by_key(|&(hash, occurrences, _)| (std::cmp::Reverse(occurrences), hash));
+    if !collided.is_empty() {
+        println!("\n   Most collided hashes:");
+        println!("   {:>20}  {:>11}  {:>6}", "hash", "occurrences", "files");
+        for (hash, occurrences, files) in collided.into_iter().take(TOP_COLLISIONS) {
+            println!("   {:>20}  {:>11}  {:>6}", hash, occurrences, files);
+        }
+    }
+}
+
+/// Fingerprints of `file_name` and its duration in seconds
+fn fingerprint_file(
+    file_name: &str,
+    scheme: HashScheme,
+    config: &Config,
+) -> (Vec<FingerprintInfo>, f64) {
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name.to_string());
+    let duration = audio_samples.len() as f64 / sample_rate as f64;
+    (
+        cap_song_fingerprints(
+            Pipeline::from_config(config, scheme).fingerprints(audio_samples, sample_rate),
+            &config.fingerprint,
+        ),
+        duration,
+    )
+}
+
+/// Fingerprints of `samples` by the pipeline of `builder`, and their tempo
+/// and key estimated from the same spectra
+fn fingerprints_with_analysis(
+    builder: PipelineBuilder,
+    samples: Vec<f32>,
+    sample_rate: u32,
+) -> (Vec<FingerprintInfo>, SpectraAnalysis) {
+    let analysis = Arc::new(Mutex::new(SpectraAnalysis::default()));
+    let fingerprints = builder
+        .insert_after("stft", AnalysisStage::new(Arc::clone(&analysis)))
+        .build()
+        .fingerprints(samples, sample_rate);
+    let analysis = analysis
+        .lock()
+        .map(|analysis| *analysis)
+        .unwrap_or_default();
+    (fingerprints, analysis)
+}
+
+/// A song uploaded to `POST /ingest` of the server. Uploads carry no tags
+/// that are read, so the metadata comes with the request.
+pub struct Upload {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub collection: String,
+}
+
+/// Ingest an uploaded audio file into an existing or new collection of its
+/// default scheme. Returns the song's id and its fingerprint count, `None`
+/// when the same file was ingested before.
+pub fn ingest_upload(
+    bytes: Vec<u8>,
+    upload: &Upload,
+    config: &Config,
+) -> Result<(i32, Option<usize>), String> {
+    let db = DB::new();
+    let collection = upload.collection.as_str();
+    // Same as the checksum of the file on disk, so both ways of ingesting
+    // see each other's songs
+    let checksum = format!("{:x}", Sha256::digest(&bytes));
+    if let Some(existing) = db.find_song_by_checksum(&checksum, collection) {
+        return Ok((existing.id, None));
+    }
+
+    let scheme = db.ensure_collection(collection, HashScheme::default(), CollectionKind::Songs);
+    let kind = db.collection_kind(collection);
+    let config = config.for_collection(kind);
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+
+    let (audio_samples, sample_rate) = audio_processor
+        .get_decoded_audio_from_bytes(bytes)
+        .map_err(|e| format!("could not decode audio: {}", e))?;
+    let (fingerprints, analysis) = fingerprints_with_analysis(
+        Pipeline::builder(&config, scheme),
+        audio_samples.clone(),
+        sample_rate,
+    );
+    let fingerprints = cap_song_fingerprints(fingerprints, &config.fingerprint);
+    if fingerprints.is_empty() {
+        return Err("no fingerprints generated".to_string());
+    }
+
+    let song = NewSong {
+        title: upload.title.clone(),
+        created_at: Some(SystemTime::now()),
+        duration: Some(audio_samples.len() as f64 / sample_rate as f64),
+        artist: upload.artist.clone(),
+        album: upload.album.clone(),
+        track_number: None,
+        year: None,
+        checksum: Some(checksum),
+        collection: collection.to_string(),
+        fingerprint_version: config.fingerprint_version(),
+        file_path: None,
+        bpm: analysis.bpm,
+        musical_key: analysis.key.map(|key| key.to_string()),
+    };
+    let (song_id, count) = store_song(&db, &song, fingerprints, scheme)
+        .map_err(|e| format!("nothing was saved: {}", e))?;
+    if config.cover.ingest_profiles && kind == CollectionKind::Songs {
+        cover::store_profile(&db, song_id, &audio_samples, sample_rate);
+    }
+    info!(
+        song_id,
+        fingerprints = count,
+        "Ingested upload '{}'",
+        upload.title
+    );
+    Ok((song_id, Some(count)))
+}
+
+/// Ingest an audio file using in-memory processing, with the settings of the
+/// target collection's kind. A file already in the collection is skipped and
+/// counts as success.
+fn ingest_file(
+    db: &DB,
+    file_name: String,
+    target: &IngestTarget,
+    enricher: Option<&mut Enricher>,
+    config: &Config,
+) -> Result<(), String> {
+    if file_name == AudioProcessor::STDIN {
+        return Err("--ingest needs a file; stdin has no title, tags or checksum".to_string());
+    }
+
+    let song_name = file_name
+        .rsplit('/')
+        .next()
+        .unwrap_or("Unknown Song")
+        .to_string();
+
+    info!("Ingesting song: {}", song_name);
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let collection = target.collection;
+
+    let checksum = audio_processor.file_checksum(file_name.clone());
+    if let Some(existing) = db.find_song_by_checksum(&checksum, collection) {
+        println!(
+            "⏭️ Already ingested as id={} title=\"{}\", skipping",
+            existing.id, existing.title
+        );
+        return Ok(());
+    }
+
+    let scheme = db.ensure_collection(
+        collection,
+        target.hash_scheme.unwrap_or_default(),
+        target.kind.unwrap_or_default(),
+    );
+    if let Some(requested) = target.hash_scheme.filter(|&requested| requested != scheme) {
+        return Err(format!(
+            "Collection '{}' stores {:?} hashes, cannot ingest with {:?}",
+            collection, scheme, requested
+        ));
+    }
+    let kind = db.collection_kind(collection);
+    if let Some(requested) = target.kind.filter(|&requested| requested != kind) {
+        return Err(format!(
+            "Collection '{}' holds {}, cannot ingest as {}",
+            collection,
+            kind.as_str(),
+            requested.as_str()
+        ));
+    }
+    let config = config.for_collection(kind);
+
+    let mut metadata = audio_processor.get_metadata(file_name.clone());
+    debug!("Metadata: {:?}", metadata);
+
+    // Absolute, so playing the song back works from any directory
+    let file_path = fs::canonicalize(&file_name)
+        .ok()
+        .map(|path| path.to_string_lossy().to_string());
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name);
+    if let Some(enricher) = enricher {
+        enricher.enrich(
+            &song_name,
+            &checksum,
+            &audio_samples,
+            sample_rate,
+            &mut metadata,
+        );
+    }
+
+    let (downsampled_samples, downsampled_rate) =
+        Pipeline::from_config(&config, scheme).audio(audio_samples.clone(), sample_rate);
+
+    info!(
+        "Processed to {} samples at {} Hz",
+        downsampled_samples.len(),
+        downsampled_rate
+    );
+
+    let silence_secs = Arc::new(Mutex::new(0.0));
+    let (fingerprints, analysis) = fingerprints_with_analysis(
+        Pipeline::builder(&config, scheme)
+            .starting_at("stft")
+            .replace(StftStage::new(&config.fft).reporting_silence(Arc::clone(&silence_secs))),
+        downsampled_samples,
+        downsampled_rate,
+    );
+    let silence_secs = silence_secs.lock().map_or(0.0, |secs| *secs);
+    let fingerprints = cap_song_fingerprints(fingerprints, &config.fingerprint);
+    info!("Generated {} fingerprints", fingerprints.len());
+    if let Some(bpm) = analysis.bpm {
+        info!("Estimated tempo: {:.1} BPM", bpm);
+    }
+    if let Some(key) = analysis.key {
+        info!("Estimated key: {}", key);
+    }
+
+    if fingerprints.is_empty() {
+        return Err(format!("No fingerprints generated for '{}'", song_name));
+    }
+
+    let song = NewSong {
+        title: song_name.clone(),
+        created_at: Some(SystemTime::now()),
+        duration: Some(audio_samples.len() as f64 / sample_rate as f64),
+        artist: metadata.artist,
+        album: metadata.album,
+        track_number: metadata.track_number,
+        year: metadata.year,
+        checksum: Some(checksum),
+        collection: collection.to_string(),
+        fingerprint_version: config.fingerprint_version(),
+        file_path,
+        bpm: analysis.bpm,
+        musical_key: analysis.key.map(|key| key.to_string()),
+    };
+
+    match store_song(db, &song, fingerprints, scheme) {
+        Ok((song_id, count)) => {
+            info!("Inserted {} fingerprints for song_id: {}", count, song_id);
+            if silence_secs > 0.0 {
+                println!("🔇 Skipped {:.1}s of silence", silence_secs);
+            }
+            if config.cover.ingest_profiles && kind == CollectionKind::Songs {
+                cover::store_profile(db, song_id, &audio_samples, sample_rate);
+            }
+            println!("✅ Successfully ingested and fingerprinted '{}'", song_name);
+            Ok(())
+        }
+        Err(e) => Err(format!(
+            "Failed to ingest '{}', nothing was saved: {}",
+            song_name, e
+        )),
+    }
+}
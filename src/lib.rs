@@ -0,0 +1,82 @@
+//! Audio fingerprinting and matching. The `sabi` command line tool and the
+//! C API of `sabi-ffi` are both built on these modules.
+//!
+//! Without the default `native` feature only the DSP pipeline (preprocessing,
+//! STFT, peak picking, hashing), fingerprint files and index files are built,
+//! with no microphone, database or server dependencies, so it compiles for
+//! `wasm32-unknown-unknown`; see `sabi-wasm`. The `capture`, `db-postgres`
+//! and `decode` features add microphone input, the Postgres library and file
+//! decoding back one at a time.
+
+// Handles such as `DB::new()` connect or allocate, so they stay out of `Default`
+#![allow(clippy::new_without_default)]
+
+#[cfg(feature = "native")]
+pub mod acoustid;
+#[cfg(feature = "native")]
+pub mod archive;
+pub mod audio_processor;
+#[cfg(feature = "native")]
+pub mod auth;
+#[cfg(feature = "native")]
+pub mod bench;
+pub mod cancel;
+pub mod chroma;
+#[cfg(feature = "native")]
+pub mod chromaprint;
+#[cfg(feature = "native")]
+pub mod clip;
+pub mod config;
+#[cfg(feature = "native")]
+pub mod cover;
+pub mod db;
+#[cfg(feature = "native")]
+pub mod detect;
+pub mod dsp;
+pub mod encoding;
+pub mod fft;
+pub mod fingerprint;
+pub mod fpfile;
+#[cfg(all(test, feature = "decode"))]
+mod golden_tests;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "native")]
+pub mod http;
+#[cfg(feature = "native")]
+pub mod ingest;
+#[cfg(feature = "native")]
+pub mod library;
+#[cfg(feature = "native")]
+pub mod listen;
+#[cfg(feature = "native")]
+pub mod logging;
+#[cfg(feature = "capture")]
+pub mod meter;
+#[cfg(feature = "native")]
+pub mod metrics;
+#[cfg(feature = "native")]
+pub mod monitor;
+#[cfg(feature = "native")]
+pub mod musicbrainz;
+#[cfg(feature = "native")]
+pub mod notifier;
+#[cfg(feature = "native")]
+pub mod pipeline;
+#[cfg(feature = "native")]
+pub mod playback;
+#[cfg(feature = "native")]
+pub mod scanner;
+#[cfg(feature = "db-postgres")]
+pub mod schema;
+#[cfg(feature = "native")]
+pub mod server;
+#[cfg(feature = "native")]
+pub mod spectrogram;
+pub mod tempo;
+#[cfg(feature = "native")]
+pub mod tester;
+#[cfg(feature = "native")]
+pub mod timings;
+#[cfg(feature = "native")]
+pub mod visualization;
@@ -0,0 +1,775 @@
+use crate::audio_processor::AudioProcessor;
+use crate::cancel::CancelToken;
+use crate::config::Config;
+use crate::cover;
+use crate::db::{
+    bindings::{HashSpread, Songs},
+    connector::{CollectionKind, DB, MatchSource, SongSort},
+    lmdb_store::LmdbStore,
+    storage::fingerprint_store,
+};
+use crate::dsp::{AnalysisStage, Pipeline, SpectraAnalysis};
+use crate::fingerprint::{
+    FingerprintInfo, HashScheme, cap_song_fingerprints, generate_audio_fingerprint,
+    vote_best_matches,
+};
+use crate::fpfile::similarity;
+use crate::scanner::format_timestamp;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+/// Remove songs selected by id or title pattern, together with their
+/// fingerprints. With `dry_run` only the affected row counts are printed.
+pub fn delete_songs(db: &DB, id: Option<i32>, title_pattern: Option<&str>, dry_run: bool) {
+    let songs = db.find_songs(id, title_pattern);
+    if songs.is_empty() {
+        println!("❌ No songs matched");
+        return;
+    }
+
+    let song_ids: Vec<i32> = songs.iter().map(|s| s.id).collect();
+    let fingerprint_count = db.count_fingerprints(&song_ids);
+
+    println!("🗑️ Songs to delete:");
+    for song in &songs {
+        println!("  id={} title=\"{}\"", song.id, song.title);
+    }
+    println!(
+        "   {} song(s), {} fingerprint(s)",
+        songs.len(),
+        fingerprint_count
+    );
+
+    if dry_run {
+        println!("Dry run, nothing deleted.");
+        return;
+    }
+
+    match db.delete_songs(&song_ids) {
+        Ok((songs_deleted, fingerprints_deleted)) => println!(
+            "✅ Deleted {} song(s) and {} fingerprint(s)",
+            songs_deleted, fingerprints_deleted
+        ),
+        Err(e) => {
+            eprintln!("❌ Transaction failed, nothing deleted: {:?}", e);
+            return;
+        }
+    }
+
+    if let Some(store) = LmdbStore::from_env() {
+        match store.delete_songs(&song_ids) {
+            Ok(deleted) => println!("✅ Deleted {} fingerprint(s) from the LMDB index", deleted),
+            Err(e) => eprintln!("❌ Failed to delete from the LMDB index: {}", e),
+        }
+    }
+}
+
+/// Remove songs that have no fingerprints, left behind by ingests that
+/// failed part-way. With `dry_run` the orphans are only listed.
+pub fn clean_orphans(db: &DB, dry_run: bool) {
+    if LmdbStore::configured() {
+        eprintln!(
+            "❌ cleanup only checks the Postgres fingerprint table, unset FINGERPRINT_LMDB_PATH"
+        );
+        return;
+    }
+
+    let orphans = db.find_orphan_songs();
+    if orphans.is_empty() {
+        println!("✅ No orphaned songs found");
+        return;
+    }
+
+    println!("🧹 Songs without fingerprints:");
+    for song in &orphans {
+        println!("  id={} title=\"{}\"", song.id, song.title);
+    }
+
+    if dry_run {
+        println!("Dry run, nothing deleted.");
+        return;
+    }
+
+    let song_ids: Vec<i32> = orphans.iter().map(|s| s.id).collect();
+    match db.delete_songs(&song_ids) {
+        Ok((songs_deleted, _)) => println!("✅ Deleted {} orphaned song(s)", songs_deleted),
+        Err(e) => eprintln!("❌ Transaction failed, nothing deleted: {:?}", e),
+    }
+}
+
+/// Find clusters of near-identical songs in `collection` (the same recording
+/// ingested twice, different encodes) by matching every song's own
+/// fingerprints against the rest of the index. Songs join a cluster from
+/// `min_similarity` (see [`similarity`]) on. With `merge`, each cluster is
+/// folded into its earliest-ingested song; `dry_run` only lists the clusters.
+pub fn dedupe(
+    db: &DB,
+    collection: &str,
+    min_similarity: f32,
+    merge: bool,
+    dry_run: bool,
+    config: &Config,
+) {
+    // Candidates per song checked for duplication, besides the song itself
+    const CANDIDATES: usize = 5;
+
+    if LmdbStore::configured() {
+        eprintln!("❌ dedupe reads the Postgres fingerprint tables, unset FINGERPRINT_LMDB_PATH");
+        return;
+    }
+
+    let store = fingerprint_store(db, collection, config.fingerprint_version());
+    let songs = db.fetch_all_songs(Some(collection));
+    let load_fingerprints = |song_id: i32| -> Vec<FingerprintInfo> {
+        db.fetch_fingerprints(song_id)
+            .into_iter()
+            .map(|(hash, anchor_time)| FingerprintInfo {
+                hash: hash as u64,
+                abs_anchor_tm_offset: anchor_time as f32,
+                strength: 1.0,
+                neighbor: false,
+            })
+            .collect()
+    };
+
+    // Union-find over song ids, every cluster rooted at its lowest id
+    let mut parent: HashMap<i32, i32> = songs.iter().map(|s| (s.id, s.id)).collect();
+    let root = |parent: &HashMap<i32, i32>, mut id: i32| {
+        while parent[&id] != id {
+            id = parent[&id];
+        }
+        id
+    };
+    // Best similarity with which each song joined its cluster
+    let mut similarities: HashMap<i32, f32> = HashMap::new();
+
+    println!(
+        "🔍 Cross-matching {} song(s) in '{}'",
+        songs.len(),
+        collection
+    );
+    for song in &songs {
+        let fingerprints = store.without_denied(load_fingerprints(song.id));
+        if fingerprints.is_empty() {
+            continue;
+        }
+
+        let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+        let db_matches_by_hash = match store.fetch_matches_grouped_by_hash(&hash_vec) {
+            Ok(matches) => matches,
+            Err(e) => {
+                eprintln!("❌ Fingerprint lookup failed: {}", e);
+                return;
+            }
+        };
+        let candidates = vote_best_matches(
+            &fingerprints,
+            &db_matches_by_hash,
+            store.song_count(),
+            CANDIDATES + 1,
+            &config.matching,
+        );
+
+        for candidate in candidates {
+            let other = candidate.song_id as i32;
+            if !parent.contains_key(&other) {
+                continue;
+            }
+            let (song_root, other_root) = (root(&parent, song.id), root(&parent, other));
+            if song_root == other_root {
+                continue;
+            }
+            let Some((similarity, _)) =
+                similarity(&fingerprints, &load_fingerprints(other), &config.matching)
+            else {
+                continue;
+            };
+            if similarity < min_similarity {
+                continue;
+            }
+
+            parent.insert(song_root.max(other_root), song_root.min(other_root));
+            for id in [song.id, other] {
+                let best = similarities.entry(id).or_default();
+                *best = best.max(similarity);
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<i32, Vec<&Songs>> = BTreeMap::new();
+    for song in &songs {
+        clusters
+            .entry(root(&parent, song.id))
+            .or_default()
+            .push(song);
+    }
+    clusters.retain(|_, members| members.len() > 1);
+
+    if clusters.is_empty() {
+        println!("✅ No duplicates found");
+        return;
+    }
+
+    for members in clusters.values() {
+        println!("🔁 {} copies:", members.len());
+        for (n, song) in members.iter().enumerate() {
+            let duration = song
+                .duration
+                .map_or("-".to_string(), |d| format_timestamp(d as f32));
+            println!(
+                "   {} id={} title=\"{}\" ({}, similarity {:.1}%)",
+                if n == 0 { "keep" } else { "dup " },
+                song.id,
+                song.title,
+                duration,
+                similarities.get(&song.id).copied().unwrap_or_default() * 100.0
+            );
+        }
+    }
+    let duplicates: usize = clusters.values().map(|members| members.len() - 1).sum();
+    println!(
+        "   {} cluster(s), {} duplicate song(s)",
+        clusters.len(),
+        duplicates
+    );
+
+    if !merge {
+        return;
+    }
+    if dry_run {
+        println!("Dry run, nothing merged.");
+        return;
+    }
+
+    let (mut songs_deleted, mut fingerprints_deleted) = (0, 0);
+    for (&keep_id, members) in &clusters {
+        let duplicate_ids: Vec<i32> = members[1..].iter().map(|s| s.id).collect();
+        match db.merge_songs(keep_id, &duplicate_ids) {
+            Ok((songs, fingerprints)) => {
+                songs_deleted += songs;
+                fingerprints_deleted += fingerprints;
+            }
+            Err(e) => eprintln!("❌ Failed to merge into id={}: {:?}", keep_id, e),
+        }
+    }
+    println!(
+        "✅ Merged {} song(s) into their originals, deleting {} fingerprint(s)",
+        songs_deleted, fingerprints_deleted
+    );
+}
+
+/// Re-fingerprint the songs whose source files are in `dir`, matched by
+/// checksum, with the current parameters, and estimate their tempo and key
+/// again.
+/// Ids, metadata and match history are kept. With `outdated_only`, songs already at the current
+/// [`Config::fingerprint_version`] are left alone. Cancelling stops after the file in progress; each song's
+/// fingerprints are replaced in one transaction.
+pub fn reingest(db: &DB, dir: &str, outdated_only: bool, config: &Config, cancel: &CancelToken) {
+    if LmdbStore::configured() {
+        eprintln!(
+            "❌ reingest only rewrites the Postgres fingerprint tables, unset FINGERPRINT_LMDB_PATH"
+        );
+        return;
+    }
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.collect::<Result<Vec<_>, _>>().unwrap_or_default(),
+        Err(e) => {
+            eprintln!("❌ Cannot read directory '{}': {}", dir, e);
+            return;
+        }
+    };
+
+    let (mut rebuilt, mut failed) = (0, 0);
+    for entry in entries {
+        if cancel.is_cancelled() {
+            println!("⏹️ Interrupted, run reingest again to continue");
+            break;
+        }
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let file_name = path.to_string_lossy().to_string();
+
+        let checksum = audio_processor.file_checksum(file_name.clone());
+        // Songs ingested before their paths were stored become playable
+        let absolute = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Err(e) = db.set_file_path(&checksum, &absolute.to_string_lossy()) {
+            eprintln!("⚠️ Failed to store the path of '{}': {}", file_name, e);
+        }
+        let songs: Vec<_> = db
+            .find_songs_by_checksum(&checksum)
+            .into_iter()
+            .filter(|song| {
+                let kind = db.collection_kind(&song.collection);
+                !outdated_only
+                    || song.fingerprint_version != config.for_collection(kind).fingerprint_version()
+            })
+            .collect();
+        if songs.is_empty() {
+            continue;
+        }
+
+        let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name);
+        let chroma = config
+            .cover
+            .ingest_profiles
+            .then(|| cover::chroma_profile(&audio_samples, sample_rate));
+        let (resampled, rate) =
+            Pipeline::from_config(config, HashScheme::default()).audio(audio_samples, sample_rate);
+        // Short references pick their peaks differently, so each kind gets
+        // its own spectrogram, and the tempo and key estimated from it
+        let mut distributions = HashMap::new();
+
+        // The same file may be in several collections, each with its own
+        // scheme and kind
+        for song in songs {
+            let scheme = db
+                .collection_hash_scheme(&song.collection)
+                .unwrap_or_default();
+            let kind = db.collection_kind(&song.collection);
+            let song_config = config.for_collection(kind);
+            let version = song_config.fingerprint_version();
+            let (fft_distribution, analysis) = distributions.entry(kind).or_insert_with(|| {
+                let analysis = Arc::new(Mutex::new(SpectraAnalysis::default()));
+                let frames = Pipeline::builder(&song_config, scheme)
+                    .starting_at("stft")
+                    .insert_after("stft", AnalysisStage::new(Arc::clone(&analysis)))
+                    .build()
+                    .peak_frames(resampled.clone(), rate);
+                let analysis = analysis
+                    .lock()
+                    .map(|analysis| *analysis)
+                    .unwrap_or_default();
+                (frames, analysis)
+            });
+            let fingerprints = cap_song_fingerprints(
+                generate_audio_fingerprint(fft_distribution, scheme, &song_config.fingerprint),
+                &song_config.fingerprint,
+            );
+
+            match db.replace_fingerprints(song.id, fingerprints, scheme, version) {
+                Ok(count) => {
+                    println!(
+                        "✅ Rebuilt id={} title=\"{}\" with {} fingerprints (v{} → v{})",
+                        song.id, song.title, count, song.fingerprint_version, version
+                    );
+                    rebuilt += 1;
+                    let key = analysis.key.map(|key| key.to_string());
+                    if let Err(e) = db.set_analysis(song.id, analysis.bpm, key.as_deref()) {
+                        eprintln!(
+                            "⚠️ Failed to store the tempo and key of id={}: {}",
+                            song.id, e
+                        );
+                    }
+                    let chroma = chroma.as_ref().filter(|_| kind == CollectionKind::Songs);
+                    let stored = chroma.map_or(Ok(()), |chroma| {
+                        db.write_chroma_profile(&chroma.to_row(song.id))
+                    });
+                    if let Err(e) = stored {
+                        eprintln!(
+                            "⚠️ Failed to store the chroma profile of id={}: {}",
+                            song.id, e
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "❌ Failed to rebuild id={}, old fingerprints kept: {:?}",
+                        song.id, e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!(
+        "Rebuilt {} song(s), {} failed; {} outdated song(s) left",
+        rebuilt,
+        failed,
+        count_outdated_songs(db, config)
+    );
+}
+
+/// Number of songs in any collection fingerprinted at another version than
+/// their collection's kind is under `config`
+fn count_outdated_songs(db: &DB, config: &Config) -> i64 {
+    db.song_counts_by_version()
+        .into_iter()
+        .filter(|(collection, version, _)| {
+            let kind = db.collection_kind(collection);
+            *version != config.for_collection(kind).fingerprint_version()
+        })
+        .map(|(_, _, count)| count)
+        .sum()
+}
+
+/// Print one page of the library, optionally restricted to songs whose
+/// title, artist or album contains `query`.
+pub fn list_songs(
+    db: &DB,
+    query: Option<&str>,
+    sort: SongSort,
+    descending: bool,
+    limit: u32,
+    page: u32,
+) {
+    let pattern = match query {
+        Some(q) => format!("%{}%", escape_like(q)),
+        None => "%".to_string(),
+    };
+    let offset = i64::from(page - 1) * i64::from(limit);
+    let rows = match db.list_songs(&pattern, sort, descending, limit.into(), offset) {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to list songs: {}", e);
+            return;
+        }
+    };
+
+    if rows.is_empty() {
+        println!("❌ No songs found");
+        return;
+    }
+
+    println!(
+        "{:>6}  {:<50}  {:>12}  {:>8}  {:>5}  {:<8}  {:<16}",
+        "id", "title", "fingerprints", "duration", "bpm", "key", "ingested"
+    );
+    for row in &rows {
+        let duration = row
+            .duration
+            .map_or("-".to_string(), |d| format_timestamp(d as f32));
+        let bpm = row.bpm.map_or("-".to_string(), |bpm| format!("{:.0}", bpm));
+        let ingested = row.created_at.map_or("-".to_string(), |t| {
+            DateTime::<Utc>::from(t)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        });
+        println!(
+            "{:>6}  {:<50}  {:>12}  {:>8}  {:>5}  {:<8}  {:<16}",
+            row.id,
+            truncate(&row.title, 50),
+            row.fingerprint_count,
+            duration,
+            bpm,
+            row.musical_key.as_deref().unwrap_or("-"),
+            ingested
+        );
+    }
+    println!("-- page {} ({} songs)", page, rows.len());
+}
+
+/// `text` as a `LIKE` pattern matching it literally, with `%`, `_` and `\`
+/// escaped by a `\`
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Print the most recent recognition events, newest first
+pub fn print_history(db: &DB, source: Option<MatchSource>, limit: u32) {
+    let rows = match db.fetch_match_history(source, limit.into()) {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to load the recognition history: {}", e);
+            return;
+        }
+    };
+    if rows.is_empty() {
+        println!("❌ No recognitions recorded yet");
+        return;
+    }
+
+    println!(
+        "{:<19}  {:>6}  {:<40}  {:>6}  {:>10}  {:<6}  input",
+        "recognised", "id", "title", "score", "confidence", "source"
+    );
+    for (record, title) in &rows {
+        let recognised = DateTime::<Utc>::from(record.matched_at)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        println!(
+            "{:<19}  {:>6}  {:<40}  {:>6}  {:>10.2}  {:<6}  {}",
+            recognised,
+            record.song_id,
+            truncate(title, 40),
+            record.score,
+            record.confidence,
+            record.source,
+            record.input.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        let cut: String = text.chars().take(max_chars - 1).collect();
+        format!("{}…", cut)
+    }
+}
+
+/// Print library-wide statistics useful for tuning fingerprint parameters
+pub fn print_stats(db: &DB, top_hashes: u32, config: &Config) {
+    let total_songs = db.count_songs();
+    let total_fingerprints = db.count_all_fingerprints();
+    let mut per_song: Vec<i64> = db
+        .fingerprint_counts_per_song()
+        .into_iter()
+        .map(|(_, count)| count)
+        .collect();
+    per_song.sort_unstable();
+
+    println!("📊 Library statistics");
+    println!("   Songs: {}", total_songs);
+    println!("   Fingerprints: {}", total_fingerprints);
+
+    let without_fingerprints = total_songs - per_song.len() as i64;
+    if without_fingerprints > 0 {
+        println!("   Songs without fingerprints: {}", without_fingerprints);
+    }
+
+    let outdated = count_outdated_songs(db, config);
+    if outdated > 0 {
+        println!("   Songs with outdated fingerprints: {}", outdated);
+    }
+
+    if !per_song.is_empty() {
+        let mean = per_song.iter().sum::<i64>() as f64 / per_song.len() as f64;
+        let percentile = |p: f64| per_song[((per_song.len() - 1) as f64 * p).round() as usize];
+        println!("\n   Fingerprints per song:");
+        println!(
+            "   min={} p10={} median={} p90={} max={} mean={:.0}",
+            per_song[0],
+            percentile(0.1),
+            percentile(0.5),
+            percentile(0.9),
+            per_song[per_song.len() - 1],
+            mean
+        );
+    }
+
+    match db.most_common_hashes(top_hashes.into()) {
+        Ok(hashes) if !hashes.is_empty() => {
+            println!("\n   Most common hashes:");
+            println!("   {:>20}  {:>11}  {:>6}", "hash", "occurrences", "songs");
+            for h in hashes {
+                println!(
+                    "   {:>20}  {:>11}  {:>6}",
+                    h.hash, h.occurrences, h.song_count
+                );
+            }
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("❌ Failed to find the most common hashes: {}", e),
+    }
+
+    let size = db.fingerprint_relation_size();
+    println!("\n   Fingerprint table: {}", format_bytes(size.table_bytes));
+    println!("   Fingerprint indexes: {}", format_bytes(size.index_bytes));
+}
+
+/// Report how the hashes of `collection` spread over its songs and suggest
+/// deny-listing the `limit` most widespread of those stored for more than
+/// `max_songs` songs, or, given a `percentile`, for more songs than that
+/// percentile of its distinct hashes. `apply` stores the suggestion as the
+/// collection's deny-list, `clear` empties it.
+pub fn analyze_index(
+    db: &DB,
+    collection: &str,
+    max_songs: i64,
+    percentile: Option<f64>,
+    limit: i64,
+    apply: bool,
+    clear: bool,
+) {
+    if percentile.is_some_and(|p| !(0.0..=100.0).contains(&p)) {
+        eprintln!("❌ --percentile must be between 0 and 100");
+        return;
+    }
+
+    let Some(scheme) = db.collection_hash_scheme(collection) else {
+        eprintln!("❌ No collection '{}'", collection);
+        return;
+    };
+
+    if clear {
+        match db.replace_denied_hashes(collection, scheme, &[]) {
+            Ok(_) => println!("🧹 Cleared the deny-list of '{}'", collection),
+            Err(e) => eprintln!("❌ Failed to clear the deny-list: {}", e),
+        }
+        return;
+    }
+
+    let spread = db.hash_spread(collection, scheme);
+    if spread.is_empty() {
+        println!("No fingerprints in '{}'", collection);
+        return;
+    }
+    let total_hashes: i64 = spread.iter().map(|s| s.hashes).sum();
+    let total_fingerprints: i64 = spread.iter().map(|s| s.occurrences).sum();
+    let percent = |part: i64, whole: i64| 100.0 * part as f64 / whole as f64;
+
+    println!("📊 Hashes of '{}' ({}-bit)", collection, scheme.bits());
+    println!(
+        "   Distinct hashes: {}, fingerprints: {}",
+        total_hashes, total_fingerprints
+    );
+    println!(
+        "\n   {:>12}  {:>10}  {:>7}  {:>12}",
+        "songs", "hashes", "", "fingerprints"
+    );
+    // Power-of-two buckets of song counts: 1, 2, 3-4, 5-8, ...
+    let mut buckets: BTreeMap<u32, (i64, i64)> = BTreeMap::new();
+    for s in &spread {
+        let bucket = (s.song_count.max(1) as u64)
+            .next_power_of_two()
+            .trailing_zeros();
+        let entry = buckets.entry(bucket).or_default();
+        entry.0 += s.hashes;
+        entry.1 += s.occurrences;
+    }
+    for (bucket, (hashes, occurrences)) in buckets {
+        let high = 1u64 << bucket;
+        let low = if bucket == 0 { 1 } else { (high >> 1) + 1 };
+        let range = if low == high {
+            high.to_string()
+        } else {
+            format!("{}-{}", low, high)
+        };
+        println!(
+            "   {:>12}  {:>10}  {:>6.2}%  {:>12}",
+            range,
+            hashes,
+            percent(hashes, total_hashes),
+            occurrences
+        );
+    }
+
+    let max_songs = match percentile {
+        Some(percentile) => {
+            let max_songs = percentile_song_count(&spread, percentile);
+            println!(
+                "\n   {}% of the distinct hashes are in at most {} songs",
+                percentile, max_songs
+            );
+            max_songs
+        }
+        None => max_songs,
+    };
+    let (crowded_hashes, crowded_fingerprints) = spread
+        .iter()
+        .filter(|s| s.song_count > max_songs)
+        .fold((0, 0), |(h, o), s| (h + s.hashes, o + s.occurrences));
+    println!(
+        "\n   In more than {} songs: {} hashes ({:.2}%) holding {} fingerprints ({:.2}%)",
+        max_songs,
+        crowded_hashes,
+        percent(crowded_hashes, total_hashes),
+        crowded_fingerprints,
+        percent(crowded_fingerprints, total_fingerprints)
+    );
+
+    let denied = db.denied_hashes(collection);
+    let suggested = db.widespread_hashes(collection, scheme, max_songs, limit);
+    if suggested.is_empty() {
+        println!("   ✅ No degenerate hashes to deny");
+    } else {
+        let new = suggested
+            .iter()
+            .filter(|h| !denied.contains(&(h.hash as u64)))
+            .count();
+        println!(
+            "   Suggested deny-list: {} hashes ({} not denied yet), most widespread:",
+            suggested.len(),
+            new
+        );
+        println!("   {:>20}  {:>6}  {:>11}", "hash", "songs", "occurrences");
+        for h in suggested.iter().take(10) {
+            println!(
+                "   {:>20}  {:>6}  {:>11}",
+                h.hash, h.song_count, h.occurrences
+            );
+        }
+    }
+
+    if apply {
+        match db.replace_denied_hashes(collection, scheme, &suggested) {
+            Ok(count) => println!("   💾 Deny-list now holds {} hashes", count),
+            Err(e) => eprintln!("❌ Failed to store the deny-list: {}", e),
+        }
+        println!(
+            "\nRebuild index files with `export --format index` to drop the denied hashes from them too"
+        );
+    } else {
+        println!("   Deny-list currently holds {} hashes", denied.len());
+    }
+}
+
+/// The smallest song count that at least `percentile` percent of the
+/// distinct hashes in `spread`, ordered by song count, do not exceed
+fn percentile_song_count(spread: &[HashSpread], percentile: f64) -> i64 {
+    let total: i64 = spread.iter().map(|s| s.hashes).sum();
+    let wanted = (total as f64 * percentile / 100.0).ceil() as i64;
+    let mut covered = 0;
+    for s in spread {
+        covered += s.hashes;
+        if covered >= wanted {
+            return s.song_count;
+        }
+    }
+    spread.last().map_or(0, |s| s.song_count)
+}
+
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_wildcards_are_matched_literally() {
+        assert_eq!(escape_like("Dreams"), "Dreams");
+        assert_eq!(escape_like("100%_pure\\"), "100\\%\\_pure\\\\");
+    }
+
+    #[test]
+    fn percentile_picks_the_song_count_covering_that_share_of_hashes() {
+        let spread: Vec<HashSpread> = [(1, 900), (2, 90), (10, 9), (200, 1)]
+            .into_iter()
+            .map(|(song_count, hashes)| HashSpread {
+                song_count,
+                hashes,
+                occurrences: song_count * hashes,
+            })
+            .collect();
+        assert_eq!(percentile_song_count(&spread, 90.0), 1);
+        assert_eq!(percentile_song_count(&spread, 99.0), 2);
+        assert_eq!(percentile_song_count(&spread, 99.5), 10);
+        assert_eq!(percentile_song_count(&spread, 100.0), 200);
+        assert_eq!(percentile_song_count(&[], 99.0), 0);
+    }
+}
@@ -0,0 +1,630 @@
+use crate::audio_processor::AudioProcessor;
+use crate::cancel::{CancelToken, POLL_INTERVAL};
+use crate::config::Config;
+use crate::db::{
+    bindings::Songs,
+    connector::{DB, MatchSource},
+    storage::{FingerprintStore, fingerprint_store},
+};
+use crate::dsp::Pipeline;
+use crate::fingerprint::{HashScheme, VoteResult, expand_query, vote_best_matches};
+use crate::meter::{LEVEL_SECS, SPECTRUM_LEN, SPECTRUM_MIN_FREQ, Spectrum, level_db, level_ratio};
+use crate::notifier::{MatchEvent, Notifier};
+use chrono::{DateTime, Local};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Bar, BarChart, BarGroup, Block, Gauge, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+/// Candidates matched per window
+const TOP_K: usize = 5;
+/// Rows the dashboard keeps, newest first
+const MAX_ROWS: usize = 100;
+/// How long the dashboard waits for a key between redraws
+const FRAME_INTERVAL: Duration = Duration::from_millis(50);
+/// Bands of the dashboard's spectrum
+const SPECTRUM_BANDS: usize = 48;
+
+/// Listen to the default input device and match its last `window_secs`
+/// every `hop_secs`, like `--recognise` in a loop. Each new song is printed,
+/// logged to the match history as `mic` and sent to the `[notify]` sinks.
+/// With `tui`, a dashboard shows the input level, its spectrum and the
+/// candidates instead, and nothing is logged until a match is confirmed.
+pub fn run_listen(
+    window_secs: f32,
+    hop_secs: f32,
+    collection: &str,
+    tui: bool,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    if window_secs <= 0.0 || hop_secs <= 0.0 {
+        eprintln!("Error: --window and --hop must be positive");
+        return;
+    }
+
+    let db = DB::new();
+    let matcher = Matcher::new(&db, collection, config);
+    let notifier = Arc::new(Notifier::from_config(&config.notify));
+    let audio_processor = AudioProcessor::new();
+    let mut mic = Microphone::open(&audio_processor, window_secs);
+
+    if tui {
+        let mut dashboard = Dashboard::new(collection, window_secs, &db, &notifier);
+        let terminal = ratatui::init();
+        let outcome = dashboard.run(terminal, &mut mic, &matcher, hop_secs, cancel);
+        ratatui::restore();
+        if let Err(e) = outcome {
+            eprintln!("❌ Dashboard failed: {}", e);
+        }
+        return;
+    }
+
+    println!(
+        "🎤 Listening in {}s windows every {}s, Ctrl-C to stop",
+        window_secs, hop_secs
+    );
+    let hop_len = ((hop_secs * mic.sample_rate as f32) as usize).max(1);
+    // Song being played, so it is logged once and not every hop
+    let mut playing: Option<u32> = None;
+    let mut since_match = 0;
+    while !cancel.is_cancelled() {
+        thread::sleep(POLL_INTERVAL);
+        since_match += mic.poll(&audio_processor);
+        if since_match < hop_len || !mic.is_full() {
+            continue;
+        }
+        since_match = 0;
+
+        let candidates = match matcher.best_matches(&mic.window(), mic.sample_rate) {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                eprintln!("⚠️ {}", e);
+                continue;
+            }
+        };
+        let Some(best) = candidates.into_iter().next() else {
+            playing = None;
+            continue;
+        };
+        if playing == Some(best.result.song_id) {
+            continue;
+        }
+        playing = Some(best.result.song_id);
+
+        let event = MatchEvent::new(&best.result, best.song.as_ref(), MatchSource::Mic, None);
+        println!(
+            "🎵 {} {} (confidence={:.2})",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            event.title,
+            best.result.confidence
+        );
+        if let Err(e) = db.record_match(&best.result, MatchSource::Mic, None) {
+            eprintln!("⚠️ Failed to record match history: {}", e);
+        }
+        notifier.notify(&event);
+    }
+    println!("⏹️ Stopped listening");
+}
+
+/// The default input device, downmixed to mono into a ring buffer of the
+/// last window. Recording stops when it is dropped.
+struct Microphone {
+    _stream: cpal::Stream,
+    incoming: Arc<Mutex<Vec<f32>>>,
+    /// Interleaved samples of an incomplete frame, kept for the next poll
+    pending: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    ring: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl Microphone {
+    fn open(audio_processor: &AudioProcessor, window_secs: f32) -> Self {
+        let (stream, incoming, config_cpal) = audio_processor.start_input_stream();
+        let sample_rate = config_cpal.sample_rate().0;
+        let capacity = (window_secs * sample_rate as f32) as usize;
+        Self {
+            _stream: stream,
+            incoming,
+            pending: Vec::new(),
+            channels: config_cpal.channels() as usize,
+            sample_rate,
+            ring: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Move what the device captured since the last poll into the ring
+    /// buffer, returning how many mono samples arrived
+    fn poll(&mut self, audio_processor: &AudioProcessor) -> usize {
+        self.pending.extend(self.incoming.lock().unwrap().drain(..));
+        let whole = self.pending.len() / self.channels * self.channels;
+        let mono = audio_processor.to_mono(&self.pending[..whole], self.channels);
+        self.pending.drain(..whole);
+
+        let arrived = mono.len();
+        self.ring.extend(mono);
+        if self.ring.len() > self.capacity {
+            self.ring.drain(..self.ring.len() - self.capacity);
+        }
+        arrived
+    }
+
+    fn is_full(&self) -> bool {
+        self.ring.len() >= self.capacity
+    }
+
+    fn window(&self) -> Vec<f32> {
+        self.ring.iter().copied().collect()
+    }
+
+    /// The newest `len` samples, or fewer right after opening
+    fn latest(&self, len: usize) -> Vec<f32> {
+        self.ring
+            .iter()
+            .skip(self.ring.len().saturating_sub(len))
+            .copied()
+            .collect()
+    }
+}
+
+/// One candidate of a window
+struct Candidate {
+    result: VoteResult,
+    song: Option<Songs>,
+}
+
+/// Matches windows of microphone audio through the `--recognise` pipeline
+struct Matcher<'a> {
+    db: &'a DB,
+    store: Box<dyn FingerprintStore>,
+    scheme: HashScheme,
+    pipeline: Pipeline,
+    config: &'a Config,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(db: &'a DB, collection: &str, config: &'a Config) -> Self {
+        let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+        Self {
+            db,
+            store: fingerprint_store(db, collection, config.fingerprint_version()),
+            scheme,
+            pipeline: Pipeline::for_recording(config, scheme),
+            config,
+        }
+    }
+
+    /// The [`TOP_K`] best matches of mono `samples`, best first
+    fn best_matches(&self, samples: &[f32], sample_rate: u32) -> Result<Vec<Candidate>, String> {
+        let fingerprints = self.store.without_denied(expand_query(
+            self.pipeline.fingerprints(samples.to_vec(), sample_rate),
+            self.scheme,
+            &self.config.matching,
+        ));
+        if fingerprints.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+        let db_matches_by_hash = self
+            .store
+            .fetch_matches_grouped_by_hash(&hash_vec)
+            .map_err(|e| format!("Fingerprint lookup failed: {}", e))?;
+        let results = vote_best_matches(
+            &fingerprints,
+            &db_matches_by_hash,
+            self.store.song_count(),
+            TOP_K,
+            &self.config.matching,
+        );
+
+        let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
+        let mut songs = self.db.fetch_songs(&song_ids);
+        Ok(results
+            .into_iter()
+            .map(|result| Candidate {
+                song: songs.remove(&(result.song_id as i32)),
+                result,
+            })
+            .collect())
+    }
+}
+
+/// What was done with a row on the dashboard
+#[derive(Clone, Copy, PartialEq)]
+enum RowStatus {
+    New,
+    Confirmed,
+    Saved,
+}
+
+/// A song heard since the dashboard started, with its latest match
+struct MatchRow {
+    candidate: Candidate,
+    first_heard: DateTime<Local>,
+    last_heard: DateTime<Local>,
+    /// Windows it was a candidate of
+    windows: usize,
+    status: RowStatus,
+}
+
+impl MatchRow {
+    fn title(&self) -> &str {
+        self.candidate
+            .song
+            .as_ref()
+            .map_or("<unknown>", |song| song.title.as_str())
+    }
+}
+
+/// Full-screen view of `listen --tui`: the input level and spectrum, and
+/// every candidate heard, which can be confirmed, saved or ignored
+struct Dashboard<'a> {
+    collection: &'a str,
+    window_secs: f32,
+    db: &'a DB,
+    notifier: &'a Arc<Notifier>,
+    spectrum: Spectrum,
+    rows: Vec<MatchRow>,
+    table: TableState,
+    /// Songs of the latest window
+    current: HashSet<u32>,
+    /// Songs ignored until the dashboard closes
+    ignored: HashSet<u32>,
+    /// Feedback of the last key press or failure
+    status: Option<String>,
+    matching: bool,
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(collection: &'a str, window_secs: f32, db: &'a DB, notifier: &'a Arc<Notifier>) -> Self {
+        Self {
+            collection,
+            window_secs,
+            db,
+            notifier,
+            spectrum: Spectrum::new(),
+            rows: Vec::new(),
+            table: TableState::default(),
+            current: HashSet::new(),
+            ignored: HashSet::new(),
+            status: None,
+            matching: false,
+        }
+    }
+
+    /// Redraw until `q`, Esc, Ctrl-C or `cancel`. Matching runs on a worker
+    /// thread, so the meters stay live while a window is looked up.
+    fn run(
+        &mut self,
+        mut terminal: DefaultTerminal,
+        mic: &mut Microphone,
+        matcher: &Matcher,
+        hop_secs: f32,
+        cancel: &CancelToken,
+    ) -> std::io::Result<()> {
+        let audio_processor = AudioProcessor::new();
+        let hop_len = ((hop_secs * mic.sample_rate as f32) as usize).max(1);
+        let sample_rate = mic.sample_rate;
+
+        thread::scope(|scope| {
+            let (windows, windows_rx) = mpsc::channel::<Vec<f32>>();
+            let (results_tx, results) = mpsc::channel();
+            scope.spawn(move || {
+                for window in windows_rx {
+                    if results_tx
+                        .send(matcher.best_matches(&window, sample_rate))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+
+            let mut since_match = 0;
+            while !cancel.is_cancelled() {
+                since_match += mic.poll(&audio_processor);
+                if since_match >= hop_len && mic.is_full() && !self.matching {
+                    since_match = 0;
+                    self.matching = windows.send(mic.window()).is_ok();
+                }
+                if let Ok(outcome) = results.try_recv() {
+                    self.matching = false;
+                    match outcome {
+                        Ok(candidates) => self.update(candidates),
+                        Err(e) => self.status = Some(format!("⚠️ {}", e)),
+                    }
+                }
+
+                terminal.draw(|frame| self.draw(frame, mic))?;
+                if event::poll(FRAME_INTERVAL)?
+                    && let Event::Key(key) = event::read()?
+                    && key.kind == KeyEventKind::Press
+                {
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => break,
+                        // Raw mode turns Ctrl-C into a key press
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            break;
+                        }
+                        KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                        KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                        KeyCode::Char('c') => self.confirm(),
+                        KeyCode::Char('s') => self.save(mic),
+                        KeyCode::Char('i') => self.ignore(),
+                        _ => {}
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Merge the candidates of a window into the rows, newest first
+    fn update(&mut self, candidates: Vec<Candidate>) {
+        let now = Local::now();
+        self.current = candidates.iter().map(|c| c.result.song_id).collect();
+        let selected = self.selected_song();
+        for candidate in candidates.into_iter().rev() {
+            let song_id = candidate.result.song_id;
+            if self.ignored.contains(&song_id) {
+                continue;
+            }
+            let row = match self
+                .rows
+                .iter()
+                .position(|row| row.candidate.result.song_id == song_id)
+            {
+                Some(i) => {
+                    let mut row = self.rows.remove(i);
+                    row.candidate = candidate;
+                    row.last_heard = now;
+                    row.windows += 1;
+                    row
+                }
+                None => MatchRow {
+                    candidate,
+                    first_heard: now,
+                    last_heard: now,
+                    windows: 1,
+                    status: RowStatus::New,
+                },
+            };
+            self.rows.insert(0, row);
+        }
+        self.rows.truncate(MAX_ROWS);
+
+        // Keep the selection on the same song as rows move
+        let position = selected.and_then(|song_id| {
+            self.rows
+                .iter()
+                .position(|row| row.candidate.result.song_id == song_id)
+        });
+        self.table
+            .select(position.or((!self.rows.is_empty()).then_some(0)));
+    }
+
+    fn selected_song(&self) -> Option<u32> {
+        self.table
+            .selected()
+            .and_then(|i| self.rows.get(i))
+            .map(|row| row.candidate.result.song_id)
+    }
+
+    fn selected_row(&mut self) -> Option<&mut MatchRow> {
+        self.table.selected().and_then(|i| self.rows.get_mut(i))
+    }
+
+    /// Log the selected match to the history and the `[notify]` sinks
+    fn confirm(&mut self) {
+        let db = self.db;
+        let notifier = Arc::clone(self.notifier);
+        let Some(row) = self.selected_row() else {
+            return;
+        };
+        let candidate = &row.candidate;
+        let status = match db.record_match(&candidate.result, MatchSource::Mic, None) {
+            Ok(()) => {
+                row.status = RowStatus::Confirmed;
+                let event = MatchEvent::new(
+                    &candidate.result,
+                    candidate.song.as_ref(),
+                    MatchSource::Mic,
+                    None,
+                );
+                // Sinks block on the network; keep redrawing meanwhile
+                thread::spawn(move || notifier.notify(&event));
+                format!("✅ Confirmed '{}'", row.title())
+            }
+            Err(e) => format!("⚠️ Failed to record match history: {}", e),
+        };
+        self.status = Some(status);
+    }
+
+    /// Write the current window to a WAV file, to replay it with
+    /// `--match --replay`
+    fn save(&mut self, mic: &Microphone) {
+        let path = format!("listen-{}.wav", Local::now().format("%Y%m%d-%H%M%S"));
+        let saved = AudioProcessor::new().write_wav_float(&path, &mic.window(), mic.sample_rate);
+        let status = match saved {
+            Ok(()) => match self.selected_row() {
+                Some(row) => {
+                    row.status = RowStatus::Saved;
+                    format!("💾 Saved the window of '{}' to {}", row.title(), path)
+                }
+                None => format!("💾 Saved the window to {}", path),
+            },
+            Err(e) => format!("⚠️ Failed to save {}: {}", path, e),
+        };
+        self.status = Some(status);
+    }
+
+    /// Drop the selected song and never show it again
+    fn ignore(&mut self) {
+        let Some(i) = self.table.selected().filter(|&i| i < self.rows.len()) else {
+            return;
+        };
+        let row = self.rows.remove(i);
+        self.ignored.insert(row.candidate.result.song_id);
+        self.status = Some(format!("🙈 Ignoring '{}'", row.title()));
+        if self.rows.is_empty() {
+            self.table.select(None);
+        } else {
+            self.table.select(Some(i.min(self.rows.len() - 1)));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, mic: &Microphone) {
+        let [meter, spectrum, matches, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(10),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let level = level_db(&mic.latest((LEVEL_SECS * mic.sample_rate as f32) as usize));
+        let color = match level {
+            l if l > -3.0 => Color::Red,
+            l if l > -12.0 => Color::Yellow,
+            _ => Color::Green,
+        };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(format!(
+                    " 🎤 Input {} Hz · collection '{}' ",
+                    mic.sample_rate, self.collection
+                )))
+                .gauge_style(Style::default().fg(color))
+                .ratio(level_ratio(level) as f64)
+                .label(format!("{:.0} dBFS", level)),
+            meter,
+        );
+
+        self.draw_spectrum(frame, spectrum, mic);
+        self.draw_matches(frame, matches);
+
+        let help = "↑/↓ select · c confirm · s save · i ignore · q quit";
+        let footer_text = match (&self.status, self.matching) {
+            (Some(status), _) => format!("{}  ·  {}", status, help),
+            (None, true) => format!("🔎 Matching the last {}s…  ·  {}", self.window_secs, help),
+            (None, false) => help.to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(footer_text).style(Style::default().fg(Color::DarkGray)),
+            footer,
+        );
+    }
+
+    /// The newest samples in log-spaced bands
+    fn draw_spectrum(&self, frame: &mut Frame, area: Rect, mic: &Microphone) {
+        let nyquist = mic.sample_rate as f32 / 2.0;
+        let bars: Vec<Bar> = self
+            .spectrum
+            .bands(&mic.latest(SPECTRUM_LEN), mic.sample_rate, SPECTRUM_BANDS)
+            .into_iter()
+            .map(|height| {
+                Bar::default()
+                    .value((height * 100.0) as u64)
+                    .text_value(String::new())
+            })
+            .collect();
+
+        let inner_width = area.width.saturating_sub(2) as usize;
+        let bar_width = (inner_width / SPECTRUM_BANDS).max(1) as u16;
+        frame.render_widget(
+            BarChart::default()
+                .block(Block::bordered().title(format!(
+                    " Spectrum {:.0} Hz – {:.1} kHz ",
+                    SPECTRUM_MIN_FREQ,
+                    nyquist / 1000.0
+                )))
+                .data(BarGroup::default().bars(&bars))
+                .bar_width(bar_width)
+                .bar_gap(0)
+                .max(100)
+                .bar_style(Style::default().fg(Color::Cyan)),
+            area,
+        );
+    }
+
+    fn draw_matches(&mut self, frame: &mut Frame, area: Rect) {
+        let header = Row::new([
+            "Last heard",
+            "Title",
+            "Artist",
+            "Score",
+            "Conf.",
+            "Offset",
+            "Windows",
+            "",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows: Vec<Row> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let result = &row.candidate.result;
+                let status = match row.status {
+                    RowStatus::New => "",
+                    RowStatus::Confirmed => "✅ confirmed",
+                    RowStatus::Saved => "💾 saved",
+                };
+                let style = if self.current.contains(&result.song_id) {
+                    Style::default().fg(Color::Green)
+                } else {
+                    Style::default().fg(Color::Gray)
+                };
+                Row::new([
+                    row.last_heard.format("%H:%M:%S").to_string(),
+                    row.title().to_string(),
+                    row.candidate
+                        .song
+                        .as_ref()
+                        .and_then(|song| song.artist.clone())
+                        .unwrap_or_default(),
+                    result.score.to_string(),
+                    format!("{:.2}", result.confidence),
+                    format!("{:.1}s", result.time_offset),
+                    row.windows.to_string(),
+                    status.to_string(),
+                ])
+                .style(style)
+            })
+            .collect();
+
+        let title = match self.rows.first() {
+            Some(row) if self.current.contains(&row.candidate.result.song_id) => format!(
+                " Now: {} (since {}) ",
+                row.title(),
+                row.first_heard.format("%H:%M:%S")
+            ),
+            _ => " Candidates ".to_string(),
+        };
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(10),
+                Constraint::Fill(3),
+                Constraint::Fill(2),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(12),
+            ],
+        )
+        .header(header)
+        .block(Block::bordered().title(title))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+}
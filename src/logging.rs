@@ -0,0 +1,24 @@
+use tracing::level_filters::LevelFilter;
+
+/// Level of progress output for `-v` given `verbose` times, or `--quiet`
+pub fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Send tracing events to stderr, leaving stdout to results. `json` writes
+/// one JSON object per event, for log collectors in server mode.
+pub fn init(level: LevelFilter, json: bool) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(level);
+    if json {
+        subscriber.json().with_current_span(true).init();
+    } else {
+        subscriber.without_time().with_target(false).init();
+    }
+}
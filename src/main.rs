@@ -1,66 +1,935 @@
-mod audio_processor;
-mod db;
-mod fft;
-mod fingerprint;
-mod schema;
-mod tester;
-
-use crate::db::connector::DB;
-use crate::fingerprint::{generate_audio_fingerprint, vote_best_matches};
-use crate::{audio_processor::AudioProcessor, fft::fft::CooleyTukeyFFT};
-use clap::{ArgGroup, Parser};
+use clap::{ArgAction, ArgGroup, Parser, Subcommand};
+use sabi::archive::ExportFormat;
+use sabi::auth::Scope;
+use sabi::cancel::CancelToken;
+use sabi::config::{Config, MatchingConfig};
+use sabi::cover::MatchMode;
+use sabi::db::{
+    bindings::Songs,
+    connector::{CollectionKind, DB, MatchSource, SongSort},
+    mmap_index::MmapIndex,
+    storage::{FingerprintStore, fingerprint_store, retain_songs, warn_outdated},
+};
+use sabi::fingerprint::{
+    FingerprintInfo, HashScheme, VoteResult, alignment_hits, concurrent_matches, expand_query,
+    generate_audio_fingerprint, offset_histogram, vote_best_matches,
+};
+use sabi::ingest::IngestTarget;
+use sabi::notifier::{MatchEvent, Notifier};
+use sabi::playback::{self, PlayMode};
+use sabi::scanner::format_timestamp;
+use sabi::spectrogram::SpectrogramFormat;
+use sabi::tester::{TestOptions, TuneGrid};
+use sabi::timings::{StageTimings, TimingsFormat};
+use sabi::visualization::{Colormap, FreqScale, HeatmapView, ImageFormat, VoteHistogram};
+use sabi::{
+    acoustid, archive, auth, bench, clip, cover, detect, fpfile, ingest, library, listen, logging,
+    monitor, scanner, server, spectrogram, tester, visualization,
+};
+use sabi::{audio_processor::AudioProcessor, dsp::Pipeline};
+use std::collections::HashMap;
+use tracing::info;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 #[command(group(
-    ArgGroup::new("mode")
+    ArgGroup::new("run_mode")
         .required(true)
         .args(&["ingest", "recognise", "match" , "random_test"]),
 ))]
 struct Args {
-    /// Ingest a file into the database
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Ingest a file, or every file in a directory, into the database
     #[arg(long)]
     ingest: bool,
 
+    /// Continue an interrupted directory ingest where it left off
+    #[arg(long, requires = "ingest")]
+    resume: bool,
+
+    /// Fingerprint and print statistics only, without touching the database
+    #[arg(long, requires = "ingest", conflicts_with = "resume")]
+    dry_run: bool,
+
+    /// Fill in missing artist, album, track and year from MusicBrainz (see `[enrich]` in the config)
+    #[arg(long, requires = "ingest", conflicts_with = "dry_run")]
+    enrich: bool,
+
     /// Recognise audio from microphone input
     #[arg(long)]
     recognise: bool,
 
+    /// Save the microphone capture to this WAV file, to replay it with --match --replay
+    #[arg(long, requires = "recognise")]
+    save_recording: Option<String>,
+
     /// Match a snippet file against DB
     #[arg(long, id = "match")]
     match_: bool,
 
-    /// Path to the audio file (required for --ingest and --match)
+    /// Path to the audio file or, for --ingest, a directory (required for --ingest and --match); `-` reads stdin for --match
     #[arg(short, long)]
     file: Option<String>,
 
     /// Run a test with random snippets from the songs directory
     #[arg(long)]
     random_test: bool,
+
+    /// Match against a prebuilt index file (`export --format index`) instead of the database
+    #[arg(long, requires = "match")]
+    index: Option<String>,
+
+    /// Decode this track of the file (0-based) instead of its default audio track
+    #[arg(long, requires = "match")]
+    track: Option<usize>,
+
+    /// Compare by fingerprint hashes, or by chroma to also find covers and live versions
+    #[arg(long, value_enum, default_value_t = MatchMode::default())]
+    #[arg(requires = "match", conflicts_with_all = ["index", "replay"])]
+    mode: MatchMode,
+
+    /// Treat --file as a capture saved by --save-recording and run it through the microphone pipeline
+    #[arg(long, requires = "match", conflicts_with_all = ["index", "track"])]
+    replay: bool,
+
+    #[command(flatten)]
+    results: ResultArgs,
+
+    #[command(flatten)]
+    plots: PlotArgs,
+
+    #[command(flatten)]
+    test: TestOptions,
+
+    /// Collection to ingest into or match against; collections never cross-match
+    #[arg(long, default_value = "default")]
+    collection: String,
+
+    /// Hash layout of a new collection; an existing collection keeps its own
+    #[arg(long, value_enum, requires = "ingest")]
+    hash_scheme: Option<HashScheme>,
+
+    /// Kind of a new collection, `short-reference` for jingles and ads; an existing collection keeps its own
+    #[arg(long, value_enum, requires = "ingest")]
+    collection_kind: Option<CollectionKind>,
+
+    /// Configuration file with pipeline tunables [default: ./sabi.toml if present]
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// Override a configuration value, e.g. `--set fft.chunk_size=4096` (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    overrides: Vec<String>,
+
+    /// Show more pipeline progress on stderr (`-vv` for per-batch detail)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only show warnings and errors on stderr
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Identify every song in a long recording (DJ mix, radio aircheck)
+    Scan {
+        /// Path to the recording
+        file: String,
+
+        /// Length of each matching window in seconds
+        #[arg(long, default_value_t = 10.0)]
+        window: f32,
+
+        /// Step between consecutive windows in seconds
+        #[arg(long, default_value_t = 5.0)]
+        hop: f32,
+
+        /// First second to scan
+        #[arg(long, default_value_t = 0.0)]
+        start: f32,
+
+        /// Last second to scan [default: end of the recording]
+        #[arg(long)]
+        end: Option<f32>,
+
+        /// Collection to match against
+        #[arg(long, default_value = "default")]
+        collection: String,
+    },
+
+    /// List every airing of the jingles, ads or idents of a short-reference
+    /// collection in a long recording
+    Detect {
+        /// Path to the recording
+        file: String,
+
+        /// Short-reference collection holding the clips
+        #[arg(long, default_value = "jingles")]
+        collection: String,
+    },
+
+    /// Follow an internet radio stream and log every song it plays
+    Monitor {
+        /// Stream URL (Icecast/Shoutcast, HLS, or anything ffmpeg can open)
+        url: String,
+
+        /// Length of each matching window in seconds
+        #[arg(long, default_value_t = 10.0)]
+        window: f32,
+
+        /// Step between consecutive windows in seconds
+        #[arg(long, default_value_t = 5.0)]
+        hop: f32,
+
+        /// Collection to match against
+        #[arg(long, default_value = "default")]
+        collection: String,
+    },
+
+    /// Listen to the microphone continuously and report every song heard
+    Listen {
+        /// Length of each matching window in seconds
+        #[arg(long, default_value_t = 10.0)]
+        window: f32,
+
+        /// Step between consecutive windows in seconds
+        #[arg(long, default_value_t = 2.0)]
+        hop: f32,
+
+        /// Collection to match against
+        #[arg(long, default_value = "default")]
+        collection: String,
+
+        /// Show a live dashboard (level meter, spectrum, candidates) and log matches only when confirmed
+        #[arg(long)]
+        tui: bool,
+    },
+
+    /// Delete songs and all their fingerprints
+    Delete {
+        /// Id of the song to delete
+        #[arg(long, required_unless_present = "title")]
+        id: Option<i32>,
+
+        /// Case-insensitive SQL LIKE pattern on the title, e.g. "%hey jude%"
+        #[arg(long)]
+        title: Option<String>,
+
+        /// Only print the number of rows that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove songs left without fingerprints by a failed ingest
+    Cleanup {
+        /// Only list the orphaned songs
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Find songs ingested more than once (same recording, different encodes)
+    Dedupe {
+        /// Collection to search for duplicates
+        #[arg(long, default_value = "default")]
+        collection: String,
+
+        /// Share of aligned fingerprints from which two songs count as copies
+        #[arg(long, default_value_t = fpfile::SAME_RECORDING_SIMILARITY)]
+        min_similarity: f32,
+
+        /// Fold each cluster into its earliest-ingested song, keeping tags and match history
+        #[arg(long)]
+        merge: bool,
+
+        /// Only list the clusters that would be merged
+        #[arg(long, requires = "merge")]
+        dry_run: bool,
+    },
+
+    /// Rebuild the fingerprints of songs whose source files are in a directory
+    Reingest {
+        /// Directory holding the original audio files, matched by checksum
+        dir: String,
+
+        /// Only rebuild songs fingerprinted with an older parameter set
+        #[arg(long)]
+        outdated: bool,
+    },
+
+    /// Write all songs and fingerprints to a library archive or index file
+    Export {
+        /// File to create
+        #[arg(long)]
+        out: String,
+
+        /// Archive for `import`, or a read-only index for `--match --index`
+        #[arg(long, value_enum, default_value_t = ExportFormat::Archive)]
+        format: ExportFormat,
+
+        /// Only export songs in this collection
+        #[arg(long)]
+        collection: Option<String>,
+    },
+
+    /// Load songs and fingerprints from a library archive
+    Import {
+        /// Archive file written by `export`
+        file: String,
+
+        /// Import every song into this collection instead of its original one
+        #[arg(long)]
+        collection: Option<String>,
+    },
+
+    /// Create or upgrade the database schema
+    Migrate,
+
+    /// Serve recognition over HTTP (`POST /recognise` with an audio file body)
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        addr: String,
+
+        /// Also serve gRPC on this address (needs `--features grpc`)
+        #[arg(long)]
+        grpc_addr: Option<String>,
+
+        /// Log one JSON object per event instead of plain text
+        #[arg(long)]
+        log_json: bool,
+    },
+
+    /// Print library statistics (song/fingerprint counts, hash hotspots, index size)
+    Stats {
+        /// Number of most common hashes to show
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        top_hashes: u32,
+    },
+
+    /// Analyze hash collisions among the songs of a collection and suggest
+    /// a deny-list of degenerate hashes for its lookups to ignore
+    AnalyzeIndex {
+        /// Collection to analyze
+        #[arg(long, default_value = "default")]
+        collection: String,
+
+        /// Hashes stored for more than this many songs count as degenerate
+        #[arg(long, default_value_t = 50)]
+        max_songs: i64,
+
+        /// Hashes stored for more songs than this percentile of distinct
+        /// hashes count as degenerate, e.g. 99.9; replaces --max-songs
+        #[arg(long)]
+        percentile: Option<f64>,
+
+        /// Most widespread degenerate hashes suggested
+        #[arg(long, default_value_t = 1000)]
+        limit: i64,
+
+        /// Store the suggestion as the deny-list, replacing the previous one
+        #[arg(long, conflicts_with = "clear")]
+        apply: bool,
+
+        /// Empty the deny-list
+        #[arg(long)]
+        clear: bool,
+    },
+
+    /// Show recently recognised songs
+    History {
+        /// Number of events to show
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+        limit: u32,
+
+        /// Only show events from this source
+        #[arg(long, value_enum)]
+        source: Option<MatchSource>,
+    },
+
+    /// Show the duration and format of an audio file
+    Info {
+        /// Path to the audio file
+        file: String,
+
+        /// Describe this track (0-based) instead of the default audio track
+        #[arg(long)]
+        track: Option<usize>,
+    },
+
+    /// Cut a range out of an audio file into a WAV file
+    Clip {
+        /// Path to the audio file
+        file: String,
+
+        /// Second the clip starts at
+        #[arg(long, default_value_t = 0.0)]
+        start: f64,
+
+        /// Length of the clip in seconds
+        #[arg(long, default_value_t = 10.0)]
+        duration: f64,
+
+        /// WAV file to create
+        #[arg(long)]
+        out: String,
+
+        /// Resample to this rate in Hz [default: the rate of the file]
+        #[arg(long)]
+        sample_rate: Option<u32>,
+
+        /// Cut from this track (0-based) instead of the default audio track
+        #[arg(long)]
+        track: Option<usize>,
+    },
+
+    /// Time each recognition stage on an audio file
+    Bench {
+        /// Path to the audio file
+        file: String,
+
+        /// Number of timed runs
+        #[arg(long, default_value_t = 3)]
+        runs: usize,
+
+        /// Collection to match against
+        #[arg(long, default_value = "default")]
+        collection: String,
+    },
+
+    /// Grid-search pipeline parameters on the songs in a directory
+    Tune {
+        /// Directory of songs to ingest and draw labeled snippets from
+        dir: String,
+
+        #[command(flatten)]
+        grid: TuneGrid,
+
+        /// Random snippets tested per song
+        #[arg(long, default_value_t = 3)]
+        snippets: u32,
+    },
+
+    /// Write the fingerprints of an audio file to a standalone fingerprint file
+    Fingerprint {
+        /// Path to the audio file
+        file: String,
+
+        /// Fingerprint file to create [default: the audio file name with an .fp extension]
+        #[arg(long)]
+        out: Option<String>,
+
+        /// Hash layout; must match the collection the fingerprints are matched against
+        #[arg(long, value_enum, default_value_t = HashScheme::default())]
+        hash_scheme: HashScheme,
+
+        /// Print a Chromaprint fingerprint as `fpcalc` does instead of writing a fingerprint file
+        #[arg(long, conflicts_with_all = ["out", "hash_scheme"])]
+        chromaprint: bool,
+    },
+
+    /// Identify a song missing from the library through the AcoustID web
+    /// service, printing its MusicBrainz metadata
+    Identify {
+        /// Path to the audio file
+        file: String,
+
+        /// AcoustID application API key (register one at https://acoustid.org/new-application)
+        #[arg(long)]
+        acoustid_key: String,
+    },
+
+    /// Score the similarity of the audio behind two fingerprint files
+    Compare {
+        /// First fingerprint file
+        a: String,
+
+        /// Second fingerprint file
+        b: String,
+    },
+
+    /// Draw the spectrogram peaks of an audio file as a heatmap
+    Visualize {
+        /// Path to the audio file
+        file: String,
+
+        /// Image file to write [default: spectro.svg or spectro.png]
+        #[arg(long)]
+        out: Option<String>,
+
+        /// Image format; PNG stays small for long files
+        #[arg(long, value_enum, default_value_t = ImageFormat::Svg)]
+        format: ImageFormat,
+
+        /// Color scale of the heatmap
+        #[arg(long, value_enum, default_value_t = Colormap::Viridis)]
+        colormap: Colormap,
+
+        /// Stack the waveform above the heatmap to spot silence and clipping (SVG only)
+        #[arg(long)]
+        waveform: bool,
+
+        /// First second to draw
+        #[arg(long, default_value_t = 0.0)]
+        start: f32,
+
+        /// Last second to draw [default: end of the file]
+        #[arg(long)]
+        end: Option<f32>,
+
+        /// Bottom of the frequency axis in Hz [default: fft.min_freq]
+        #[arg(long)]
+        min_freq: Option<f32>,
+
+        /// Top of the frequency axis in Hz [default: fft.max_freq]
+        #[arg(long)]
+        max_freq: Option<f32>,
+
+        /// Spacing of the frequency axis
+        #[arg(long, value_enum, default_value_t = FreqScale::Linear)]
+        freq_scale: FreqScale,
+
+        /// Mark the picked peaks (the fingerprint constellation)
+        #[arg(long)]
+        peaks: bool,
+
+        /// Draw the anchor → target pairing lines within the target zone
+        #[arg(long)]
+        pairs: bool,
+    },
+
+    /// Write the full magnitude spectrogram of a file as a matrix, for
+    /// analysis in other tools
+    ExportSpectrogram {
+        /// Path to the audio file
+        file: String,
+
+        /// File to write [default: spectrogram.csv or spectrogram.npy]
+        #[arg(long)]
+        out: Option<String>,
+
+        /// Matrix format; NPY also writes the time and frequency axes beside it
+        #[arg(long, value_enum, default_value_t = SpectrogramFormat::Csv)]
+        format: SpectrogramFormat,
+
+        /// Sum the bins into this many mel bands between fft.min_freq and fft.max_freq
+        #[arg(long)]
+        mel: Option<usize>,
+    },
+
+    /// Browse the songs in the library
+    Songs {
+        #[command(subcommand)]
+        action: SongsCommand,
+    },
+
+    /// Manage the API keys `serve` accepts
+    ApiKey {
+        #[command(subcommand)]
+        action: ApiKeyCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SongsCommand {
+    /// List all ingested songs
+    List {
+        #[command(flatten)]
+        listing: ListingArgs,
+    },
+
+    /// Search songs by title, artist or album
+    Search {
+        /// Text to look for (case-insensitive)
+        query: String,
+
+        #[command(flatten)]
+        listing: ListingArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ApiKeyCommand {
+    /// Create a key and print it; only its hash is stored
+    Create {
+        /// Name to list and revoke the key by
+        name: String,
+
+        /// What the key may do (repeatable)
+        #[arg(long = "scope", value_enum, default_values_t = [Scope::Recognise])]
+        scopes: Vec<Scope>,
+    },
+
+    /// List the keys with their scopes
+    List,
+
+    /// Delete a key, refusing it from then on
+    Revoke {
+        /// Name of the key
+        name: String,
+    },
+}
+
+/// Which matches `--match` and `--recognise` print, and what else
+#[derive(clap::Args, Debug)]
+struct ResultArgs {
+    /// Number of matches to print, best first
+    #[arg(long, default_value_t = 5, conflicts_with_all = ["ingest", "random_test"])]
+    top_k: usize,
+
+    /// Drop matches with fewer aligned hash hits [default: matching.min_score]
+    #[arg(long, conflicts_with_all = ["ingest", "random_test"])]
+    min_score: Option<usize>,
+
+    /// Drop matches below this confidence, 0 to 1 [default: matching.min_confidence]
+    #[arg(long, conflicts_with_all = ["ingest", "random_test"])]
+    min_confidence: Option<f32>,
+
+    /// Report every song above the cutoff as heard at once, e.g. in a mashup
+    /// or a transition, rating each on its own [default: matching.multi_match]
+    #[arg(long, conflicts_with_all = ["ingest", "random_test"])]
+    multi: bool,
+
+    /// Play the rest of the best match from where the query ended, or with
+    /// `print` only show how; see `[playback]`
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "play")]
+    #[arg(conflicts_with_all = ["ingest", "random_test"])]
+    play_from_match: Option<PlayMode>,
+
+    /// Print the time spent in each stage of --match or --recognise, as text or JSON
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "text")]
+    #[arg(conflicts_with_all = ["ingest", "random_test"])]
+    timings: Option<TimingsFormat>,
+}
+
+/// Diagnostic plots written by `--match`, in the format of the file extension
+#[derive(clap::Args, Debug)]
+struct PlotArgs {
+    /// Plot query vs. song time of the top candidate's hash hits to this file (.svg or .png)
+    #[arg(long, requires = "match")]
+    debug_plot: Option<String>,
+
+    /// Plot the offset-bin votes of the top candidates to this file (.svg or .png)
+    #[arg(long, requires = "match")]
+    vote_plot: Option<String>,
+
+    /// Number of candidates in --vote-plot
+    #[arg(long, default_value_t = 3, requires = "vote_plot")]
+    plot_candidates: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListingArgs {
+    /// Column to sort by
+    #[arg(long, value_enum, default_value_t = SongSort::Id)]
+    sort: SongSort,
+
+    /// Sort in descending order
+    #[arg(long)]
+    desc: bool,
+
+    /// Songs per page
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    limit: u32,
+
+    /// Page number, starting at 1
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    page: u32,
 }
 
 fn main() {
     let args = Args::parse();
 
+    let log_json = matches!(args.command, Some(Command::Serve { log_json: true, .. }));
+    logging::init(logging::level(args.verbose, args.quiet), log_json);
+
+    let mut config = match Config::load(args.config.as_deref(), &args.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(min_score) = args.results.min_score {
+        config.matching.min_score = min_score;
+    }
+    if let Some(min_confidence) = args.results.min_confidence {
+        config.matching.min_confidence = min_confidence;
+    }
+    if args.results.multi {
+        config.matching.multi_match = true;
+    }
+
+    if let Some(command) = args.command {
+        match command {
+            Command::Scan {
+                file,
+                window,
+                hop,
+                start,
+                end,
+                collection,
+            } => scanner::run_scan(&file, window, hop, start, end, &collection, &config),
+            Command::Detect { file, collection } => detect::run_detect(&file, &collection, &config),
+            Command::Monitor {
+                url,
+                window,
+                hop,
+                collection,
+            } => monitor::run_monitor(
+                &url,
+                window,
+                hop,
+                &collection,
+                &config,
+                &CancelToken::on_signals(),
+            ),
+            Command::Listen {
+                window,
+                hop,
+                collection,
+                tui,
+            } => listen::run_listen(
+                window,
+                hop,
+                &collection,
+                tui,
+                &config,
+                &CancelToken::on_signals(),
+            ),
+            Command::Delete { id, title, dry_run } => {
+                library::delete_songs(&DB::new(), id, title.as_deref(), dry_run)
+            }
+            Command::Cleanup { dry_run } => library::clean_orphans(&DB::new(), dry_run),
+            Command::Dedupe {
+                collection,
+                min_similarity,
+                merge,
+                dry_run,
+            } => library::dedupe(
+                &DB::new(),
+                &collection,
+                min_similarity,
+                merge,
+                dry_run,
+                &config,
+            ),
+            Command::Reingest { dir, outdated } => library::reingest(
+                &DB::new(),
+                &dir,
+                outdated,
+                &config,
+                &CancelToken::on_signals(),
+            ),
+            Command::Export {
+                out,
+                format,
+                collection,
+            } => archive::export_library(&out, format, collection.as_deref()),
+            Command::Import { file, collection } => {
+                archive::import_library(&file, collection.as_deref())
+            }
+            Command::Migrate => {
+                // DB::new applies pending migrations on connect
+                DB::new();
+                println!("✅ Database schema is up to date");
+            }
+            Command::Serve {
+                addr, grpc_addr, ..
+            } => server::run_server(&addr, grpc_addr.as_deref(), config),
+            Command::Stats { top_hashes } => library::print_stats(&DB::new(), top_hashes, &config),
+            Command::AnalyzeIndex {
+                collection,
+                max_songs,
+                percentile,
+                limit,
+                apply,
+                clear,
+            } => library::analyze_index(
+                &DB::new(),
+                &collection,
+                max_songs,
+                percentile,
+                limit,
+                apply,
+                clear,
+            ),
+            Command::History { limit, source } => library::print_history(&DB::new(), source, limit),
+            Command::Info { file, track } => print_audio_info(&file, track),
+            Command::Clip {
+                file,
+                start,
+                duration,
+                out,
+                sample_rate,
+                track,
+            } => clip::run_clip(&file, start, duration, &out, sample_rate, track),
+            Command::Bench {
+                file,
+                runs,
+                collection,
+            } => bench::run_bench(&file, &collection, runs, &config),
+            Command::Tune {
+                dir,
+                grid,
+                snippets,
+            } => tester::run_tune(&dir, &grid, snippets, &config, &CancelToken::on_signals()),
+            Command::Fingerprint {
+                chromaprint: true,
+                file,
+                ..
+            } => acoustid::print_chromaprint(&file, &config),
+            Command::Fingerprint {
+                file,
+                out,
+                hash_scheme,
+                ..
+            } => fpfile::run_fingerprint(&file, out.as_deref(), hash_scheme, &config),
+            Command::Identify { file, acoustid_key } => {
+                acoustid::run_identify(&file, &acoustid_key, &config)
+            }
+            Command::Compare { a, b } => fpfile::run_compare(&a, &b, &config),
+            Command::Visualize {
+                file,
+                out,
+                format,
+                colormap,
+                waveform,
+                start,
+                end,
+                min_freq,
+                max_freq,
+                freq_scale,
+                peaks,
+                pairs,
+            } => visualization::run_visualize(
+                &file,
+                out.as_deref(),
+                format,
+                colormap,
+                waveform,
+                &HeatmapView {
+                    start,
+                    end,
+                    min_freq: min_freq.unwrap_or(config.fft.min_freq),
+                    max_freq: max_freq.unwrap_or(config.fft.max_freq),
+                    freq_scale,
+                    show_peaks: peaks,
+                    pairs: pairs.then_some((
+                        config.fingerprint.min_target_zone_dist,
+                        config.fingerprint.max_target_zone,
+                    )),
+                },
+                &config,
+            ),
+            Command::ExportSpectrogram {
+                file,
+                out,
+                format,
+                mel,
+            } => spectrogram::run_export_spectrogram(&file, out.as_deref(), format, mel, &config),
+            Command::ApiKey { action } => {
+                let db = DB::new();
+                match action {
+                    ApiKeyCommand::Create { name, scopes } => {
+                        auth::run_create_key(&db, &name, &scopes)
+                    }
+                    ApiKeyCommand::List => auth::run_list_keys(&db),
+                    ApiKeyCommand::Revoke { name } => auth::run_revoke_key(&db, &name),
+                }
+            }
+            Command::Songs { action } => match action {
+                SongsCommand::List { listing } => library::list_songs(
+                    &DB::new(),
+                    None,
+                    listing.sort,
+                    listing.desc,
+                    listing.limit,
+                    listing.page,
+                ),
+                SongsCommand::Search { query, listing } => library::list_songs(
+                    &DB::new(),
+                    Some(&query),
+                    listing.sort,
+                    listing.desc,
+                    listing.limit,
+                    listing.page,
+                ),
+            },
+        }
+        return;
+    }
+
     if args.ingest {
         if let Some(file) = args.file {
-            ingest_file(file);
+            if args.dry_run {
+                ingest::run_dry_run(
+                    &file,
+                    args.hash_scheme,
+                    args.collection_kind.unwrap_or_default(),
+                    &config,
+                    &CancelToken::on_signals(),
+                );
+            } else {
+                ingest::run_ingest(
+                    &DB::new(),
+                    &file,
+                    &IngestTarget {
+                        collection: &args.collection,
+                        hash_scheme: args.hash_scheme,
+                        kind: args.collection_kind,
+                    },
+                    args.resume,
+                    args.enrich,
+                    &config,
+                    &CancelToken::on_signals(),
+                );
+            }
         } else {
             eprintln!("Error: --ingest requires --file <path>");
             std::process::exit(1);
         }
     } else if args.recognise {
-        ingest_audio();
+        ingest_audio(
+            &args.collection,
+            args.save_recording.as_deref(),
+            &args.results,
+            &config,
+            &CancelToken::on_signals(),
+        );
     } else if args.match_ {
         if let Some(file) = args.file {
-            match_file(file);
+            if args.replay {
+                replay_recording(file, &args.collection, &args.results, &config);
+            } else if args.mode == MatchMode::Cover {
+                cover::run_cover_match(&file, args.track, &args.collection, &config);
+            } else {
+                match_file(
+                    file,
+                    args.track,
+                    args.index,
+                    &args.plots,
+                    &args.collection,
+                    &args.results,
+                    &config,
+                );
+            }
         } else {
             eprintln!("Error: --match requires --file <path>");
             std::process::exit(1);
         }
     } else if args.random_test {
         if let Some(dir) = args.file {
-            tester::run_random_snippet_test(&dir);
+            tester::run_random_snippet_test(
+                &dir,
+                &args.collection,
+                &args.test,
+                &config,
+                &CancelToken::on_signals(),
+            );
         } else {
             eprintln!("Error: --random-test requires --file <songs_dir>");
             std::process::exit(1);
@@ -68,171 +937,547 @@ fn main() {
     }
 }
 
+/// Print the duration and format of an audio file
+fn print_audio_info(file_name: &str, track: Option<usize>) {
+    let audio_processor = AudioProcessor::new().with_track(track);
+    let info = match audio_processor.probe_info(file_name.to_string()) {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("❌ Cannot read {}: {}", file_name, e);
+            return;
+        }
+    };
+    println!("File:        {}", file_name);
+    println!(
+        "Duration:    {} ({:.2}s)",
+        format_timestamp(info.duration as f32),
+        info.duration
+    );
+    println!("Sample rate: {} Hz", info.sample_rate);
+    println!("Channels:    {}", info.channels);
+    println!("Codec:       {}", info.codec);
+    println!("Track:       {} of {}", info.track, info.track_count);
+}
+
+/// Summarize the tag metadata of a song, e.g. "Artist — Album (track 3, 1977, 4:17)"
+fn describe_song_metadata(song: &Songs) -> Option<String> {
+    let mut extras = Vec::new();
+    if let Some(track) = song.track_number {
+        extras.push(format!("track {}", track));
+    }
+    if let Some(year) = song.year {
+        extras.push(year.to_string());
+    }
+    if let Some(duration) = song.duration {
+        extras.push(format_timestamp(duration as f32));
+    }
+    if let Some(bpm) = song.bpm {
+        extras.push(format!("{:.0} BPM", bpm));
+    }
+    if let Some(key) = &song.musical_key {
+        extras.push(key.clone());
+    }
+
+    let names: Vec<&str> = [song.artist.as_deref(), song.album.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if names.is_empty() && extras.is_empty() {
+        return None;
+    }
+
+    let mut summary = names.join(" — ");
+    if !extras.is_empty() {
+        if !summary.is_empty() {
+            summary.push(' ');
+        }
+        summary.push_str(&format!("({})", extras.join(", ")));
+    }
+    Some(summary)
+}
+
+/// Describe where in the database song a match was found, flagging
+/// positions that fall outside the song's stored duration.
+fn describe_match_position(result: &VoteResult, song: Option<&Songs>) -> String {
+    // A little slack for offset-bin rounding and the final STFT frame
+    const DURATION_TOLERANCE_SECS: f32 = 1.0;
+
+    let title = song.map_or("<unknown>", |s| s.title.as_str());
+    let position = format!(
+        "matched at {}–{} of '{}'",
+        format_timestamp(result.match_start),
+        format_timestamp(result.match_end),
+        title
+    );
+
+    let duration = song.and_then(|s| s.duration).map(|d| d as f32);
+    let out_of_range = result.match_start < -DURATION_TOLERANCE_SECS
+        || duration.is_some_and(|d| result.match_end > d + DURATION_TOLERANCE_SECS);
+
+    if out_of_range {
+        format!(
+            "{} ⚠️ outside the song's duration, likely spurious",
+            position
+        )
+    } else {
+        position
+    }
+}
+
 /// Decode a snippet file and try to match against DB
-fn match_file(file_name: String) {
-    let audio_processor = AudioProcessor::new();
-    let fft = CooleyTukeyFFT::default();
+fn match_file(
+    file_name: String,
+    track: Option<usize>,
+    index_path: Option<String>,
+    plots: &PlotArgs,
+    collection: &str,
+    output: &ResultArgs,
+    config: &Config,
+) {
+    let audio_processor = AudioProcessor::from_config(&config.decode).with_track(track);
+    let mut timings = StageTimings::default();
 
     // Decode snippet
-    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name.clone());
-    println!(
+    let decoded = StageTimings::time(&mut timings.decode, || {
+        audio_processor.decode_file(&file_name)
+    });
+    let (audio_samples, sample_rate) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("❌ Cannot decode {}: {}", file_name, e);
+            return;
+        }
+    };
+    info!(
         "Loaded snippet {} samples @ {} Hz",
         audio_samples.len(),
         sample_rate
     );
+    let query_secs = audio_samples.len() as f32 / sample_rate as f32;
 
-    // Filter and resample to target
-    let filtered_samples =
-        audio_processor.apply_low_pass_filter(&audio_samples, sample_rate, 5000.0);
-    let rec_resampled = audio_processor.resample_linear(
-        &filtered_samples,
+    // Up to the peaks; hashing waits for the collection's scheme
+    let fft_distribution = Pipeline::for_query(config, HashScheme::default()).peak_frames_timed(
+        audio_samples,
         sample_rate,
-        AudioProcessor::TARGET_SAMPLE_RATE,
+        |stage, elapsed| timings.observe(stage, elapsed),
     );
 
-    // FFT distribution
-    let fft_distribution =
-        fft.generate_freq_time_distribution(rec_resampled, AudioProcessor::TARGET_SAMPLE_RATE);
-
-    // Fingerprint
-    let fingerprints = generate_audio_fingerprint(&fft_distribution);
-    println!("Generated {} fingerprints", fingerprints.len());
-
-    // Query DB
-    let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
-    let mut db = DB::new();
-    let db_matches_by_hash = db.fetch_matches_grouped_by_hash(&hash_vec);
+    // Fingerprint with the collection's hash scheme, then query the
+    // prebuilt index if given, else the DB
+    let (fingerprints, db_matches_by_hash, song_count, db, index_songs) = match index_path {
+        Some(path) => {
+            let index = match MmapIndex::open(&path) {
+                Ok(index) => index,
+                Err(e) => {
+                    eprintln!("❌ Cannot open {}: {}", path, e);
+                    return;
+                }
+            };
+            let scheme = index.collection_hash_scheme(collection);
+            let fingerprints = StageTimings::time(&mut timings.fingerprint, || {
+                expand_query(
+                    generate_audio_fingerprint(&fft_distribution, scheme, &config.fingerprint),
+                    scheme,
+                    &config.matching,
+                )
+            });
+            let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+            let (matches, song_ids) = StageTimings::time(&mut timings.db_fetch, || {
+                let mut matches = index
+                    .fetch_matches_grouped_by_hash(&hash_vec)
+                    .expect("Fingerprint lookup failed");
+                let song_ids = index.song_ids_at_version(collection, config.fingerprint_version());
+                retain_songs(&mut matches, &song_ids);
+                (matches, song_ids)
+            });
+            warn_outdated(index.outdated_song_count(collection, config.fingerprint_version()));
+            (
+                fingerprints,
+                matches,
+                Some(song_ids.len()),
+                None,
+                index.into_songs(),
+            )
+        }
+        None => {
+            let db = DB::new();
+            let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+            let store = fingerprint_store(&db, collection, config.fingerprint_version());
+            let fingerprints = StageTimings::time(&mut timings.fingerprint, || {
+                store.without_denied(expand_query(
+                    generate_audio_fingerprint(&fft_distribution, scheme, &config.fingerprint),
+                    scheme,
+                    &config.matching,
+                ))
+            });
+            let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+            let (matches, song_count) = StageTimings::time(&mut timings.db_fetch, || {
+                let matches = store
+                    .fetch_matches_grouped_by_hash(&hash_vec)
+                    .expect("Fingerprint lookup failed");
+                (matches, store.song_count())
+            });
+            (fingerprints, matches, song_count, Some(db), HashMap::new())
+        }
+    };
+    info!("Generated {} fingerprints", fingerprints.len());
+    timings.hashes = fingerprints.len();
+    timings.rows = db_matches_by_hash.values().map(Vec::len).sum();
 
     // Vote
-    let results = vote_best_matches(&fingerprints, &db_matches_by_hash, 5);
+    let results = StageTimings::time(&mut timings.vote, || {
+        vote_best_matches(
+            &fingerprints,
+            &db_matches_by_hash,
+            song_count,
+            output.top_k,
+            &config.matching,
+        )
+    });
+
+    if plots.debug_plot.is_some() || plots.vote_plot.is_some() {
+        write_match_plots(
+            plots,
+            &fingerprints,
+            &db_matches_by_hash,
+            song_count,
+            config,
+        );
+    }
 
     if results.is_empty() {
         println!("❌ No matches found");
     } else {
-        // Fetch titles
+        // Fetch song rows
         let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
-        let titles = db.fetch_song_titles(&song_ids);
+        let songs = match &db {
+            Some(db) => {
+                if let Err(e) = db.record_match(&results[0], MatchSource::File, Some(&file_name)) {
+                    eprintln!("⚠️ Failed to record match history: {}", e);
+                }
+                db.fetch_songs(&song_ids)
+            }
+            None => index_songs,
+        };
+        print_matches(
+            &results,
+            &songs,
+            concurrent_matches(&results, &config.matching),
+        );
+        play_from_match(&results, &songs, query_secs, output, config);
+    }
+    if let Some(format) = output.timings {
+        println!("{}", timings.render(format));
+    }
+}
 
+/// Print ranked matches with the songs they are of, or, when `concurrent`,
+/// the songs heard at once along with where in the query each was heard
+fn print_matches(results: &[VoteResult], songs: &HashMap<i32, Songs>, concurrent: bool) {
+    if concurrent {
+        println!("🎛️ Multiple concurrent matches detected:");
+    } else {
         println!("✅ Top matches:");
-        for r in results {
-            let title = titles
-                .get(&(r.song_id as i32))
-                .cloned()
-                .unwrap_or_else(|| "<unknown>".to_string());
+    }
+    for (rank, r) in results.iter().enumerate() {
+        let song = songs.get(&(r.song_id as i32));
+        let title = song.map_or("<unknown>", |s| s.title.as_str());
+        println!(
+            "  #{} id={} title=\"{}\" score={} confidence={:.2} coverage={:.0}% offset={:.3}s matched={:.1}s",
+            rank + 1,
+            r.song_id,
+            title,
+            r.score,
+            r.confidence,
+            r.coverage * 100.0,
+            r.refined_offset,
+            r.match_end - r.match_start
+        );
+        if let Some(details) = song.and_then(describe_song_metadata) {
+            println!("    {}", details);
+        }
+        println!("    {}", describe_match_position(r, song));
+        if concurrent {
+            let query_time = |song_time: f32| (song_time - r.time_offset) / r.speed;
             println!(
-                "  id={} title=\"{}\" score={} offset={:.2}s",
-                r.song_id, title, r.score, r.time_offset
+                "    heard at {:.1}s–{:.1}s of the query",
+                query_time(r.match_start),
+                query_time(r.match_end)
             );
         }
+        if r.speed != 1.0 {
+            println!("    played at {:.1}% speed", r.speed * 100.0);
+        }
     }
 }
 
-/// Ingest an audio file using in-memory processing
-fn ingest_file(file_name: String) {
-    let song_name = file_name
-        .rsplit('/')
-        .next()
-        .unwrap_or("Unknown Song")
-        .to_string();
-
-    println!("Ingesting song: {}", song_name);
-
-    let mut db = DB::new();
-    let audio_processor = AudioProcessor::new();
-    let fft = CooleyTukeyFFT::default();
-
-    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file_name);
-
-    let filtered_samples =
-        audio_processor.apply_low_pass_filter(&audio_samples, sample_rate, 5000.0);
-
-    let downsampled_samples = audio_processor.resample_linear(
-        &filtered_samples,
-        sample_rate,
-        AudioProcessor::TARGET_SAMPLE_RATE,
-    );
+/// With `--play-from-match`, hand the best match over to a player from
+/// where the song is `elapsed` seconds after the query started
+fn play_from_match(
+    results: &[VoteResult],
+    songs: &HashMap<i32, Songs>,
+    elapsed: f32,
+    output: &ResultArgs,
+    config: &Config,
+) {
+    let (Some(mode), Some(best)) = (output.play_from_match, results.first()) else {
+        return;
+    };
+    let Some(song) = songs.get(&(best.song_id as i32)) else {
+        eprintln!("❌ Cannot play id={}: song not found", best.song_id);
+        return;
+    };
+    let start = best.refined_offset + best.speed * elapsed;
+    if let Err(e) = playback::play_from(song, start, mode, &config.playback) {
+        eprintln!("❌ Cannot play '{}': {}", song.title, e);
+    }
+}
 
-    println!(
-        "Processed to {} samples at {} Hz",
-        downsampled_samples.len(),
-        AudioProcessor::TARGET_SAMPLE_RATE
+/// Write the requested diagnostic plots. Candidates below the confidence
+/// cutoff are included, to see why a snippet did or did not match.
+fn write_match_plots(
+    plots: &PlotArgs,
+    fingerprints: &[FingerprintInfo],
+    db_matches_by_hash: &HashMap<u64, Vec<(u32, f32)>>,
+    song_count: Option<usize>,
+    config: &Config,
+) {
+    let unfiltered = MatchingConfig {
+        min_confidence: 0.0,
+        min_score: 0,
+        ..config.matching.clone()
+    };
+    let candidates = vote_best_matches(
+        fingerprints,
+        db_matches_by_hash,
+        song_count,
+        plots.plot_candidates.max(1),
+        &unfiltered,
     );
+    let Some(top) = candidates.first() else {
+        println!("⚠️ No hash hits, skipping the debug plots");
+        return;
+    };
+    let bin_width = config.matching.vote_bin_width;
 
-    let fft_distribution = fft
-        .generate_freq_time_distribution(downsampled_samples, AudioProcessor::TARGET_SAMPLE_RATE);
-
-    let fingerprints = generate_audio_fingerprint(&fft_distribution);
-    println!("Generated {} fingerprints", fingerprints.len());
-
-    let song_id = db.write_song(&song_name);
-    db.write_fingerprints(song_id, fingerprints);
+    if let Some(path) = &plots.debug_plot {
+        let hits = alignment_hits(fingerprints, db_matches_by_hash, top.song_id);
+        let title = format!(
+            "song id={} score={} confidence={:.2}",
+            top.song_id, top.score, top.confidence
+        );
+        match visualization::write_alignment_plot(path, &title, &hits, top.time_offset, bin_width) {
+            Ok(()) => println!("📈 Wrote alignment plot of {} hits to {}", hits.len(), path),
+            Err(e) => eprintln!("⚠️ Failed to write {}: {}", path, e),
+        }
+    }
 
-    println!("✅ Successfully ingested and fingerprinted '{}'", song_name);
+    if let Some(path) = &plots.vote_plot {
+        let histograms: Vec<VoteHistogram> = candidates
+            .iter()
+            .map(|c| VoteHistogram {
+                label: format!("song id={} confidence={:.2}", c.song_id, c.confidence),
+                bins: offset_histogram(
+                    &alignment_hits(fingerprints, db_matches_by_hash, c.song_id),
+                    bin_width,
+                ),
+            })
+            .collect();
+        match visualization::write_vote_histograms(path, &histograms, bin_width) {
+            Ok(()) => println!(
+                "📊 Wrote vote histograms of {} candidates to {}",
+                histograms.len(),
+                path
+            ),
+            Err(e) => eprintln!("⚠️ Failed to write {}: {}", path, e),
+        }
+    }
 }
 
-/// Record audio via microphone and attempt recognition using in-memory processing
-fn ingest_audio() {
+/// Record audio via microphone and attempt recognition using in-memory
+/// processing. Cancelling stops the recording and skips recognition.
+fn ingest_audio(
+    collection: &str,
+    save_recording: Option<&str>,
+    output: &ResultArgs,
+    config: &Config,
+    cancel: &CancelToken,
+) {
     let audio_processor = AudioProcessor::new();
-    let fft = CooleyTukeyFFT::default();
 
-    let recording_time_duration = 5;
-    println!("🎤 Recording for {} seconds...", recording_time_duration);
-    let (recorded_samples, config) = audio_processor.record_audio(recording_time_duration);
-
-    println!("-- Applying Low Pass Filter");
-    let filtered_samples =
-        audio_processor.apply_low_pass_filter(&recorded_samples, config.sample_rate().0, 5000.0);
+    let recording = &config.recording;
+    let (recorded_samples, stream_config) = if recording.vad {
+        println!("🎤 Listening, recording starts when sound is heard...");
+        audio_processor.record_until_active(recording, cancel)
+    } else {
+        println!("🎤 Recording for {} seconds...", recording.duration);
+        audio_processor.record_audio(recording, cancel)
+    };
+    if cancel.is_cancelled() {
+        println!("⏹️ Recording cancelled");
+        return;
+    }
+    if recording.vad && recorded_samples.is_empty() {
+        println!(
+            "❌ No sound heard within {} seconds",
+            recording.max_duration
+        );
+        return;
+    }
 
-    println!("-- Downsampling Audio");
-    let downsampled_samples = audio_processor.resample_linear(
-        &filtered_samples,
-        config.sample_rate().0,
-        AudioProcessor::TARGET_SAMPLE_RATE,
+    let sample_rate = stream_config.sample_rate().0;
+    let recorded_samples =
+        audio_processor.to_mono(&recorded_samples, stream_config.channels() as usize);
+    if let Some(path) = save_recording {
+        match audio_processor.write_wav_float(path, &recorded_samples, sample_rate) {
+            Ok(()) => println!("💾 Saved the recording to {}", path),
+            Err(e) => eprintln!("⚠️ Failed to save the recording: {}", e),
+        }
+    }
+    let mut timings = StageTimings::default();
+    recognise_recording(
+        &recorded_samples,
+        sample_rate,
+        None,
+        collection,
+        output,
+        config,
+        &mut timings,
     );
+    if let Some(format) = output.timings {
+        println!("{}", timings.render(format));
+    }
+}
 
+/// Run a capture saved with `--save-recording` through the microphone
+/// pipeline again, e.g. to debug why it was not recognised
+fn replay_recording(file_name: String, collection: &str, output: &ResultArgs, config: &Config) {
+    let mut timings = StageTimings::default();
+    let decoded = StageTimings::time(&mut timings.decode, || {
+        AudioProcessor::new().decode_file(&file_name)
+    });
+    let (samples, sample_rate) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("❌ Cannot decode {}: {}", file_name, e);
+            return;
+        }
+    };
     println!(
-        "Processed to {} samples at {} Hz",
-        downsampled_samples.len(),
-        AudioProcessor::TARGET_SAMPLE_RATE
+        "🔁 Replaying {:.1}s recorded at {} Hz",
+        samples.len() as f32 / sample_rate as f32,
+        sample_rate
     );
+    recognise_recording(
+        &samples,
+        sample_rate,
+        Some(&file_name),
+        collection,
+        output,
+        config,
+        &mut timings,
+    );
+    if let Some(format) = output.timings {
+        println!("{}", timings.render(format));
+    }
+}
 
-    println!("-- Generating FFT Distribution");
-    let fft_distribution = fft
-        .generate_freq_time_distribution(downsampled_samples, AudioProcessor::TARGET_SAMPLE_RATE);
+/// Recognise mono microphone audio and print the top matches, logged to the
+/// match history as `mic`, or as `file` with `source_file` when replaying a
+/// capture. The time of each stage is added to `timings`.
+fn recognise_recording(
+    recorded_samples: &[f32],
+    sample_rate: u32,
+    source_file: Option<&str>,
+    collection: &str,
+    output: &ResultArgs,
+    config: &Config,
+    timings: &mut StageTimings,
+) {
+    let source = match source_file {
+        Some(_) => MatchSource::File,
+        None => MatchSource::Mic,
+    };
+    let db = DB::new();
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
 
-    let fingerprints = generate_audio_fingerprint(&fft_distribution);
-    println!("Generated {} fingerprints", fingerprints.len());
+    info!("Fingerprinting {} samples", recorded_samples.len());
+    let fingerprints = Pipeline::for_recording(config, scheme).fingerprints_timed(
+        recorded_samples.to_vec(),
+        sample_rate,
+        |stage, elapsed| timings.observe(stage, elapsed),
+    );
+    let fingerprints = StageTimings::time(&mut timings.fingerprint, || {
+        store.without_denied(expand_query(fingerprints, scheme, &config.matching))
+    });
+    info!("Generated {} fingerprints", fingerprints.len());
 
     let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
-    let mut db = DB::new();
-    println!("-- Fetching Hash Matches From DB");
-    let db_matches_by_hash = db.fetch_matches_grouped_by_hash(&hash_vec);
-    println!("-- Voting For The Best Matching Result");
-    let results = vote_best_matches(&fingerprints, &db_matches_by_hash, 5);
+    info!("Fetching hash matches from DB");
+    let (db_matches_by_hash, song_count) = StageTimings::time(&mut timings.db_fetch, || {
+        let matches = store
+            .fetch_matches_grouped_by_hash(&hash_vec)
+            .expect("Fingerprint lookup failed");
+        (matches, store.song_count())
+    });
+    timings.hashes = fingerprints.len();
+    timings.rows = db_matches_by_hash.values().map(Vec::len).sum();
+    info!("Voting for the best matching result");
+    let results = StageTimings::time(&mut timings.vote, || {
+        vote_best_matches(
+            &fingerprints,
+            &db_matches_by_hash,
+            song_count,
+            output.top_k,
+            &config.matching,
+        )
+    });
 
     if results.is_empty() {
         println!("❌ No matches found");
     } else {
-        let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
-        let titles = db.fetch_song_titles(&song_ids);
+        if let Err(e) = db.record_match(&results[0], source, source_file) {
+            eprintln!("⚠️ Failed to record match history: {}", e);
+        }
 
-        println!("✅ Top matches:");
-        for r in results {
-            let title = titles
-                .get(&(r.song_id as i32))
-                .cloned()
-                .unwrap_or_else(|| "<unknown>".to_string());
-
-            let abs_offset = r.time_offset.abs();
-            let minutes = (abs_offset / 60.0) as u32;
-            let seconds = (abs_offset % 60.0) as u32;
-            let sign = if r.time_offset < 0.0 { "-" } else { "" };
-            let time_str = format!("{}{:02}:{:02}", sign, minutes, seconds);
+        let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
+        let songs = db.fetch_songs(&song_ids);
 
-            println!(
-                "song_id={} title=\"{}\" score={} time_offset={}s ({})",
-                r.song_id, title, r.score, r.time_offset, time_str
-            );
+        // Replayed captures were already reported when they were recorded
+        if matches!(source, MatchSource::Mic) {
+            let song = songs.get(&(results[0].song_id as i32));
+            Notifier::from_config(&config.notify).notify(&MatchEvent::new(
+                &results[0],
+                song,
+                source,
+                source_file,
+            ));
         }
+
+        print_matches(
+            &results,
+            &songs,
+            concurrent_matches(&results, &config.matching),
+        );
+        // The song played on while the capture was being recognised
+        let latency = match source {
+            MatchSource::Mic => timings.total().as_secs_f32(),
+            _ => 0.0,
+        };
+        let query_secs = recorded_samples.len() as f32 / sample_rate as f32;
+        play_from_match(&results, &songs, query_secs + latency, output, config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition_is_valid() {
+        Args::command().debug_assert();
     }
 }
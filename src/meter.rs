@@ -0,0 +1,135 @@
+use crate::fft::fft::CooleyTukeyFFT;
+use std::f32::consts::PI;
+use std::io::{IsTerminal, Write};
+
+/// Bottom of the level meter and the spectrum, in dBFS
+pub const FLOOR_DB: f32 = -72.0;
+/// The level covers the last this many seconds of input
+pub const LEVEL_SECS: f32 = 0.05;
+/// Samples the spectrum is computed from, a power of two
+pub const SPECTRUM_LEN: usize = 2048;
+/// Lowest frequency of the spectrum; the highest is Nyquist
+pub const SPECTRUM_MIN_FREQ: f32 = 40.0;
+/// Bands of the spectrum on the terminal meter
+const TERMINAL_BANDS: usize = 16;
+/// Width of the level bar on the terminal meter, in characters
+const TERMINAL_LEVEL_WIDTH: usize = 20;
+/// Eighth-height steps of a spectrum bar
+const BAR_CHARS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// RMS level of `samples` in dBFS, [`FLOOR_DB`] for silence
+pub fn level_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return FLOOR_DB;
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+    (20.0 * rms.max(1e-10).log10()).max(FLOOR_DB)
+}
+
+/// Position of `db` between [`FLOOR_DB`] and full scale, in 0..=1
+pub fn level_ratio(db: f32) -> f32 {
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+}
+
+/// Coarse spectrum of live input, for meters rather than fingerprints
+pub struct Spectrum {
+    fft: CooleyTukeyFFT,
+}
+
+impl Spectrum {
+    pub fn new() -> Self {
+        Self {
+            fft: CooleyTukeyFFT::new(SPECTRUM_LEN, 0),
+        }
+    }
+
+    /// Peak level of each of `bands` log-spaced bands from
+    /// [`SPECTRUM_MIN_FREQ`] to Nyquist, as [`level_ratio`]s. Uses the
+    /// newest [`SPECTRUM_LEN`] of mono `samples`, Hann-windowed.
+    pub fn bands(&self, samples: &[f32], sample_rate: u32, bands: usize) -> Vec<f32> {
+        let mut frame = samples[samples.len().saturating_sub(SPECTRUM_LEN)..].to_vec();
+        frame.resize(SPECTRUM_LEN, 0.0);
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample *= 0.5 - 0.5 * (2.0 * PI * i as f32 / (SPECTRUM_LEN - 1) as f32).cos();
+        }
+        let spectrum = self.fft.perform_fft(frame);
+
+        let nyquist = sample_rate as f32 / 2.0;
+        let bin_hz = sample_rate as f32 / SPECTRUM_LEN as f32;
+        let ratio = (nyquist / SPECTRUM_MIN_FREQ).powf(1.0 / bands as f32);
+        (0..bands)
+            .map(|band| {
+                let low = SPECTRUM_MIN_FREQ * ratio.powi(band as i32);
+                let lo = ((low / bin_hz) as usize).max(1);
+                let hi = ((low * ratio / bin_hz) as usize).clamp(lo, SPECTRUM_LEN / 2);
+                let power = spectrum[lo..=hi]
+                    .iter()
+                    .map(|c| c.norm_sqr())
+                    .fold(0.0f32, f32::max);
+                // A full-scale sine peaks at (N/4)² with the Hann window
+                let db = 10.0
+                    * (power / (SPECTRUM_LEN as f32 / 4.0).powi(2))
+                        .max(1e-12)
+                        .log10();
+                level_ratio(db)
+            })
+            .collect()
+    }
+}
+
+/// A level bar and spectrum on one stderr line, redrawn in place while the
+/// microphone records, so a dead or muted input shows before matching.
+/// Draws nothing when stderr is not a terminal.
+pub struct TerminalMeter {
+    spectrum: Spectrum,
+    enabled: bool,
+    drawn: bool,
+}
+
+impl TerminalMeter {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            spectrum: Spectrum::new(),
+            enabled: enabled && std::io::stderr().is_terminal(),
+            drawn: false,
+        }
+    }
+
+    /// Redraw from the newest mono `samples`, at least [`SPECTRUM_LEN`] of
+    /// them for the whole spectrum, followed by `status`
+    pub fn update(&mut self, samples: &[f32], sample_rate: u32, status: &str) {
+        if !self.enabled {
+            return;
+        }
+        let level_len = (LEVEL_SECS * sample_rate as f32) as usize;
+        let level = level_db(&samples[samples.len().saturating_sub(level_len)..]);
+
+        let filled = (level_ratio(level) * TERMINAL_LEVEL_WIDTH as f32).round() as usize;
+        let bars: String = self
+            .spectrum
+            .bands(samples, sample_rate, TERMINAL_BANDS)
+            .iter()
+            .map(|&height| BAR_CHARS[(height * 8.0).round() as usize])
+            .collect();
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r\x1b[2K🎚️ [{}{}] {:>4.0} dBFS  {}  {}",
+            "█".repeat(filled),
+            "·".repeat(TERMINAL_LEVEL_WIDTH - filled),
+            level,
+            bars,
+            status
+        );
+        let _ = stderr.flush();
+        self.drawn = true;
+    }
+
+    /// Erase the line, before other output or once recording stopped
+    pub fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[2K");
+            self.drawn = false;
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::db::storage::PoolStatus;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A stage of recognising an upload, timed separately
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Decoding an uploaded file
+    Decode,
+    /// Filtering, resampling and the STFT
+    Fft,
+    /// Peak pairing and hashing
+    Fingerprint,
+    /// Hash and collection lookups
+    DbFetch,
+    Vote,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Decode,
+        Stage::Fft,
+        Stage::Fingerprint,
+        Stage::DbFetch,
+        Stage::Vote,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Fft => "fft",
+            Stage::Fingerprint => "fingerprint",
+            Stage::DbFetch => "db_fetch",
+            Stage::Vote => "vote",
+        }
+    }
+}
+
+/// Durations counted into [`LATENCY_BUCKETS`]
+struct Histogram {
+    /// Observations at most each bucket's bound, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Prometheus text lines of the histogram `name`, with `labels` (`""`
+    /// or `key="value"`) on every line
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, count);
+    }
+}
+
+/// Counters and latency histograms of `serve`, exposed on `/metrics` in the
+/// Prometheus text format. Updated lock-free from every request.
+pub struct Metrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    busy: AtomicU64,
+    matched: AtomicU64,
+    unmatched: AtomicU64,
+    upload_bytes: AtomicU64,
+    fingerprints: AtomicU64,
+    ingested_songs: AtomicU64,
+    ingested_fingerprints: AtomicU64,
+    request_latency: Histogram,
+    ingest_latency: Histogram,
+    stage_latency: [Histogram; Stage::ALL.len()],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+            matched: AtomicU64::new(0),
+            unmatched: AtomicU64::new(0),
+            upload_bytes: AtomicU64::new(0),
+            fingerprints: AtomicU64::new(0),
+            ingested_songs: AtomicU64::new(0),
+            ingested_fingerprints: AtomicU64::new(0),
+            request_latency: Histogram::new(),
+            ingest_latency: Histogram::new(),
+            stage_latency: std::array::from_fn(|_| Histogram::new()),
+        }
+    }
+
+    /// A recognition request of `bytes` arrived
+    pub fn request(&self, bytes: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A request finished after `elapsed`: with a match or not, or `None`
+    /// when it failed
+    pub fn finish(&self, elapsed: Duration, matched: Option<bool>) {
+        let counter = match matched {
+            Some(true) => &self.matched,
+            Some(false) => &self.unmatched,
+            None => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.request_latency.observe(elapsed);
+    }
+
+    /// A request was refused at `server.max_concurrent_recognitions`
+    pub fn busy(&self) {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An upload was turned into `count` query fingerprints
+    pub fn fingerprinted(&self, count: usize) {
+        self.fingerprints.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// An upload was ingested as a song of `fingerprints` in `elapsed`
+    pub fn ingested(&self, fingerprints: usize, elapsed: Duration) {
+        self.ingested_songs.fetch_add(1, Ordering::Relaxed);
+        self.ingested_fingerprints
+            .fetch_add(fingerprints as u64, Ordering::Relaxed);
+        self.ingest_latency.observe(elapsed);
+    }
+
+    pub fn observe(&self, stage: Stage, elapsed: Duration) {
+        self.stage_latency[stage as usize].observe(elapsed);
+    }
+
+    /// Everything in the Prometheus text exposition format, with the
+    /// connection pool's state if there is one
+    pub fn render(&self, pool: Option<PoolStatus>) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "sabi_recognise_requests_total",
+                "Recognition requests received",
+                &self.requests,
+            ),
+            (
+                "sabi_recognise_errors_total",
+                "Recognition requests that failed",
+                &self.errors,
+            ),
+            (
+                "sabi_recognise_busy_total",
+                "Recognition requests refused at the concurrency limit",
+                &self.busy,
+            ),
+            (
+                "sabi_upload_bytes_total",
+                "Bytes of audio and fingerprint files uploaded",
+                &self.upload_bytes,
+            ),
+            (
+                "sabi_query_fingerprints_total",
+                "Fingerprints generated from or read out of uploads",
+                &self.fingerprints,
+            ),
+            (
+                "sabi_ingested_songs_total",
+                "Songs ingested through /ingest",
+                &self.ingested_songs,
+            ),
+            (
+                "sabi_ingested_fingerprints_total",
+                "Fingerprints stored for songs ingested through /ingest",
+                &self.ingested_fingerprints,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP sabi_recognise_results_total Completed recognitions by outcome"
+        );
+        let _ = writeln!(out, "# TYPE sabi_recognise_results_total counter");
+        for (outcome, counter) in [("match", &self.matched), ("no_match", &self.unmatched)] {
+            let _ = writeln!(
+                out,
+                "sabi_recognise_results_total{{outcome=\"{}\"}} {}",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let name = "sabi_recognise_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time to answer a recognition request", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.request_latency.render(&mut out, name, "");
+
+        let name = "sabi_ingest_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time to ingest an upload", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.ingest_latency.render(&mut out, name, "");
+
+        let name = "sabi_stage_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent in each recognition stage", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for stage in Stage::ALL {
+            self.stage_latency[stage as usize].render(
+                &mut out,
+                name,
+                &format!("stage=\"{}\"", stage.label()),
+            );
+        }
+
+        if let Some(pool) = pool {
+            let gauges = [
+                (
+                    "sabi_db_pool_max_connections",
+                    "Connections the pool may open",
+                    pool.max_size,
+                ),
+                ("sabi_db_pool_connections", "Connections open", pool.size),
+                (
+                    "sabi_db_pool_idle_connections",
+                    "Connections open and idle",
+                    pool.available,
+                ),
+                (
+                    "sabi_db_pool_waiting",
+                    "Requests waiting for a connection",
+                    pool.waiting,
+                ),
+            ];
+            for (name, help, value) in gauges {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} gauge", name);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+        out
+    }
+}
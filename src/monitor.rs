@@ -0,0 +1,186 @@
+use crate::audio_processor::ffmpeg_available;
+use crate::cancel::{CancelToken, POLL_INTERVAL};
+use crate::config::Config;
+use crate::db::{
+    connector::{DB, MatchSource},
+    storage::fingerprint_store,
+};
+use crate::dsp::Pipeline;
+use crate::fft::fft::CooleyTukeyFFT;
+use crate::fingerprint::{FingerprintStream, expand_query, vote_best_matches};
+use crate::notifier::{MatchEvent, Notifier};
+use crate::scanner::format_timestamp;
+use chrono::Local;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Rate ffmpeg decodes the stream to; the usual preprocessing takes it from there
+const DECODE_SAMPLE_RATE: u32 = 44_100;
+/// Wait before reconnecting after the stream dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Follow an internet radio stream (Icecast/Shoutcast, HLS, or anything else
+/// ffmpeg can open) and match its last `window_secs` every `hop_secs`.
+/// Each new song is printed with the time it was heard, logged to the match
+/// history as `stream` and sent to the `[notify]` sinks. Reconnects whenever
+/// the stream drops, and stops after the current hop once `cancel` fires.
+pub fn run_monitor(
+    url: &str,
+    window_secs: f32,
+    hop_secs: f32,
+    collection: &str,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    if window_secs <= 0.0 || hop_secs <= 0.0 {
+        eprintln!("Error: --window and --hop must be positive");
+        return;
+    }
+    if !ffmpeg_available() {
+        eprintln!("❌ monitor needs ffmpeg on the PATH to read the stream");
+        return;
+    }
+
+    let fft = CooleyTukeyFFT::from_config(&config.fft);
+    let db = DB::new();
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+    let notifier = Notifier::from_config(&config.notify);
+    let pipeline = Pipeline::from_config(config, scheme);
+
+    let window_len = (window_secs * DECODE_SAMPLE_RATE as f32) as usize;
+    let hop_len = ((hop_secs * DECODE_SAMPLE_RATE as f32) as usize).max(1);
+
+    // Song currently on air, so it is logged once and not every hop
+    let mut on_air: Option<u32> = None;
+    let mut stream_secs = 0.0f32;
+
+    loop {
+        println!("📻 Connecting to {}", url);
+        let mut child = match ffmpeg_decoder(url) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("❌ Failed to start ffmpeg: {}", e);
+                return;
+            }
+        };
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut window: VecDeque<f32> = VecDeque::with_capacity(window_len + hop_len);
+        let mut hop = vec![0u8; hop_len * 4];
+        while !cancel.is_cancelled() && stdout.read_exact(&mut hop).is_ok() {
+            window.extend(
+                hop.chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            );
+            if window.len() > window_len {
+                window.drain(..window.len() - window_len);
+            }
+            stream_secs += hop_secs;
+            if window.len() < window_len {
+                continue;
+            }
+
+            let samples: Vec<f32> = window.iter().copied().collect();
+            let (resampled, rate) = pipeline.audio(samples, DECODE_SAMPLE_RATE);
+            let mut fingerprint_stream = FingerprintStream::new(scheme, config.fingerprint.clone());
+            let mut fingerprints = Vec::new();
+            for frame in fft.frames(&resampled, rate) {
+                fingerprints.extend(fingerprint_stream.push(frame));
+            }
+            fingerprints.extend(fingerprint_stream.finish());
+            let fingerprints =
+                store.without_denied(expand_query(fingerprints, scheme, &config.matching));
+            if fingerprints.is_empty() {
+                continue;
+            }
+
+            let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+            let db_matches_by_hash = match store.fetch_matches_grouped_by_hash(&hash_vec) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    eprintln!("⚠️ Fingerprint lookup failed: {}", e);
+                    continue;
+                }
+            };
+            let best = vote_best_matches(
+                &fingerprints,
+                &db_matches_by_hash,
+                store.song_count(),
+                1,
+                &config.matching,
+            )
+            .into_iter()
+            .next();
+
+            let Some(result) = best else {
+                on_air = None;
+                continue;
+            };
+            if on_air == Some(result.song_id) {
+                continue;
+            }
+            on_air = Some(result.song_id);
+
+            let event = MatchEvent::new(
+                &result,
+                db.fetch_songs(&[result.song_id as i32])
+                    .get(&(result.song_id as i32)),
+                MatchSource::Stream,
+                Some(url),
+            );
+            println!(
+                "🎵 {} [{}] {} (confidence={:.2})",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                format_timestamp(stream_secs),
+                event.title,
+                result.confidence
+            );
+            if let Err(e) = db.record_match(&result, MatchSource::Stream, Some(url)) {
+                eprintln!("⚠️ Failed to record match history: {}", e);
+            }
+            notifier.notify(&event);
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+        if cancel.is_cancelled() {
+            println!("⏹️ Stopped monitoring");
+            return;
+        }
+        on_air = None;
+        println!(
+            "⚠️ Stream ended, reconnecting in {}s",
+            RECONNECT_DELAY.as_secs()
+        );
+        let reconnect_at = Instant::now() + RECONNECT_DELAY;
+        while Instant::now() < reconnect_at {
+            if cancel.is_cancelled() {
+                println!("⏹️ Stopped monitoring");
+                return;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Start ffmpeg reading `url` and writing mono f32le at
+/// [`DECODE_SAMPLE_RATE`] to its stdout
+fn ffmpeg_decoder(url: &str) -> std::io::Result<std::process::Child> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-loglevel", "error", "-nostdin"]);
+    if url.starts_with("http://") || url.starts_with("https://") {
+        // Ride out short network hiccups without dropping the connection
+        command.args(["-reconnect", "1", "-reconnect_streamed", "1"]);
+        command.args(["-reconnect_delay_max", "30"]);
+    }
+    command
+        .args(["-i", url, "-vn", "-f", "f32le", "-ac", "1"])
+        .args(["-ar", &DECODE_SAMPLE_RATE.to_string(), "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+}
@@ -0,0 +1,331 @@
+use crate::acoustid;
+use crate::audio_processor::SongMetadata;
+use crate::config::EnrichConfig;
+use crate::http::{curl_available, get_json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+const RECORDING_URL: &str = "https://musicbrainz.org/ws/2/recording";
+/// Artist credits and releases, with their track lists, fetched along with a
+/// recording
+const RECORDING_INC: &str = "artist-credits+releases+media";
+/// MusicBrainz allows one request per second and client
+const MUSICBRAINZ_INTERVAL: Duration = Duration::from_secs(1);
+/// AcoustID allows three
+const ACOUSTID_INTERVAL: Duration = Duration::from_millis(334);
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+    id: String,
+    /// Relevance of a search result, 0 to 100
+    #[serde(default)]
+    score: u32,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(Deserialize)]
+struct ArtistCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    title: String,
+    status: Option<String>,
+    /// "2001", "2001-03" or "2001-03-07"
+    date: Option<String>,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+/// A disc of a release, listing only the recording's own track
+#[derive(Deserialize)]
+struct Medium {
+    #[serde(default, alias = "track")]
+    tracks: Vec<Track>,
+}
+
+#[derive(Deserialize)]
+struct Track {
+    position: Option<i32>,
+}
+
+/// Metadata a lookup found for a song
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Enrichment {
+    recording_id: String,
+    artist: Option<String>,
+    album: Option<String>,
+    track_number: Option<i32>,
+    year: Option<i32>,
+}
+
+/// Spaces requests to one web service at least `interval` apart
+struct RateLimiter {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Sleep until the next request may be sent
+    fn wait(&mut self) {
+        if let Some(last) = self.last {
+            let elapsed = last.elapsed();
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+        self.last = Some(Instant::now());
+    }
+}
+
+/// Fills in song metadata from MusicBrainz for `--ingest --enrich`.
+///
+/// A song is looked up by the MusicBrainz recording id in its tags, else by
+/// its audio through AcoustID (when an API key is configured), else by its
+/// title and artist tags. Lookups, misses included, are cached in
+/// `enrich.cache_file`, so re-ingesting a library sends no requests again.
+pub struct Enricher {
+    config: EnrichConfig,
+    cache_path: PathBuf,
+    /// Lookup key -> what it found, `None` for nothing
+    cache: HashMap<String, Option<Enrichment>>,
+    musicbrainz: RateLimiter,
+    acoustid: RateLimiter,
+}
+
+impl Enricher {
+    pub fn new(config: &EnrichConfig) -> Result<Self, String> {
+        if !curl_available() {
+            return Err("--enrich needs curl on the PATH to reach MusicBrainz".to_string());
+        }
+        let cache_path = PathBuf::from(&config.cache_file);
+        let cache = if cache_path.exists() {
+            let text = fs::read_to_string(&cache_path)
+                .map_err(|e| format!("Cannot read {}: {}", cache_path.display(), e))?;
+            serde_json::from_str(&text)
+                .map_err(|e| format!("{} is corrupt: {}", cache_path.display(), e))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            cache_path,
+            cache,
+            musicbrainz: RateLimiter::new(MUSICBRAINZ_INTERVAL),
+            acoustid: RateLimiter::new(ACOUSTID_INTERVAL),
+        })
+    }
+
+    /// Fill the fields of `metadata` its tags left empty. A failed lookup is
+    /// only reported, the song is ingested with the metadata it has.
+    /// `samples` are the decoded audio, for AcoustID.
+    pub fn enrich(
+        &mut self,
+        song_name: &str,
+        checksum: &str,
+        samples: &[f32],
+        sample_rate: u32,
+        metadata: &mut SongMetadata,
+    ) {
+        let found = match self.find(checksum, samples, sample_rate, metadata) {
+            Ok(found) => found,
+            Err(e) => {
+                eprintln!("⚠️ MusicBrainz lookup of '{}' failed: {}", song_name, e);
+                return;
+            }
+        };
+        let Some(found) = found else {
+            info!("No MusicBrainz match for '{}'", song_name);
+            return;
+        };
+
+        println!(
+            "🏷️ MusicBrainz: {} — {} (recording {})",
+            found.artist.as_deref().unwrap_or("<unknown artist>"),
+            found.album.as_deref().unwrap_or("<no release>"),
+            found.recording_id
+        );
+        metadata.artist = metadata.artist.take().or(found.artist);
+        metadata.album = metadata.album.take().or(found.album);
+        metadata.track_number = metadata.track_number.or(found.track_number);
+        metadata.year = metadata.year.or(found.year);
+        metadata
+            .musicbrainz_recording_id
+            .get_or_insert(found.recording_id);
+    }
+
+    fn find(
+        &mut self,
+        checksum: &str,
+        samples: &[f32],
+        sample_rate: u32,
+        metadata: &SongMetadata,
+    ) -> Result<Option<Enrichment>, String> {
+        if let Some(id) = &metadata.musicbrainz_recording_id {
+            return self.cached(format!("recording:{}", id), |enricher| {
+                enricher.fetch_recording(id).map(Some)
+            });
+        }
+
+        if let Some(api_key) = self.config.acoustid_key.clone() {
+            let found = self.cached(format!("acoustid:{}", checksum), |enricher| {
+                enricher.acoustid.wait();
+                let results = acoustid::lookup(samples, sample_rate, &api_key)?;
+                let recording_id = results
+                    .into_iter()
+                    .filter(|result| result.score >= enricher.config.min_acoustid_score)
+                    .find_map(|result| result.recordings.into_iter().next())
+                    .map(|recording| recording.id);
+                match recording_id {
+                    Some(id) => enricher.fetch_recording(&id).map(Some),
+                    None => Ok(None),
+                }
+            })?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+
+        match (&metadata.artist, &metadata.title) {
+            (Some(artist), Some(title)) => self
+                .cached(format!("search:{}\t{}", artist, title), |enricher| {
+                    enricher.search_recording(artist, title)
+                }),
+            _ => Ok(None),
+        }
+    }
+
+    /// The cached result for `key`, else the result of `lookup`, which is
+    /// then cached. Errors are not cached.
+    fn cached(
+        &mut self,
+        key: String,
+        lookup: impl FnOnce(&mut Self) -> Result<Option<Enrichment>, String>,
+    ) -> Result<Option<Enrichment>, String> {
+        if let Some(found) = self.cache.get(&key) {
+            debug!("MusicBrainz cache hit for {}", key);
+            return Ok(found.clone());
+        }
+        let found = lookup(self)?;
+        self.cache.insert(key, found.clone());
+        if let Err(e) = self.save() {
+            eprintln!("⚠️ Cannot write {}: {}", self.cache_path.display(), e);
+        }
+        Ok(found)
+    }
+
+    /// Write via a temporary file, so a crash mid-write keeps the old cache
+    fn save(&self) -> std::io::Result<()> {
+        let tmp = self.cache_path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string(&self.cache)?)?;
+        fs::rename(tmp, &self.cache_path)
+    }
+
+    fn fetch_recording(&mut self, id: &str) -> Result<Enrichment, String> {
+        self.musicbrainz.wait();
+        let recording: Recording = get_json(
+            &format!("{}/{}", RECORDING_URL, id),
+            &[("inc", RECORDING_INC), ("fmt", "json")],
+        )?;
+        Ok(enrichment(recording))
+    }
+
+    /// Best search result for `title` by `artist`, if relevant enough
+    fn search_recording(
+        &mut self,
+        artist: &str,
+        title: &str,
+    ) -> Result<Option<Enrichment>, String> {
+        self.musicbrainz.wait();
+        let query = format!(
+            "recording:\"{}\" AND artist:\"{}\"",
+            escape_query(title),
+            escape_query(artist)
+        );
+        let response: SearchResponse = get_json(
+            RECORDING_URL,
+            &[("query", &query), ("limit", "1"), ("fmt", "json")],
+        )?;
+        Ok(response
+            .recordings
+            .into_iter()
+            .next()
+            .filter(|recording| recording.score >= self.config.min_search_score)
+            .map(enrichment))
+    }
+}
+
+/// Metadata of `recording` from its earliest official release, or its
+/// earliest release if none is official
+fn enrichment(recording: Recording) -> Enrichment {
+    let artist = (!recording.artist_credit.is_empty()).then(|| {
+        recording
+            .artist_credit
+            .iter()
+            .map(|credit| format!("{}{}", credit.name, credit.joinphrase))
+            .collect::<String>()
+    });
+
+    let release = recording.releases.iter().min_by_key(|release| {
+        (
+            release.status.as_deref() != Some("Official"),
+            // Undated releases last
+            release.date.is_none(),
+            release.date.clone(),
+        )
+    });
+
+    Enrichment {
+        recording_id: recording.id.clone(),
+        artist,
+        album: release.map(|release| release.title.clone()),
+        track_number: release.and_then(|release| {
+            release
+                .media
+                .iter()
+                .flat_map(|medium| &medium.tracks)
+                .find_map(|track| track.position)
+        }),
+        year: release
+            .and_then(|release| release.date.as_deref())
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse().ok()),
+    }
+}
+
+/// Escape the Lucene special characters in a search term
+fn escape_query(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
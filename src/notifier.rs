@@ -0,0 +1,241 @@
+use crate::config::NotifyConfig;
+use crate::db::{bindings::Songs, connector::MatchSource};
+use crate::fingerprint::VoteResult;
+use crate::http::{curl_available, post_form, post_json};
+use chrono::{DateTime, Local, SecondsFormat, TimeDelta, Utc};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+const LASTFM_API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// A confident match, as sent to every sink
+#[derive(Serialize, Clone, Debug)]
+pub struct MatchEvent {
+    pub song_id: u32,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<f64>,
+    pub confidence: f32,
+    /// Position in the song the snippet starts at, in seconds
+    pub time_offset: f32,
+    /// `mic`, `file` or `stream`
+    pub source: &'static str,
+    /// Stream URL or file name the snippet came from
+    pub input: Option<String>,
+    #[serde(serialize_with = "rfc3339")]
+    pub heard_at: DateTime<Utc>,
+}
+
+impl MatchEvent {
+    /// `song` is the matched song's row, if it could be fetched
+    pub fn new(
+        result: &VoteResult,
+        song: Option<&Songs>,
+        source: MatchSource,
+        input: Option<&str>,
+    ) -> Self {
+        Self {
+            song_id: result.song_id,
+            title: song.map_or_else(|| "<unknown>".to_string(), |s| s.title.clone()),
+            artist: song.and_then(|s| s.artist.clone()),
+            album: song.and_then(|s| s.album.clone()),
+            duration: song.and_then(|s| s.duration),
+            confidence: result.confidence,
+            time_offset: result.time_offset,
+            source: source.as_str(),
+            input: input.map(str::to_string),
+            heard_at: Utc::now(),
+        }
+    }
+
+    /// When the song started playing, going by where in it the snippet is
+    fn started_at(&self) -> DateTime<Utc> {
+        self.heard_at - TimeDelta::milliseconds((self.time_offset.max(0.0) * 1000.0) as i64)
+    }
+}
+
+fn rfc3339<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// Somewhere matches are reported to
+trait Sink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn send(&self, event: &MatchEvent) -> Result<(), String>;
+}
+
+/// POSTs every event as JSON
+struct Webhook {
+    url: String,
+}
+
+impl Sink for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(&self, event: &MatchEvent) -> Result<(), String> {
+        post_json(&self.url, event)
+    }
+}
+
+/// Appends `Oct 16 14:02 : player: played "Artist - Title"` lines, the way
+/// MPD logs what it played
+struct MpdLog {
+    path: String,
+}
+
+impl Sink for MpdLog {
+    fn name(&self) -> &'static str {
+        "MPD log"
+    }
+
+    fn send(&self, event: &MatchEvent) -> Result<(), String> {
+        let played = match &event.artist {
+            Some(artist) => format!("{} - {}", artist, event.title),
+            None => event.title.clone(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("cannot open {}: {}", self.path, e))?;
+        writeln!(
+            file,
+            "{} : player: played \"{}\"",
+            event.heard_at.with_timezone(&Local).format("%b %d %H:%M"),
+            played
+        )
+        .map_err(|e| format!("cannot write {}: {}", self.path, e))
+    }
+}
+
+/// Scrobbles to Last.fm with `track.scrobble`
+struct Lastfm {
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+}
+
+#[derive(Deserialize)]
+struct LastfmResponse {
+    error: Option<u32>,
+    message: Option<String>,
+}
+
+impl Lastfm {
+    /// `api_sig`: the MD5 of every parameter name and value, sorted by name,
+    /// followed by the API secret
+    fn signature(&self, params: &[(&str, &str)]) -> String {
+        let mut sorted = params.to_vec();
+        sorted.sort_by_key(|&(name, _)| name);
+        let mut text: String = sorted
+            .iter()
+            .map(|(name, value)| format!("{}{}", name, value))
+            .collect();
+        text.push_str(&self.api_secret);
+        Md5::digest(text.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+impl Sink for Lastfm {
+    fn name(&self) -> &'static str {
+        "Last.fm"
+    }
+
+    fn send(&self, event: &MatchEvent) -> Result<(), String> {
+        let Some(artist) = &event.artist else {
+            return Err(format!("'{}' has no artist to scrobble", event.title));
+        };
+        let timestamp = event.started_at().timestamp().to_string();
+        let mut params = vec![
+            ("method", "track.scrobble"),
+            ("api_key", self.api_key.as_str()),
+            ("sk", self.session_key.as_str()),
+            ("artist", artist.as_str()),
+            ("track", event.title.as_str()),
+            ("timestamp", timestamp.as_str()),
+        ];
+        if let Some(album) = &event.album {
+            params.push(("album", album.as_str()));
+        }
+        let signature = self.signature(&params);
+        params.push(("api_sig", signature.as_str()));
+        // Not part of the signature
+        params.push(("format", "json"));
+
+        let response: LastfmResponse = post_form(LASTFM_API_URL, &params)?;
+        match response.error {
+            Some(code) => Err(format!(
+                "error {}: {}",
+                code,
+                response.message.unwrap_or_default()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reports confident matches to the sinks configured in `[notify]`. A sink
+/// that fails is warned about and the others still run.
+pub struct Notifier {
+    min_confidence: f32,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Notifier {
+    pub fn from_config(config: &NotifyConfig) -> Self {
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        let web_sinks = config.webhook_url.is_some() || config.lastfm_api_key.is_some();
+        if web_sinks && !curl_available() {
+            eprintln!("⚠️ [notify] needs curl on the PATH for webhooks and Last.fm, skipping them");
+        } else {
+            if let Some(url) = &config.webhook_url {
+                sinks.push(Box::new(Webhook { url: url.clone() }));
+            }
+            if let (Some(api_key), Some(api_secret), Some(session_key)) = (
+                &config.lastfm_api_key,
+                &config.lastfm_api_secret,
+                &config.lastfm_session_key,
+            ) {
+                sinks.push(Box::new(Lastfm {
+                    api_key: api_key.clone(),
+                    api_secret: api_secret.clone(),
+                    session_key: session_key.clone(),
+                }));
+            }
+        }
+        if let Some(path) = &config.mpd_log {
+            sinks.push(Box::new(MpdLog { path: path.clone() }));
+        }
+
+        Self {
+            min_confidence: config.min_confidence,
+            sinks,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Send `event` to every sink, unless its confidence is too low. Blocks
+    /// until each sink answered or timed out.
+    pub fn notify(&self, event: &MatchEvent) {
+        if event.confidence < self.min_confidence {
+            return;
+        }
+        for sink in &self.sinks {
+            if let Err(e) = sink.send(event) {
+                eprintln!("⚠️ {} notification failed: {}", sink.name(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Recognition for `serve`, as stages connected by bounded channels:
+//! decode → STFT → fingerprint → lookup → vote. Each CPU-bound stage runs up
+//! to `server.pipeline_workers` jobs at a time on tokio's blocking thread
+//! pool and the lookup stage as many on the async workers, so one request's
+//! database lookup overlaps the next one's decoding. A stage whose next
+//! channel is full waits, which holds back the stages before it, and with
+//! at most `server.max_concurrent_recognitions` requests admitted at once
+//! the audio and spectrograms in flight stay bounded.
+
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::db::storage::{AsyncFingerprintStore, retain_songs, warn_outdated};
+use crate::dsp;
+use crate::fft::fft::FFTDistribution;
+use crate::fingerprint::{
+    FingerprintInfo, HashScheme, VoteResult, expand_query, generate_audio_fingerprint,
+    vote_best_matches,
+};
+use crate::fpfile::{FingerprintFile, MAGIC as FP_MAGIC};
+use crate::metrics::{Metrics, Stage};
+use crate::timings::StageTimings;
+use axum::body::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+use tracing::{Instrument, Span};
+
+/// What a recognition is matched against
+#[derive(Clone)]
+pub(crate) struct MatchTarget {
+    pub(crate) collection: String,
+    pub(crate) scheme: HashScheme,
+    pub(crate) top_k: usize,
+}
+
+/// The outcome of a recognition
+pub(crate) struct Recognition {
+    /// Query fingerprints looked up
+    pub(crate) fingerprints: usize,
+    pub(crate) results: Vec<VoteResult>,
+    pub(crate) timings: StageTimings,
+}
+
+/// Why a recognition failed
+pub(crate) enum PipelineError {
+    /// `server.max_concurrent_recognitions` requests are in flight
+    Busy,
+    /// The upload is not audio or a usable fingerprint file
+    BadInput(String),
+    Internal(String),
+}
+
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Busy => write!(f, "too many recognitions in progress, retry later"),
+            PipelineError::BadInput(e) | PipelineError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Where a job came from and where its outcome goes, passed from stage to
+/// stage with the job's data
+struct Ticket {
+    target: Arc<MatchTarget>,
+    /// Span of the request, entered by every stage
+    span: Span,
+    /// Filled in by each stage the job passes
+    timings: StageTimings,
+    reply: oneshot::Sender<Result<Recognition, PipelineError>>,
+}
+
+impl Ticket {
+    /// The requester stopped waiting, e.g. the client hung up, so the
+    /// remaining stages can skip the job
+    fn abandoned(&self) -> bool {
+        self.reply.is_closed()
+    }
+
+    /// Hand `data` to the `next` stage from a blocking worker, waiting while
+    /// its channel is full
+    fn forward<T>(self, next: &mpsc::Sender<Job<T>>, data: T) {
+        // A closed channel drops the ticket, which fails the request
+        let _ = next.blocking_send(Job { data, ticket: self });
+    }
+
+    fn finish(self, outcome: Result<Recognition, PipelineError>) {
+        let _ = self.reply.send(outcome);
+    }
+}
+
+struct Job<T> {
+    data: T,
+    ticket: Ticket,
+}
+
+/// Hash matches of the query fingerprints among the songs of the collection
+struct Lookup {
+    fingerprints: Vec<FingerprintInfo>,
+    matches: HashMap<u64, Vec<(u32, f32)>>,
+    song_count: usize,
+}
+
+/// Handle to the stages, cheap to clone. The stages run until every handle
+/// is dropped.
+#[derive(Clone)]
+pub(crate) struct Pipeline {
+    admitted: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+    decode: mpsc::Sender<Job<Bytes>>,
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    stft: mpsc::Sender<Job<(Vec<f32>, u32)>>,
+}
+
+impl Pipeline {
+    /// Start the stages on the current tokio runtime, looking fingerprints up
+    /// in `store`
+    pub(crate) fn new<S>(store: S, config: Arc<Config>, metrics: Arc<Metrics>) -> Self
+    where
+        S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+    {
+        let server = &config.server;
+        let workers = match server.pipeline_workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            workers => workers,
+        };
+        let (decode, decode_jobs) = mpsc::channel(server.pipeline_queue);
+        let (stft, stft_jobs) = mpsc::channel(server.pipeline_queue);
+        let (fingerprint, fingerprint_jobs) = mpsc::channel(server.pipeline_queue);
+        let (lookup, lookup_jobs) = mpsc::channel(server.pipeline_queue);
+        let (vote, vote_jobs) = mpsc::channel(server.pipeline_queue);
+
+        let stage_config = Arc::clone(&config);
+        let stage_metrics = Arc::clone(&metrics);
+        let (to_stft, to_lookup) = (stft.clone(), lookup.clone());
+        blocking_stage(decode_jobs, workers, move |bytes: Bytes, mut ticket| {
+            if bytes.starts_with(FP_MAGIC) {
+                match read_fingerprint_file(&bytes, ticket.target.scheme, &stage_config) {
+                    Ok(fingerprints) => ticket.forward(&to_lookup, fingerprints),
+                    Err(e) => ticket.finish(Err(PipelineError::BadInput(e))),
+                }
+                return;
+            }
+            let started = Instant::now();
+            match AudioProcessor::new().get_decoded_audio_from_bytes(bytes.to_vec()) {
+                Ok(decoded) => {
+                    ticket.timings.decode = started.elapsed();
+                    stage_metrics.observe(Stage::Decode, ticket.timings.decode);
+                    ticket.forward(&to_stft, decoded);
+                }
+                Err(e) => ticket.finish(Err(PipelineError::BadInput(format!(
+                    "could not decode audio: {}",
+                    e
+                )))),
+            }
+        });
+
+        let stage_metrics = Arc::clone(&metrics);
+        // Hashing is left to the next stage, which knows the scheme
+        let peaks = dsp::Pipeline::builder(&config, HashScheme::default())
+            .until("hash")
+            .build();
+        blocking_stage(
+            stft_jobs,
+            workers,
+            move |(samples, sample_rate): (Vec<f32>, u32), mut ticket| {
+                let started = Instant::now();
+                let timings = &mut ticket.timings;
+                let distribution =
+                    peaks.peak_frames_timed(samples, sample_rate, |stage, elapsed| {
+                        timings.observe(stage, elapsed)
+                    });
+                stage_metrics.observe(Stage::Fft, started.elapsed());
+                ticket.forward(&fingerprint, distribution);
+            },
+        );
+
+        let stage_config = Arc::clone(&config);
+        let stage_metrics = Arc::clone(&metrics);
+        blocking_stage(
+            fingerprint_jobs,
+            workers,
+            move |distribution: Vec<FFTDistribution>, mut ticket| {
+                let started = Instant::now();
+                let scheme = ticket.target.scheme;
+                let fingerprints = expand_query(
+                    generate_audio_fingerprint(&distribution, scheme, &stage_config.fingerprint),
+                    scheme,
+                    &stage_config.matching,
+                );
+                ticket.timings.fingerprint = started.elapsed();
+                stage_metrics.observe(Stage::Fingerprint, ticket.timings.fingerprint);
+                ticket.forward(&lookup, fingerprints);
+            },
+        );
+
+        let version = config.fingerprint_version();
+        lookup_stage(
+            lookup_jobs,
+            workers,
+            store,
+            version,
+            vote,
+            Arc::clone(&metrics),
+        );
+
+        let stage_config = Arc::clone(&config);
+        let stage_metrics = Arc::clone(&metrics);
+        blocking_stage(vote_jobs, workers, move |lookup: Lookup, mut ticket| {
+            let started = Instant::now();
+            let results = vote_best_matches(
+                &lookup.fingerprints,
+                &lookup.matches,
+                Some(lookup.song_count),
+                ticket.target.top_k,
+                &stage_config.matching,
+            );
+            ticket.timings.vote = started.elapsed();
+            stage_metrics.observe(Stage::Vote, ticket.timings.vote);
+            let timings = std::mem::take(&mut ticket.timings);
+            ticket.finish(Ok(Recognition {
+                fingerprints: lookup.fingerprints.len(),
+                results,
+                timings,
+            }));
+        });
+
+        Self {
+            admitted: Arc::new(Semaphore::new(server.max_concurrent_recognitions)),
+            metrics,
+            decode,
+            stft,
+        }
+    }
+
+    /// Admit a request, which may use the pipeline until the permit is
+    /// dropped, or refuse it when the server is at its limit
+    pub(crate) fn admit(&self) -> Result<OwnedSemaphorePermit, PipelineError> {
+        Arc::clone(&self.admitted).try_acquire_owned().map_err(|_| {
+            self.metrics.busy();
+            PipelineError::Busy
+        })
+    }
+
+    /// Match an uploaded audio or fingerprint file (`sabi fingerprint`); a
+    /// fingerprint file skips the stages up to the lookup
+    pub(crate) async fn recognise_upload(
+        &self,
+        bytes: Bytes,
+        target: MatchTarget,
+    ) -> Result<Recognition, PipelineError> {
+        submit(&self.decode, bytes, target).await
+    }
+
+    /// Match decoded mono `samples`, skipping the decode stage
+    #[cfg(feature = "grpc")]
+    pub(crate) async fn recognise_samples(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        target: MatchTarget,
+    ) -> Result<Recognition, PipelineError> {
+        submit(&self.stft, (samples, sample_rate), target).await
+    }
+}
+
+/// Queue `data` at `stage`, waiting while its channel is full, and wait for
+/// the outcome
+async fn submit<T>(
+    stage: &mpsc::Sender<Job<T>>,
+    data: T,
+    target: MatchTarget,
+) -> Result<Recognition, PipelineError> {
+    let (reply, outcome) = oneshot::channel();
+    let ticket = Ticket {
+        target: Arc::new(target),
+        span: Span::current(),
+        timings: StageTimings::default(),
+        reply,
+    };
+    stage
+        .send(Job { data, ticket })
+        .await
+        .map_err(|_| PipelineError::Internal("the recognition pipeline stopped".to_string()))?;
+    // A stage that panicked dropped the ticket
+    outcome
+        .await
+        .unwrap_or_else(|_| Err(PipelineError::Internal("recognition failed".to_string())))
+}
+
+/// Run `work` on the jobs of `jobs` on the blocking thread pool, up to
+/// `workers` at a time. `work` passes the job on with [`Ticket::forward`]
+/// or answers it with [`Ticket::finish`].
+fn blocking_stage<T, F>(mut jobs: mpsc::Receiver<Job<T>>, workers: usize, work: F)
+where
+    T: Send + 'static,
+    F: Fn(T, Ticket) + Send + Sync + 'static,
+{
+    let work = Arc::new(work);
+    let running = Arc::new(Semaphore::new(workers));
+    tokio::spawn(async move {
+        while let Some(Job { data, ticket }) = jobs.recv().await {
+            if ticket.abandoned() {
+                continue;
+            }
+            let Ok(permit) = Arc::clone(&running).acquire_owned().await else {
+                return;
+            };
+            let work = Arc::clone(&work);
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let span = ticket.span.clone();
+                let _span = span.enter();
+                work(data, ticket);
+            });
+        }
+    });
+}
+
+/// Look the fingerprints of `jobs` up in `store` among the songs
+/// fingerprinted at `version`, up to `workers` at a time, and pass the
+/// matches on to the `vote` stage
+fn lookup_stage<S>(
+    mut jobs: mpsc::Receiver<Job<Vec<FingerprintInfo>>>,
+    workers: usize,
+    store: S,
+    version: i32,
+    vote: mpsc::Sender<Job<Lookup>>,
+    metrics: Arc<Metrics>,
+) where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let running = Arc::new(Semaphore::new(workers));
+    tokio::spawn(async move {
+        while let Some(Job { data, mut ticket }) = jobs.recv().await {
+            if ticket.abandoned() {
+                continue;
+            }
+            let Ok(permit) = Arc::clone(&running).acquire_owned().await else {
+                return;
+            };
+            let (store, vote, metrics) = (store.clone(), vote.clone(), Arc::clone(&metrics));
+            let span = ticket.span.clone();
+            tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    metrics.fingerprinted(data.len());
+                    let started = Instant::now();
+                    match lookup(&store, &ticket.target, version, data).await {
+                        Ok(lookup) => {
+                            ticket.timings.db_fetch = started.elapsed();
+                            ticket.timings.hashes = lookup.fingerprints.len();
+                            ticket.timings.rows = lookup.matches.values().map(Vec::len).sum();
+                            metrics.observe(Stage::DbFetch, ticket.timings.db_fetch);
+                            let _ = vote
+                                .send(Job {
+                                    data: lookup,
+                                    ticket,
+                                })
+                                .await;
+                        }
+                        Err(e) => ticket.finish(Err(PipelineError::Internal(e))),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    });
+}
+
+/// Matches of `fingerprints` among the songs of the collection of `target`
+/// fingerprinted at `version`; the others cannot match and are skipped with
+/// a warning. Fingerprints deny-listed for the collection are dropped from
+/// the query.
+async fn lookup<S>(
+    store: &S,
+    target: &MatchTarget,
+    version: i32,
+    mut fingerprints: Vec<FingerprintInfo>,
+) -> Result<Lookup, String>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let (denied, versions) = tokio::try_join!(
+        store.denied_hashes(&target.collection),
+        store.song_versions_in_collection(&target.collection),
+    )
+    .map_err(|e| e.to_string())?;
+    let song_ids: HashSet<u32> = versions
+        .iter()
+        .filter(|&(_, &song_version)| song_version == version)
+        .map(|(&song_id, _)| song_id)
+        .collect();
+    warn_outdated(versions.len() - song_ids.len());
+    fingerprints.retain(|fp| !denied.contains(&fp.hash));
+
+    let hashes: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+    let mut matches = store
+        .fetch_matches_grouped_by_hash(&hashes, target.scheme)
+        .await
+        .map_err(|e| e.to_string())?;
+    retain_songs(&mut matches, &song_ids);
+    Ok(Lookup {
+        fingerprints,
+        matches,
+        song_count: song_ids.len(),
+    })
+}
+
+/// The fingerprints of a fingerprint file, if they were made like the ones
+/// of the collection
+fn read_fingerprint_file(
+    bytes: &[u8],
+    scheme: HashScheme,
+    config: &Config,
+) -> Result<Vec<FingerprintInfo>, String> {
+    let fp_file = FingerprintFile::read_from(bytes)
+        .map_err(|e| format!("invalid fingerprint file: {}", e))?;
+    if fp_file.scheme != scheme {
+        return Err(format!(
+            "fingerprint file has {:?} hashes but the collection stores {:?}",
+            fp_file.scheme, scheme
+        ));
+    }
+    if fp_file.fingerprint_version != config.fingerprint_version() {
+        return Err(format!(
+            "fingerprint file is version {}, the server uses version {}",
+            fp_file.fingerprint_version,
+            config.fingerprint_version()
+        ));
+    }
+    Ok(expand_query(fp_file.fingerprints, scheme, &config.matching))
+}
@@ -0,0 +1,99 @@
+//! `--play-from-match`: play the rest of the matched song from where the
+//! query left off, through the default output device or an external player.
+
+use crate::audio_processor::AudioProcessor;
+use crate::config::PlaybackConfig;
+use crate::db::bindings::Songs;
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use std::path::Path;
+use std::process::Command;
+
+/// What `--play-from-match` does with the best match
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum PlayMode {
+    /// Play the song, or run `playback.command`
+    #[default]
+    Play,
+    /// Only print the command, or the file and position without one
+    Print,
+}
+
+/// Hand `song` over to a player, `start` seconds in
+pub fn play_from(
+    song: &Songs,
+    start: f32,
+    mode: PlayMode,
+    config: &PlaybackConfig,
+) -> Result<(), String> {
+    let path = song.file_path.as_deref().ok_or_else(|| {
+        format!(
+            "no file is known for '{}', run reingest on its directory to record it",
+            song.title
+        )
+    })?;
+    if !Path::new(path).is_file() {
+        return Err(format!("'{}' no longer exists", path));
+    }
+    let start = start.max(0.0);
+    if song
+        .duration
+        .is_some_and(|duration| start as f64 >= duration)
+    {
+        return Err(format!("'{}' is already over", song.title));
+    }
+
+    match (&config.command, mode) {
+        (Some(template), PlayMode::Play) => run_command(&command_line(template, path, start)),
+        (Some(template), PlayMode::Print) => {
+            println!("{}", command_line(template, path, start).join(" "));
+            Ok(())
+        }
+        (None, PlayMode::Play) => {
+            play_file(path, start);
+            Ok(())
+        }
+        (None, PlayMode::Print) => {
+            println!("{} from {:.3}s", path, start);
+            Ok(())
+        }
+    }
+}
+
+/// The words of `template` with `{path}` and `{start}` filled in. Splitting
+/// before substituting keeps a path with spaces one argument.
+fn command_line(template: &str, path: &str, start: f32) -> Vec<String> {
+    let start = format!("{:.3}", start);
+    template
+        .split_whitespace()
+        .map(|word| word.replace("{path}", path).replace("{start}", &start))
+        .collect()
+}
+
+fn run_command(command_line: &[String]) -> Result<(), String> {
+    let (program, args) = command_line
+        .split_first()
+        .ok_or("playback.command is empty")?;
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} exited with {}", program, status))
+    }
+}
+
+/// Decode `path` from `start` seconds on and play it to the end
+fn play_file(path: &str, start: f32) {
+    let audio_processor = AudioProcessor::new();
+    let (samples, sample_rate) =
+        audio_processor.get_decoded_audio_range(path.to_string(), start as f64, None);
+    // Decoding mixes down to mono
+    let config = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(sample_rate),
+        buffer_size: BufferSize::Default,
+    };
+    audio_processor.play_recording(samples, &config);
+}
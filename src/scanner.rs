@@ -0,0 +1,151 @@
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::db::{connector::DB, storage::fingerprint_store};
+use crate::dsp::Pipeline;
+use crate::fft::fft::CooleyTukeyFFT;
+use crate::fingerprint::{FingerprintInfo, FingerprintStream, expand_query, vote_best_matches};
+
+/// A contiguous stretch of the recording attributed to one song (or to none).
+struct Segment {
+    start: f32,
+    end: f32,
+    song_id: Option<u32>,
+    best_confidence: f32,
+}
+
+/// Format seconds as `mm:ss`
+pub fn format_timestamp(secs: f32) -> String {
+    let total = secs.max(0.0) as u32;
+    format!("{:02}:{:02}", total / 60, total % 60)
+}
+
+/// Identifies every song in a long recording (DJ mix, radio aircheck) by
+/// matching overlapping windows and merging them into a timeline.
+/// Only the part from `start` to `end` seconds is decoded; timestamps stay
+/// relative to the whole recording.
+pub fn run_scan(
+    file_name: &str,
+    window_secs: f32,
+    hop_secs: f32,
+    start: f32,
+    end: Option<f32>,
+    collection: &str,
+    config: &Config,
+) {
+    if window_secs <= 0.0 || hop_secs <= 0.0 {
+        eprintln!("Error: --window and --hop must be positive");
+        return;
+    }
+    if start < 0.0 || end.is_some_and(|end| end <= start) {
+        eprintln!("Error: --start must be positive and before --end");
+        return;
+    }
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let fft = CooleyTukeyFFT::from_config(&config.fft);
+    let db = DB::new();
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let scheme = db.collection_hash_scheme(collection).unwrap_or_default();
+
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio_range(
+        file_name.to_string(),
+        start as f64,
+        end.map(|end| (end - start) as f64),
+    );
+    let (resampled, target_sr) =
+        Pipeline::from_config(config, scheme).audio(audio_samples, sample_rate);
+    let duration = resampled.len() as f32 / target_sr as f32;
+
+    println!(
+        "🎚️ Scanning {:.1}s of audio in {}s windows every {}s",
+        duration, window_secs, hop_secs
+    );
+
+    // Fingerprint the whole recording once, then slice by anchor time per window.
+    // Streamed frame by frame so long recordings never hold every STFT frame.
+    let mut stream = FingerprintStream::new(scheme, config.fingerprint.clone());
+    let mut fingerprints = Vec::new();
+    for frame in fft.frames(&resampled, target_sr) {
+        fingerprints.extend(stream.push(frame));
+    }
+    fingerprints.extend(stream.finish());
+    let fingerprints = store.without_denied(expand_query(fingerprints, scheme, &config.matching));
+    println!("Generated {} fingerprints", fingerprints.len());
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut window_start = 0.0f32;
+
+    while window_start < duration {
+        let window_end = window_start + window_secs;
+        let window_fps: Vec<FingerprintInfo> = fingerprints
+            .iter()
+            .filter(|f| (window_start..window_end).contains(&f.abs_anchor_tm_offset))
+            .copied()
+            .collect();
+
+        let best = if window_fps.is_empty() {
+            None
+        } else {
+            let hash_vec: Vec<i64> = window_fps.iter().map(|f| f.hash as i64).collect();
+            let db_matches_by_hash = store
+                .fetch_matches_grouped_by_hash(&hash_vec)
+                .expect("Fingerprint lookup failed");
+            vote_best_matches(
+                &window_fps,
+                &db_matches_by_hash,
+                store.song_count(),
+                1,
+                &config.matching,
+            )
+            .into_iter()
+            .next()
+        };
+
+        let song_id = best.as_ref().map(|r| r.song_id);
+        let confidence = best.as_ref().map_or(0.0, |r| r.confidence);
+        let segment_end = (window_start + hop_secs).min(duration);
+
+        match segments.last_mut() {
+            Some(last) if last.song_id == song_id => {
+                last.end = segment_end;
+                last.best_confidence = last.best_confidence.max(confidence);
+            }
+            _ => segments.push(Segment {
+                start: window_start,
+                end: segment_end,
+                song_id,
+                best_confidence: confidence,
+            }),
+        }
+
+        window_start += hop_secs;
+    }
+
+    let song_ids: Vec<i32> = segments
+        .iter()
+        .filter_map(|s| s.song_id.map(|id| id as i32))
+        .collect();
+    let titles = db.fetch_song_titles(&song_ids);
+
+    println!("📜 Timeline:");
+    for segment in segments {
+        let range = format!(
+            "{}–{}",
+            format_timestamp(start + segment.start),
+            format_timestamp(start + segment.end)
+        );
+        match segment.song_id {
+            Some(id) => {
+                let title = titles
+                    .get(&(id as i32))
+                    .cloned()
+                    .unwrap_or_else(|| "<unknown>".to_string());
+                println!(
+                    "  {} {} (confidence={:.2})",
+                    range, title, segment.best_confidence
+                );
+            }
+            None => println!("  {} <no match>", range),
+        }
+    }
+}
@@ -1,5 +1,58 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    /// View over `fingerprint` and `fingerprint_compact`
+    all_fingerprints (song_id, absolute_time_offset, hash) {
+        hash -> Int8,
+        absolute_time_offset -> Float8,
+        song_id -> Int4,
+    }
+}
+
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        #[max_length = 64]
+        name -> Varchar,
+        #[max_length = 64]
+        key_hash -> Bpchar,
+        #[max_length = 32]
+        scopes -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    chroma_profiles (song_id) {
+        song_id -> Int4,
+        tempo -> Float4,
+        beats -> Int4,
+        profile -> Bytea,
+    }
+}
+
+diesel::table! {
+    collections (name) {
+        #[max_length = 64]
+        name -> Varchar,
+        hash_bits -> Int2,
+        created_at -> Nullable<Timestamp>,
+        #[max_length = 16]
+        kind -> Varchar,
+    }
+}
+
+diesel::table! {
+    denied_hashes (collection, hash) {
+        #[max_length = 64]
+        collection -> Varchar,
+        hash -> Int8,
+        hash_bits -> Int2,
+        song_count -> Int4,
+        created_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     fingerprint (song_id, absolute_time_offset, hash) {
         hash -> Int8,
@@ -9,15 +62,66 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    fingerprint_compact (song_id, absolute_time_offset, hash) {
+        hash -> Int4,
+        absolute_time_offset -> Float4,
+        song_id -> Int4,
+    }
+}
+
+diesel::table! {
+    matches (id) {
+        id -> Int4,
+        song_id -> Int4,
+        score -> Int4,
+        confidence -> Float4,
+        #[max_length = 16]
+        source -> Varchar,
+        #[max_length = 1024]
+        input -> Nullable<Varchar>,
+        matched_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     songs (id) {
         id -> Int4,
         #[max_length = 255]
         title -> Varchar,
         created_at -> Nullable<Timestamp>,
+        duration -> Nullable<Float8>,
+        #[max_length = 255]
+        artist -> Nullable<Varchar>,
+        #[max_length = 255]
+        album -> Nullable<Varchar>,
+        track_number -> Nullable<Int4>,
+        year -> Nullable<Int4>,
+        #[max_length = 64]
+        checksum -> Nullable<Varchar>,
+        #[max_length = 64]
+        collection -> Varchar,
+        fingerprint_version -> Int4,
+        file_path -> Nullable<Text>,
+        bpm -> Nullable<Float4>,
+        #[max_length = 16]
+        musical_key -> Nullable<Varchar>,
     }
 }
 
+diesel::joinable!(chroma_profiles -> songs (song_id));
 diesel::joinable!(fingerprint -> songs (song_id));
+diesel::joinable!(fingerprint_compact -> songs (song_id));
+diesel::joinable!(matches -> songs (song_id));
 
-diesel::allow_tables_to_appear_in_same_query!(fingerprint, songs,);
+diesel::allow_tables_to_appear_in_same_query!(
+    all_fingerprints,
+    api_keys,
+    chroma_profiles,
+    collections,
+    denied_hashes,
+    fingerprint,
+    fingerprint_compact,
+    matches,
+    songs,
+);
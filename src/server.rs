@@ -0,0 +1,516 @@
+use crate::auth::{RateLimiter, Scope, has_scope, hash_key};
+use crate::config::Config;
+use crate::db::{
+    async_connector::AsyncDB,
+    bindings::Songs,
+    connector::MatchSource,
+    storage::{AsyncFingerprintStore, StoreError},
+};
+use crate::fingerprint::{VoteResult, concurrent_matches};
+use crate::ingest::{Upload, ingest_upload};
+use crate::metrics::Metrics;
+use crate::notifier::{MatchEvent, Notifier};
+use crate::pipeline::{MatchTarget, Pipeline, PipelineError, Recognition};
+use crate::timings::StageTimings;
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{Span, info, instrument, warn};
+
+/// Largest accepted upload; a few minutes of compressed audio
+pub(crate) const MAX_UPLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct RecogniseParams {
+    top_k: Option<usize>,
+    collection: Option<String>,
+    /// Add the time spent in each stage to the response
+    #[serde(default)]
+    timings: bool,
+}
+
+#[derive(Deserialize)]
+struct IngestParams {
+    title: String,
+    artist: Option<String>,
+    album: Option<String>,
+    collection: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IngestResponse {
+    song_id: i32,
+    /// Fingerprints stored; absent when the file was ingested before
+    fingerprints: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MatchResponse {
+    song_id: u32,
+    title: String,
+    score: usize,
+    confidence: f32,
+    time_offset: f32,
+    refined_offset: f32,
+    speed: f32,
+    match_start: f32,
+    match_end: f32,
+    coverage: f32,
+    /// Estimated tempo and key of the song, if known
+    bpm: Option<f32>,
+    key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RecogniseResponse {
+    fingerprints: usize,
+    matches: Vec<MatchResponse>,
+    /// Several songs were heard at once, with `matching.multi_match` on
+    concurrent: bool,
+    /// Only with `timings=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<StageTimings>,
+}
+
+type ApiError = (StatusCode, String);
+
+/// Shared by every request handler, HTTP and gRPC alike
+#[derive(Clone)]
+pub(crate) struct AppState<S> {
+    pub(crate) store: S,
+    pub(crate) config: Arc<Config>,
+    pub(crate) notifier: Arc<Notifier>,
+    pub(crate) metrics: Arc<Metrics>,
+    /// `None` without a limit
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) pipeline: Pipeline,
+}
+
+impl<S> AppState<S>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    /// Also starts the recognition pipeline, so needs a tokio runtime
+    fn new(store: S, config: Config) -> Self {
+        let config = Arc::new(config);
+        let metrics = Arc::new(Metrics::new());
+        Self {
+            notifier: Arc::new(Notifier::from_config(&config.notify)),
+            rate_limiter: RateLimiter::new(
+                config.server.rate_limit_per_minute,
+                config.server.rate_limit_burst,
+            )
+            .map(Arc::new),
+            pipeline: Pipeline::new(store.clone(), Arc::clone(&config), Arc::clone(&metrics)),
+            store,
+            config,
+            metrics,
+        }
+    }
+}
+
+/// Why a request was refused access
+pub(crate) enum Denied {
+    MissingKey,
+    UnknownKey,
+    MissingScope(Scope),
+    /// Retry after this long
+    RateLimited(Duration),
+    Store(StoreError),
+}
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denied::MissingKey => write!(f, "missing API key"),
+            Denied::UnknownKey => write!(f, "unknown API key"),
+            Denied::MissingScope(scope) => {
+                write!(f, "API key lacks the {} scope", scope.as_str())
+            }
+            Denied::RateLimited(_) => write!(f, "rate limit exceeded"),
+            Denied::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Serve recognition over HTTP, and over gRPC on `grpc_addr`, until Ctrl-C,
+/// which stops accepting connections and lets the requests in flight finish
+pub fn run_server(addr: &str, grpc_addr: Option<&str>, config: Config) {
+    if grpc_addr.is_some() && !cfg!(feature = "grpc") {
+        eprintln!("❌ --grpc-addr needs sabi built with `--features grpc`");
+        return;
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+    runtime.block_on(async {
+        let require_auth = config.server.require_auth;
+        let state = AppState::new(AsyncDB::new(), config);
+        #[cfg(feature = "grpc")]
+        let grpc_server = grpc_addr.map(|grpc_addr| {
+            let grpc_addr = grpc_addr
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid --grpc-addr {}: {}", grpc_addr, e));
+            tokio::spawn(crate::grpc::serve(grpc_addr, state.clone()))
+        });
+        let app = router(state);
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("failed to bind {}: {}", addr, e));
+
+        info!("🌐 Listening on http://{}", addr);
+        let local = listener.local_addr().map(|local| local.ip());
+        if !require_auth && local.is_ok_and(|ip| !ip.is_loopback()) {
+            warn!(
+                "Recognition is open to anyone who can reach {}; set server.require_auth",
+                addr
+            );
+        }
+        // Client addresses key the rate limiter of requests without an API key
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("Shutting down after the requests in flight");
+        })
+        .await
+        .expect("server error");
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_server) = grpc_server {
+            let _ = grpc_server.await;
+        }
+    });
+}
+
+fn router<S>(state: AppState<S>) -> Router
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route(
+            "/recognise",
+            post(recognise::<S>).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_recognise::<S>,
+            )),
+        )
+        .route(
+            "/ingest",
+            post(ingest::<S>).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_ingest::<S>,
+            )),
+        )
+        .route("/metrics", get(metrics::<S>))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .with_state(state)
+}
+
+async fn authorize_recognise<S>(
+    State(state): State<AppState<S>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let required = state.config.server.require_auth;
+    match authorize(
+        &state,
+        client,
+        request.headers(),
+        Scope::Recognise,
+        required,
+    )
+    .await
+    {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => rejection,
+    }
+}
+
+async fn authorize_ingest<S>(
+    State(state): State<AppState<S>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    match authorize(&state, client, request.headers(), Scope::Ingest, true).await {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => rejection,
+    }
+}
+
+/// The `Authorization: Bearer <key>` of a request checked for `scope`, as
+/// an HTTP error response. Takes the headers rather than the request, whose
+/// body is not `Sync`, so the future stays `Send`.
+async fn authorize<S>(
+    state: &AppState<S>,
+    client: SocketAddr,
+    headers: &HeaderMap,
+    scope: Scope,
+    required: bool,
+) -> Result<(), Response>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    check_access(state, key, Some(client.ip()), scope, required)
+        .await
+        .map_err(|denied| {
+            let status = match denied {
+                Denied::MissingKey | Denied::UnknownKey => StatusCode::UNAUTHORIZED,
+                Denied::MissingScope(_) => StatusCode::FORBIDDEN,
+                Denied::RateLimited(retry_after) => {
+                    return (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(
+                            header::RETRY_AFTER,
+                            retry_after.as_secs().max(1).to_string(),
+                        )],
+                        denied.to_string(),
+                    )
+                        .into_response();
+                }
+                Denied::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, denied.to_string()).into_response()
+        })
+}
+
+/// Check API `key` for `scope`, then take a rate limiter token of the key,
+/// or of the `client` address when there is no key and `required` is off
+pub(crate) async fn check_access<S>(
+    state: &AppState<S>,
+    key: Option<&str>,
+    client: Option<IpAddr>,
+    scope: Scope,
+    required: bool,
+) -> Result<(), Denied>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let bucket = match key {
+        Some(key) => {
+            let key_hash = hash_key(key.trim());
+            match state
+                .store
+                .api_key_scopes(&key_hash)
+                .await
+                .map_err(Denied::Store)?
+            {
+                None => return Err(Denied::UnknownKey),
+                Some(scopes) if !has_scope(&scopes, scope) => {
+                    return Err(Denied::MissingScope(scope));
+                }
+                Some(_) => format!("key:{}", key_hash),
+            }
+        }
+        None if required => return Err(Denied::MissingKey),
+        None => format!(
+            "ip:{}",
+            client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+        ),
+    };
+
+    if let Some(limiter) = &state.rate_limiter {
+        limiter.check(&bucket).map_err(Denied::RateLimited)?;
+    }
+    Ok(())
+}
+
+/// `GET /metrics` in the Prometheus text format
+async fn metrics<S>(
+    State(AppState { store, metrics, .. }): State<AppState<S>>,
+) -> ([(header::HeaderName, &'static str); 1], String)
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(store.pool_status()),
+    )
+}
+
+/// `POST /ingest?title=...` with an audio file as the request body
+#[instrument(skip_all, fields(bytes = body.len(), collection = params.collection))]
+async fn ingest<S>(
+    State(AppState {
+        config, metrics, ..
+    }): State<AppState<S>>,
+    Query(params): Query<IngestParams>,
+    body: Bytes,
+) -> Result<Json<IngestResponse>, ApiError>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let upload = Upload {
+        title: params.title,
+        artist: params.artist,
+        album: params.album,
+        collection: params.collection.unwrap_or_else(|| "default".to_string()),
+    };
+    let started = Instant::now();
+    let span = Span::current();
+    // Decoding, the STFT and the blocking database writes stay off the
+    // async workers
+    let (song_id, fingerprints) = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        ingest_upload(body.to_vec(), &upload, &config)
+    })
+    .await
+    .map_err(internal_error)?
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if let Some(count) = fingerprints {
+        metrics.ingested(count, started.elapsed());
+    }
+
+    Ok(Json(IngestResponse {
+        song_id,
+        fingerprints,
+    }))
+}
+
+/// `POST /recognise` with an audio or fingerprint file as the request body
+#[instrument(skip_all, fields(bytes = body.len(), collection = params.collection))]
+async fn recognise<S>(
+    State(state): State<AppState<S>>,
+    Query(params): Query<RecogniseParams>,
+    body: Bytes,
+) -> Result<Json<RecogniseResponse>, ApiError>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let started = Instant::now();
+    let metrics = Arc::clone(&state.metrics);
+    metrics.request(body.len());
+    let response = recognise_upload(state, params, body).await;
+    metrics.finish(
+        started.elapsed(),
+        response
+            .as_ref()
+            .ok()
+            .map(|response| !response.matches.is_empty()),
+    );
+    response
+}
+
+async fn recognise_upload<S>(
+    AppState {
+        store,
+        config,
+        notifier,
+        pipeline,
+        ..
+    }: AppState<S>,
+    params: RecogniseParams,
+    body: Bytes,
+) -> Result<Json<RecogniseResponse>, ApiError>
+where
+    S: AsyncFingerprintStore + Clone + Send + Sync + 'static,
+{
+    let _admitted = pipeline.admit().map_err(pipeline_error)?;
+    let collection = params.collection.unwrap_or_else(|| "default".to_string());
+    let target = MatchTarget {
+        scheme: store
+            .collection_hash_scheme(&collection)
+            .await
+            .map_err(internal_error)?
+            .unwrap_or_default(),
+        collection,
+        top_k: params.top_k.unwrap_or(5),
+    };
+    let Recognition {
+        fingerprints,
+        results,
+        timings,
+    } = pipeline
+        .recognise_upload(body, target)
+        .await
+        .map_err(pipeline_error)?;
+
+    let song_ids: Vec<i32> = results.iter().map(|r| r.song_id as i32).collect();
+    let songs = store.fetch_songs(&song_ids).await.map_err(internal_error)?;
+    if let Some(best) = results.first() {
+        notify(&notifier, best, songs.get(&(best.song_id as i32)));
+    }
+
+    let concurrent = concurrent_matches(&results, &config.matching);
+    let matches: Vec<MatchResponse> = results
+        .into_iter()
+        .map(|r| MatchResponse {
+            song_id: r.song_id,
+            title: songs
+                .get(&(r.song_id as i32))
+                .map_or_else(|| "<unknown>".to_string(), |s| s.title.clone()),
+            score: r.score,
+            confidence: r.confidence,
+            time_offset: r.time_offset,
+            refined_offset: r.refined_offset,
+            speed: r.speed,
+            match_start: r.match_start,
+            match_end: r.match_end,
+            coverage: r.coverage,
+            bpm: songs.get(&(r.song_id as i32)).and_then(|s| s.bpm),
+            key: songs
+                .get(&(r.song_id as i32))
+                .and_then(|s| s.musical_key.clone()),
+        })
+        .collect();
+
+    info!(
+        fingerprints,
+        top = matches.first().map(|m| m.song_id),
+        "Recognised with {} match(es)",
+        matches.len()
+    );
+    Ok(Json(RecogniseResponse {
+        fingerprints,
+        matches,
+        concurrent,
+        timings: params.timings.then_some(timings),
+    }))
+}
+
+/// Report the best match of a request to the `[notify]` sinks. They block
+/// on the network, so the response does not wait for them.
+pub(crate) fn notify(notifier: &Arc<Notifier>, best: &VoteResult, song: Option<&Songs>) {
+    if notifier.is_empty() {
+        return;
+    }
+    let event = MatchEvent::new(best, song, MatchSource::File, None);
+    let notifier = Arc::clone(notifier);
+    tokio::task::spawn_blocking(move || notifier.notify(&event));
+}
+
+fn pipeline_error(e: PipelineError) -> ApiError {
+    let status = match e {
+        PipelineError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        PipelineError::BadInput(_) => StatusCode::BAD_REQUEST,
+        PipelineError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+fn internal_error(e: impl std::fmt::Display) -> ApiError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
@@ -0,0 +1,158 @@
+//! `export-spectrogram`: the full magnitude spectrogram of a file as a
+//! matrix, for analysis outside Sabi, e.g. with NumPy.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::audio_processor::AudioProcessor;
+use crate::config::Config;
+use crate::dsp::Pipeline;
+use crate::fft::fft::{CooleyTukeyFFT, Spectrogram};
+use crate::fingerprint::HashScheme;
+
+/// Output format of `export-spectrogram`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum SpectrogramFormat {
+    /// Text with a header row of frequencies and a leading column of times
+    Csv,
+    /// NumPy array of `f32`, frames × bins, with the times and frequencies
+    /// in `.times.npy` and `.freqs.npy` next to it
+    Npy,
+}
+
+impl SpectrogramFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SpectrogramFormat::Csv => "csv",
+            SpectrogramFormat::Npy => "npy",
+        }
+    }
+}
+
+/// Decode `file`, preprocess it as for fingerprinting and write its
+/// spectrogram to `out`, `spectrogram.csv` / `spectrogram.npy` if not given.
+/// With `mel`, the bins are summed into that many mel bands between
+/// `fft.min_freq` and `fft.max_freq`.
+pub fn run_export_spectrogram(
+    file: &str,
+    out: Option<&str>,
+    format: SpectrogramFormat,
+    mel: Option<usize>,
+    config: &Config,
+) {
+    if mel == Some(0) {
+        eprintln!("Error: --mel needs at least one band");
+        std::process::exit(1);
+    }
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file.to_string());
+    let (samples, rate) =
+        Pipeline::from_config(config, HashScheme::default()).audio(audio_samples, sample_rate);
+    let mut spectrogram = CooleyTukeyFFT::from_config(&config.fft).spectrogram(&samples, rate);
+    if let Some(bands) = mel {
+        spectrogram = spectrogram.to_mel(bands, config.fft.min_freq, config.fft.max_freq);
+    }
+
+    let out = out.map_or_else(
+        || format!("spectrogram.{}", format.extension()),
+        str::to_string,
+    );
+    let written = match format {
+        SpectrogramFormat::Csv => write_csv(&spectrogram, &out),
+        SpectrogramFormat::Npy => write_npy_files(&spectrogram, &out),
+    };
+    match written {
+        Ok(()) => println!(
+            "✅ Wrote {} frames × {} {} to {}",
+            spectrogram.times.len(),
+            spectrogram.freqs.len(),
+            if mel.is_some() { "mel bands" } else { "bins" },
+            out
+        ),
+        Err(e) => {
+            eprintln!("❌ Failed to write {}: {}", out, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// One line per frame: its time, then its magnitudes, under a header of the
+/// column frequencies
+pub fn write_csv<P: AsRef<Path>>(spectrogram: &Spectrogram, path: P) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "time")?;
+    for freq in &spectrogram.freqs {
+        write!(writer, ",{}", freq)?;
+    }
+    writeln!(writer)?;
+    for (time, frame) in spectrogram.times.iter().zip(&spectrogram.magnitudes) {
+        write!(writer, "{}", time)?;
+        for magnitude in frame {
+            write!(writer, ",{}", magnitude)?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// The matrix to `path`, and its time and frequency axes to the `.times.npy`
+/// and `.freqs.npy` files beside it
+fn write_npy_files(spectrogram: &Spectrogram, path: &str) -> io::Result<()> {
+    let stem = path.strip_suffix(".npy").unwrap_or(path);
+    let rows = spectrogram.times.len();
+    let columns = spectrogram.freqs.len();
+    write_npy(
+        path,
+        &[rows, columns],
+        spectrogram.magnitudes.iter().flatten(),
+    )?;
+    write_npy(
+        format!("{}.times.npy", stem),
+        &[rows],
+        spectrogram.times.iter(),
+    )?;
+    write_npy(
+        format!("{}.freqs.npy", stem),
+        &[columns],
+        spectrogram.freqs.iter(),
+    )
+}
+
+/// `values` as a little-endian `f32` array of `shape` in NPY format 1.0
+pub fn write_npy<'a, P: AsRef<Path>>(
+    path: P,
+    shape: &[usize],
+    values: impl Iterator<Item = &'a f32>,
+) -> io::Result<()> {
+    let shape = match shape {
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // Magic, version and header length take 10 bytes; the header is padded
+    // with spaces and ends in a newline so the data starts 64-byte aligned
+    let padded = (10 + header.len() + 1).div_ceil(64) * 64 - 10;
+    header.push_str(&" ".repeat(padded - header.len() - 1));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()
+}
@@ -0,0 +1,138 @@
+//! Tempo estimation from magnitude spectra: spectral flux gives an onset
+//! strength envelope, and its autocorrelation peaks at the beat period.
+//! Cover matching places beats with it, and ingest stores the tempo of each
+//! song.
+
+/// Tempo range considered, in BPM
+pub const MIN_BPM: f32 = 60.0;
+pub const MAX_BPM: f32 = 180.0;
+/// Tempo preferred when multiples of the beat period fit about as well
+pub const PREFERRED_BPM: f32 = 120.0;
+/// Resolution of [`estimate_bpm`], in BPM
+const BPM_STEP: f32 = 0.25;
+/// Multiples of the beat period [`estimate_bpm`] checks the onsets repeat at
+const COMB_BEATS: usize = 4;
+
+/// Onset strength of consecutive frames: the spectral flux of their
+/// log-compressed magnitudes
+#[derive(Default)]
+pub struct OnsetDetector {
+    previous: Option<Vec<f32>>,
+}
+
+impl OnsetDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Onset strength of the frame after the last one given, 0 for the first
+    pub fn next(&mut self, magnitudes: &[f32]) -> f32 {
+        let compressed: Vec<f32> = magnitudes.iter().map(|m| m.ln_1p()).collect();
+        let flux = self.previous.as_ref().map_or(0.0, |previous| {
+            compressed
+                .iter()
+                .zip(previous)
+                .map(|(now, before)| (now - before).max(0.0))
+                .sum()
+        });
+        self.previous = Some(compressed);
+        flux
+    }
+}
+
+/// Mean and standard deviation of `values`
+pub fn mean_and_std(values: &[f32]) -> (f32, f32) {
+    let n = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+    (mean, std)
+}
+
+/// `onsets` scaled to zero mean and unit variance, `None` when they are flat
+fn standardized(onsets: &[f32]) -> Option<Vec<f32>> {
+    let (mean, std) = mean_and_std(onsets);
+    if std <= f32::EPSILON {
+        return None;
+    }
+    Some(onsets.iter().map(|o| (o - mean) / std).collect())
+}
+
+/// Mean product of `centered` with itself shifted by `lag` frames
+fn autocorrelation(centered: &[f32], lag: usize) -> f32 {
+    centered
+        .iter()
+        .zip(&centered[lag..])
+        .map(|(a, b)| a * b)
+        .sum::<f32>()
+        / (centered.len() - lag) as f32
+}
+
+/// Beat period, in frames, of an onset strength envelope: the
+/// autocorrelation lag in the [`MIN_BPM`]–[`MAX_BPM`] range weighted best
+/// around [`PREFERRED_BPM`]. `None` for audio too short or without onsets.
+pub fn beat_period(onsets: &[f32], frame_secs: f32) -> Option<usize> {
+    let min_lag = (60.0 / MAX_BPM / frame_secs).round().max(1.0) as usize;
+    let max_lag = (60.0 / MIN_BPM / frame_secs).round() as usize;
+    if onsets.len() < 2 * max_lag {
+        return None;
+    }
+    let centered = standardized(onsets)?;
+
+    let preferred_lag = 60.0 / PREFERRED_BPM / frame_secs;
+    let tempo_score = |lag: usize| {
+        // Log-Gaussian of one octave around the preferred tempo
+        let octaves = (lag as f32 / preferred_lag).log2();
+        autocorrelation(&centered, lag) * (-0.5 * octaves * octaves).exp()
+    };
+    (min_lag..=max_lag).max_by(|&a, &b| tempo_score(a).total_cmp(&tempo_score(b)))
+}
+
+/// Tempo in BPM of an onset strength envelope. A period of whole frames is
+/// too coarse at fingerprinting hops of about 90 ms, where 120 and 129 BPM
+/// are a frame apart, so each tempo on a [`BPM_STEP`] grid is scored by the
+/// autocorrelation, interpolated between lags, at its first [`COMB_BEATS`]
+/// periods, weighted around [`PREFERRED_BPM`] as in [`beat_period`].
+pub fn estimate_bpm(onsets: &[f32], frame_secs: f32) -> Option<f32> {
+    let longest = 60.0 / MIN_BPM / frame_secs;
+    let max_lag = (COMB_BEATS as f32 * longest).ceil() as usize + 1;
+    if onsets.len() < 2 * max_lag {
+        return None;
+    }
+    let centered = standardized(onsets)?;
+    let correlations: Vec<f32> = (0..=max_lag)
+        .map(|lag| autocorrelation(&centered, lag))
+        .collect();
+    let interpolated = |lag: f32| {
+        let (below, fraction) = (lag.floor() as usize, lag.fract());
+        correlations[below] * (1.0 - fraction) + correlations[below + 1] * fraction
+    };
+
+    let steps = ((MAX_BPM - MIN_BPM) / BPM_STEP).round() as usize;
+    let tempo_score = |bpm: f32| {
+        let period = 60.0 / bpm / frame_secs;
+        let comb = (1..=COMB_BEATS)
+            .map(|beat| interpolated(beat as f32 * period))
+            .sum::<f32>()
+            / COMB_BEATS as f32;
+        let octaves = (bpm / PREFERRED_BPM).log2();
+        comb * (-0.5 * octaves * octaves).exp()
+    };
+    (0..=steps)
+        .map(|step| MIN_BPM + step as f32 * BPM_STEP)
+        .max_by(|&a, &b| tempo_score(a).total_cmp(&tempo_score(b)))
+}
+
+/// Tempo in BPM of magnitude spectra, as the `stft` stage of
+/// [`crate::dsp`] yields them: frame start in seconds and magnitudes
+pub fn spectra_bpm(frames: &[(f32, Vec<f32>)]) -> Option<f32> {
+    let frame_secs = match frames {
+        [(first, _), (second, _), ..] if second > first => second - first,
+        _ => return None,
+    };
+    let mut detector = OnsetDetector::new();
+    let onsets: Vec<f32> = frames
+        .iter()
+        .map(|(_, magnitudes)| detector.next(magnitudes))
+        .collect();
+    estimate_bpm(&onsets, frame_secs)
+}
@@ -1,121 +1,1457 @@
-use crate::audio_processor::AudioProcessor;
-use crate::db::connector::DB;
-use crate::fft::fft::CooleyTukeyFFT;
-use crate::fingerprint::{generate_audio_fingerprint, vote_best_matches};
-use rand::Rng;
+use crate::audio_processor::{AudioProcessor, ffmpeg_available};
+use crate::cancel::CancelToken;
+use crate::config::{Config, DecodeConfig, MatchingConfig};
+use crate::db::{
+    bindings::NewSong,
+    connector::{CollectionKind, DB},
+    lmdb_store::LmdbStore,
+    storage::{FingerprintStore, fingerprint_store, store_song},
+};
+use crate::dsp::Pipeline;
+use crate::fingerprint::{HashScheme, cap_song_fingerprints, expand_query, vote_best_matches};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Options of `--random-test`
+#[derive(clap::Args, Debug, Clone)]
+pub struct TestOptions {
+    /// Also test every snippet with noise mixed in at these signal-to-noise
+    /// ratios in dB, e.g. `--snr 20,10,5,0`
+    #[arg(long, value_delimiter = ',', requires = "random_test")]
+    pub snr: Vec<f32>,
+
+    /// Color of the noise mixed in for `--snr`
+    #[arg(long, value_enum, default_value_t = NoiseKind::White, requires = "random_test")]
+    pub noise: NoiseKind,
+
+    /// Also test every snippet played this many percent faster and slower
+    /// (tempo and pitch change together), e.g. `--speed 1,2,5`
+    #[arg(long, value_delimiter = ',', requires = "random_test")]
+    pub speed: Vec<f32>,
+
+    /// Also test every snippet shifted this many semitones up and down at
+    /// the original tempo, e.g. `--pitch 0.5,1`
+    #[arg(long, value_delimiter = ',', requires = "random_test")]
+    pub pitch: Vec<f32>,
+
+    /// Also test every snippet as if played through a speaker in these rooms
+    /// and captured with a phone microphone, e.g. `--rerecord small,hall`
+    #[arg(long, value_enum, value_delimiter = ',', requires = "random_test")]
+    pub rerecord: Vec<Room>,
+
+    /// Also test every snippet after re-encoding it with these lossy codecs
+    /// at each `--bitrates` value; needs `ffmpeg` on the PATH
+    #[arg(long, value_enum, value_delimiter = ',', requires = "random_test")]
+    pub transcode: Vec<Codec>,
+
+    /// Bitrates in kbps for `--transcode`
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "64,96,128",
+        requires = "transcode"
+    )]
+    pub bitrates: Vec<u32>,
+
+    /// Snippet lengths in seconds. Each snippet is tested at every length,
+    /// cut from the same start, e.g. `--durations 3,5,10,15,20`
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "5",
+        requires = "random_test"
+    )]
+    pub durations: Vec<f32>,
+
+    /// Write a per-song report (counts, confusions, failed snippets) to this
+    /// file, as JSON for a `.json` path and CSV otherwise
+    #[arg(long, requires = "random_test")]
+    pub report: Option<String>,
+
+    /// Also test snippets of the songs in this directory, which must NOT be
+    /// ingested, and report how often they are falsely accepted
+    #[arg(long, requires = "random_test")]
+    pub holdout: Option<String>,
+
+    /// Seed for picking snippets and generating noise; a run with the same
+    /// seed, songs and options tests exactly the same snippets
+    #[arg(long, requires = "random_test")]
+    pub seed: Option<u64>,
+
+    /// Worker threads testing snippets in parallel [default: one per CPU]
+    #[arg(long, requires = "random_test")]
+    pub jobs: Option<usize>,
+
+    /// Confidence cutoffs to report false and true accept rates at
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "0.05,0.1,0.15,0.2,0.25,0.3,0.4,0.5",
+        requires = "holdout"
+    )]
+    pub thresholds: Vec<f32>,
+}
+
+/// Spectrum of the noise mixed into snippets
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum NoiseKind {
+    /// Equal power per Hz, like hiss
+    White,
+    /// Equal power per octave, closer to room and crowd noise
+    Pink,
+}
+
+/// Simulated room for `--rerecord`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Room {
+    /// Bedroom or car, short reverb tail
+    Small,
+    /// Living room or cafe
+    Medium,
+    /// Large hall or club, long reverb tail
+    Hall,
+}
+
+impl Room {
+    /// Reverberation time in seconds (decay by 60 dB)
+    fn rt60(&self) -> f32 {
+        match self {
+            Room::Small => 0.25,
+            Room::Medium => 0.5,
+            Room::Hall => 1.2,
+        }
+    }
+}
+
+/// Lossy codec for `--transcode`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Codec {
+    Mp3,
+    Aac,
+}
+
+impl Codec {
+    /// ffmpeg encoder and output container
+    fn ffmpeg_args(&self) -> [&'static str; 4] {
+        match self {
+            Codec::Mp3 => ["-c:a", "libmp3lame", "-f", "mp3"],
+            Codec::Aac => ["-c:a", "aac", "-f", "adts"],
+        }
+    }
+}
+
+/// A way a snippet is degraded before it is recognised
+#[derive(Clone, Copy, Debug)]
+enum Condition {
+    Clean,
+    /// Noise mixed in at this signal-to-noise ratio
+    Noise {
+        kind: NoiseKind,
+        snr_db: f32,
+    },
+    /// Played faster (positive) or slower by this many percent
+    Speed {
+        percent: f32,
+    },
+    /// Shifted up (positive) or down by this many semitones
+    Pitch {
+        semitones: f32,
+    },
+    /// Played through a speaker in this room and captured by a phone mic
+    Rerecord {
+        room: Room,
+    },
+    /// Encoded with this codec at this bitrate in kbps and decoded again
+    Transcode {
+        codec: Codec,
+        kbps: u32,
+    },
+}
+
+impl Condition {
+    fn label(&self) -> String {
+        match self {
+            Condition::Clean => "clean".to_string(),
+            Condition::Noise { kind, snr_db } => {
+                format!("{:?} noise @ {} dB", kind, snr_db).to_lowercase()
+            }
+            Condition::Speed { percent } => format!("speed {:+}%", percent),
+            Condition::Pitch { semitones } => format!("pitch {:+} st", semitones),
+            Condition::Rerecord { room } => format!("rerecorded, {:?} room", room).to_lowercase(),
+            Condition::Transcode { codec, kbps } => {
+                format!("{:?} @ {} kbps", codec, kbps).to_lowercase()
+            }
+        }
+    }
+
+    fn apply(&self, snippet: &[f32], sample_rate: u32, rng: &mut impl Rng) -> Vec<f32> {
+        match *self {
+            Condition::Clean => snippet.to_vec(),
+            Condition::Noise { kind, snr_db } => add_noise(snippet, kind, snr_db, rng),
+            Condition::Speed { percent } => change_speed(snippet, 1.0 + percent / 100.0),
+            Condition::Pitch { semitones } => {
+                let ratio = 2f32.powf(semitones / 12.0);
+                change_speed(&time_stretch(snippet, ratio), ratio)
+            }
+            Condition::Rerecord { room } => rerecord(snippet, room, sample_rate, rng),
+            // A failed encode leaves nothing to fingerprint and counts as a miss
+            Condition::Transcode { codec, kbps } => transcode(snippet, sample_rate, codec, kbps)
+                .unwrap_or_else(|e| {
+                    eprintln!("⚠️ Failed to transcode snippet: {}", e);
+                    Vec::new()
+                }),
+        }
+    }
+}
+
+/// Mix `kind` noise into `samples`, scaled so the result has the given
+/// signal-to-noise ratio
+fn add_noise(samples: &[f32], kind: NoiseKind, snr_db: f32, rng: &mut impl Rng) -> Vec<f32> {
+    let mut noise: Vec<f32> = (0..samples.len())
+        .map(|_| rng.random_range(-1.0f32..1.0))
+        .collect();
+    if let NoiseKind::Pink = kind {
+        // Paul Kellet's economy filter, -3 dB/octave
+        let (mut b0, mut b1, mut b2) = (0.0f32, 0.0f32, 0.0f32);
+        for sample in noise.iter_mut() {
+            let white = *sample;
+            b0 = 0.99765 * b0 + white * 0.0990460;
+            b1 = 0.96300 * b1 + white * 0.2965164;
+            b2 = 0.57000 * b2 + white * 1.0526913;
+            *sample = b0 + b1 + b2 + white * 0.1848;
+        }
+    }
+
+    let power = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>() / s.len().max(1) as f32;
+    let (signal_power, noise_power) = (power(samples), power(&noise));
+    if noise_power <= 0.0 {
+        return samples.to_vec();
+    }
+    let gain = (signal_power / noise_power / 10f32.powf(snr_db / 10.0)).sqrt();
+
+    samples
+        .iter()
+        .zip(&noise)
+        .map(|(signal, noise)| signal + noise * gain)
+        .collect()
+}
+
+/// Simulate playing `samples` through a speaker in `room` and recording them
+/// with a phone microphone: reverb from a synthetic impulse response, the
+/// band limits of small speakers and mics, and the mic's automatic gain
+/// control squashing the dynamics
+fn rerecord(samples: &[f32], room: Room, sample_rate: u32, rng: &mut impl Rng) -> Vec<f32> {
+    let impulse_response = room_impulse_response(room.rt60(), sample_rate, rng);
+
+    // Sparse convolution, the tail past the end of the snippet is dropped
+    let mut out = vec![0.0f32; samples.len()];
+    for &(delay, gain) in &impulse_response {
+        for (o, s) in out[delay.min(samples.len())..].iter_mut().zip(samples) {
+            *o += s * gain;
+        }
+    }
+
+    let out = band_limit(&out, sample_rate, 150.0, 7_000.0);
+    let out = compress(&out, sample_rate);
+
+    // Back to the original peak level
+    let peak = |s: &[f32]| s.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+    let (original, current) = (peak(samples), peak(&out));
+    if current <= 0.0 {
+        return out;
+    }
+    out.iter().map(|x| x * original / current).collect()
+}
+
+/// Impulse response of a room with reverberation time `rt60`, as sparse
+/// `(delay in samples, gain)` taps: the direct sound, then velvet noise (one
+/// impulse of random sign per short cell) decaying exponentially
+fn room_impulse_response(rt60: f32, sample_rate: u32, rng: &mut impl Rng) -> Vec<(usize, f32)> {
+    const IMPULSES_PER_SEC: f32 = 2_000.0;
+    const REVERB_LEVEL: f32 = 0.3;
+    /// Delay of the first reflection
+    const PREDELAY_SECS: f32 = 0.005;
+
+    let cell = (sample_rate as f32 / IMPULSES_PER_SEC).max(1.0) as usize;
+    let first = (PREDELAY_SECS * sample_rate as f32) as usize;
+    let length = (rt60 * sample_rate as f32) as usize;
+
+    let mut taps = vec![(0, 1.0)];
+    let mut cell_start = first;
+    while cell_start < length {
+        let delay = cell_start + rng.random_range(0..cell);
+        let t = delay as f32 / sample_rate as f32;
+        // -60 dB at rt60
+        let decay = (-6.91 * t / rt60).exp();
+        let sign = if rng.random_bool(0.5) { 1.0 } else { -1.0 };
+        taps.push((delay, sign * REVERB_LEVEL * decay));
+        cell_start += cell;
+    }
+    taps
+}
+
+/// Keep roughly `low..high` Hz of `samples` with one-pole high- and low-pass
+/// filters
+fn band_limit(samples: &[f32], sample_rate: u32, low: f32, high: f32) -> Vec<f32> {
+    let dt = 1.0 / sample_rate as f32;
+    // RC time constants of the filters
+    let rc = |cutoff: f32| 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+    let high_pass_alpha = rc(low) / (rc(low) + dt);
+    let low_pass_alpha = dt / (rc(high) + dt);
+
+    let mut out = Vec::with_capacity(samples.len());
+    let (mut previous_in, mut high_passed, mut low_passed) = (0.0f32, 0.0f32, 0.0f32);
+    for &sample in samples {
+        high_passed = high_pass_alpha * (high_passed + sample - previous_in);
+        previous_in = sample;
+        low_passed += low_pass_alpha * (high_passed - low_passed);
+        out.push(low_passed);
+    }
+    out
+}
+
+/// Feed-forward compressor with a fast attack and slower release, like the
+/// automatic gain control of a phone recording
+fn compress(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    const THRESHOLD: f32 = 0.1;
+    const RATIO: f32 = 4.0;
+
+    let coefficient = |secs: f32| (-1.0 / (secs * sample_rate as f32)).exp();
+    let (attack, release) = (coefficient(0.005), coefficient(0.1));
+
+    let mut envelope = 0.0f32;
+    samples
+        .iter()
+        .map(|&sample| {
+            let level = sample.abs();
+            let coefficient = if level > envelope { attack } else { release };
+            envelope = coefficient * envelope + (1.0 - coefficient) * level;
+            if envelope <= THRESHOLD {
+                return sample;
+            }
+            // Above the threshold, the level only grows by 1/RATIO
+            let target = THRESHOLD * (envelope / THRESHOLD).powf(1.0 / RATIO);
+            sample * target / envelope
+        })
+        .collect()
+}
+
+/// Encode `samples` with `codec` at `kbps` through ffmpeg and decode the
+/// result again, at the original sample rate
+fn transcode(
+    samples: &[f32],
+    sample_rate: u32,
+    codec: Codec,
+    kbps: u32,
+) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let mut child = Command::new("ffmpeg")
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-f",
+            "f32le",
+            "-ac",
+            "1",
+        ])
+        .args(["-ar", &sample_rate.to_string(), "-i", "pipe:0"])
+        .args(codec.ffmpeg_args())
+        .args(["-b:a", &format!("{}k", kbps), "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Feed stdin from another thread, so a full stdout pipe cannot deadlock us
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let raw: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let writer = std::thread::spawn(move || stdin.write_all(&raw));
+    let output = child.wait_with_output()?;
+    writer.join().expect("ffmpeg writer panicked")?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+    }
+
+    let audio_processor = AudioProcessor::new();
+    let (decoded, decoded_rate) = audio_processor.get_decoded_audio_from_bytes(output.stdout)?;
+    if decoded_rate == sample_rate {
+        return Ok(decoded);
+    }
+    Ok(audio_processor.resample_linear(&decoded, decoded_rate, sample_rate))
+}
+
+/// Play `samples` `factor` times as fast by linear-interpolation resampling,
+/// shortening them and raising the pitch by the same factor
+fn change_speed(samples: &[f32], factor: f32) -> Vec<f32> {
+    let out_len = (samples.len() as f32 / factor) as usize;
+    (0..out_len)
+        .map(|i| {
+            let position = i as f32 * factor;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = samples.get(index).copied().unwrap_or(0.0);
+            let next = samples.get(index + 1).copied().unwrap_or(current);
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+/// Make `samples` `ratio` times as long without changing the pitch, by
+/// overlap-adding Hann-windowed grains taken at a different hop than they
+/// are placed at. Crude next to a phase vocoder, but enough for the small
+/// ratios of a pitch-shift test.
+fn time_stretch(samples: &[f32], ratio: f32) -> Vec<f32> {
+    const GRAIN: usize = 1024;
+    const SYNTHESIS_HOP: usize = GRAIN / 4;
+    let analysis_hop = SYNTHESIS_HOP as f32 / ratio;
+
+    let out_len = (samples.len() as f32 * ratio) as usize;
+    let mut out = vec![0.0f32; out_len + GRAIN];
+    let mut weight = vec![0.0f32; out_len + GRAIN];
+    let window: Vec<f32> = (0..GRAIN)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / GRAIN as f32).cos())
+        .collect();
+
+    let mut grain = 0;
+    while grain * SYNTHESIS_HOP < out_len {
+        let in_start = (grain as f32 * analysis_hop) as usize;
+        let out_start = grain * SYNTHESIS_HOP;
+        for (i, w) in window.iter().enumerate() {
+            let sample = samples.get(in_start + i).copied().unwrap_or(0.0);
+            out[out_start + i] += sample * w;
+            weight[out_start + i] += w;
+        }
+        grain += 1;
+    }
+
+    out.truncate(out_len);
+    out.iter()
+        .zip(&weight)
+        .map(|(sample, w)| if *w > 1e-3 { sample / w } else { 0.0 })
+        .collect()
+}
+
+/// Best match of a tested snippet
+struct Prediction {
+    title: String,
+    score: usize,
+    confidence: f32,
+    correct: bool,
+    /// Confidence reaches `matching.min_confidence`; otherwise the snippet
+    /// counts as "no match"
+    accepted: bool,
+}
+
+/// A snippet that was not recognised as its own song
+#[derive(Serialize)]
+struct Failure {
+    /// Snippet start in seconds
+    start: f64,
+    condition: String,
+    /// Song it was matched to instead, if any
+    predicted: Option<String>,
+}
+
+/// Results of all snippets of one song, across conditions
+#[derive(Serialize, Default)]
+struct SongReport {
+    song: String,
+    tests: u32,
+    correct: u32,
+    /// Matched to another song
+    incorrect: u32,
+    no_match: u32,
+    /// Mean score of the best match, over snippets that matched anything
+    avg_score: f32,
+    /// Mean confidence of the best match, over snippets that matched anything
+    avg_confidence: f32,
+    /// Songs this one was mistaken for, with counts
+    confused_with: BTreeMap<String, u32>,
+    failures: Vec<Failure>,
+}
+
+impl SongReport {
+    fn record(&mut self, start: f64, condition: &str, prediction: Option<&Prediction>) {
+        // Snippets that matched something so far
+        let matched = self.tests - self.no_match;
+        self.tests += 1;
+        match prediction {
+            Some(p) => {
+                self.avg_score =
+                    (self.avg_score * matched as f32 + p.score as f32) / (matched + 1) as f32;
+                self.avg_confidence =
+                    (self.avg_confidence * matched as f32 + p.confidence) / (matched + 1) as f32;
+                if p.correct {
+                    self.correct += 1;
+                    return;
+                }
+                self.incorrect += 1;
+                *self.confused_with.entry(p.title.clone()).or_default() += 1;
+            }
+            None => self.no_match += 1,
+        }
+        self.failures.push(Failure {
+            start,
+            condition: condition.to_string(),
+            predicted: prediction.map(|p| p.title.clone()),
+        });
+    }
+}
+
+/// Write `reports` as JSON if `path` ends in `.json`, else as CSV with one
+/// row per song
+fn write_report(path: &str, reports: &[SongReport]) -> std::io::Result<()> {
+    if path.to_lowercase().ends_with(".json") {
+        let json = serde_json::to_string_pretty(reports).map_err(std::io::Error::other)?;
+        return fs::write(path, json);
+    }
+
+    let csv_field = |field: &str| {
+        if field.contains([',', '"', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    };
+
+    let mut csv = String::from(
+        "song,tests,correct,incorrect,no_match,avg_score,avg_confidence,confused_with,failed_starts\n",
+    );
+    for r in reports {
+        let confused: Vec<String> = r
+            .confused_with
+            .iter()
+            .map(|(song, count)| format!("{} x{}", song, count))
+            .collect();
+        let failed: Vec<String> = r
+            .failures
+            .iter()
+            .map(|f| format!("{:.2}s ({})", f.start, f.condition))
+            .collect();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.1},{:.3},{},{}\n",
+            csv_field(&r.song),
+            r.tests,
+            r.correct,
+            r.incorrect,
+            r.no_match,
+            r.avg_score,
+            r.avg_confidence,
+            csv_field(&confused.join("; ")),
+            csv_field(&failed.join("; ")),
+        ));
+    }
+    fs::write(path, csv)
+}
+
+/// Recognitions of one snippet length under one condition
+struct Tally {
+    condition: Condition,
+    /// Snippet length in seconds
+    duration: f32,
+    /// Condition, and the length when several are tested
+    label: String,
+    correct: u32,
+    total: u32,
+    /// Snippets matched to any song, and the sum of their scores
+    matched: u32,
+    score_sum: usize,
+}
+
+/// Entries of `dir` sorted by path, so runs visit songs in the same order
+fn sorted_entries(dir: &str) -> std::io::Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.path());
+    Ok(entries)
+}
+
+/// Length of a song in seconds, decoding it if symphonia cannot probe it
+/// (only the ffmpeg fallback can decode such files)
+fn song_duration(
+    audio_processor: &AudioProcessor,
+    file_path: &str,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    audio_processor
+        .probe_info(file_path.to_string())
+        .map(|info| info.duration)
+        .or_else(|_| {
+            let (samples, sample_rate) = audio_processor.decode_file(file_path)?;
+            Ok(samples.len() as f64 / sample_rate as f64)
+        })
+}
+
+/// One snippet picked for testing
+struct SnippetJob {
+    /// Index into the tested songs
+    song: usize,
+    /// 1-based number of the snippet within its song
+    number: u32,
+    /// Start in seconds
+    start: f64,
+    /// Seeds the noise of this snippet, so results do not depend on which
+    /// worker ran it
+    noise_seed: u64,
+}
+
+/// Path and file name of a tested song
+type SongFile = (String, String);
+
+/// Pick `snippets_per_song` random snippets of `duration` seconds from each
+/// file in `dir`. Returns the paths and names of the songs long enough to test
+/// and the snippets, in a fixed order for a given `rng` seed.
+fn pick_snippets(
+    dir: &str,
+    snippets_per_song: u32,
+    duration: f64,
+    rng: &mut StdRng,
+    config: &DecodeConfig,
+) -> std::io::Result<(Vec<SongFile>, Vec<SnippetJob>)> {
+    let audio_processor = AudioProcessor::from_config(config);
+    let mut songs = Vec::new();
+    let mut jobs = Vec::new();
+    for entry in sorted_entries(dir)? {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let file_path_str = path.to_string_lossy().to_string();
+        let song_name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        // Ensure song is long enough for a snippet
+        let song_length = match song_duration(&audio_processor, &file_path_str) {
+            Ok(length) => length,
+            Err(e) => {
+                println!("   -> Skipping {}, cannot read it: {}", song_name, e);
+                continue;
+            }
+        };
+        if song_length < duration + 5.0 {
+            println!("   -> Skipping {}, song is too short.", song_name);
+            continue;
+        }
+
+        for number in 1..=snippets_per_song {
+            let start = rng.random_range(0.0..=song_length - duration);
+            println!("   Picked {} #{} at {:.3}s", song_name, number, start);
+            jobs.push(SnippetJob {
+                song: songs.len(),
+                number,
+                start,
+                noise_seed: rng.random(),
+            });
+        }
+        songs.push((file_path_str, song_name));
+    }
+    Ok((songs, jobs))
+}
+
+/// Run `work` on every job with `workers` threads and return the results in
+/// job order. Once `cancel` fires, workers finish their current job and take
+/// no new ones, leaving `None` for the jobs that did not run.
+fn run_parallel<J: Sync, R: Send>(
+    jobs: &[J],
+    workers: usize,
+    cancel: &CancelToken,
+    work: impl Fn(&J) -> R + Sync,
+) -> Vec<Option<R>> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..jobs.len()).map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                while !cancel.is_cancelled() {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(idx) else {
+                        break;
+                    };
+                    let result = work(job);
+                    results.lock().unwrap()[idx] = Some(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// One line describing the outcome of a tested snippet
+fn describe_prediction(prediction: Option<&Prediction>) -> String {
+    match prediction {
+        None => "❌ No match found in DB.".to_string(),
+        Some(p) if !p.accepted => format!(
+            "❌ No match above the confidence cutoff (best: '{}', confidence: {:.2})",
+            p.title, p.confidence
+        ),
+        Some(p) if p.correct => format!(
+            "✅ Correct! (score: {}, confidence: {:.2})",
+            p.score, p.confidence
+        ),
+        Some(p) => format!("❌ Incorrect. Matched '{}' (score: {})", p.title, p.score),
+    }
+}
 
 /// Runs a comprehensive test by taking random snippets from each song
 /// and processing them through the full recognition pipeline.
-pub fn run_random_snippet_test(songs_dir: &str) {
-    let audio_processor = AudioProcessor::new();
-    let fft = CooleyTukeyFFT::default();
-    let mut db = DB::new();
+///
+/// Every snippet is tested clean and once per `--snr` level and `--speed` /
+/// `--pitch` step in each direction, `--rerecord` room and `--transcode`
+/// bitrate, and the accuracy is reported per condition.
+/// With `--holdout`, snippets of songs outside the database are tested too and
+/// the false and true accept rates are reported per confidence cutoff.
+///
+/// Snippets are tested by `--jobs` worker threads sharing one connection
+/// pool; they are picked up front and results are tallied in that order, so
+/// the totals do not depend on the number of workers.
+///
+/// Cancelling lets the workers finish the snippets in progress and reports
+/// on the snippets tested so far.
+pub fn run_random_snippet_test(
+    songs_dir: &str,
+    collection: &str,
+    options: &TestOptions,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    let workers = options
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    // Each worker holds at most one connection at a time
+    let db = DB::with_pool_size(workers as u32);
+    let store = fingerprint_store(&db, collection, config.fingerprint_version());
+    let recogniser = Recogniser {
+        db: &db,
+        store: store.as_ref(),
+        scheme: db.collection_hash_scheme(collection).unwrap_or_default(),
+        config,
+    };
 
-    let mut total_tests = 0;
-    let mut correct_matches = 0;
     const SNIPPETS_PER_SONG: u32 = 3;
-    const SNIPPET_DURATION_SECS: usize = 5;
+
+    if !options.transcode.is_empty() && !ffmpeg_available() {
+        eprintln!("❌ --transcode needs ffmpeg, install it or drop the option");
+        return;
+    }
+
+    let mut durations = options.durations.clone();
+    durations.retain(|&d| d > 0.0);
+    durations.sort_by(f32::total_cmp);
+    durations.dedup();
+    let Some(&longest) = durations.last() else {
+        eprintln!("❌ --durations needs at least one positive length");
+        return;
+    };
+
+    let conditions: Vec<Condition> = std::iter::once(Condition::Clean)
+        .chain(options.snr.iter().map(|&snr_db| Condition::Noise {
+            kind: options.noise,
+            snr_db,
+        }))
+        .chain(
+            options
+                .speed
+                .iter()
+                .flat_map(|&p| [p, -p])
+                .map(|percent| Condition::Speed { percent }),
+        )
+        .chain(
+            options
+                .pitch
+                .iter()
+                .flat_map(|&s| [s, -s])
+                .map(|semitones| Condition::Pitch { semitones }),
+        )
+        .chain(
+            options
+                .rerecord
+                .iter()
+                .map(|&room| Condition::Rerecord { room }),
+        )
+        .chain(options.transcode.iter().flat_map(|&codec| {
+            options
+                .bitrates
+                .iter()
+                .map(move |&kbps| Condition::Transcode { codec, kbps })
+        }))
+        .collect();
+    let mut tallies: Vec<Tally> = durations
+        .iter()
+        .flat_map(|&duration| {
+            conditions.iter().map(move |&condition| Tally {
+                condition,
+                duration,
+                label: if options.durations.len() > 1 {
+                    format!("{}s, {}", duration, condition.label())
+                } else {
+                    condition.label()
+                },
+                correct: 0,
+                total: 0,
+                matched: 0,
+                score_sum: 0,
+            })
+        })
+        .collect();
 
     println!("🎵 Starting random snippet test...");
     println!("   Snippets per song: {}", SNIPPETS_PER_SONG);
-    println!("   Snippet duration: {}s", SNIPPET_DURATION_SECS);
+    let lengths: Vec<String> = durations.iter().map(|d| format!("{}s", d)).collect();
+    println!("   Snippet duration: {}", lengths.join(", "));
+    println!("   Worker threads: {}", workers);
+    let seed = options.seed.unwrap_or_else(|| rand::rng().random());
+    println!(
+        "   Seed: {} (pass --seed {} to replay this run)",
+        seed, seed
+    );
+    let mut rng = StdRng::seed_from_u64(seed);
+    if conditions.len() > 1 {
+        let labels: Vec<String> = conditions.iter().map(|c| c.label()).collect();
+        println!("   Conditions: {}", labels.join(", "));
+    }
 
-    let song_entries = match fs::read_dir(songs_dir) {
-        Ok(entries) => entries.collect::<Result<Vec<_>, _>>().unwrap_or_default(),
+    // 1. Pick every snippet up front, in a fixed order
+    let (songs, jobs) = match pick_snippets(
+        songs_dir,
+        SNIPPETS_PER_SONG,
+        longest as f64,
+        &mut rng,
+        &config.decode,
+    ) {
+        Ok(picked) => picked,
         Err(e) => {
             eprintln!("Error reading songs directory '{}': {}", songs_dir, e);
             return;
         }
     };
 
+    // 2. Decode each snippet at its longest, seeking straight to it, and test
+    //    it at every length under every condition
+    let variants: Vec<(f32, Condition, String)> = tallies
+        .iter()
+        .map(|t| (t.duration, t.condition, t.label.clone()))
+        .collect();
+    let outcomes = run_parallel(&jobs, workers, cancel, |job| {
+        let audio_processor = AudioProcessor::from_config(&config.decode);
+        let (file_path_str, true_song_name) = &songs[job.song];
+        let (snippet, sample_rate) = audio_processor.get_decoded_audio_range(
+            file_path_str.clone(),
+            job.start,
+            Some(longest as f64),
+        );
+
+        let mut noise_rng = StdRng::seed_from_u64(job.noise_seed);
+        variants
+            .iter()
+            .map(|(duration, condition, label)| {
+                let len = ((duration * sample_rate as f32) as usize).min(snippet.len());
+                let degraded = condition.apply(&snippet[..len], sample_rate, &mut noise_rng);
+                let prediction = test_snippet(&degraded, sample_rate, true_song_name, &recogniser);
+                println!(
+                    "   {} #{} (starts at {:.3}s, {}): {}",
+                    true_song_name,
+                    job.number,
+                    job.start,
+                    label,
+                    describe_prediction(prediction.as_ref())
+                );
+                prediction
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // 3. Tally in snippet order
+    let mut reports: Vec<SongReport> = songs
+        .iter()
+        .map(|(_, song)| SongReport {
+            song: song.clone(),
+            ..Default::default()
+        })
+        .collect();
+    // (best confidence, correct) of every clean snippet, before the cutoff
+    let mut clean_results: Vec<(f32, bool)> = Vec::new();
+    if cancel.is_cancelled() {
+        println!("⏹️ Interrupted, reporting the snippets tested so far");
+    }
+    let tested = jobs
+        .iter()
+        .zip(outcomes)
+        .filter_map(|(job, predictions)| Some((job, predictions?)));
+    for (job, predictions) in tested {
+        for (tally, prediction) in tallies.iter_mut().zip(predictions) {
+            tally.total += 1;
+            if matches!(tally.condition, Condition::Clean) && tally.duration == durations[0] {
+                clean_results.push(
+                    prediction
+                        .as_ref()
+                        .map_or((0.0, false), |p| (p.confidence, p.correct)),
+                );
+            }
+            let prediction = prediction.filter(|p| p.accepted);
+            if let Some(p) = &prediction {
+                tally.matched += 1;
+                tally.score_sum += p.score;
+                if p.correct {
+                    tally.correct += 1;
+                }
+            }
+            reports[job.song].record(job.start, &tally.label, prediction.as_ref());
+        }
+    }
+
+    println!("\n--- 📊 Test Finished ---");
+    if tallies[0].total == 0 {
+        println!("No tests were run. Check the songs directory path.");
+        return;
+    }
+    for tally in &tallies {
+        let accuracy = (tally.correct as f32 / tally.total as f32) * 100.0;
+        if tallies.len() > 1 {
+            println!("   [{}]", tally.label);
+        }
+        println!(
+            "   Correct Matches: {} / {}\n   Accuracy: {:.2}%",
+            tally.correct, tally.total, accuracy
+        );
+        if durations.len() > 1 {
+            println!(
+                "   Average Score: {:.1}",
+                tally.score_sum as f32 / tally.matched.max(1) as f32
+            );
+        }
+    }
+
+    if let Some(path) = &options.report {
+        match write_report(path, &reports) {
+            Ok(()) => println!("📝 Wrote per-song report to {}", path),
+            Err(e) => eprintln!("⚠️ Failed to write report {}: {}", path, e),
+        }
+    }
+
+    if let Some(holdout_dir) = &options.holdout
+        && !cancel.is_cancelled()
+    {
+        let holdout_confidences = run_holdout(
+            holdout_dir,
+            &recogniser,
+            durations[0] as f64,
+            &mut rng,
+            workers,
+            cancel,
+        );
+        print_accept_rates(
+            &options.thresholds,
+            &holdout_confidences,
+            &clean_results,
+            config.matching.min_confidence,
+        );
+    }
+}
+
+/// Test random `snippet_duration_secs` snippets of the songs in `holdout_dir`,
+/// none of which should be in the database, and return the best match
+/// confidence of each one tested before `cancel` fired (0 for no candidate
+/// at all)
+fn run_holdout(
+    holdout_dir: &str,
+    recogniser: &Recogniser,
+    snippet_duration_secs: f64,
+    rng: &mut StdRng,
+    workers: usize,
+    cancel: &CancelToken,
+) -> Vec<f32> {
+    const SNIPPETS_PER_SONG: u32 = 3;
+
+    println!("\n--- Holdout: {} ---", holdout_dir);
+    let (songs, jobs) = match pick_snippets(
+        holdout_dir,
+        SNIPPETS_PER_SONG,
+        snippet_duration_secs,
+        rng,
+        &recogniser.config.decode,
+    ) {
+        Ok(picked) => picked,
+        Err(e) => {
+            eprintln!("Error reading holdout directory '{}': {}", holdout_dir, e);
+            return Vec::new();
+        }
+    };
+
+    run_parallel(&jobs, workers, cancel, |job| {
+        let audio_processor = AudioProcessor::from_config(&recogniser.config.decode);
+        let (file_path_str, song_name) = &songs[job.song];
+        let (snippet, sample_rate) = audio_processor.get_decoded_audio_range(
+            file_path_str.clone(),
+            job.start,
+            Some(snippet_duration_secs),
+        );
+        // No title in the database can be the right one
+        let prediction = test_snippet(&snippet, sample_rate, "", recogniser);
+        println!(
+            "   {} #{} (starts at {:.3}s): {}",
+            song_name,
+            job.number,
+            job.start,
+            describe_prediction(prediction.as_ref())
+        );
+        prediction.map_or(0.0, |p| p.confidence)
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Print the false accept rate of the holdout snippets and the true accept
+/// rate of the clean in-database snippets at each confidence cutoff
+fn print_accept_rates(
+    thresholds: &[f32],
+    holdout_confidences: &[f32],
+    clean_results: &[(f32, bool)],
+    configured_cutoff: f32,
+) {
+    println!("\n--- 🚫 False Accept Test ---");
+    if holdout_confidences.is_empty() {
+        println!("No holdout snippets were tested. Check the holdout directory path.");
+        return;
+    }
+    println!(
+        "   {} holdout snippets, {} clean in-database snippets",
+        holdout_confidences.len(),
+        clean_results.len()
+    );
+    println!("   cutoff   false accepts   true accepts");
+
+    let rate = |count: usize, total: usize| count as f32 / total.max(1) as f32 * 100.0;
+    let mut thresholds = thresholds.to_vec();
+    thresholds.sort_by(f32::total_cmp);
+    for threshold in thresholds {
+        let false_accepts = holdout_confidences
+            .iter()
+            .filter(|&&c| c > 0.0 && c >= threshold)
+            .count();
+        let true_accepts = clean_results
+            .iter()
+            .filter(|&&(c, correct)| correct && c >= threshold)
+            .count();
+        println!(
+            "   {:6.2}   {:12.2}%   {:11.2}%{}",
+            threshold,
+            rate(false_accepts, holdout_confidences.len()),
+            rate(true_accepts, clean_results.len()),
+            if threshold == configured_cutoff {
+                "   <- matching.min_confidence"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+/// The database and settings snippets are recognised against
+struct Recogniser<'a> {
+    db: &'a DB,
+    store: &'a dyn FingerprintStore,
+    scheme: HashScheme,
+    config: &'a Config,
+}
+
+/// Run one snippet through the FULL recognition pipeline (filter -> resample
+/// -> FFT -> fingerprint -> vote) and return its best match, if any, even
+/// below the confidence cutoff
+fn test_snippet(
+    snippet: &[f32],
+    sample_rate: u32,
+    true_song_name: &str,
+    recogniser: &Recogniser,
+) -> Option<Prediction> {
+    let Recogniser {
+        db,
+        store,
+        scheme,
+        config,
+    } = *recogniser;
+    // Snippets stand in for recordings, so they are denoised like them
+    let fingerprints = store.without_denied(expand_query(
+        Pipeline::for_query(config, scheme).fingerprints(snippet.to_vec(), sample_rate),
+        scheme,
+        &config.matching,
+    ));
+    if fingerprints.is_empty() {
+        return None;
+    }
+
+    let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+    let db_matches_by_hash = store
+        .fetch_matches_grouped_by_hash(&hash_vec)
+        .expect("Fingerprint lookup failed");
+
+    // Vote without the cutoff, so the confidence of rejected snippets is known
+    let matching = MatchingConfig {
+        min_confidence: 0.0,
+        min_score: 0,
+        ..config.matching.clone()
+    };
+    let results = vote_best_matches(
+        &fingerprints,
+        &db_matches_by_hash,
+        store.song_count(),
+        1,
+        &matching,
+    );
+
+    // 4. Check the result
+    let best_match = results.first()?;
+
+    let titles = db.fetch_song_titles(&[best_match.song_id as i32]);
+    let predicted_name = titles.get(&(best_match.song_id as i32)).unwrap();
+
+    let correct = predicted_name == true_song_name;
+    let accepted = best_match.confidence >= config.matching.min_confidence;
+
+    Some(Prediction {
+        title: predicted_name.clone(),
+        score: best_match.score,
+        confidence: best_match.confidence,
+        correct,
+        accepted,
+    })
+}
+
+/// Values to sweep in `tune`. Each parameter left out keeps its configured
+/// value.
+#[derive(clap::Args, Debug, Clone)]
+pub struct TuneGrid {
+    /// `fft.chunk_size` values, e.g. `1024,2048,4096`
+    #[arg(long, value_delimiter = ',')]
+    pub chunk_size: Vec<usize>,
+
+    /// `fft.overlap_size` values; combinations not below the chunk size are skipped
+    #[arg(long, value_delimiter = ',')]
+    pub overlap: Vec<usize>,
+
+    /// `fft.threshold_multiplier` values
+    #[arg(long, value_delimiter = ',')]
+    pub threshold: Vec<f32>,
+
+    /// `fft.max_peaks_per_band` values
+    #[arg(long, value_delimiter = ',')]
+    pub max_peaks: Vec<usize>,
+
+    /// `fingerprint.max_target_zone` values
+    #[arg(long, value_delimiter = ',')]
+    pub target_zone: Vec<usize>,
+
+    /// `fft.whitening` values, e.g. `false,true`
+    #[arg(long, value_delimiter = ',')]
+    pub whitening: Vec<bool>,
+
+    /// `matching.vote_bin_width` values; these need no re-ingest
+    #[arg(long, value_delimiter = ',')]
+    pub bin_width: Vec<f32>,
+}
+
+/// `values`, or just `configured` if none were given
+fn or_configured<T: Copy>(values: &[T], configured: T) -> Vec<T> {
+    if values.is_empty() {
+        vec![configured]
+    } else {
+        values.to_vec()
+    }
+}
+
+impl TuneGrid {
+    /// Every valid combination of the fingerprint-affecting parameters, on
+    /// top of `base`
+    fn expand(&self, base: &Config) -> Vec<Config> {
+        let mut candidates = Vec::new();
+        for chunk_size in or_configured(&self.chunk_size, base.fft.chunk_size) {
+            for overlap_size in or_configured(&self.overlap, base.fft.overlap_size) {
+                for threshold in or_configured(&self.threshold, base.fft.threshold_multiplier) {
+                    for max_peaks in or_configured(&self.max_peaks, base.fft.max_peaks_per_band) {
+                        for zone in
+                            or_configured(&self.target_zone, base.fingerprint.max_target_zone)
+                        {
+                            for whitening in or_configured(&self.whitening, base.fft.whitening) {
+                                let mut candidate = base.clone();
+                                candidate.fft.chunk_size = chunk_size;
+                                candidate.fft.overlap_size = overlap_size;
+                                candidate.fft.threshold_multiplier = threshold;
+                                candidate.fft.max_peaks_per_band = max_peaks;
+                                candidate.fft.whitening = whitening;
+                                candidate.fingerprint.max_target_zone = zone;
+                                if candidate.validate().is_ok() {
+                                    candidates.push(candidate);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        candidates
+    }
+}
+
+/// One-line summary of the swept parameters of `config`
+fn describe_tuning(config: &Config) -> String {
+    format!(
+        "chunk={} overlap={} threshold={} peaks={} whiten={} zone={} bin={}",
+        config.fft.chunk_size,
+        config.fft.overlap_size,
+        config.fft.threshold_multiplier,
+        config.fft.max_peaks_per_band,
+        config.fft.whitening,
+        config.fingerprint.max_target_zone,
+        config.matching.vote_bin_width
+    )
+}
+
+/// Accuracy of one configuration in `tune`
+struct TuneResult {
+    config: Config,
+    correct: usize,
+    confidence_sum: f32,
+}
+
+/// Grid-search the pipeline parameters over the songs in `songs_dir`.
+///
+/// Each combination of fingerprint-affecting parameters gets a temporary
+/// collection with every song ingested, then the same random snippets (at the
+/// same positions for every combination) are matched against it with each
+/// offset bin width. The temporary collections are removed afterwards and the
+/// configurations are ranked by accuracy, then mean confidence. Cancelling
+/// finishes (and removes) the configuration in progress and ranks those done.
+pub fn run_tune(
+    songs_dir: &str,
+    grid: &TuneGrid,
+    snippets_per_song: u32,
+    config: &Config,
+    cancel: &CancelToken,
+) {
+    const SNIPPET_DURATION_SECS: usize = 5;
+
+    if LmdbStore::configured() {
+        eprintln!(
+            "❌ tune ingests into temporary Postgres collections, unset FINGERPRINT_LMDB_PATH"
+        );
+        return;
+    }
+
+    let candidates = grid.expand(config);
+    let bin_widths = or_configured(&grid.bin_width, config.matching.vote_bin_width);
+    if candidates.is_empty() {
+        eprintln!("❌ No valid configuration in the grid (overlap must be below chunk size)");
+        return;
+    }
+
+    let song_entries = match sorted_entries(songs_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error reading songs directory '{}': {}", songs_dir, e);
+            return;
+        }
+    };
+
+    // 1. Decode and preprocess every song once; `[audio]` is not swept
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+    let preprocess = Pipeline::builder(config, HashScheme::default())
+        .until("stft")
+        .build();
+    let rate = config.audio.target_sample_rate;
+    let mut songs: Vec<(String, Vec<f32>)> = Vec::new();
     for entry in song_entries {
+        if cancel.is_cancelled() {
+            println!("⏹️ Interrupted while decoding songs");
+            return;
+        }
         let path = entry.path();
         if !path.is_file() {
             continue;
         }
+        let title = path.file_name().unwrap().to_string_lossy().to_string();
+        let (samples, sample_rate) =
+            audio_processor.get_decoded_audio(path.to_string_lossy().to_string());
+        songs.push((title, preprocess.audio(samples, sample_rate).0));
+    }
 
-        let file_path_str = path.to_string_lossy().to_string();
-        let true_song_name = path.file_name().unwrap().to_string_lossy().to_string();
-
-        println!("\n--- Testing: {} ---", true_song_name);
-
-        // 1. Decode the full song once
-        let (full_samples, sample_rate) = audio_processor.get_decoded_audio(file_path_str);
-
-        // Ensure song is long enough for a snippet
-        let min_len = sample_rate as usize * (SNIPPET_DURATION_SECS + 5);
-        if full_samples.len() < min_len {
-            println!("   -> Skipping, song is too short.");
+    // 2. Pick the labeled snippets once, so every configuration sees the same audio
+    let snippet_len = SNIPPET_DURATION_SECS * rate as usize;
+    let mut rng = rand::rng();
+    let mut snippets: Vec<(usize, Vec<f32>)> = Vec::new();
+    for (song_idx, (_, samples)) in songs.iter().enumerate() {
+        if samples.len() < snippet_len + 5 * rate as usize {
             continue;
         }
+        for _ in 0..snippets_per_song {
+            let start = rng.random_range(0..=samples.len() - snippet_len);
+            snippets.push((song_idx, samples[start..start + snippet_len].to_vec()));
+        }
+    }
+    if snippets.is_empty() {
+        println!("No songs long enough to test. Check the songs directory path.");
+        return;
+    }
 
-        for i in 0..SNIPPETS_PER_SONG {
-            total_tests += 1;
+    println!(
+        "🎛️ Tuning {} configuration(s) x {} bin width(s) on {} snippets from {} songs",
+        candidates.len(),
+        bin_widths.len(),
+        snippets.len(),
+        songs.len()
+    );
+
+    // 3. Ingest and test each configuration in its own temporary collection
+    let db = DB::new();
+    let mut results: Vec<TuneResult> = Vec::new();
+    for (n, candidate) in candidates.iter().enumerate() {
+        if cancel.is_cancelled() {
+            println!("\n⏹️ Interrupted, ranking the configurations tested so far");
+            break;
+        }
+        println!(
+            "\n--- Configuration {}/{}: {} ---",
+            n + 1,
+            candidates.len(),
+            describe_tuning(candidate)
+        );
+        let collection = format!("tune-{}-{}", std::process::id(), n);
+        let tallies =
+            evaluate_candidate(&db, &collection, candidate, &songs, &snippets, &bin_widths);
 
-            // 2. Extract a random snippet
-            let snippet_len = SNIPPET_DURATION_SECS * sample_rate as usize;
-            let max_start_index = full_samples.len() - snippet_len;
-            let start_index = rand::rng().random_range(0..=max_start_index);
-            let end_index = start_index + snippet_len;
-            let snippet = &full_samples[start_index..end_index];
+        if let Err(e) = db
+            .delete_songs(&db.song_ids_in_collection(&collection))
+            .and_then(|_| db.delete_collection(&collection))
+        {
+            eprintln!("⚠️ Failed to remove collection '{}': {}", collection, e);
+        }
 
-            let start_time_secs = start_index as f32 / sample_rate as f32;
-            print!(
-                "   Snippet #{} (starts at {:.2}s): ",
-                i + 1,
-                start_time_secs
+        for (&bin_width, (correct, confidence_sum)) in bin_widths.iter().zip(tallies) {
+            let mut config = candidate.clone();
+            config.matching.vote_bin_width = bin_width;
+            println!(
+                "   bin={} -> {} / {} correct",
+                bin_width,
+                correct,
+                snippets.len()
             );
+            results.push(TuneResult {
+                config,
+                correct,
+                confidence_sum,
+            });
+        }
+    }
 
-            // 3. Run through the FULL recognition pipeline (resample -> filter -> FFT -> fingerprint -> vote)
-            let target_sr = AudioProcessor::TARGET_SAMPLE_RATE;
+    // 4. Rank
+    results.sort_by(|a, b| {
+        b.correct
+            .cmp(&a.correct)
+            .then(b.confidence_sum.total_cmp(&a.confidence_sum))
+    });
 
-            let filtered = audio_processor.apply_low_pass_filter(snippet, target_sr, 5500.0);
-            let resampled = audio_processor.resample_linear(&filtered, sample_rate, target_sr);
-            let fft_distribution = fft.generate_freq_time_distribution(resampled, target_sr);
-            let fingerprints = generate_audio_fingerprint(&fft_distribution);
-            println!("⌛ Fingerprinting Done");
+    println!("\n--- 🏆 Tuning Finished ---");
+    for r in results.iter().take(10) {
+        println!(
+            "   {:6.2}%  mean confidence {:.2}  {}",
+            r.correct as f32 / snippets.len() as f32 * 100.0,
+            r.confidence_sum / snippets.len() as f32,
+            describe_tuning(&r.config)
+        );
+    }
+    if let Some(best) = results.first() {
+        println!("\nBest configuration, for sabi.toml (reingest after changing it):\n");
+        match toml::to_string(&best.config) {
+            Ok(text) => println!("{}", text),
+            Err(e) => eprintln!("⚠️ Failed to format configuration: {}", e),
+        }
+    }
+}
 
-            if fingerprints.is_empty() {
-                println!("❌ No fingerprints generated, match failed.");
-                continue;
-            }
+/// Ingest `songs` into `collection` with `candidate` and match `snippets`
+/// against them once per bin width. Returns `(correct, confidence sum)` per
+/// bin width.
+fn evaluate_candidate(
+    db: &DB,
+    collection: &str,
+    candidate: &Config,
+    songs: &[(String, Vec<f32>)],
+    snippets: &[(usize, Vec<f32>)],
+    bin_widths: &[f32],
+) -> Vec<(usize, f32)> {
+    let scheme = db.ensure_collection(collection, HashScheme::default(), CollectionKind::Songs);
+    // The songs and snippets are preprocessed already
+    let pipeline = Pipeline::builder(candidate, scheme)
+        .starting_at("stft")
+        .build();
+    let rate = candidate.audio.target_sample_rate;
 
-            let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
-            let db_matches_by_hash = db.fetch_matches_grouped_by_hash(&hash_vec);
-            println!("🤾 Fetched from database");
-            let results = vote_best_matches(&fingerprints, &db_matches_by_hash, 1);
-            println!("🗳️ Voting Done");
-
-            // 4. Check the result
-            if let Some(best_match) = results.first() {
-                let titles = db.fetch_song_titles(&[best_match.song_id as i32]);
-                let predicted_name = titles.get(&(best_match.song_id as i32)).unwrap();
-
-                if predicted_name == &true_song_name {
-                    println!("✅ Correct! (score: {})", best_match.score);
-                    println!("✅ The db fetch as {:?} ", results.first().unwrap());
-                    correct_matches += 1;
-                } else {
-                    println!(
-                        "❌ Incorrect. Matched '{}' (score: {})",
-                        predicted_name, best_match.score
-                    );
-                }
-            } else {
-                println!("❌ No match found in DB.");
+    let mut song_ids = Vec::with_capacity(songs.len());
+    for (title, samples) in songs {
+        let fingerprints = cap_song_fingerprints(
+            pipeline.fingerprints(samples.clone(), rate),
+            &candidate.fingerprint,
+        );
+        let song = NewSong {
+            title: title.clone(),
+            created_at: Some(SystemTime::now()),
+            duration: Some(samples.len() as f64 / rate as f64),
+            artist: None,
+            album: None,
+            track_number: None,
+            year: None,
+            checksum: None,
+            collection: collection.to_string(),
+            fingerprint_version: candidate.fingerprint_version(),
+            file_path: None,
+            bpm: None,
+            musical_key: None,
+        };
+        match store_song(db, &song, fingerprints, scheme) {
+            Ok((song_id, _)) => song_ids.push(Some(song_id as u32)),
+            Err(e) => {
+                eprintln!("❌ Failed to ingest '{}': {}", title, e);
+                song_ids.push(None);
             }
         }
     }
 
-    println!("\n--- 📊 Test Finished ---");
-    if total_tests > 0 {
-        let accuracy = (correct_matches as f32 / total_tests as f32) * 100.0;
-        println!(
-            "   Correct Matches: {} / {}\n   Accuracy: {:.2}%",
-            correct_matches, total_tests, accuracy
+    let store = fingerprint_store(db, collection, candidate.fingerprint_version());
+    let mut tallies = vec![(0usize, 0.0f32); bin_widths.len()];
+    for (song_idx, snippet) in snippets {
+        let fingerprints = expand_query(
+            pipeline.fingerprints(snippet.clone(), rate),
+            scheme,
+            &candidate.matching,
         );
-    } else {
-        println!("No tests were run. Check the songs directory path.");
+        let hash_vec: Vec<i64> = fingerprints.iter().map(|f| f.hash as i64).collect();
+        let db_matches_by_hash = store
+            .fetch_matches_grouped_by_hash(&hash_vec)
+            .expect("Fingerprint lookup failed");
+
+        for (tally, &bin_width) in tallies.iter_mut().zip(bin_widths) {
+            let matching = MatchingConfig {
+                vote_bin_width: bin_width,
+                ..candidate.matching.clone()
+            };
+            let results = vote_best_matches(
+                &fingerprints,
+                &db_matches_by_hash,
+                store.song_count(),
+                1,
+                &matching,
+            );
+            if let Some(best) = results.first()
+                && Some(best.song_id) == song_ids[*song_idx]
+            {
+                tally.0 += 1;
+                tally.1 += best.confidence;
+            }
+        }
     }
+    tallies
 }
@@ -0,0 +1,106 @@
+//! Where the time of one recognition went, reported by `--timings` and the
+//! `timings` query parameter of `/recognise`.
+
+use serde::{Serialize, Serializer};
+use std::time::{Duration, Instant};
+
+/// How `--timings` prints the report
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum TimingsFormat {
+    /// One line per stage
+    #[default]
+    Text,
+    /// One JSON object on a line, with durations in milliseconds
+    Json,
+}
+
+/// Time spent in each stage of one recognition, and how much the lookup
+/// fetched. Stages a recognition skipped stay zero, e.g. decoding for
+/// microphone input.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StageTimings {
+    #[serde(rename = "decode_ms", serialize_with = "millis")]
+    pub decode: Duration,
+    /// The stages of `[pipeline]` before the STFT: mixing down, filtering
+    /// and resampling
+    #[serde(rename = "resample_ms", serialize_with = "millis")]
+    pub resample: Duration,
+    /// The STFT and peak picking
+    #[serde(rename = "fft_ms", serialize_with = "millis")]
+    pub fft: Duration,
+    /// Hashing the peaks and expanding the query
+    #[serde(rename = "fingerprint_ms", serialize_with = "millis")]
+    pub fingerprint: Duration,
+    #[serde(rename = "db_fetch_ms", serialize_with = "millis")]
+    pub db_fetch: Duration,
+    #[serde(rename = "vote_ms", serialize_with = "millis")]
+    pub vote: Duration,
+    /// Hashes looked up
+    pub hashes: usize,
+    /// Song and time pairs the lookup returned for them
+    pub rows: usize,
+}
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+impl StageTimings {
+    /// Add the time of the [`crate::dsp`] stage `stage` to the stage of the
+    /// report it belongs to
+    pub fn observe(&mut self, stage: &str, elapsed: Duration) {
+        match stage {
+            "source" | "filter" | "resample" => self.resample += elapsed,
+            "stft" | "peaks" => self.fft += elapsed,
+            "hash" => self.fingerprint += elapsed,
+            _ => {}
+        }
+    }
+
+    /// Run `f` and add the elapsed time to `slot`
+    pub fn time<T>(slot: &mut Duration, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        *slot += started.elapsed();
+        result
+    }
+
+    pub fn total(&self) -> Duration {
+        self.decode + self.resample + self.fft + self.fingerprint + self.db_fetch + self.vote
+    }
+
+    pub fn render(&self, format: TimingsFormat) -> String {
+        match format {
+            TimingsFormat::Text => self.to_text(),
+            TimingsFormat::Json => {
+                serde_json::to_string(self).expect("timings always serialize to JSON")
+            }
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let row = |name: &str, duration: Duration| {
+            format!(
+                "   {:<12} {:>9.2} ms",
+                name,
+                duration.as_secs_f64() * 1000.0
+            )
+        };
+        [
+            "⏱️ Timings:".to_string(),
+            row("decode", self.decode),
+            row("resample", self.resample),
+            row("fft", self.fft),
+            row("fingerprint", self.fingerprint),
+            format!(
+                "{}  ({} hashes, {} rows)",
+                row("db fetch", self.db_fetch),
+                self.hashes,
+                self.rows
+            ),
+            row("vote", self.vote),
+            row("total", self.total()),
+        ]
+        .join("\n")
+    }
+}
@@ -1,64 +1,241 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-use crate::fft::fft::{FFTDistribution, FreqRange};
+use image::{Rgb, RgbImage};
 
-pub fn write_heatmap_svg<P: AsRef<Path>>(
-    fingerprints: &Vec<FFTDistribution>,
-    output_path: P,
-    song_name: &str,
-) -> std::io::Result<()> {
-    let (width, height) = (1920.0f32, 1080.0f32);
+use crate::audio_processor::AudioProcessor;
+use crate::config::{Config, FftConfig};
+use crate::dsp::Pipeline;
+use crate::fft::fft::FFTDistribution;
+use crate::fingerprint::HashScheme;
 
-    if fingerprints.is_empty() {
-        let empty_svg = format!(
-            "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n  <rect width='100%' height='100%' fill='black'/>\n  <text x='{cx}' y='{cy}' fill='white' font-family='monospace' font-size='20' text-anchor='middle'>No fingerprints</text>\n</svg>",
-            w = width,
-            h = height,
-            cx = width / 2.0,
-            cy = height / 2.0
-        );
-        let mut f = File::create(output_path)?;
-        f.write_all(empty_svg.as_bytes())?;
-        return Ok(());
+/// Part of the spectrogram to draw
+pub struct HeatmapView {
+    /// First second shown
+    pub start: f32,
+    /// Last second shown, the end of the audio if `None`
+    pub end: Option<f32>,
+    /// Bottom of the frequency axis in Hz
+    pub min_freq: f32,
+    /// Top of the frequency axis in Hz
+    pub max_freq: f32,
+    /// Spacing of the frequency axis
+    pub freq_scale: FreqScale,
+    /// Mark every picked peak, i.e. the fingerprint constellation
+    pub show_peaks: bool,
+    /// Join each anchor to the peaks it is paired with, given the target zone
+    /// as (`min_target_zone_dist`, `max_target_zone`) frames
+    pub pairs: Option<(usize, usize)>,
+}
+
+impl Default for HeatmapView {
+    fn default() -> Self {
+        let fft = FftConfig::default();
+        Self {
+            start: 0.0,
+            end: None,
+            min_freq: fft.min_freq,
+            max_freq: fft.max_freq,
+            freq_scale: FreqScale::Linear,
+            show_peaks: false,
+            pairs: None,
+        }
     }
+}
 
-    let min_freq = FreqRange::Low.get_freq();
-    let max_freq = FreqRange::High.get_freq();
+/// Spacing of the heatmap's frequency axis
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum FreqScale {
+    /// Equal height per Hz
+    #[default]
+    Linear,
+    /// Equal height per octave, so the bass is not squashed
+    Log,
+    /// Mel scale, close to perceived pitch: linear below ~1 kHz, log above
+    Mel,
+}
 
-    let max_time = fingerprints
-        .last()
-        .map(|t| t.time.into_inner())
-        .unwrap()
-        .max(
-            fingerprints
-                .iter()
-                .map(|t| t.time.into_inner())
-                .fold(0.0f32, f32::max),
-        );
+impl FreqScale {
+    fn warp(&self, freq: f32) -> f32 {
+        match self {
+            FreqScale::Linear => freq,
+            FreqScale::Log => freq.ln(),
+            FreqScale::Mel => 2595.0 * (1.0 + freq / 700.0).log10(),
+        }
+    }
+
+    /// Tick frequencies in Hz, spread evenly on this scale
+    fn ticks(&self) -> &'static [f32] {
+        match self {
+            FreqScale::Linear => &[300.0, 500.0, 1000.0, 2000.0, 5000.0],
+            FreqScale::Log | FreqScale::Mel => &[
+                20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0,
+            ],
+        }
+    }
+}
+
+impl HeatmapView {
+    /// Height of `freq` on the frequency axis, 0 at `min_freq` and 1 at `max_freq`
+    fn freq_position(&self, freq: f32) -> f32 {
+        let scale = self.freq_scale;
+        (scale.warp(freq) - scale.warp(self.min_freq))
+            / (scale.warp(self.max_freq) - scale.warp(self.min_freq))
+    }
+}
+
+/// Decode `file`, run the STFT with the configured parameters and write the
+/// picked peaks as a heatmap to `out`, `spectro.svg` / `spectro.png` if not given
+pub fn run_visualize(
+    file: &str,
+    out: Option<&str>,
+    format: ImageFormat,
+    colormap: Colormap,
+    waveform: bool,
+    view: &HeatmapView,
+    config: &Config,
+) {
+    if view.min_freq >= view.max_freq || view.end.is_some_and(|end| end <= view.start) {
+        eprintln!("Error: the time range and frequency limits must be increasing");
+        std::process::exit(1);
+    }
+    if waveform && !matches!(format, ImageFormat::Svg) {
+        eprintln!("Error: --waveform is only available for SVG output");
+        std::process::exit(1);
+    }
+    if view.freq_scale == FreqScale::Log && view.min_freq <= 0.0 {
+        eprintln!("Error: a log frequency axis needs --min-freq above 0");
+        std::process::exit(1);
+    }
+
+    let audio_processor = AudioProcessor::from_config(&config.decode);
+
+    let (audio_samples, sample_rate) = audio_processor.get_decoded_audio(file.to_string());
+    let fft_distribution = Pipeline::from_config(config, HashScheme::default())
+        .peak_frames(audio_samples.clone(), sample_rate);
+
+    let out = out.map_or_else(|| format!("spectro.{}", format.extension()), str::to_string);
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let written = if waveform {
+        write_waveform_heatmap_svg(
+            &fft_distribution,
+            (&audio_samples, sample_rate),
+            &out,
+            name,
+            view,
+            colormap,
+        )
+    } else {
+        write_heatmap(&fft_distribution, &out, name, view, colormap, format)
+    };
+    match written {
+        Ok(()) => println!(
+            "✅ Wrote spectrogram of {} frames to {}",
+            fft_distribution.len(),
+            out
+        ),
+        Err(e) => {
+            eprintln!("❌ Failed to write {}: {}", out, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Output format of `visualize`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    /// Vector image with axis labels, one element per cell; large for long files
+    Svg,
+    /// Raster image of the same heatmap, compact at any length
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    /// PNG for a `.png` path, SVG otherwise
+    pub fn from_path(path: &str) -> Self {
+        let is_png = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+        if is_png {
+            ImageFormat::Png
+        } else {
+            ImageFormat::Svg
+        }
+    }
+}
+
+// Heat map parameters
+const TIME_BINS: usize = 400; // Number of time bins
+const FREQ_BINS: usize = 200; // Number of frequency bins
+
+const WIDTH: f32 = 1920.0;
+const HEIGHT: f32 = 1080.0;
+const PADDING_LEFT: f32 = 170.0; // extra space for y-axis labels
+const PADDING_RIGHT: f32 = 50.0;
+const PADDING_TOP: f32 = 70.0; // space for title
+const PADDING_BOTTOM: f32 = 60.0; // space for x-axis label
+
+/// Peak magnitudes binned into a `FREQ_BINS` × `TIME_BINS` grid
+struct Heatmap {
+    /// `cells[freq_bin][time_bin]`, lowest frequency first
+    cells: Vec<Vec<f32>>,
+    max_mag: f32,
+    min_time: f32,
+    max_time: f32,
+}
 
-    // Heat map parameters
-    let time_bins = 400; // Number of time bins
-    let freq_bins = 200; // Number of frequency bins
+/// Bin the frames inside `view`, `None` if there are none
+fn build_heatmap(fingerprints: &[FFTDistribution], view: &HeatmapView) -> Option<Heatmap> {
+    let min_time = view.start;
+    let fingerprints: Vec<&FFTDistribution> = fingerprints
+        .iter()
+        .filter(|f| {
+            let time = f.time.into_inner();
+            time >= min_time && view.end.is_none_or(|end| time <= end)
+        })
+        .collect();
+
+    if fingerprints.is_empty() {
+        return None;
+    }
+
+    let min_freq = view.min_freq;
+    let max_freq = view.max_freq;
+
+    let max_time = view.end.unwrap_or_else(|| {
+        fingerprints
+            .iter()
+            .map(|t| t.time.into_inner())
+            .fold(min_time, f32::max)
+    });
+    // A single frame still gets a non-empty axis
+    let time_span = (max_time - min_time).max(f32::EPSILON);
 
     // Create heat map grid
-    let mut heatmap = vec![vec![0.0f32; time_bins]; freq_bins];
+    let mut heatmap = vec![vec![0.0f32; TIME_BINS]; FREQ_BINS];
 
     // Fill heat map with data
     for fingerprint in fingerprints.iter() {
         let time = fingerprint.time.into_inner();
-        let time_bin = ((time / max_time) * (time_bins - 1) as f32)
-            .clamp(0.0, (time_bins - 1) as f32) as usize;
+        let time_bin = (((time - min_time) / time_span) * (TIME_BINS - 1) as f32)
+            .clamp(0.0, (TIME_BINS - 1) as f32) as usize;
 
         for peak in &fingerprint.peaks {
             let freq = peak.freq.into_inner();
             let mag = peak.magnitude.into_inner();
 
             if freq >= min_freq && freq <= max_freq && mag.is_finite() {
-                let freq_bin = (((freq - min_freq) / (max_freq - min_freq))
-                    * (freq_bins - 1) as f32)
-                    .clamp(0.0, (freq_bins - 1) as f32) as usize;
+                let freq_bin = (view.freq_position(freq) * (FREQ_BINS - 1) as f32)
+                    .clamp(0.0, (FREQ_BINS - 1) as f32) as usize;
 
                 // Accumulate magnitude in the bin (use max to avoid double counting)
                 heatmap[freq_bin][time_bin] = heatmap[freq_bin][time_bin].max(mag);
@@ -72,6 +249,141 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
         .flatten()
         .fold(0.0f32, |acc, &val| acc.max(val));
 
+    Some(Heatmap {
+        cells: heatmap,
+        max_mag,
+        min_time,
+        max_time,
+    })
+}
+
+/// Peaks and anchor → target pairs inside the view, in image coordinates
+#[derive(Default)]
+struct Constellation {
+    points: Vec<(f32, f32)>,
+    pairs: Vec<[(f32, f32); 2]>,
+}
+
+/// Place the overlay requested by `view` on the cells of `heatmap`
+fn build_constellation(
+    fingerprints: &[FFTDistribution],
+    heatmap: &Heatmap,
+    view: &HeatmapView,
+) -> Constellation {
+    let plot_w = WIDTH - PADDING_LEFT - PADDING_RIGHT;
+    let plot_h = HEIGHT - PADDING_TOP - PADDING_BOTTOM;
+    let time_span = (heatmap.max_time - heatmap.min_time).max(f32::EPSILON);
+
+    // Center of the cell a peak was binned into, if it is in view
+    let project = |time: f32, freq: f32| -> Option<(f32, f32)> {
+        if time < heatmap.min_time
+            || time > heatmap.max_time
+            || freq < view.min_freq
+            || freq > view.max_freq
+        {
+            return None;
+        }
+        let time_pos = (time - heatmap.min_time) / time_span * (TIME_BINS - 1) as f32;
+        let freq_pos = view.freq_position(freq) * (FREQ_BINS - 1) as f32;
+        Some((
+            PADDING_LEFT + (time_pos.floor() + 0.5) * plot_w / TIME_BINS as f32,
+            PADDING_TOP + plot_h - (freq_pos.floor() + 0.5) * plot_h / FREQ_BINS as f32,
+        ))
+    };
+
+    let mut constellation = Constellation::default();
+    for (idx, frame) in fingerprints.iter().enumerate() {
+        let time = frame.time.into_inner();
+        for anchor in &frame.peaks {
+            let Some(anchor_point) = project(time, anchor.freq.into_inner()) else {
+                continue;
+            };
+            if view.show_peaks {
+                constellation.points.push(anchor_point);
+            }
+
+            // Same target zone as `generate_audio_fingerprint`
+            let Some((min_dist, max_zone)) = view.pairs else {
+                continue;
+            };
+            let start_idx = idx + min_dist;
+            let end_idx = (idx + max_zone).min(fingerprints.len());
+            if start_idx >= end_idx {
+                continue;
+            }
+            for slice in &fingerprints[start_idx..end_idx] {
+                for target in &slice.peaks {
+                    if let Some(target_point) =
+                        project(slice.time.into_inner(), target.freq.into_inner())
+                    {
+                        constellation.pairs.push([anchor_point, target_point]);
+                    }
+                }
+            }
+        }
+    }
+    constellation
+}
+
+/// Write the heatmap in `format`
+pub fn write_heatmap<P: AsRef<Path>>(
+    fingerprints: &[FFTDistribution],
+    output_path: P,
+    song_name: &str,
+    view: &HeatmapView,
+    colormap: Colormap,
+    format: ImageFormat,
+) -> std::io::Result<()> {
+    match format {
+        ImageFormat::Svg => write_heatmap_svg(fingerprints, output_path, song_name, view, colormap),
+        ImageFormat::Png => write_heatmap_png(fingerprints, output_path, view, colormap),
+    }
+}
+
+pub fn write_heatmap_svg<P: AsRef<Path>>(
+    fingerprints: &[FFTDistribution],
+    output_path: P,
+    song_name: &str,
+    view: &HeatmapView,
+    colormap: Colormap,
+) -> std::io::Result<()> {
+    let svg = heatmap_svg(fingerprints, song_name, view, colormap);
+    let mut file = File::create(output_path)?;
+    file.write_all(svg.as_bytes())?;
+    Ok(())
+}
+
+/// The SVG document written by [`write_heatmap_svg`]
+fn heatmap_svg(
+    fingerprints: &[FFTDistribution],
+    song_name: &str,
+    view: &HeatmapView,
+    colormap: Colormap,
+) -> String {
+    let (width, height) = (WIDTH, HEIGHT);
+
+    let Some(heatmap) = build_heatmap(fingerprints, view) else {
+        let empty_svg = format!(
+            "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n  <rect width='100%' height='100%' fill='black'/>\n  <text x='{cx}' y='{cy}' fill='white' font-family='monospace' font-size='20' text-anchor='middle'>No fingerprints</text>\n</svg>",
+            w = width,
+            h = height,
+            cx = width / 2.0,
+            cy = height / 2.0
+        );
+        return empty_svg;
+    };
+    let constellation = build_constellation(fingerprints, &heatmap, view);
+    let Heatmap {
+        cells: heatmap,
+        max_mag,
+        min_time,
+        max_time,
+    } = heatmap;
+
+    let min_freq = view.min_freq;
+    let max_freq = view.max_freq;
+    let (time_bins, freq_bins) = (TIME_BINS, FREQ_BINS);
+
     if max_mag <= 0.0 {
         let empty_svg = format!(
             "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n  <rect width='100%' height='100%' fill='black'/>\n  <text x='{cx}' y='{cy}' fill='white' font-family='monospace' font-size='20' text-anchor='middle'>No data to visualize</text>\n</svg>",
@@ -80,15 +392,11 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
             cx = width / 2.0,
             cy = height / 2.0
         );
-        let mut f = File::create(output_path)?;
-        f.write_all(empty_svg.as_bytes())?;
-        return Ok(());
+        return empty_svg;
     }
 
-    let padding_left = 170.0f32; // extra space for y-axis labels
-    let padding_right = 50.0f32;
-    let padding_top = 70.0f32; // space for title
-    let padding_bottom = 60.0f32; // space for x-axis label
+    let (padding_left, padding_right) = (PADDING_LEFT, PADDING_RIGHT);
+    let (padding_top, padding_bottom) = (PADDING_TOP, PADDING_BOTTOM);
     let plot_w = width - padding_left - padding_right;
     let plot_h = height - padding_top - padding_bottom;
 
@@ -107,22 +415,25 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
     );
 
     // y-axis ticks and labels
-    let tick_freqs: [f32; 5] = [300.0, 500.0, 1000.0, 2000.0, 5000.0];
     let mut y_ticks = String::new();
-    for f in tick_freqs.iter() {
+    for f in view
+        .freq_scale
+        .ticks()
+        .iter()
+        .filter(|f| (min_freq..=max_freq).contains(*f))
+    {
         let y = {
-            let clamped = f.clamp(min_freq, max_freq);
-            let norm = (clamped - min_freq) / (max_freq - min_freq);
+            let norm = view.freq_position(*f);
             padding_top + (1.0 - norm) * plot_h
         };
         y_ticks.push_str(&format!(
-            "<g>\n  <line x1='{x1:.2}' y1='{y:.2}' x2='{x2:.2}' y2='{y:.2}' stroke='white' stroke-opacity='0.25' stroke-width='1'/>\n  <text x='{tx:.2}' y='{ty:.2}' fill='white' font-family='monospace' font-size='11' text-anchor='end'>{label}</text>\n</g>\n",
+            "<g>\n  <line x1='{x1:.2}' y1='{y:.2}' x2='{x2:.2}' y2='{y:.2}' stroke='white' stroke-opacity='0.25' stroke-width='1'/>\n  <text x='{tx:.2}' y='{ty:.2}' fill='white' font-family='monospace' font-size='11' text-anchor='end'>{label} Hz</text>\n</g>\n",
             x1 = padding_left - 6.0,
             x2 = padding_left + plot_w,
             y = y,
             tx = padding_left - 10.0,
             ty = y + 4.0,
-            label = format!("{} Hz", *f as i32)
+            label = *f as i32
         ));
     }
 
@@ -140,7 +451,7 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
 
                 // Normalize magnitude and convert to color
                 let normalized_mag = (magnitude / max_mag).clamp(0.0, 1.0);
-                let color = magnitude_to_color(normalized_mag);
+                let color = colormap.hex(normalized_mag);
 
                 heatmap_rects.push_str(&format!(
                     "<rect x='{x:.2}' y='{y:.2}' width='{w:.2}' height='{h:.2}' fill='{color}'/>\n",
@@ -154,13 +465,28 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
         }
     }
 
+    // Pairing lines under the peaks they connect
+    let mut overlay = String::new();
+    for [(x1, y1), (x2, y2)] in &constellation.pairs {
+        overlay.push_str(&format!(
+            "<line x1='{x1:.2}' y1='{y1:.2}' x2='{x2:.2}' y2='{y2:.2}' stroke='white' stroke-opacity='0.15' stroke-width='1'/>\n"
+        ));
+    }
+    for (x, y) in &constellation.points {
+        overlay.push_str(&format!(
+            "<circle cx='{x:.2}' cy='{y:.2}' r='2.5' fill='white'/>\n"
+        ));
+    }
+
     let labels = format!(
-        "<g fill='white' font-family='monospace' font-size='12'>\n  <text x='{px}' y='{py}' text-anchor='start'>freq: {min} Hz → {max} Hz</text>\n  <text x='{px}' y='{py2}' dy='20' text-anchor='start'>time: 0 → {tmax:.2}s</text>\n</g>",
+        "<g fill='white' font-family='monospace' font-size='12'>\n  <text x='{px}' y='{py}' text-anchor='start'>freq: {min} Hz → {max} Hz ({scale:?})</text>\n  <text x='{px}' y='{py2}' dy='20' text-anchor='start'>time: {tmin:.2}s → {tmax:.2}s</text>\n</g>",
         px = padding_left,
         py = padding_top - 10.0,
         py2 = padding_top + plot_h,
         min = min_freq as i32,
         max = max_freq as i32,
+        scale = view.freq_scale,
+        tmin = min_time,
         tmax = max_time,
     );
 
@@ -172,59 +498,579 @@ pub fn write_heatmap_svg<P: AsRef<Path>>(
     );
 
     let svg = format!(
-        "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n{bg}\n{axes}\n{y_ticks}<g>\n{heatmap_rects}</g>\n{labels}\n{title}\n</svg>",
+        "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n{bg}\n{axes}\n{y_ticks}<g>\n{heatmap_rects}</g>\n<g>\n{overlay}</g>\n{labels}\n{title}\n</svg>",
         w = width,
         h = height,
         bg = bg,
         axes = axes,
         y_ticks = y_ticks,
         heatmap_rects = heatmap_rects,
+        overlay = overlay,
         labels = labels,
         title = title,
     );
 
+    svg
+}
+
+/// Waveform of the raw decoded audio stacked above the heatmap, sharing its
+/// time axis, so silence and clipping line up with the peaks they affect.
+/// `samples` are mono at `sample_rate`.
+pub fn write_waveform_heatmap_svg<P: AsRef<Path>>(
+    fingerprints: &[FFTDistribution],
+    (samples, sample_rate): (&[f32], u32),
+    output_path: P,
+    song_name: &str,
+    view: &HeatmapView,
+    colormap: Colormap,
+) -> std::io::Result<()> {
+    // Same time range as `build_heatmap`
+    let end = view.end.unwrap_or_else(|| {
+        fingerprints
+            .iter()
+            .map(|f| f.time.into_inner())
+            .filter(|&time| time >= view.start)
+            .fold(view.start, f32::max)
+    });
+
+    let svg = format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n<rect x='0' y='0' width='{w}' height='{h}' fill='black'/>\n{waveform}<g transform='translate(0 {wh})'>\n{heatmap}\n</g>\n</svg>",
+        w = WIDTH,
+        h = HEIGHT + WAVEFORM_HEIGHT,
+        wh = WAVEFORM_HEIGHT,
+        waveform = waveform_svg(samples, sample_rate, view.start, end),
+        heatmap = heatmap_svg(fingerprints, song_name, view, colormap),
+    );
     let mut file = File::create(output_path)?;
-    file.write_all(svg.as_bytes())?;
-    Ok(())
+    file.write_all(svg.as_bytes())
+}
+
+/// Height of the waveform panel above a stacked heatmap
+const WAVEFORM_HEIGHT: f32 = 300.0;
+
+/// Samples at or beyond this level count as clipped
+const CLIP_LEVEL: f32 = 0.99;
+
+/// Lowest and highest sample in each of `columns` equal slices of
+/// `start..end` seconds, `None` past the end of the audio
+fn waveform_envelope(
+    samples: &[f32],
+    sample_rate: u32,
+    start: f32,
+    end: f32,
+    columns: usize,
+) -> Vec<Option<(f32, f32)>> {
+    let seconds_per_column = (end - start) / columns as f32;
+    let sample_index = |seconds: f32| (seconds.max(0.0) * sample_rate as f32) as usize;
+
+    (0..columns)
+        .map(|column| {
+            let from = sample_index(start + column as f32 * seconds_per_column);
+            let to = sample_index(start + (column + 1) as f32 * seconds_per_column)
+                .max(from + 1)
+                .min(samples.len());
+            let slice = samples.get(from..to).filter(|slice| !slice.is_empty())?;
+            Some(
+                slice
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &sample| {
+                        (lo.min(sample), hi.max(sample))
+                    }),
+            )
+        })
+        .collect()
 }
 
-fn magnitude_to_color(normalized_mag: f32) -> String {
-    // Create a color gradient from black (low) to bright colors (high)
-    // Using a perceptually uniform color scheme: black -> blue -> cyan -> yellow -> red
-
-    if normalized_mag <= 0.0 {
-        return "#000000".to_string(); // Black for no data
-    }
-
-    let clamped = normalized_mag.clamp(0.0, 1.0);
-
-    if clamped < 0.2 {
-        // Black to dark blue
-        let intensity = (clamped / 0.2) * 0.3;
-        format!("#{:02x}{:02x}{:02x}", 0, 0, (intensity * 255.0) as u8)
-    } else if clamped < 0.4 {
-        // Dark blue to blue
-        let intensity = ((clamped - 0.2) / 0.2) * 0.5 + 0.3;
-        format!("#{:02x}{:02x}{:02x}", 0, 0, (intensity * 255.0) as u8)
-    } else if clamped < 0.6 {
-        // Blue to cyan
-        let intensity = ((clamped - 0.4) / 0.2) * 0.5 + 0.5;
-        let green = (intensity * 255.0) as u8;
-        format!("#{:02x}{:02x}{:02x}", 0, green, 255)
-    } else if clamped < 0.8 {
-        // Cyan to yellow
-        let intensity = ((clamped - 0.6) / 0.2) * 0.5 + 0.5;
-        let red = (intensity * 255.0) as u8;
-        let green = 255;
-        let blue = ((1.0 - intensity) * 255.0) as u8;
-        format!("#{:02x}{:02x}{:02x}", red, green, blue)
+/// Min/max envelope of `start..end` seconds as one SVG group, one pixel
+/// column per slice, laid out over the heatmap's plot area. Clipped columns
+/// are drawn red.
+fn waveform_svg(samples: &[f32], sample_rate: u32, start: f32, end: f32) -> String {
+    let plot_w = WIDTH - PADDING_LEFT - PADDING_RIGHT;
+    let (top, bottom) = (40.0, WAVEFORM_HEIGHT - 20.0);
+    let center = (top + bottom) / 2.0;
+    let half_h = (bottom - top) / 2.0;
+
+    let envelope = waveform_envelope(samples, sample_rate, start, end, plot_w as usize);
+    let mut normal_path = String::new();
+    let mut clipped_path = String::new();
+    for (column, range) in envelope.iter().enumerate() {
+        let Some((lo, hi)) = *range else {
+            continue;
+        };
+        let x = PADDING_LEFT + column as f32 + 0.5;
+        let path = if hi >= CLIP_LEVEL || lo <= -CLIP_LEVEL {
+            &mut clipped_path
+        } else {
+            &mut normal_path
+        };
+        // Keep near-silent columns one pixel tall so they stay visible
+        let (y_hi, y_lo) = (
+            center - hi.clamp(-1.0, 1.0) * half_h,
+            center - lo.clamp(-1.0, 1.0) * half_h,
+        );
+        path.push_str(&format!(
+            "M{x:.1} {:.1}V{:.1}",
+            y_hi.min(center - 0.5),
+            y_lo.max(center + 0.5)
+        ));
+    }
+
+    let peak = envelope
+        .iter()
+        .flatten()
+        .fold(0.0f32, |acc, &(lo, hi)| acc.max(lo.abs()).max(hi.abs()));
+    let peak_label = if peak > 0.0 {
+        format!("peak {:.1} dBFS", 20.0 * peak.log10())
     } else {
-        // Yellow to red
-        let intensity = ((clamped - 0.8) / 0.2) * 0.5 + 0.5;
-        let red = 255;
-        let green = ((1.0 - intensity) * 255.0) as u8;
-        let blue = 0;
-        format!("#{:02x}{:02x}{:02x}", red, green, blue)
+        "silent".to_string()
+    };
+    let clipped_columns = envelope
+        .iter()
+        .flatten()
+        .filter(|&&(lo, hi)| hi >= CLIP_LEVEL || lo <= -CLIP_LEVEL)
+        .count();
+
+    format!(
+        "<g>\n<line x1='{x1}' y1='{center}' x2='{x2}' y2='{center}' stroke='white' stroke-opacity='0.25'/>\n<path d='{normal_path}' stroke='#4080ff' stroke-width='1'/>\n<path d='{clipped_path}' stroke='#ff3030' stroke-width='1'/>\n<text x='{x1}' y='{ty}' fill='white' font-family='monospace' font-size='12'>waveform: {peak_label}, {clipped_columns} clipped columns</text>\n</g>\n",
+        x1 = PADDING_LEFT,
+        x2 = PADDING_LEFT + plot_w,
+        ty = top - 10.0,
+    )
+}
+
+/// Raster version of [`write_heatmap_svg`] with the same layout and colors.
+/// Text is left out; the file size no longer grows with the number of cells.
+pub fn write_heatmap_png<P: AsRef<Path>>(
+    fingerprints: &[FFTDistribution],
+    output_path: P,
+    view: &HeatmapView,
+    colormap: Colormap,
+) -> std::io::Result<()> {
+    let (width, height) = (WIDTH as u32, HEIGHT as u32);
+    let mut image = RgbImage::new(width, height);
+
+    if let Some(heatmap) = build_heatmap(fingerprints, view).filter(|h| h.max_mag > 0.0) {
+        let (left, top) = (PADDING_LEFT as u32, PADDING_TOP as u32);
+        let plot_w = width - left - PADDING_RIGHT as u32;
+        let plot_h = height - top - PADDING_BOTTOM as u32;
+
+        for y in 0..plot_h {
+            // Row 0 of the image is the highest frequency
+            let freq_bin = FREQ_BINS - 1 - (y as usize * FREQ_BINS / plot_h as usize);
+            for x in 0..plot_w {
+                let time_bin = x as usize * TIME_BINS / plot_w as usize;
+                let magnitude = heatmap.cells[freq_bin][time_bin];
+                if magnitude > 0.0 {
+                    let normalized_mag = (magnitude / heatmap.max_mag).clamp(0.0, 1.0);
+                    image.put_pixel(left + x, top + y, Rgb(colormap.rgb(normalized_mag)));
+                }
+            }
+        }
+
+        // Axes, white at the SVG's 0.6 opacity
+        let axis = Rgb([153, 153, 153]);
+        for y in top..=top + plot_h {
+            image.put_pixel(left, y, axis);
+        }
+        for x in left..=left + plot_w {
+            image.put_pixel(x, top + plot_h, axis);
+        }
+
+        let constellation = build_constellation(fingerprints, &heatmap, view);
+        let white = Rgb([255, 255, 255]);
+        for &[from, to] in &constellation.pairs {
+            draw_line(&mut image, from, to, white, 0.15);
+        }
+        for &point in &constellation.points {
+            draw_dot(&mut image, point, white);
+        }
+    }
+
+    image
+        .save_with_format(output_path, image::ImageFormat::Png)
+        .map_err(std::io::Error::other)
+}
+
+/// Scatter plot of a candidate's hash hits, query time against song time.
+///
+/// A true match shows up as a dense diagonal `song = query + time_offset`;
+/// hits in the winning offset bin (`bin_width` wide) are highlighted and the
+/// diagonal is drawn through them. The format follows the extension of
+/// `output_path`.
+pub fn write_alignment_plot(
+    output_path: &str,
+    title: &str,
+    hits: &[(f32, f32)],
+    time_offset: f32,
+    bin_width: f32,
+) -> std::io::Result<()> {
+    let plot_w = WIDTH - PADDING_LEFT - PADDING_RIGHT;
+    let plot_h = HEIGHT - PADDING_TOP - PADDING_BOTTOM;
+
+    let max_query = hits.iter().map(|h| h.0).fold(0.0f32, f32::max);
+    let min_song = hits.iter().map(|h| h.1).fold(f32::INFINITY, f32::min);
+    let max_song = hits.iter().map(|h| h.1).fold(f32::NEG_INFINITY, f32::max);
+    let query_span = max_query.max(f32::EPSILON);
+    let song_span = (max_song - min_song).max(f32::EPSILON);
+
+    let to_xy = |query: f32, song: f32| -> (f32, f32) {
+        (
+            PADDING_LEFT + query / query_span * plot_w,
+            PADDING_TOP + plot_h - (song - min_song) / song_span * plot_h,
+        )
+    };
+    let winning_bin = (time_offset / bin_width).round() as i32;
+    let is_winning =
+        |&(query, song): &(f32, f32)| ((song - query) / bin_width).round() as i32 == winning_bin;
+
+    // The winning diagonal, cut to the plotted song-time range
+    let diagonal_start = (min_song - time_offset).max(0.0);
+    let diagonal_end = (max_song - time_offset).min(max_query);
+    let diagonal = (!hits.is_empty() && diagonal_start < diagonal_end).then(|| {
+        [
+            to_xy(diagonal_start, diagonal_start + time_offset),
+            to_xy(diagonal_end, diagonal_end + time_offset),
+        ]
+    });
+
+    match ImageFormat::from_path(output_path) {
+        ImageFormat::Svg => {
+            let mut points = String::new();
+            for hit in hits {
+                let (x, y) = to_xy(hit.0, hit.1);
+                let color = if is_winning(hit) {
+                    "#ff3030"
+                } else {
+                    "#4080ff"
+                };
+                points.push_str(&format!(
+                    "<circle cx='{x:.2}' cy='{y:.2}' r='2' fill='{color}' fill-opacity='0.7'/>\n"
+                ));
+            }
+            let diagonal_line = diagonal.map_or_else(String::new, |[(x1, y1), (x2, y2)]| {
+                format!(
+                    "<line x1='{x1:.2}' y1='{y1:.2}' x2='{x2:.2}' y2='{y2:.2}' stroke='white' stroke-opacity='0.5' stroke-dasharray='6 4'/>\n"
+                )
+            });
+            let winning = hits.iter().filter(|h| is_winning(h)).count();
+
+            let svg = format!(
+                "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n<rect x='0' y='0' width='{w}' height='{h}' fill='black'/>\n<g stroke='white' stroke-width='1' opacity='0.6'>\n  <line x1='{px}' y1='{py}' x2='{px}' y2='{py2}'/>\n  <line x1='{px}' y1='{py2}' x2='{px2}' y2='{py2}'/>\n</g>\n{diagonal_line}<g>\n{points}</g>\n<g fill='white' font-family='monospace' font-size='12'>\n  <text x='{px}' y='{py}' dy='-10' text-anchor='start'>song time: {smin:.2}s → {smax:.2}s</text>\n  <text x='{px}' y='{py2}' dy='20' text-anchor='start'>query time: 0 → {qmax:.2}s</text>\n  <text x='{px2}' y='{py2}' dy='20' text-anchor='end'>{total} hits, {winning} in the winning bin (offset {offset:.2}s)</text>\n</g>\n<text x='{px2}' y='{ty}' fill='white' font-family='monospace' font-size='14' text-anchor='end'>{name}</text>\n</svg>",
+                w = WIDTH,
+                h = HEIGHT,
+                px = PADDING_LEFT,
+                py = PADDING_TOP,
+                px2 = PADDING_LEFT + plot_w,
+                py2 = PADDING_TOP + plot_h,
+                ty = PADDING_TOP - 20.0,
+                smin = if hits.is_empty() { 0.0 } else { min_song },
+                smax = if hits.is_empty() { 0.0 } else { max_song },
+                qmax = max_query,
+                total = hits.len(),
+                offset = time_offset,
+                name = svg_escape(title),
+            );
+            let mut file = File::create(output_path)?;
+            file.write_all(svg.as_bytes())
+        }
+        ImageFormat::Png => {
+            let (width, height) = (WIDTH as u32, HEIGHT as u32);
+            let mut image = RgbImage::new(width, height);
+
+            let axis = Rgb([153, 153, 153]);
+            let (left, top) = (PADDING_LEFT, PADDING_TOP);
+            draw_line(&mut image, (left, top), (left, top + plot_h), axis, 1.0);
+            draw_line(
+                &mut image,
+                (left, top + plot_h),
+                (left + plot_w, top + plot_h),
+                axis,
+                1.0,
+            );
+            if let Some([from, to]) = diagonal {
+                draw_line(&mut image, from, to, Rgb([255, 255, 255]), 0.5);
+            }
+            for hit in hits {
+                let (x, y) = to_xy(hit.0, hit.1);
+                let color = if is_winning(hit) {
+                    Rgb([255, 48, 48])
+                } else {
+                    Rgb([64, 128, 255])
+                };
+                draw_dot(&mut image, (x, y), color);
+            }
+
+            image
+                .save_with_format(output_path, image::ImageFormat::Png)
+                .map_err(std::io::Error::other)
+        }
+    }
+}
+
+/// Offset-bin votes of one candidate song
+pub struct VoteHistogram {
+    pub label: String,
+    /// Votes per offset bin, see [`crate::fingerprint::offset_histogram`]
+    pub bins: BTreeMap<i32, usize>,
+}
+
+/// x, y, width and height of a bar, and whether it is the winning bin
+type Bar = (f32, f32, f32, f32, bool);
+
+/// Bar charts of the offset votes of each candidate, stacked top to bottom.
+///
+/// A decisive match is one tall bar (drawn red) over a flat floor; several
+/// bars of similar height suggest `vote_bin_width` is too narrow or the match
+/// is spurious. The format follows the extension of `output_path`.
+pub fn write_vote_histograms(
+    output_path: &str,
+    histograms: &[VoteHistogram],
+    bin_width: f32,
+) -> std::io::Result<()> {
+    let plot_w = WIDTH - PADDING_LEFT - PADDING_RIGHT;
+    let plot_h = HEIGHT - PADDING_TOP - PADDING_BOTTOM;
+    let panel_gap = 40.0;
+    let panel_h = (plot_h - panel_gap * (histograms.len().max(1) - 1) as f32)
+        / histograms.len().max(1) as f32;
+
+    let panels: Vec<Vec<Bar>> = histograms
+        .iter()
+        .enumerate()
+        .map(|(i, histogram)| {
+            let (Some((&first, _)), Some((&last, _))) = (
+                histogram.bins.first_key_value(),
+                histogram.bins.last_key_value(),
+            ) else {
+                return Vec::new();
+            };
+            let max_votes = histogram.bins.values().copied().max().unwrap_or(1) as f32;
+            let bar_w = plot_w / (last - first + 1) as f32;
+            let bottom = PADDING_TOP + i as f32 * (panel_h + panel_gap) + panel_h;
+            histogram
+                .bins
+                .iter()
+                .map(|(&bin, &votes)| {
+                    let bar_h = votes as f32 / max_votes * panel_h;
+                    (
+                        PADDING_LEFT + (bin - first) as f32 * bar_w,
+                        bottom - bar_h,
+                        // Keep single bars of wide ranges visible
+                        bar_w.max(1.0),
+                        bar_h,
+                        votes as f32 == max_votes,
+                    )
+                })
+                .collect()
+        })
+        .collect();
+
+    match ImageFormat::from_path(output_path) {
+        ImageFormat::Svg => {
+            let mut body = String::new();
+            for (i, (histogram, bars)) in histograms.iter().zip(&panels).enumerate() {
+                let top = PADDING_TOP + i as f32 * (panel_h + panel_gap);
+                let bottom = top + panel_h;
+                body.push_str(&format!(
+                    "<line x1='{x1}' y1='{y:.2}' x2='{x2}' y2='{y:.2}' stroke='white' stroke-opacity='0.6'/>\n",
+                    x1 = PADDING_LEFT,
+                    x2 = PADDING_LEFT + plot_w,
+                    y = bottom,
+                ));
+                for &(x, y, w, h, best) in bars {
+                    let color = if best { "#ff3030" } else { "#4080ff" };
+                    body.push_str(&format!(
+                        "<rect x='{x:.2}' y='{y:.2}' width='{w:.2}' height='{h:.2}' fill='{color}'/>\n"
+                    ));
+                }
+
+                let range = match (
+                    histogram.bins.first_key_value(),
+                    histogram.bins.last_key_value(),
+                ) {
+                    (Some((&first, _)), Some((&last, _))) => format!(
+                        "offset {:.2}s → {:.2}s",
+                        first as f32 * bin_width,
+                        last as f32 * bin_width
+                    ),
+                    _ => "no votes".to_string(),
+                };
+                let best = histogram.bins.iter().max_by_key(|&(_, &votes)| votes);
+                let summary = best.map_or_else(String::new, |(&bin, &votes)| {
+                    format!(
+                        ", best {} votes at {:.2}s of {} total",
+                        votes,
+                        bin as f32 * bin_width,
+                        histogram.bins.values().sum::<usize>()
+                    )
+                });
+                body.push_str(&format!(
+                    "<text x='{x}' y='{y:.2}' fill='white' font-family='monospace' font-size='12'>{label}: {range}{summary}</text>\n",
+                    x = PADDING_LEFT,
+                    y = top - 8.0,
+                    label = svg_escape(&histogram.label),
+                ));
+            }
+
+            let svg = format!(
+                "<svg xmlns='http://www.w3.org/2000/svg' width='{w}' height='{h}' viewBox='0 0 {w} {h}'>\n<rect x='0' y='0' width='{w}' height='{h}' fill='black'/>\n{body}<text x='{tx}' y='{ty}' fill='white' font-family='monospace' font-size='14' text-anchor='end'>offset votes per {bw:.3}s bin</text>\n</svg>",
+                w = WIDTH,
+                h = HEIGHT,
+                tx = PADDING_LEFT + plot_w,
+                ty = PADDING_TOP - 40.0,
+                bw = bin_width,
+            );
+            let mut file = File::create(output_path)?;
+            file.write_all(svg.as_bytes())
+        }
+        ImageFormat::Png => {
+            let mut image = RgbImage::new(WIDTH as u32, HEIGHT as u32);
+            for (i, bars) in panels.iter().enumerate() {
+                let bottom = PADDING_TOP + i as f32 * (panel_h + panel_gap) + panel_h;
+                draw_line(
+                    &mut image,
+                    (PADDING_LEFT, bottom),
+                    (PADDING_LEFT + plot_w, bottom),
+                    Rgb([153, 153, 153]),
+                    1.0,
+                );
+                for &(x, y, w, h, best) in bars {
+                    let color = if best {
+                        Rgb([255, 48, 48])
+                    } else {
+                        Rgb([64, 128, 255])
+                    };
+                    for px in x as u32..(x + w).ceil() as u32 {
+                        for py in y as u32..(y + h).ceil() as u32 {
+                            if px < image.width() && py < image.height() {
+                                image.put_pixel(px, py, color);
+                            }
+                        }
+                    }
+                }
+            }
+
+            image
+                .save_with_format(output_path, image::ImageFormat::Png)
+                .map_err(std::io::Error::other)
+        }
+    }
+}
+
+/// 3x3 dot centered on `(x, y)`
+fn draw_dot(image: &mut RgbImage, (x, y): (f32, f32), color: Rgb<u8>) {
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let (px, py) = (x as i32 + dx, y as i32 + dy);
+            if px >= 0 && py >= 0 && (px as u32) < image.width() && (py as u32) < image.height() {
+                image.put_pixel(px as u32, py as u32, color);
+            }
+        }
+    }
+}
+
+/// Blend a straight line from `from` to `to` over the image
+fn draw_line(image: &mut RgbImage, from: (f32, f32), to: (f32, f32), color: Rgb<u8>, opacity: f32) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as u32;
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let (x, y) = ((from.0 + dx * t) as u32, (from.1 + dy * t) as u32);
+        if x >= image.width() || y >= image.height() {
+            continue;
+        }
+        let pixel = image.get_pixel_mut(x, y);
+        for (channel, &target) in pixel.0.iter_mut().zip(color.0.iter()) {
+            *channel = (*channel as f32 * (1.0 - opacity) + target as f32 * opacity) as u8;
+        }
+    }
+}
+
+/// Color scale of the heatmap
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Colormap {
+    /// Dark purple → teal → yellow
+    #[default]
+    Viridis,
+    /// Black → purple → pink → pale yellow
+    Magma,
+    /// Black → purple → orange → pale yellow
+    Inferno,
+    /// Black → white
+    Grayscale,
+}
+
+// Evenly spaced samples of the matplotlib colormaps, interpolated linearly
+const VIRIDIS: [[u8; 3]; 11] = [
+    [68, 1, 84],
+    [72, 36, 117],
+    [65, 68, 135],
+    [53, 95, 141],
+    [42, 120, 142],
+    [33, 145, 140],
+    [34, 168, 132],
+    [68, 191, 112],
+    [122, 209, 81],
+    [189, 223, 38],
+    [253, 231, 37],
+];
+const MAGMA: [[u8; 3]; 11] = [
+    [0, 0, 4],
+    [20, 14, 54],
+    [59, 15, 112],
+    [100, 26, 128],
+    [140, 41, 129],
+    [183, 55, 121],
+    [222, 73, 104],
+    [247, 112, 92],
+    [254, 159, 109],
+    [254, 207, 146],
+    [252, 253, 191],
+];
+const INFERNO: [[u8; 3]; 11] = [
+    [0, 0, 4],
+    [22, 11, 57],
+    [66, 10, 104],
+    [106, 23, 110],
+    [147, 38, 103],
+    [188, 55, 84],
+    [221, 81, 58],
+    [243, 120, 25],
+    [252, 165, 10],
+    [246, 215, 70],
+    [252, 255, 164],
+];
+const GRAYSCALE: [[u8; 3]; 2] = [[0, 0, 0], [255, 255, 255]];
+
+impl Colormap {
+    fn table(&self) -> &'static [[u8; 3]] {
+        match self {
+            Colormap::Viridis => &VIRIDIS,
+            Colormap::Magma => &MAGMA,
+            Colormap::Inferno => &INFERNO,
+            Colormap::Grayscale => &GRAYSCALE,
+        }
+    }
+
+    /// Color of a magnitude normalized to 0..1
+    fn rgb(&self, normalized_mag: f32) -> [u8; 3] {
+        let table = self.table();
+        let position = normalized_mag.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+        let lower = (position.floor() as usize).min(table.len() - 2);
+        let fraction = position - lower as f32;
+
+        let mut rgb = [0u8; 3];
+        for (channel, value) in rgb.iter_mut().enumerate() {
+            let from = table[lower][channel] as f32;
+            let to = table[lower + 1][channel] as f32;
+            *value = (from + (to - from) * fraction).round() as u8;
+        }
+        rgb
+    }
+
+    /// `rgb` as an SVG hex color
+    fn hex(&self, normalized_mag: f32) -> String {
+        let [r, g, b] = self.rgb(normalized_mag);
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
     }
 }
 